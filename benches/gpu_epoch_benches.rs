@@ -108,6 +108,7 @@ fn setup_inputs(config: &Config) -> (Data, Results, GPU, ResultsGPU, EpochKernel
             .sensors
             .count() as i32,
         results.estimations.measurements.num_steps() as i32,
+        data.simulation.sample_rate_hz,
     );
 
     (data, results, gpu, results_gpu, epoch_kernel)
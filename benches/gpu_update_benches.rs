@@ -221,6 +221,7 @@ fn setup_inputs(
             .voxels
             .count_states() as i32,
         results.estimations.measurements.num_steps() as i32,
+        data.simulation.sample_rate_hz,
         &config.algorithm,
     );
 
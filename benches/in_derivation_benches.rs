@@ -178,7 +178,10 @@ fn bench_gains(group: &mut criterion::BenchmarkGroup<criterion::measurement::Wal
                     &results.derivatives.maximum_regularization,
                     &results.derivatives.mapped_residuals,
                     &config.algorithm,
-                    results.estimations.measurements.num_sensors(),
+                    model
+                        .functional_description
+                        .sensor_weights
+                        .active_weight_sum(results.estimations.measurements.num_sensors()),
                 );
             })
         });
@@ -266,7 +269,13 @@ fn setup_inputs(config: &Config) -> (Data, Model, Results) {
         BEAT,
         STEP,
     );
-    calculate_residuals(&mut results.estimations, &data, BEAT, STEP);
+    calculate_residuals(
+        &mut results.estimations,
+        &data,
+        &model.functional_description.sensor_weights,
+        BEAT,
+        STEP,
+    );
     (data, model, results)
 }
 
@@ -94,7 +94,13 @@ fn bench_residuals(group: &mut criterion::BenchmarkGroup<criterion::measurement:
         group.throughput(criterion::Throughput::Elements(number_of_voxels as u64));
         group.bench_function(BenchmarkId::new("residuals", voxel_size), |b| {
             b.iter(|| {
-                calculate_residuals(&mut results.estimations, &data, BEAT, STEP);
+                calculate_residuals(
+                    &mut results.estimations,
+                    &data,
+                    &model.functional_description.sensor_weights,
+                    BEAT,
+                    STEP,
+                );
             })
         });
     }
@@ -192,8 +198,10 @@ fn bench_metrics(group: &mut criterion::BenchmarkGroup<criterion::measurement::W
                     &mut results.metrics,
                     &results.estimations,
                     results.derivatives.maximum_regularization_sum,
-                    config.algorithm.maximum_regularization_strength,
+                    &model.functional_description.sensor_weights,
+                    &config.algorithm,
                     STEP,
+                    true,
                 );
             })
         });
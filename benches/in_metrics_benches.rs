@@ -42,8 +42,15 @@ fn bench_step(group: &mut criterion::BenchmarkGroup<criterion::measurement::Wall
                     &mut results.metrics,
                     &results.estimations,
                     results.derivatives.maximum_regularization_sum,
-                    config.algorithm.maximum_regularization_strength,
+                    &results
+                        .model
+                        .as_ref()
+                        .unwrap()
+                        .functional_description
+                        .sensor_weights,
+                    &config.algorithm,
                     STEP,
+                    true,
                 );
             })
         });
@@ -66,9 +73,10 @@ fn bench_epoch(group: &mut criterion::BenchmarkGroup<criterion::measurement::Wal
             .voxels
             .count();
         group.throughput(criterion::Throughput::Elements(number_of_voxels as u64));
+        let number_of_steps = results.estimations.system_states.num_steps();
         group.bench_function(BenchmarkId::new("epoch", voxel_size), |b| {
             b.iter(|| {
-                metrics::calculate_batch(&mut results.metrics, 0);
+                metrics::calculate_batch(&mut results.metrics, 0, number_of_steps);
             })
         });
     }
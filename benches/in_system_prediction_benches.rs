@@ -64,6 +64,7 @@ fn bench_control_function(group: &mut criterion::BenchmarkGroup<criterion::measu
                 add_control_function(
                     &mut results.estimations,
                     &model.functional_description,
+                    BEAT,
                     STEP,
                 );
             })
@@ -2,33 +2,44 @@ pub mod estimation;
 pub mod gpu;
 pub mod metrics;
 pub mod refinement;
+pub mod regularization;
 #[cfg(test)]
 mod tests;
 
 use nalgebra::{DMatrix, SVD};
 use ndarray::{s, Array1};
 use rand::{seq::SliceRandom, thread_rng};
-use refinement::derivation::{calculate_average_delays, calculate_batch_derivatives};
+use refinement::derivation::{calculate_average_delays, calculate_batch_derivatives, Derivatives};
+use regularization::SpatioTemporalSolver;
 use tracing::{debug, trace};
 
 use self::estimation::{
-    calculate_residuals, calculate_system_update, prediction::calculate_system_prediction,
+    adaptation::{self, adapt_covariances},
+    calculate_residuals, calculate_system_update,
+    ensemble::calculate_ensemble_kalman_gain,
+    prediction::calculate_system_prediction,
+    smoothing::{calculate_smoothed_states, store_state_covariances},
+    unscented::calculate_unscented_kalman_gain,
+    Estimations,
 };
 use super::{
-    config::algorithm::Algorithm,
+    config::algorithm::{Algorithm, EstimationBackend},
     data::{shapes::SystemStates, Data},
-    model::functional::FunctionalDescription,
+    model::functional::{allpass::shapes::Gains, FunctionalDescription},
     scenario::results::Results,
 };
 use crate::core::algorithm::{
     estimation::update_kalman_gain_and_check_convergence,
-    refinement::derivation::calculate_step_derivatives,
+    refinement::{derivation::calculate_step_derivatives, loss, time_offset, window::FitWindows},
 };
 
 /// Calculates a pseudo inverse of the measurement matrix and estimates the system states, residuals, derivatives, and metrics.
 ///
 /// This iterates through each time step, calculating the system state estimate, residuals, derivatives, and metrics at each step.
 /// It uses SVD to calculate the pseudo inverse of the measurement matrix.
+/// If a spatial or temporal regularization strength is set in the config,
+/// the regularized problem is solved jointly over all steps of the beat
+/// instead, see [`SpatioTemporalSolver`].
 ///
 /// # Panics
 ///
@@ -53,29 +64,46 @@ pub fn calculate_pseudo_inverse(
         measurement_matrix.as_slice().expect("Slice to be some."),
     );
 
-    let decomposition = SVD::new_unordered(measurement_matrix, true, true);
-
     let num_sensors = data.simulation.measurements.num_sensors();
+    let num_steps = results.estimations.system_states.num_steps();
+
+    // masked sensors have zero rows in the measurement matrix, so missing
+    // samples can be set to zero without affecting the solution
+    let actual_measurements = data.simulation.measurements.at_beat(0);
+    let measurements = DMatrix::from_fn(num_sensors, num_steps, |sensor, step| {
+        let value = actual_measurements.at_step(step)[sensor];
+        if value.is_nan() || functional_description.sensor_weights.is_masked(sensor) {
+            0.0
+        } else {
+            value
+        }
+    });
+
+    let regularized = config.pseudo_inverse_spatial_regularization_strength > 0.0
+        || config.pseudo_inverse_temporal_regularization_strength > 0.0;
+    let system_states = if regularized {
+        SpatioTemporalSolver::new(
+            measurement_matrix,
+            &functional_description.ap_params,
+            config.pseudo_inverse_spatial_regularization_strength,
+            config.pseudo_inverse_temporal_regularization_strength,
+        )
+        .solve(&measurements)
+    } else {
+        SVD::new_unordered(measurement_matrix, true, true)
+            .solve(&measurements, 1e-5)
+            .expect("SVD to be computed.")
+    };
 
     let estimations = &mut results.estimations;
     let derivatives = &mut results.derivatives;
 
-    for step in 0..estimations.system_states.num_steps() {
+    for step in 0..num_steps {
         let mut estimated_measurements = estimations.measurements.at_beat_mut(0);
-        let actual_measurements = data.simulation.measurements.at_beat(0);
         let mut estimated_system_states = estimations.system_states.at_step_mut(step);
         let mut estimated_measurements = estimated_measurements.at_step_mut(step);
-        let actual_measurements = actual_measurements.at_step(step);
-
-        let rows = data.simulation.measurements.num_sensors();
-        let measurements =
-            DMatrix::from_row_slice(rows, 1, actual_measurements.as_slice().unwrap());
-
-        let system_states = decomposition
-            .solve(&measurements, 1e-5)
-            .expect("SVD to be computed.");
 
-        let system_states = Array1::from_iter(system_states.as_slice().iter().copied());
+        let system_states = Array1::from_iter(system_states.column(step).iter().copied());
 
         estimated_system_states.assign(&system_states);
 
@@ -83,7 +111,13 @@ pub fn calculate_pseudo_inverse(
 
         estimated_measurements.assign(&measurement_matrix.dot(&*estimated_system_states));
 
-        calculate_residuals(estimations, data, 0, step);
+        calculate_residuals(
+            estimations,
+            data,
+            &functional_description.sensor_weights,
+            0,
+            step,
+        );
 
         calculate_step_derivatives(
             derivatives,
@@ -99,23 +133,37 @@ pub fn calculate_pseudo_inverse(
             &mut results.metrics,
             estimations,
             derivatives.maximum_regularization_sum,
-            config.maximum_regularization_strength,
+            &functional_description.sensor_weights,
+            config,
             step,
+            true,
         );
     }
-    metrics::calculate_batch(&mut results.metrics, 0);
+    metrics::calculate_batch(
+        &mut results.metrics,
+        0,
+        results.estimations.system_states.num_steps(),
+    );
 }
 
 /// Runs the algorithm for one epoch.
 ///
 /// This includes calculating the system estimates
 /// and performing one gradient descent step.
+/// The prediction covers the whole beat, but only the steps inside the
+/// fit windows contribute to the loss and the gradients.
+/// If enabled, the time offset of every beat is updated after its steps
+/// and the control function is updated together with the allpass parameters.
 #[tracing::instrument(skip_all, level = "debug")]
 pub fn run_epoch(results: &mut Results, batch_index: &mut usize, data: &Data, config: &Algorithm) {
     results.derivatives.reset();
+    results.derivatives.fit_windows = FitWindows::from_config(config, data);
     results.estimations.kalman_gain_converged = false;
     let num_steps = results.estimations.system_states.num_steps();
     let num_beats = data.simulation.measurements.num_beats();
+    // the gradients and losses only accumulate inside of the fit windows,
+    // both are normalized by the fitted steps per beat of the batch
+    let mut num_fitted_steps_of_batch = 0;
 
     let mut batch = match config.batch_size {
         0 => None,
@@ -131,20 +179,79 @@ pub fn run_epoch(results: &mut Results, batch_index: &mut usize, data: &Data, co
 
     let num_sensors = data.simulation.measurements.num_sensors();
 
+    if config.rts_smoothing && estimations.system_states_predicted.is_none() {
+        let num_states = estimations.system_states.num_states();
+        estimations.system_states_predicted = Some(SystemStates::empty(num_steps, num_states));
+        estimations.state_covariances_predicted = Some(vec![Gains::empty(num_states); num_steps]);
+        estimations.state_covariances_filtered = Some(vec![Gains::empty(num_states); num_steps]);
+    }
+
     for beat in beat_indices {
         estimations.reset();
+        num_fitted_steps_of_batch += derivatives
+            .fit_windows
+            .number_of_fitted_steps(beat, num_steps);
         estimations.kalman_gain_converged = false;
 
+        let use_steady_state_kalman_gain = config.model.common.apply_system_update
+            && config.update_kalman_gain
+            && config.steady_state_kalman_gain
+            && config.estimation_backend == EstimationBackend::Kalman;
+        if use_steady_state_kalman_gain {
+            estimations.steady_state_kalman_gains.apply(
+                &mut results.model.as_mut().unwrap().functional_description,
+                config,
+                beat,
+            );
+        }
+
+        if loss::uses_beat_losses(config) {
+            // the beat-wise losses need the estimates of the whole beat, so
+            // the beat is estimated once before the derivatives are calculated
+            let mut functional_description = results
+                .model
+                .as_ref()
+                .unwrap()
+                .functional_description
+                .clone();
+            let mut beat_estimations = estimations.clone();
+            for step in 0..num_steps {
+                predict_step(
+                    &mut beat_estimations,
+                    &functional_description,
+                    data,
+                    beat,
+                    step,
+                );
+                update_step(
+                    &mut functional_description,
+                    &mut beat_estimations,
+                    data,
+                    config,
+                    beat,
+                    step,
+                    use_steady_state_kalman_gain,
+                );
+            }
+            loss::calculate_beat_loss_gradients(
+                derivatives,
+                &beat_estimations,
+                data,
+                &functional_description.sensor_weights,
+                config,
+                beat,
+            );
+        }
+
         for step in 0..num_steps {
-            calculate_system_prediction(
+            predict_step(
                 estimations,
-                &results.model.as_mut().unwrap().functional_description,
+                &results.model.as_ref().unwrap().functional_description,
+                data,
                 beat,
                 step,
             );
 
-            calculate_residuals(estimations, data, beat, step);
-
             calculate_step_derivatives(
                 derivatives,
                 estimations,
@@ -155,124 +262,223 @@ pub fn run_epoch(results: &mut Results, batch_index: &mut usize, data: &Data, co
                 num_sensors,
             );
 
-            if config.model.common.apply_system_update {
-                if config.update_kalman_gain {
-                    update_kalman_gain_and_check_convergence(
-                        &mut results.model.as_mut().unwrap().functional_description,
-                        estimations,
-                        beat,
-                    );
-                }
-                calculate_system_update(
-                    &mut estimations.system_states,
-                    &results
-                        .model
-                        .as_ref()
-                        .unwrap()
-                        .functional_description
-                        .kalman_gain,
-                    &estimations.residuals,
-                    step,
-                    config,
-                );
-            }
+            update_step(
+                &mut results.model.as_mut().unwrap().functional_description,
+                estimations,
+                data,
+                config,
+                beat,
+                step,
+                use_steady_state_kalman_gain,
+            );
 
             metrics::calculate_step(
                 &mut results.metrics,
                 estimations,
                 derivatives.maximum_regularization_sum,
-                config.maximum_regularization_strength,
+                &results
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .sensor_weights,
+                config,
                 step,
+                derivatives.fit_windows.contains(beat, step),
+            );
+        }
+        loss::calculate_beat_losses(
+            &mut results.metrics,
+            derivatives,
+            estimations,
+            data,
+            &results
+                .model
+                .as_ref()
+                .unwrap()
+                .functional_description
+                .sensor_weights,
+            config,
+            beat,
+        );
+        if config.estimate_time_offsets {
+            time_offset::update_time_offset(
+                &mut results.model.as_mut().unwrap().functional_description,
+                estimations,
+                data,
+                &derivatives.fit_windows,
+                config,
+                beat,
+            );
+        }
+        if config.model.common.apply_system_update {
+            adaptation::finish_beat(
+                &mut results.model.as_mut().unwrap().functional_description,
+                estimations,
+                config,
+                beat,
+            );
+        }
+        if config.rts_smoothing {
+            calculate_smoothed_states(
+                estimations,
+                &results.model.as_ref().unwrap().functional_description,
+                config,
             );
         }
         if let Some(n) = batch.as_mut() {
             *n += 1;
             if *n == config.batch_size {
-                calculate_average_delays(
-                    &mut estimations.average_delays,
-                    &results
-                        .model
-                        .as_ref()
-                        .unwrap()
-                        .functional_description
-                        .ap_params,
-                );
-                calculate_batch_derivatives(
-                    derivatives,
+                let num_fitted_steps = (num_fitted_steps_of_batch / *n).max(1);
+                update_parameters(
+                    &mut results.model.as_mut().unwrap().functional_description,
                     estimations,
-                    &results.model.as_ref().unwrap().functional_description,
+                    derivatives,
+                    data,
                     config,
+                    num_fitted_steps,
+                    *n,
                 );
-                results
-                    .model
-                    .as_mut()
-                    .unwrap()
-                    .functional_description
-                    .ap_params
-                    .update(derivatives, config, num_steps, *n);
                 derivatives.reset();
                 estimations.kalman_gain_converged = false;
                 *n = 0;
-                metrics::calculate_batch(&mut results.metrics, *batch_index);
+                num_fitted_steps_of_batch = 0;
+                metrics::calculate_batch(&mut results.metrics, *batch_index, num_fitted_steps);
                 *batch_index += 1;
             }
         }
     }
-    if let Some(n) = batch {
-        if n > 0 {
-            calculate_average_delays(
-                &mut estimations.average_delays,
-                &results
-                    .model
-                    .as_ref()
-                    .unwrap()
-                    .functional_description
-                    .ap_params,
-            );
-            calculate_batch_derivatives(
-                derivatives,
-                estimations,
-                &results.model.as_ref().unwrap().functional_description,
-                config,
-            );
-            results
-                .model
-                .as_mut()
-                .unwrap()
-                .functional_description
-                .ap_params
-                .update(&mut results.derivatives, config, num_steps, n);
-            metrics::calculate_batch(&mut results.metrics, *batch_index);
-            *batch_index += 1;
-        }
-    } else {
-        calculate_average_delays(
-            &mut estimations.average_delays,
-            &results
-                .model
-                .as_ref()
-                .unwrap()
-                .functional_description
-                .ap_params,
-        );
-        calculate_batch_derivatives(
-            derivatives,
-            estimations,
-            &results.model.as_ref().unwrap().functional_description,
-            config,
-        );
+    if config.estimate_time_offsets {
         results
             .model
             .as_mut()
             .unwrap()
             .functional_description
-            .ap_params
-            .update(&mut results.derivatives, config, num_steps, num_beats);
-        metrics::calculate_batch(&mut results.metrics, *batch_index);
+            .time_offsets
+            .initialized = true;
+    }
+    // the beats of the last, incomplete batch or of the whole epoch
+    let remaining_beats = batch.unwrap_or(num_beats);
+    if let Some(num_fitted_steps) = num_fitted_steps_of_batch.checked_div(remaining_beats) {
+        let num_fitted_steps = num_fitted_steps.max(1);
+        update_parameters(
+            &mut results.model.as_mut().unwrap().functional_description,
+            estimations,
+            derivatives,
+            data,
+            config,
+            num_fitted_steps,
+            remaining_beats,
+        );
+        metrics::calculate_batch(&mut results.metrics, *batch_index, num_fitted_steps);
         *batch_index += 1;
     }
 }
 
+/// Updates the allpass parameters and, if enabled, the control function
+/// with the derivatives accumulated over the given number of beats.
+#[tracing::instrument(level = "debug", skip_all)]
+fn update_parameters(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    derivatives: &mut Derivatives,
+    data: &Data,
+    config: &Algorithm,
+    number_of_steps: usize,
+    number_of_beats: usize,
+) {
+    calculate_average_delays(
+        &mut estimations.average_delays,
+        &functional_description.ap_params,
+    );
+    calculate_batch_derivatives(derivatives, estimations, functional_description, config);
+    functional_description
+        .ap_params
+        .update(derivatives, config, number_of_steps, number_of_beats);
+    if config.learn_control_function {
+        functional_description.control_function_values.update(
+            derivatives,
+            config,
+            number_of_steps,
+            number_of_beats,
+            data.simulation.sample_rate_hz,
+        );
+    }
+}
+
+/// Predicts the system states of the given step and calculates the
+/// residuals.
+#[tracing::instrument(level = "trace", skip_all)]
+fn predict_step(
+    estimations: &mut Estimations,
+    functional_description: &FunctionalDescription,
+    data: &Data,
+    beat: usize,
+    step: usize,
+) {
+    calculate_system_prediction(estimations, functional_description, beat, step);
+
+    if let Some(predicted) = estimations.system_states_predicted.as_mut() {
+        predicted
+            .at_step_mut(step)
+            .assign(&*estimations.system_states.at_step(step));
+    }
+
+    calculate_residuals(
+        estimations,
+        data,
+        &functional_description.sensor_weights,
+        beat,
+        step,
+    );
+}
+
+/// Updates the gain of the configured estimation backend, the system states
+/// and the adapted covariances of the given step.
+#[tracing::instrument(level = "trace", skip_all)]
+fn update_step(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    data: &Data,
+    config: &Algorithm,
+    beat: usize,
+    step: usize,
+    use_steady_state_kalman_gain: bool,
+) {
+    if !config.model.common.apply_system_update {
+        return;
+    }
+    match config.estimation_backend {
+        EstimationBackend::Kalman => {
+            if config.update_kalman_gain && !use_steady_state_kalman_gain {
+                update_kalman_gain_and_check_convergence(functional_description, estimations, beat);
+            }
+        }
+        EstimationBackend::EnsembleKalman => {
+            calculate_ensemble_kalman_gain(functional_description, estimations, config, beat, step);
+        }
+        EstimationBackend::UnscentedKalman => {
+            calculate_unscented_kalman_gain(functional_description, estimations, beat);
+        }
+    }
+    calculate_system_update(
+        &mut estimations.system_states,
+        &functional_description.kalman_gain,
+        &estimations.residuals,
+        step,
+        config,
+    );
+    store_state_covariances(estimations, step);
+    adapt_covariances(
+        functional_description,
+        estimations,
+        data,
+        config,
+        beat,
+        step,
+    );
+}
+
 #[tracing::instrument(level = "trace")]
 pub fn constrain_system_states(
     system_states: &mut SystemStates,
@@ -1,12 +1,21 @@
+pub mod adaptation;
+pub mod ensemble;
 pub mod prediction;
+pub mod smoothing;
+pub mod steady_state;
+pub mod unscented;
 
 use itertools::Itertools;
-use nalgebra::DMatrix;
+use nalgebra::{DMatrix, SymmetricEigen};
 use ndarray::s;
 use ocl::Buffer;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
+use self::{
+    adaptation::CovarianceStatistics, ensemble::Ensemble, steady_state::SteadyStateKalmanGains,
+    unscented::UnscentedCovariance,
+};
 use super::refinement::derivation::AverageDelays;
 use crate::core::{
     config::algorithm::Algorithm,
@@ -24,7 +33,7 @@ use crate::core::{
             APParameters,
         },
         kalman::KalmanGain,
-        measurement::{MeasurementCovariance, MeasurementMatrixAtBeat},
+        measurement::{MeasurementCovariance, MeasurementMatrixAtBeat, SensorWeights},
         FunctionalDescription,
     },
 };
@@ -46,6 +55,27 @@ pub struct Estimations {
     pub average_delays: AverageDelays,
     pub innovation_covariance: DMatrix<f32>,
     pub kalman_gain_converged: bool,
+    /// Predicted states of the forward pass, only stored if RTS smoothing is enabled.
+    #[serde(default)]
+    pub system_states_predicted: Option<SystemStates>,
+    /// Predicted and filtered state covariances of every step of the beat,
+    /// only stored if RTS smoothing is enabled.
+    #[serde(skip)]
+    pub state_covariances_predicted: Option<Vec<Gains>>,
+    #[serde(skip)]
+    pub state_covariances_filtered: Option<Vec<Gains>>,
+    #[serde(default)]
+    pub system_states_smoothed: Option<SystemStates>,
+    #[serde(default)]
+    pub state_covariances_smoothed: Option<Vec<Gains>>,
+    #[serde(skip)]
+    pub steady_state_kalman_gains: SteadyStateKalmanGains,
+    #[serde(skip)]
+    pub ensemble: Option<Ensemble>,
+    #[serde(skip)]
+    pub unscented: Option<UnscentedCovariance>,
+    #[serde(skip)]
+    pub covariance_statistics: Option<CovarianceStatistics>,
 }
 
 pub struct EstimationsGPU {
@@ -89,9 +119,31 @@ impl Estimations {
             average_delays: AverageDelays::empty(number_of_states),
             innovation_covariance: DMatrix::zeros(number_of_sensors, number_of_sensors),
             kalman_gain_converged: false,
+            system_states_predicted: None,
+            state_covariances_predicted: None,
+            state_covariances_filtered: None,
+            system_states_smoothed: None,
+            state_covariances_smoothed: None,
+            steady_state_kalman_gains: SteadyStateKalmanGains::default(),
+            ensemble: None,
+            unscented: None,
+            covariance_statistics: None,
         }
     }
 
+    /// Returns the smoothed system states if available, the filtered ones otherwise.
+    ///
+    /// Used for the analysis of the results, i.e. activation times and
+    /// pathology metrics.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn analysis_system_states(&self) -> &SystemStates {
+        trace!("Selecting system states for analysis");
+        self.system_states_smoothed
+            .as_ref()
+            .unwrap_or(&self.system_states)
+    }
+
     /// Resets all the internal state of the Estimations struct by filling the
     /// underlying data structures with 0.0. This is done to prepare for a new
     /// epoch.
@@ -100,6 +152,8 @@ impl Estimations {
         debug!("Resetting estimations");
         self.system_states.fill(0.0);
         self.ap_outputs_now.fill(0.0);
+        self.ensemble = None;
+        self.unscented = None;
     }
 
     /// Saves the system states and measurements to .npy files at the given path.
@@ -109,6 +163,9 @@ impl Estimations {
         trace!("Saving estimations to npy files");
         self.system_states.save_npy(path);
         self.measurements.save_npy(path);
+        if let Some(system_states_smoothed) = self.system_states_smoothed.as_ref() {
+            system_states_smoothed.save_npy(&path.join("smoothed"));
+        }
     }
 
     pub(crate) fn to_gpu(&self, queue: &ocl::Queue) -> EstimationsGPU {
@@ -153,14 +210,31 @@ impl Estimations {
 
 /// Calculates the residuals between the predicted and actual measurements for the given time index.
 /// The residuals are stored in the provided `residuals` array.
+///
+/// Masked sensors and missing (NaN) samples get a residual of zero,
+/// so they do not contribute to the update or the loss.
 #[inline]
 #[tracing::instrument(level = "trace", skip_all)]
-pub fn calculate_residuals(estimations: &mut Estimations, data: &Data, beat: usize, step: usize) {
+pub fn calculate_residuals(
+    estimations: &mut Estimations,
+    data: &Data,
+    sensor_weights: &SensorWeights,
+    beat: usize,
+    step: usize,
+) {
     trace!("Calculating residuals");
-    estimations.residuals.assign(
-        &(&*estimations.measurements.at_beat(beat).at_step(step)
-            - &*data.simulation.measurements.at_beat(beat).at_step(step)),
-    );
+    let estimated_measurements = estimations.measurements.at_beat(beat);
+    let estimated_measurements = estimated_measurements.at_step(step);
+    let actual_measurements = data.simulation.measurements.at_beat(beat);
+    let actual_measurements = actual_measurements.at_step(step);
+    for (sensor, residual) in estimations.residuals.iter_mut().enumerate() {
+        let actual = actual_measurements[sensor];
+        *residual = if actual.is_nan() || sensor_weights.is_masked(sensor) {
+            0.0
+        } else {
+            estimated_measurements[sensor] - actual
+        };
+    }
 }
 
 /// Calculates the residuals between the estimated measurements from the
@@ -445,6 +519,90 @@ pub fn calculate_s_inv(
     innovation_covariance.try_inverse_mut();
 }
 
+/// Writes the entries of a dense state covariance into the sparse
+/// neighbourhood representation used for `state_covariance_pred` and
+/// `state_covariance_est`.
+///
+/// Only the covariances between a state and the states of its
+/// neighbouring voxels are stored, entry `(i, gain_index)` holds the
+/// covariance of state `i` with the state at `output_state_indices[(i, gain_index)]`.
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn assign_sparse_covariance<F>(
+    sparse_covariance: &mut Gains,
+    ap_params: &APParameters,
+    covariance: F,
+) where
+    F: Fn(usize, usize) -> f32,
+{
+    trace!("Assigning sparse state covariance");
+    sparse_covariance
+        .indexed_iter_mut()
+        .zip(ap_params.output_state_indices.iter())
+        .for_each(|(((state, _), value), output_state_index)| {
+            *value = output_state_index.map_or(0.0, |neighbour| covariance(state, neighbour));
+        });
+}
+
+/// Singular values below this are treated as zero when a matrix can not
+/// be inverted.
+const PSEUDO_INVERSE_EPSILON: f32 = 1e-6;
+
+/// Inverts the matrix, falling back to the pseudo inverse if it is singular.
+#[must_use]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn invert_or_pseudo_invert(matrix: DMatrix<f32>) -> DMatrix<f32> {
+    trace!("Inverting matrix");
+    matrix.clone().try_inverse().unwrap_or_else(|| {
+        warn!("Matrix is singular, using the pseudo inverse");
+        matrix
+            .pseudo_inverse(PSEUDO_INVERSE_EPSILON)
+            .expect("Epsilon to be non-negative.")
+    })
+}
+
+/// Calculates a factor `L` of the process covariance, such that `L Lᵀ`
+/// is its positive semi-definite part.
+///
+/// The process covariance is expanded from its sparse neighbourhood
+/// layout and symmetrized, negative eigenvalues are clipped to zero so
+/// noise with this covariance can be sampled.
+#[must_use]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn process_noise_factor(process_covariance: &Gains, ap_params: &APParameters) -> DMatrix<f32> {
+    trace!("Calculating process noise factor");
+    let process_covariance = sparse_to_dense(process_covariance, ap_params);
+    let eigen = SymmetricEigen::new((&process_covariance + process_covariance.transpose()) * 0.5);
+    let mut factor = eigen.eigenvectors;
+    factor
+        .column_iter_mut()
+        .zip(eigen.eigenvalues.iter())
+        .for_each(|(mut column, eigenvalue)| column *= eigenvalue.max(0.0).sqrt());
+    factor
+}
+
+/// Expands a sparse neighbourhood matrix, e.g. a state covariance or the
+/// allpass gains, into a dense (`number_of_states`, `number_of_states`)
+/// matrix.
+///
+/// Entries between states that are not neighbours are zero, see
+/// [`assign_sparse_covariance`] for the layout.
+#[must_use]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn sparse_to_dense(sparse: &Gains, ap_params: &APParameters) -> DMatrix<f32> {
+    trace!("Expanding sparse matrix");
+    let num_states = sparse.shape()[0];
+    let mut dense = DMatrix::<f32>::zeros(num_states, num_states);
+    sparse
+        .indexed_iter()
+        .zip(ap_params.output_state_indices.iter())
+        .for_each(|(((state, _), value), output_state_index)| {
+            if let Some(neighbour) = output_state_index {
+                dense[(state, *neighbour)] = *value;
+            }
+        });
+    dense
+}
+
 /// Predicts the state covariance for the next time step using the
 /// autoregressive process model. Iterates over the output state indices,
 /// updating each variance using the process covariance and gains between
@@ -546,7 +704,9 @@ mod tests {
         Estimations,
     };
     use crate::core::{
-        config::algorithm::Algorithm, data::Data, model::functional::FunctionalDescription,
+        config::algorithm::Algorithm,
+        data::Data,
+        model::functional::{measurement::SensorWeights, FunctionalDescription},
     };
 
     #[test]
@@ -632,6 +792,27 @@ mod tests {
             number_of_beats,
         );
 
-        calculate_residuals(&mut estimations, &data, beat, step);
+        calculate_residuals(
+            &mut estimations,
+            &data,
+            &SensorWeights::ones(number_of_sensors),
+            beat,
+            step,
+        );
+    }
+
+    #[test]
+    fn residuals_skip_masked_and_missing() {
+        let number_of_sensors = 4;
+        let mut estimations = Estimations::empty(3, number_of_sensors, 1, 1);
+        let mut data = Data::empty(number_of_sensors, 3, 1, Dim([1, 1, 1]), 1);
+        estimations.measurements.fill(1.0);
+        data.simulation.measurements[(0, 0, 2)] = f32::NAN;
+        let mut sensor_weights = SensorWeights::ones(number_of_sensors);
+        sensor_weights[1] = 0.0;
+
+        calculate_residuals(&mut estimations, &data, &sensor_weights, 0, 0);
+
+        assert_eq!(estimations.residuals.to_vec(), vec![1.0, 0.0, 0.0, 1.0]);
     }
 }
@@ -0,0 +1,256 @@
+use ndarray::{Array1, Array2};
+use tracing::{debug, trace};
+
+use super::Estimations;
+use crate::core::{
+    config::algorithm::{Algorithm, CovarianceAdaptation},
+    data::Data,
+    model::functional::{allpass::shapes::Gains, FunctionalDescription},
+};
+
+/// Sufficient statistics for re-estimating the process and measurement
+/// covariances.
+///
+/// The measurement covariance is estimated from the post-update residuals
+/// `e = H x⁺ - y`, the process covariance from the state corrections
+/// `x⁺ - x⁻` of the Kalman update. Only the process covariance entries present in the
+/// sparse neighbourhood representation are estimated.
+#[derive(Debug, PartialEq, Clone)]
+pub struct CovarianceStatistics {
+    pub measurement_covariance_sum: Array2<f32>,
+    pub process_covariance_sum: Gains,
+    pub count: usize,
+}
+
+impl CovarianceStatistics {
+    /// Creates empty statistics for the given number of states and sensors.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn empty(number_of_states: usize, number_of_sensors: usize) -> Self {
+        debug!("Creating empty covariance statistics");
+        Self {
+            measurement_covariance_sum: Array2::zeros((number_of_sensors, number_of_sensors)),
+            process_covariance_sum: Gains::empty(number_of_states),
+            count: 0,
+        }
+    }
+
+    /// Adds the residuals and state corrections of the given step.
+    ///
+    /// Has to be called after the system update of the step.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn accumulate(
+        &mut self,
+        functional_description: &FunctionalDescription,
+        estimations: &Estimations,
+        data: &Data,
+        beat: usize,
+        step: usize,
+    ) {
+        trace!("Accumulating covariance statistics");
+        let post_update_residuals = functional_description
+            .measurement_matrix
+            .at_beat(beat)
+            .dot(&*estimations.system_states.at_step(step))
+            - *data.simulation.measurements.at_beat(beat).at_step(step);
+        let corrections: Array1<f32> = functional_description
+            .kalman_gain
+            .dot(&*estimations.residuals);
+
+        self.measurement_covariance_sum
+            .indexed_iter_mut()
+            .for_each(|((i, j), value)| {
+                *value += post_update_residuals[i] * post_update_residuals[j];
+            });
+        self.process_covariance_sum
+            .indexed_iter_mut()
+            .zip(functional_description.ap_params.output_state_indices.iter())
+            .filter_map(|(((state, _), value), neighbour)| {
+                neighbour.map(|neighbour| (state, neighbour, value))
+            })
+            .for_each(|(state, neighbour, value)| {
+                *value += corrections[state] * corrections[neighbour];
+            });
+        self.count += 1;
+    }
+
+    /// Blends the averaged statistics into the covariances of the
+    /// functional description and resets the statistics.
+    ///
+    /// The post-update residuals have the covariance `R - H P⁺ Hᵀ`, so
+    /// `H P⁺ Hᵀ` is added back to the measurement covariance estimate.
+    /// Assuming the Kalman gain is optimal for the current measurement
+    /// covariance, `H P⁺ Hᵀ = H K R`. The old covariances are weighted
+    /// with the forgetting factor.
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn apply(
+        &mut self,
+        functional_description: &mut FunctionalDescription,
+        forgetting_factor: f32,
+        beat: usize,
+    ) {
+        trace!("Applying covariance statistics");
+        if self.count == 0 {
+            return;
+        }
+        let weight = (1.0 - forgetting_factor) / self.count as f32;
+        let measured_state_covariance = functional_description
+            .measurement_matrix
+            .at_beat(beat)
+            .dot(&*functional_description.kalman_gain)
+            .dot(&*functional_description.measurement_covariance);
+        let measurement_covariance = &self.measurement_covariance_sum * weight
+            + measured_state_covariance * (1.0 - forgetting_factor);
+        functional_description.measurement_covariance.zip_mut_with(
+            &measurement_covariance,
+            |old, new| {
+                *old = forgetting_factor.mul_add(*old, *new);
+            },
+        );
+        functional_description.process_covariance.zip_mut_with(
+            &*self.process_covariance_sum,
+            |old, sum| {
+                *old = forgetting_factor.mul_add(*old, weight * sum);
+            },
+        );
+        self.measurement_covariance_sum.fill(0.0);
+        self.process_covariance_sum.fill(0.0);
+        self.count = 0;
+    }
+}
+
+/// Updates the process and measurement covariances after the system update
+/// of a step, depending on the configured adaptation mode.
+///
+/// With [`CovarianceAdaptation::Innovation`] the covariances are updated
+/// after every step, with [`CovarianceAdaptation::ExpectationMaximization`]
+/// the statistics are only collected here and applied once per beat in
+/// [`finish_beat`].
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn adapt_covariances(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    data: &Data,
+    config: &Algorithm,
+    beat: usize,
+    step: usize,
+) {
+    trace!("Adapting covariances");
+    if config.covariance_adaptation == CovarianceAdaptation::None {
+        return;
+    }
+    let mut statistics = estimations.covariance_statistics.take().unwrap_or_else(|| {
+        CovarianceStatistics::empty(
+            estimations.system_states.num_states(),
+            data.simulation.measurements.num_sensors(),
+        )
+    });
+    statistics.accumulate(functional_description, estimations, data, beat, step);
+    if config.covariance_adaptation == CovarianceAdaptation::Innovation {
+        statistics.apply(
+            functional_description,
+            config.covariance_adaptation_forgetting_factor,
+            beat,
+        );
+    }
+    estimations.covariance_statistics = Some(statistics);
+}
+
+/// Applies the statistics collected over a beat (M-step) if expectation
+/// maximization is used for the covariance adaptation.
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn finish_beat(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    config: &Algorithm,
+    beat: usize,
+) {
+    trace!("Finishing covariance adaptation for beat");
+    if config.covariance_adaptation != CovarianceAdaptation::ExpectationMaximization {
+        return;
+    }
+    if let Some(statistics) = estimations.covariance_statistics.as_mut() {
+        statistics.apply(
+            functional_description,
+            config.covariance_adaptation_forgetting_factor,
+            beat,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Dim;
+
+    use super::*;
+
+    #[test]
+    fn apply_blends_with_forgetting_factor() {
+        let number_of_states = 3;
+        let number_of_sensors = 2;
+        let mut functional_description = FunctionalDescription::empty(
+            number_of_states,
+            number_of_sensors,
+            10,
+            1,
+            Dim([1, 1, 1]),
+        );
+        functional_description.measurement_covariance.fill(1.0);
+
+        let mut statistics = CovarianceStatistics::empty(number_of_states, number_of_sensors);
+        statistics.measurement_covariance_sum.fill(6.0);
+        statistics.count = 2;
+
+        statistics.apply(&mut functional_description, 0.5, 0);
+
+        functional_description
+            .measurement_covariance
+            .iter()
+            .for_each(|value| assert_relative_eq!(*value, 0.5f32.mul_add(1.0, 0.5 * 3.0)));
+        assert_eq!(statistics.count, 0);
+        assert_relative_eq!(statistics.measurement_covariance_sum.sum(), 0.0);
+    }
+
+    #[test]
+    fn apply_without_samples_keeps_covariances() {
+        let mut functional_description = FunctionalDescription::empty(3, 2, 10, 1, Dim([1, 1, 1]));
+        functional_description.measurement_covariance.fill(1.0);
+        let mut statistics = CovarianceStatistics::empty(3, 2);
+
+        statistics.apply(&mut functional_description, 0.5, 0);
+
+        assert_relative_eq!(functional_description.measurement_covariance.sum(), 4.0);
+    }
+
+    #[test]
+    fn apply_adds_back_measured_state_covariance() {
+        let number_of_states = 3;
+        let number_of_sensors = 2;
+        let mut functional_description = FunctionalDescription::empty(
+            number_of_states,
+            number_of_sensors,
+            10,
+            1,
+            Dim([1, 1, 1]),
+        );
+        functional_description
+            .measurement_covariance
+            .diag_mut()
+            .fill(1.0);
+        for sensor in 0..number_of_sensors {
+            functional_description.measurement_matrix[(0, sensor, sensor)] = 1.0;
+            functional_description.kalman_gain[(sensor, sensor)] = 0.5;
+        }
+        let mut statistics = CovarianceStatistics::empty(number_of_states, number_of_sensors);
+        statistics.count = 1;
+
+        // without residuals only H K R = 0.5 R remains
+        statistics.apply(&mut functional_description, 0.0, 0);
+
+        for ((i, j), value) in functional_description.measurement_covariance.indexed_iter() {
+            assert_relative_eq!(*value, if i == j { 0.5 } else { 0.0 });
+        }
+    }
+}
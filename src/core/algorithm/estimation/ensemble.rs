@@ -0,0 +1,280 @@
+use nalgebra::DMatrix;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+use tracing::{debug, trace};
+
+use super::{
+    assign_sparse_covariance, invert_or_pseudo_invert, prediction::DeviationHistory,
+    process_noise_factor, Estimations,
+};
+use crate::core::{
+    config::algorithm::Algorithm,
+    model::functional::{
+        allpass::{shapes::Gains, APParameters},
+        FunctionalDescription,
+    },
+};
+
+/// Ensemble of state deviations from the estimated mean.
+///
+/// The mean itself is propagated by the allpass model in
+/// `calculate_system_prediction`, the ensemble only carries the
+/// uncertainty around it. The deviations of every member are propagated
+/// through the same allpass model, including the delays.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Ensemble {
+    /// Has dimensions (`number_of_states`, `ensemble_size`)
+    pub deviations: DMatrix<f32>,
+    history: DeviationHistory,
+    rng: ChaCha8Rng,
+    /// Process covariance the noise factor was calculated from.
+    process_covariance: Gains,
+    process_noise_factor: DMatrix<f32>,
+}
+
+impl Ensemble {
+    /// Creates a new ensemble without deviations that can propagate delays
+    /// up to `maximum_delay` samples and draws its noise from a generator
+    /// with the given seed.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn empty(
+        number_of_states: usize,
+        ensemble_size: usize,
+        maximum_delay: usize,
+        seed: u64,
+    ) -> Self {
+        debug!("Creating ensemble");
+        Self {
+            deviations: DMatrix::zeros(number_of_states, ensemble_size),
+            history: DeviationHistory::empty(number_of_states, ensemble_size, maximum_delay),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            process_covariance: Gains::empty(number_of_states),
+            process_noise_factor: DMatrix::zeros(number_of_states, number_of_states),
+        }
+    }
+
+    /// Forecasts the deviations of the given step with the allpass model
+    /// and adds process noise with the given process covariance.
+    ///
+    /// The factor of the process covariance is only recalculated if the
+    /// covariance changed, e.g. by the covariance adaptation.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn forecast(&mut self, ap_params: &APParameters, process_covariance: &Gains, step: usize) {
+        trace!("Forecasting ensemble");
+        if self.process_covariance != *process_covariance {
+            self.process_noise_factor = process_noise_factor(process_covariance, ap_params);
+            self.process_covariance.assign(&**process_covariance);
+        }
+        let rng = &mut self.rng;
+        let noise = DMatrix::<f32>::from_fn(
+            self.process_noise_factor.ncols(),
+            self.deviations.ncols(),
+            |_, _| Distribution::<f32>::sample(&StandardNormal, rng),
+        );
+        self.deviations =
+            self.history.innovate(ap_params, step) + &self.process_noise_factor * noise;
+        self.center();
+    }
+
+    /// Removes the ensemble mean from the deviations.
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn center(&mut self) {
+        trace!("Centering ensemble");
+        let mean = self.deviations.column_mean();
+        self.deviations
+            .column_iter_mut()
+            .for_each(|mut column| column -= &mean);
+    }
+
+    /// Sample covariance between two states of the ensemble.
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn covariance(&self, state_a: usize, state_b: usize) -> f32 {
+        self.deviations
+            .row(state_a)
+            .dot(&self.deviations.row(state_b))
+            / (self.deviations.ncols() - 1) as f32
+    }
+}
+
+/// Calculates the Kalman gain of a stochastic ensemble Kalman filter for the
+/// given step and writes it to the functional description.
+///
+/// The ensemble is forecast with the allpass model plus process noise of
+/// the process covariance of the functional description. The gain is
+/// computed from the ensemble covariances and the ensemble is then updated
+/// with perturbed observations. The sparse state covariances in the
+/// estimations are filled from the ensemble before and after the update.
+///
+/// The ensemble of every beat draws its noise from a generator seeded with
+/// `ensemble_seed` plus the beat index, so runs are reproducible. If the
+/// innovation covariance is singular, its pseudo inverse is used.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_ensemble_kalman_gain(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    config: &Algorithm,
+    beat: usize,
+    step: usize,
+) {
+    trace!("Calculating ensemble Kalman gain");
+    let num_states = estimations.system_states.num_states();
+    let ap_params = &functional_description.ap_params;
+    let ensemble = estimations.ensemble.get_or_insert_with(|| {
+        let maximum_delay = ap_params.delays.iter().copied().max().unwrap_or(0);
+        Ensemble::empty(
+            num_states,
+            config.ensemble_size,
+            maximum_delay,
+            config.ensemble_seed.wrapping_add(beat as u64),
+        )
+    });
+
+    // forecast
+    ensemble.forecast(ap_params, &functional_description.process_covariance, step);
+    assign_sparse_covariance(&mut estimations.state_covariance_pred, ap_params, |a, b| {
+        ensemble.covariance(a, b)
+    });
+
+    // gain
+    let measurement_matrix = functional_description.measurement_matrix.at_beat(beat);
+    let measurement_matrix = DMatrix::from_row_slice(
+        measurement_matrix.shape()[0],
+        measurement_matrix.shape()[1],
+        measurement_matrix
+            .as_standard_layout()
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+    let measurement_covariance = &functional_description.measurement_covariance;
+    let measurement_covariance = DMatrix::from_row_slice(
+        measurement_covariance.shape()[0],
+        measurement_covariance.shape()[1],
+        measurement_covariance
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+    let normalization = (config.ensemble_size - 1) as f32;
+    let measured_deviations = &measurement_matrix * &ensemble.deviations;
+    let cross_covariance = &ensemble.deviations * measured_deviations.transpose() / normalization;
+    let innovation_covariance = &measured_deviations * measured_deviations.transpose()
+        / normalization
+        + &measurement_covariance;
+    estimations
+        .innovation_covariance
+        .copy_from(&invert_or_pseudo_invert(innovation_covariance));
+    let kalman_gain = &cross_covariance * &estimations.innovation_covariance;
+    functional_description
+        .kalman_gain
+        .indexed_iter_mut()
+        .for_each(|(index, value)| *value = kalman_gain[index]);
+
+    // analysis with perturbed observations
+    let measurement_noise_factor = measurement_covariance.clone().cholesky().map_or_else(
+        || DMatrix::from_diagonal(&measurement_covariance.diagonal().map(f32::sqrt)),
+        |cholesky| cholesky.l(),
+    );
+    let observation_perturbations = &measurement_noise_factor
+        * DMatrix::<f32>::from_fn(
+            measurement_covariance.nrows(),
+            config.ensemble_size,
+            |_, _| Distribution::<f32>::sample(&StandardNormal, &mut ensemble.rng),
+        );
+    ensemble.deviations -= &kalman_gain * (measured_deviations - observation_perturbations);
+    ensemble.center();
+    ensemble.history.store(step, &ensemble.deviations);
+    assign_sparse_covariance(
+        &mut estimations.state_covariance_est,
+        &functional_description.ap_params,
+        |a, b| ensemble.covariance(a, b),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Dim;
+
+    use super::*;
+    use crate::core::model::functional::allpass::offset_to_gain_index;
+
+    /// Connects all states of two neighbouring voxels and returns the
+    /// parameters with a process covariance between them.
+    fn connected_voxels() -> (APParameters, Gains) {
+        let mut ap_params = APParameters::empty(6, Dim([2, 1, 1]));
+        for dimension in 0..3 {
+            let forward = offset_to_gain_index(1, 0, 0, dimension).unwrap();
+            let backward = offset_to_gain_index(-1, 0, 0, dimension).unwrap();
+            for state in 0..3 {
+                ap_params.output_state_indices[(state, forward)] = Some(3 + dimension);
+                ap_params.output_state_indices[(state + 3, backward)] = Some(dimension);
+            }
+        }
+        let mut process_covariance = Gains::empty(6);
+        process_covariance.fill(1.0);
+        (ap_params, process_covariance)
+    }
+
+    #[test]
+    fn ensemble_is_centered() {
+        let (ap_params, process_covariance) = connected_voxels();
+        let mut ensemble = Ensemble::empty(6, 20, 0, 42);
+        ensemble.forecast(&ap_params, &process_covariance, 0);
+        ensemble
+            .deviations
+            .column_mean()
+            .iter()
+            .for_each(|mean| assert_relative_eq!(*mean, 0.0, epsilon = 1e-5));
+    }
+
+    #[test]
+    fn ensemble_covariance_is_symmetric() {
+        let (ap_params, process_covariance) = connected_voxels();
+        let mut ensemble = Ensemble::empty(6, 20, 0, 42);
+        ensemble.forecast(&ap_params, &process_covariance, 0);
+        assert_relative_eq!(ensemble.covariance(1, 4), ensemble.covariance(4, 1));
+        assert!(ensemble.covariance(2, 2) > 0.0);
+    }
+
+    #[test]
+    fn forecast_is_reproducible_with_the_same_seed() {
+        let (ap_params, process_covariance) = connected_voxels();
+        let mut first = Ensemble::empty(6, 20, 0, 7);
+        let mut second = Ensemble::empty(6, 20, 0, 7);
+        first.forecast(&ap_params, &process_covariance, 0);
+        second.forecast(&ap_params, &process_covariance, 0);
+        assert_eq!(first.deviations, second.deviations);
+    }
+
+    #[test]
+    fn forecast_follows_allpass_delays() {
+        let delay = 2;
+        let mut ap_params = APParameters::empty(6, Dim([2, 1, 1]));
+        let offset_index = offset_to_gain_index(1, 0, 0, 0).unwrap();
+        ap_params.output_state_indices[(0, offset_index)] = Some(3);
+        ap_params.gains[(0, offset_index)] = 1.0;
+        ap_params.coefs[(0, offset_index / 3)] = 0.0;
+        ap_params.delays[(0, offset_index / 3)] = delay;
+        let mut ensemble = Ensemble::empty(6, 2, delay, 42);
+        let process_covariance = Gains::empty(6);
+
+        ensemble.deviations[(3, 0)] = 1.0;
+        ensemble.deviations[(3, 1)] = -1.0;
+        ensemble.history.store(0, &ensemble.deviations.clone());
+        for step in 1..=delay {
+            ensemble.forecast(&ap_params, &process_covariance, step);
+            assert_relative_eq!(ensemble.deviations.norm(), 0.0);
+            ensemble.history.store(step, &ensemble.deviations.clone());
+        }
+
+        // with a zero coefficient the allpass output is the input delayed by
+        // `delay + 1` samples
+        ensemble.forecast(&ap_params, &process_covariance, delay + 1);
+        assert_relative_eq!(ensemble.deviations[(0, 0)], 1.0);
+        assert_relative_eq!(ensemble.deviations[(0, 1)], -1.0);
+    }
+}
@@ -1,7 +1,9 @@
-use tracing::trace;
+use nalgebra::DMatrix;
+use ndarray::{Array3, Axis};
+use tracing::{debug, trace};
 
 use super::Estimations;
-use crate::core::model::functional::FunctionalDescription;
+use crate::core::model::functional::{allpass::APParameters, FunctionalDescription};
 
 /// Calculates the system prediction by innovating the system states,
 /// adding the control function, and predicting measurements.
@@ -19,7 +21,7 @@ pub fn calculate_system_prediction(
 ) {
     trace!("Calculating system prediction");
     innovate_system_states_v1(estimations, functional_description, step);
-    add_control_function(estimations, functional_description, step);
+    add_control_function(estimations, functional_description, beat, step);
     predict_measurements(estimations, functional_description, beat, step);
 }
 
@@ -82,20 +84,169 @@ pub fn innovate_system_states_v1(
     }
 }
 
+/// Deviations of several realizations (e.g. ensemble members) from the
+/// estimated system states.
+///
+/// The deviations are innovated with the allpass model in the same way
+/// as the system states in [`innovate_system_states_v1`], so every
+/// realization keeps its own allpass outputs. Only as many past steps as
+/// needed for the largest delay are kept.
+#[derive(Debug, PartialEq, Clone)]
+pub struct DeviationHistory {
+    /// Ring buffer with dimensions (`depth`, `number_of_states`, `number_of_realizations`)
+    deviations: Array3<f32>,
+    /// Has dimensions (`number_of_states`, 78, `number_of_realizations`)
+    ap_outputs: Array3<f32>,
+}
+
+impl DeviationHistory {
+    /// Creates an empty history that can hold deviations for delays up to
+    /// `maximum_delay` samples.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn empty(
+        number_of_states: usize,
+        number_of_realizations: usize,
+        maximum_delay: usize,
+    ) -> Self {
+        debug!("Creating empty deviation history");
+        Self {
+            deviations: Array3::zeros((
+                maximum_delay + 2,
+                number_of_states,
+                number_of_realizations,
+            )),
+            ap_outputs: Array3::zeros((number_of_states, 78, number_of_realizations)),
+        }
+    }
+
+    /// Stores the deviations of the given step, with one realization per column.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn store(&mut self, step: usize, deviations: &DMatrix<f32>) {
+        trace!("Storing deviations");
+        let depth = self.deviations.shape()[0];
+        self.deviations
+            .index_axis_mut(Axis(0), step % depth)
+            .indexed_iter_mut()
+            .for_each(|(index, value)| *value = deviations[index]);
+    }
+
+    /// Innovates the deviations of the given step from the stored ones.
+    ///
+    /// Returns one realization per column.
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn innovate(&mut self, ap_params: &APParameters, step: usize) -> DMatrix<f32> {
+        trace!("Innovating deviations");
+        let depth = self.deviations.shape()[0];
+        let number_of_realizations = self.deviations.shape()[2];
+        let mut innovated =
+            DMatrix::<f32>::zeros(self.deviations.shape()[1], number_of_realizations);
+        let output_state_indices = &ap_params.output_state_indices;
+        for index_state in 0..output_state_indices.shape()[0] {
+            for index_offset in 0..output_state_indices.shape()[1] {
+                let Some(output_state_index) = output_state_indices[(index_state, index_offset)]
+                else {
+                    continue;
+                };
+                let coef_index = (index_state / 3, index_offset / 3);
+                let coef = ap_params.coefs[coef_index];
+                let delay = ap_params.delays[coef_index];
+                let gain = ap_params.gains[(index_state, index_offset)];
+                // the deviations of the current step are still being innovated
+                let input_slot =
+                    (delay > 0 && delay <= step && delay < depth).then(|| (step - delay) % depth);
+                let input_delayed_slot =
+                    (delay < step && delay + 1 < depth).then(|| (step - delay - 1) % depth);
+                for realization in 0..number_of_realizations {
+                    let input = input_slot.map_or(0.0, |slot| {
+                        self.deviations[(slot, output_state_index, realization)]
+                    });
+                    let input_delayed = input_delayed_slot.map_or(0.0, |slot| {
+                        self.deviations[(slot, output_state_index, realization)]
+                    });
+                    let ap_output = &mut self.ap_outputs[(index_state, index_offset, realization)];
+                    *ap_output = coef.mul_add(input - *ap_output, input_delayed);
+                    innovated[(index_state, realization)] += gain * *ap_output;
+                }
+            }
+        }
+        innovated
+    }
+}
+
+/// Innovates deviations of the previous step through the allpass model,
+/// with one deviation per column.
+///
+/// Older states and allpass outputs are treated as known, as in the
+/// prediction of the system states, so only connections with a delay of
+/// at most one sample propagate the deviations.
+#[must_use]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn innovate_last_step_deviations(
+    ap_params: &APParameters,
+    deviations: &DMatrix<f32>,
+) -> DMatrix<f32> {
+    trace!("Innovating deviations of the last step");
+    let mut innovated = DMatrix::<f32>::zeros(deviations.nrows(), deviations.ncols());
+    let output_state_indices = &ap_params.output_state_indices;
+    for index_state in 0..output_state_indices.shape()[0] {
+        for index_offset in 0..output_state_indices.shape()[1] {
+            let Some(output_state_index) = output_state_indices[(index_state, index_offset)] else {
+                continue;
+            };
+            let coef_index = (index_state / 3, index_offset / 3);
+            // see `innovate_system_states_v1`, the last step enters as the
+            // delayed input for a delay of zero and as the input for a delay of one
+            let factor = match ap_params.delays[coef_index] {
+                0 => 1.0,
+                1 => ap_params.coefs[coef_index],
+                _ => continue,
+            };
+            let weight = ap_params.gains[(index_state, index_offset)] * factor;
+            for column in 0..deviations.ncols() {
+                innovated[(index_state, column)] +=
+                    weight * deviations[(output_state_index, column)];
+            }
+        }
+    }
+    innovated
+}
+
 /// Adds a control function value multiplied by the control matrix to the
 /// system states for the given time index. This allows an external control
 /// signal to be injected into the system states.
+///
+/// The control function is shifted by the time offset of the beat and, if
+/// the stimulus sites have onsets, additionally by the onset of every site.
 #[inline]
 #[tracing::instrument(level = "trace", skip_all)]
 pub fn add_control_function(
     estimations: &mut Estimations,
     functional_description: &FunctionalDescription,
+    beat: usize,
     step: usize,
 ) {
     trace!("Adding control function");
+    let stimulus_sites = &functional_description.stimulus_sites;
+    if stimulus_sites.has_onsets() {
+        let mut system_states = estimations.system_states.at_step_mut(step);
+        for (site, state) in stimulus_sites.states.iter().enumerate() {
+            system_states[*state] += functional_description.time_offsets.delayed_control_value(
+                &functional_description.control_function_values,
+                beat,
+                step,
+                stimulus_sites.onset_samples(site, beat),
+            ) * functional_description.control_matrix[*state];
+        }
+        return;
+    }
     // Add control function
     estimations.system_states.at_step_mut(step).scaled_add(
-        functional_description.control_function_values[step],
+        functional_description.time_offsets.control_value(
+            &functional_description.control_function_values,
+            beat,
+            step,
+        ),
         &*functional_description.control_matrix,
     );
 }
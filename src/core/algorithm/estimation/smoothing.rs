@@ -0,0 +1,242 @@
+use nalgebra::DVector;
+use tracing::{debug, trace};
+
+use super::{assign_sparse_covariance, sparse_to_dense, Estimations};
+use crate::core::{
+    config::algorithm::Algorithm,
+    data::shapes::SystemStates,
+    model::functional::{allpass::shapes::Gains, FunctionalDescription},
+};
+
+/// Singular values of the predicted covariance below this are treated as
+/// zero when it is inverted.
+const PSEUDO_INVERSE_EPSILON: f32 = 1e-6;
+
+/// Stores the predicted and filtered state covariances of the given step
+/// for the RTS smoother.
+///
+/// Does nothing if RTS smoothing is disabled, i.e. if no history was
+/// allocated at the start of the epoch.
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn store_state_covariances(estimations: &mut Estimations, step: usize) {
+    trace!("Storing state covariances of step {step}");
+    if let Some(predicted) = estimations.state_covariances_predicted.as_mut() {
+        predicted[step].assign(&*estimations.state_covariance_pred);
+    }
+    if let Some(filtered) = estimations.state_covariances_filtered.as_mut() {
+        filtered[step].assign(&*estimations.state_covariance_est);
+    }
+}
+
+/// Runs a backward Rauch–Tung–Striebel pass over the filtered system
+/// states of the current beat.
+///
+/// Uses the predicted and filtered state covariances the filter stored for
+/// every step, see [`store_state_covariances`]. The state transition of the
+/// covariances is the one of the filter, i.e. the allpass gains between
+/// neighbouring voxels, see [`super::predict_state_covariance`]. With
+/// `C(k) = P_f(k) Fᵀ P_p(k+1)⁺` this gives
+///
+/// `x_s(k) = x_f(k) + C(k) (x_s(k+1) - x_p(k+1))`
+///
+/// `P_s(k) = P_f(k) + C(k) (P_s(k+1) - P_p(k+1)) C(k)ᵀ`
+///
+/// The smoothed states are written to `system_states_smoothed`, the
+/// smoothed covariances to `state_covariances_smoothed`.
+///
+/// The covariances are expanded to dense matrices and the predicted ones
+/// are pseudo inverted at every step, which makes the smoother only
+/// feasible for small models.
+///
+/// # Panics
+///
+/// Panics if the predicted states or the covariances were not stored
+/// during the forward pass.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_smoothed_states(
+    estimations: &mut Estimations,
+    functional_description: &FunctionalDescription,
+    config: &Algorithm,
+) {
+    debug!("Calculating RTS smoothed states");
+    let num_steps = estimations.system_states.num_steps();
+    let num_states = estimations.system_states.num_states();
+
+    let smoothed = estimations
+        .system_states_smoothed
+        .get_or_insert_with(|| SystemStates::empty(num_steps, num_states));
+
+    // without measurement updates the filter has nothing to propagate back
+    if !config.model.common.apply_system_update {
+        smoothed.assign(&*estimations.system_states);
+        return;
+    }
+    let smoothed_covariances = estimations
+        .state_covariances_smoothed
+        .get_or_insert_with(|| vec![Gains::empty(num_states); num_steps]);
+
+    let predicted = estimations
+        .system_states_predicted
+        .as_ref()
+        .expect("Predicted states to be stored during the forward pass.");
+    let predicted_covariances = estimations
+        .state_covariances_predicted
+        .as_ref()
+        .expect("Predicted covariances to be stored during the forward pass.");
+    let filtered_covariances = estimations
+        .state_covariances_filtered
+        .as_ref()
+        .expect("Filtered covariances to be stored during the forward pass.");
+
+    let ap_params = &functional_description.ap_params;
+    let transition = sparse_to_dense(&ap_params.gains, ap_params);
+    let filtered_states = |step: usize| {
+        DVector::from_iterator(
+            num_states,
+            estimations.system_states.at_step(step).iter().copied(),
+        )
+    };
+
+    // the last step has no later measurements
+    let last_step = num_steps - 1;
+    let mut state_next = filtered_states(last_step);
+    let mut covariance_next = sparse_to_dense(&filtered_covariances[last_step], ap_params);
+    smoothed
+        .at_step_mut(last_step)
+        .assign(&*estimations.system_states.at_step(last_step));
+    smoothed_covariances[last_step].assign(&*filtered_covariances[last_step]);
+
+    for step in (0..last_step).rev() {
+        trace!("Smoothing step {step}");
+        let filtered_covariance = sparse_to_dense(&filtered_covariances[step], ap_params);
+        let predicted_covariance = sparse_to_dense(&predicted_covariances[step + 1], ap_params);
+        let predicted_state =
+            DVector::from_iterator(num_states, predicted.at_step(step + 1).iter().copied());
+
+        let smoother_gain = &filtered_covariance
+            * transition.transpose()
+            * predicted_covariance
+                .clone()
+                .pseudo_inverse(PSEUDO_INVERSE_EPSILON)
+                .expect("SVD to be computed.");
+        let state = filtered_states(step) + &smoother_gain * (&state_next - predicted_state);
+        let covariance = filtered_covariance
+            + &smoother_gain
+                * (&covariance_next - predicted_covariance)
+                * smoother_gain.transpose();
+
+        smoothed
+            .at_step_mut(step)
+            .iter_mut()
+            .zip(state.iter())
+            .for_each(|(smoothed, state)| *smoothed = *state);
+        assign_sparse_covariance(&mut smoothed_covariances[step], ap_params, |a, b| {
+            covariance[(a, b)]
+        });
+        state_next = state;
+        covariance_next = covariance;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Dim;
+
+    use super::*;
+    use crate::core::model::functional::allpass::offset_to_gain_index;
+
+    /// Allocates the history of the forward pass like `run_epoch` does.
+    fn allocate_history(estimations: &mut Estimations, num_steps: usize, num_states: usize) {
+        estimations.system_states_predicted = Some(SystemStates::empty(num_steps, num_states));
+        estimations.state_covariances_predicted = Some(vec![Gains::empty(num_states); num_steps]);
+        estimations.state_covariances_filtered = Some(vec![Gains::empty(num_states); num_steps]);
+    }
+
+    #[test]
+    fn smoothing_without_system_update_keeps_filtered_states() {
+        let number_of_states = 3;
+        let number_of_sensors = 2;
+        let number_of_steps = 10;
+        let voxels_in_dims = Dim([1, 1, 1]);
+
+        let mut estimations =
+            Estimations::empty(number_of_states, number_of_sensors, number_of_steps, 1);
+        let functional_description = FunctionalDescription::empty(
+            number_of_states,
+            number_of_sensors,
+            number_of_steps,
+            1,
+            voxels_in_dims,
+        );
+        let mut config = Algorithm::default();
+        config.model.common.apply_system_update = false;
+
+        estimations.system_states.fill(1.0);
+        allocate_history(&mut estimations, number_of_steps, number_of_states);
+
+        calculate_smoothed_states(&mut estimations, &functional_description, &config);
+
+        let smoothed = estimations.system_states_smoothed.unwrap();
+        smoothed
+            .iter()
+            .zip(estimations.system_states.iter())
+            .for_each(|(smoothed, filtered)| assert_relative_eq!(smoothed, filtered));
+    }
+
+    #[test]
+    fn smoothing_uses_stored_covariances() {
+        let number_of_states = 6;
+        let number_of_sensors = 1;
+        let number_of_steps = 2;
+        let gain = 0.8;
+        let filtered_covariance = 0.5;
+        let predicted_covariance = 2.0;
+        let innovation = 1.0;
+
+        let mut estimations =
+            Estimations::empty(number_of_states, number_of_sensors, number_of_steps, 1);
+        let mut functional_description = FunctionalDescription::empty(
+            number_of_states,
+            number_of_sensors,
+            number_of_steps,
+            1,
+            Dim([2, 1, 1]),
+        );
+        let mut config = Algorithm::default();
+        config.model.common.apply_system_update = true;
+
+        // state 0 receives state 3 of the neighbouring voxel
+        let forward = offset_to_gain_index(1, 0, 0, 0).unwrap();
+        let backward = offset_to_gain_index(-1, 0, 0, 0).unwrap();
+        let ap_params = &mut functional_description.ap_params;
+        ap_params.output_state_indices[(0, forward)] = Some(3);
+        ap_params.output_state_indices[(3, backward)] = Some(0);
+        ap_params.gains[(0, forward)] = gain;
+
+        allocate_history(&mut estimations, number_of_steps, number_of_states);
+        let filtered = estimations.state_covariances_filtered.as_mut().unwrap();
+        filtered[0][(0, forward)] = filtered_covariance;
+        filtered[0][(3, backward)] = filtered_covariance;
+        let predicted = estimations.state_covariances_predicted.as_mut().unwrap();
+        predicted[1][(0, forward)] = predicted_covariance;
+        predicted[1][(3, backward)] = predicted_covariance;
+        // the filter was surprised by state 3 at the last step
+        estimations.system_states[(1, 3)] = innovation;
+
+        calculate_smoothed_states(&mut estimations, &functional_description, &config);
+
+        // C(0) only maps state 3 of step 1 to state 0 of step 0
+        let smoothed = estimations.system_states_smoothed.unwrap();
+        for (index, value) in smoothed.indexed_iter() {
+            let expected = match index {
+                (0, 0) => filtered_covariance * gain / predicted_covariance * innovation,
+                (1, 3) => innovation,
+                _ => 0.0,
+            };
+            assert_relative_eq!(*value, expected, epsilon = 1e-6);
+        }
+        let smoothed_covariances = estimations.state_covariances_smoothed.unwrap();
+        assert_relative_eq!(smoothed_covariances[0][(0, forward)], filtered_covariance);
+    }
+}
@@ -0,0 +1,337 @@
+use nalgebra::DMatrix;
+use tracing::{debug, trace, warn};
+
+use super::{invert_or_pseudo_invert, sparse_to_dense};
+use crate::core::{
+    config::algorithm::Algorithm,
+    model::functional::{
+        allpass::shapes::{Coefs, Gains, UnitDelays},
+        kalman::KalmanGain,
+        measurement::MeasurementCovariance,
+        FunctionalDescription,
+    },
+};
+
+/// Maximum number of doubling steps, each one doubles the number of
+/// covered time steps.
+const MAX_ITERATIONS: usize = 64;
+/// Relative change of the covariance below which the doubling stops.
+const RELATIVE_TOLERANCE: f32 = 1e-6;
+
+/// Model parameters the cached steady-state Kalman gains were computed with.
+#[derive(Debug, PartialEq, Clone)]
+struct CachedParameters {
+    gains: Gains,
+    coefs: Coefs,
+    delays: UnitDelays,
+    process_covariance: Gains,
+    measurement_covariance: MeasurementCovariance,
+}
+
+impl CachedParameters {
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn from_functional_description(functional_description: &FunctionalDescription) -> Self {
+        trace!("Caching steady-state parameters");
+        let ap_params = &functional_description.ap_params;
+        Self {
+            gains: ap_params.gains.clone(),
+            coefs: ap_params.coefs.clone(),
+            delays: ap_params.delays.clone(),
+            process_covariance: functional_description.process_covariance.clone(),
+            measurement_covariance: functional_description.measurement_covariance.clone(),
+        }
+    }
+
+    /// Returns true if any parameter of the functional description differs
+    /// from the cached one by more than the tolerance.
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn is_outdated(&self, functional_description: &FunctionalDescription, tolerance: f32) -> bool {
+        trace!("Checking cached steady-state parameters");
+        let ap_params = &functional_description.ap_params;
+        let differs = |cached: &ndarray::Array2<f32>, current: &ndarray::Array2<f32>| {
+            cached
+                .iter()
+                .zip(current.iter())
+                .any(|(cached, current)| (cached - current).abs() > tolerance)
+        };
+        differs(&self.gains, &ap_params.gains)
+            || differs(&self.coefs, &ap_params.coefs)
+            || *self.delays != *ap_params.delays
+            || differs(
+                &self.process_covariance,
+                &functional_description.process_covariance,
+            )
+            || differs(
+                &self.measurement_covariance,
+                &functional_description.measurement_covariance,
+            )
+    }
+}
+
+/// Cache of steady-state Kalman gains, one per beat (i.e. per motion step
+/// measurement matrix).
+///
+/// The cached gains stay valid until the allpass parameters or the process
+/// and measurement covariances differ from the ones used to compute them by
+/// more than the configured tolerance.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct SteadyStateKalmanGains {
+    gains: Vec<Option<KalmanGain>>,
+    parameters: Option<CachedParameters>,
+}
+
+impl SteadyStateKalmanGains {
+    /// Sets the Kalman gain of the functional description to the steady-state
+    /// gain for the given beat.
+    ///
+    /// The gain is taken from the cache if possible, otherwise the Riccati
+    /// equation is solved and the result is cached. If the allpass
+    /// parameters or the covariances changed beyond
+    /// `steady_state_kalman_gain_tolerance`, all cached gains are dropped.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn apply(
+        &mut self,
+        functional_description: &mut FunctionalDescription,
+        config: &Algorithm,
+        beat: usize,
+    ) {
+        debug!("Applying steady-state Kalman gain for beat {beat}");
+        let outdated = self.parameters.as_ref().is_none_or(|cached| {
+            cached.is_outdated(
+                functional_description,
+                config.steady_state_kalman_gain_tolerance,
+            )
+        });
+        if outdated {
+            trace!("Invalidating cached steady-state Kalman gains");
+            self.gains.clear();
+            self.parameters = Some(CachedParameters::from_functional_description(
+                functional_description,
+            ));
+        }
+        let num_beats = functional_description.measurement_matrix.shape()[0];
+        if self.gains.len() != num_beats {
+            self.gains = vec![None; num_beats];
+        }
+        let kalman_gain = self.gains[beat].get_or_insert_with(|| {
+            calculate_steady_state_kalman_gain(functional_description, beat)
+        });
+        functional_description.kalman_gain.assign(&**kalman_gain);
+    }
+}
+
+/// Calculates the steady-state Kalman gain for the given beat.
+///
+/// Solves the discrete algebraic Riccati equation of the predicted state
+/// covariance
+///
+/// `P = F P Fᵀ - F P Hᵀ (H P Hᵀ + R)⁻¹ H P Fᵀ + Q`
+///
+/// with the structured doubling algorithm, see [`solve_riccati_equation`],
+/// and returns `K = P Hᵀ (H P Hᵀ + R)⁻¹`. The state transition `F` and the
+/// process covariance `Q` are the ones of the filter, see
+/// [`super::predict_state_covariance`], expanded to dense matrices, which
+/// makes this only feasible for small models.
+#[must_use]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_steady_state_kalman_gain(
+    functional_description: &FunctionalDescription,
+    beat: usize,
+) -> KalmanGain {
+    debug!("Calculating steady-state Kalman gain");
+    let ap_params = &functional_description.ap_params;
+    let measurement_matrix = functional_description.measurement_matrix.at_beat(beat);
+    let measurement_matrix = DMatrix::from_row_slice(
+        measurement_matrix.shape()[0],
+        measurement_matrix.shape()[1],
+        measurement_matrix
+            .as_standard_layout()
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+    let measurement_covariance = &functional_description.measurement_covariance;
+    let measurement_covariance = DMatrix::from_row_slice(
+        measurement_covariance.shape()[0],
+        measurement_covariance.shape()[1],
+        measurement_covariance
+            .as_standard_layout()
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+
+    let state_covariance = solve_riccati_equation(
+        &sparse_to_dense(&ap_params.gains, ap_params),
+        &measurement_matrix,
+        &sparse_to_dense(&functional_description.process_covariance, ap_params),
+        &measurement_covariance,
+    );
+    let innovation_covariance =
+        &measurement_matrix * &state_covariance * measurement_matrix.transpose()
+            + &measurement_covariance;
+    let gain = &state_covariance
+        * measurement_matrix.transpose()
+        * invert_or_pseudo_invert(innovation_covariance);
+
+    let mut kalman_gain = functional_description.kalman_gain.clone();
+    kalman_gain
+        .indexed_iter_mut()
+        .for_each(|(index, value)| *value = gain[index]);
+    kalman_gain
+}
+
+/// Solves `P = F P Fᵀ - F P Hᵀ (H P Hᵀ + R)⁻¹ H P Fᵀ + Q` for the
+/// predicted state covariance `P` with the structured doubling algorithm.
+///
+/// Starting from `A = Fᵀ`, `G = Hᵀ R⁻¹ H` and `X = Q`, every step computes
+/// `W = I + G X` and
+///
+/// `A ← A W⁻¹ A`, `G ← G + A W⁻¹ G Aᵀ`, `X ← X + Aᵀ X W⁻¹ A`
+///
+/// where `X` after `k` steps equals the covariance of the Riccati
+/// recursion after `2^k` steps, so the iteration converges quadratically.
+#[tracing::instrument(level = "debug", skip_all)]
+fn solve_riccati_equation(
+    transition: &DMatrix<f32>,
+    measurement_matrix: &DMatrix<f32>,
+    process_covariance: &DMatrix<f32>,
+    measurement_covariance: &DMatrix<f32>,
+) -> DMatrix<f32> {
+    debug!("Solving Riccati equation");
+    let num_states = transition.nrows();
+    let identity = DMatrix::<f32>::identity(num_states, num_states);
+
+    let mut a = transition.transpose();
+    let mut g = measurement_matrix.transpose()
+        * invert_or_pseudo_invert(measurement_covariance.clone())
+        * measurement_matrix;
+    let mut x = process_covariance.clone();
+
+    for iteration in 0..MAX_ITERATIONS {
+        let w = (&identity + &g * &x).lu();
+        let (Some(w_inv_a), Some(w_inv_g)) = (w.solve(&a), w.solve(&g)) else {
+            warn!("Riccati equation could not be solved, I + G X is singular");
+            return x;
+        };
+        let x_next = &x + a.transpose() * &x * &w_inv_a;
+        let g_next = &g + &a * w_inv_g * a.transpose();
+        a = &a * w_inv_a;
+        // keep the covariances symmetric
+        let x_next = (&x_next + x_next.transpose()) * 0.5;
+        g = (&g_next + g_next.transpose()) * 0.5;
+
+        let change = (&x_next - &x).norm();
+        x = x_next;
+        if change <= RELATIVE_TOLERANCE * x.norm() {
+            trace!(
+                "Riccati equation converged after {} doublings",
+                iteration + 1
+            );
+            return x;
+        }
+    }
+    warn!("Riccati equation did not converge after {MAX_ITERATIONS} doublings");
+    x
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use nalgebra::DMatrix;
+    use ndarray::Dim;
+
+    use super::{solve_riccati_equation, SteadyStateKalmanGains};
+    use crate::core::{
+        config::algorithm::Algorithm,
+        model::functional::{allpass::offset_to_gain_index, FunctionalDescription},
+    };
+
+    #[test]
+    fn doubling_solves_scalar_riccati_equation() {
+        let one = DMatrix::from_element(1, 1, 1.0);
+
+        let state_covariance = solve_riccati_equation(&one, &one, &one, &one);
+
+        // P = P / (P + 1) + 1 is solved by the golden ratio
+        assert_relative_eq!(
+            state_covariance[(0, 0)],
+            5f32.sqrt().mul_add(0.5, 0.5),
+            epsilon = 1e-5
+        );
+    }
+
+    #[test]
+    fn doubling_matches_iterated_riccati_recursion() {
+        let transition = DMatrix::from_row_slice(2, 2, &[0.9, 0.2, -0.1, 0.7]);
+        let measurement_matrix = DMatrix::from_row_slice(1, 2, &[1.0, 0.5]);
+        let process_covariance = DMatrix::from_row_slice(2, 2, &[0.5, 0.1, 0.1, 0.3]);
+        let measurement_covariance = DMatrix::from_element(1, 1, 0.1);
+
+        let state_covariance = solve_riccati_equation(
+            &transition,
+            &measurement_matrix,
+            &process_covariance,
+            &measurement_covariance,
+        );
+
+        let mut iterated = process_covariance.clone();
+        for _ in 0..1000 {
+            let innovation_covariance =
+                &measurement_matrix * &iterated * measurement_matrix.transpose()
+                    + &measurement_covariance;
+            let cross_covariance = &transition * &iterated * measurement_matrix.transpose();
+            iterated = &transition * &iterated * transition.transpose()
+                - &cross_covariance
+                    * innovation_covariance.try_inverse().unwrap()
+                    * cross_covariance.transpose()
+                + &process_covariance;
+        }
+        for (doubled, iterated) in state_covariance.iter().zip(iterated.iter()) {
+            assert_relative_eq!(doubled, iterated, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn cached_gain_is_recomputed_when_covariances_change() {
+        let number_of_states = 6;
+        let number_of_sensors = 2;
+        let mut functional_description =
+            FunctionalDescription::empty(number_of_states, number_of_sensors, 1, 1, Dim([2, 1, 1]));
+        for dimension in 0..3 {
+            let forward = offset_to_gain_index(1, 0, 0, dimension).unwrap();
+            let backward = offset_to_gain_index(-1, 0, 0, dimension).unwrap();
+            for state in 0..3 {
+                let ap_params = &mut functional_description.ap_params;
+                ap_params.output_state_indices[(state, forward)] = Some(3 + dimension);
+                ap_params.output_state_indices[(state + 3, backward)] = Some(dimension);
+                ap_params.gains[(state, forward)] = 0.1;
+                ap_params.gains[(state + 3, backward)] = 0.1;
+            }
+        }
+        functional_description.measurement_matrix[(0, 0, 0)] = 1.0;
+        functional_description.measurement_matrix[(0, 1, 3)] = 1.0;
+        functional_description
+            .measurement_covariance
+            .diag_mut()
+            .fill(0.1);
+        let config = Algorithm::default();
+
+        let mut cache = SteadyStateKalmanGains::default();
+        cache.apply(&mut functional_description, &config, 0);
+        assert!(cache.gains[0].is_some());
+
+        // unchanged parameters keep the cached gain
+        let parameters = cache.parameters.clone();
+        cache.apply(&mut functional_description, &config, 0);
+        assert_eq!(cache.parameters, parameters);
+
+        functional_description
+            .measurement_covariance
+            .diag_mut()
+            .fill(0.2);
+        cache.apply(&mut functional_description, &config, 0);
+        assert_relative_eq!(
+            cache.parameters.as_ref().unwrap().measurement_covariance[(0, 0)],
+            0.2
+        );
+    }
+}
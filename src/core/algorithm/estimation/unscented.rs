@@ -0,0 +1,280 @@
+use nalgebra::DMatrix;
+use tracing::{debug, trace};
+
+use super::{
+    assign_sparse_covariance, invert_or_pseudo_invert, prediction::innovate_last_step_deviations,
+    process_noise_factor, Estimations,
+};
+use crate::core::model::functional::FunctionalDescription;
+
+// With these parameters all weights are non-negative, which keeps the
+// covariances positive semi-definite in single precision.
+/// Spread of the sigma points around the mean.
+const ALPHA: f32 = 1.0;
+/// Prior knowledge about the distribution, 2 is optimal for gaussians.
+const BETA: f32 = 2.0;
+/// Secondary scaling parameter.
+const KAPPA: f32 = 0.0;
+/// Added to the diagonal if the covariance is not positive definite.
+const JITTER: f32 = 1e-6;
+
+/// Dense state covariance tracked by the unscented Kalman filter.
+#[derive(Debug, PartialEq, Clone)]
+pub struct UnscentedCovariance {
+    /// Has dimensions (`number_of_states`, `number_of_states`)
+    pub covariance: DMatrix<f32>,
+}
+
+impl UnscentedCovariance {
+    /// Creates a new covariance initialized with the given dense process
+    /// covariance.
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_process_covariance(process_covariance: &DMatrix<f32>) -> Self {
+        debug!("Creating unscented covariance");
+        Self {
+            covariance: process_covariance.clone(),
+        }
+    }
+}
+
+/// Weights of the sigma points for the mean and the covariance.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct SigmaWeights {
+    scaling: f32,
+    mean_center: f32,
+    covariance_center: f32,
+    others: f32,
+}
+
+impl SigmaWeights {
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "trace")]
+    fn new(number_of_states: usize) -> Self {
+        trace!("Calculating sigma point weights");
+        let n = number_of_states as f32;
+        let lambda = ALPHA.powi(2).mul_add(n + KAPPA, -n);
+        let mean_center = lambda / (n + lambda);
+        Self {
+            scaling: (n + lambda).sqrt(),
+            mean_center,
+            covariance_center: mean_center + ALPHA.mul_add(-ALPHA, 1.0) + BETA,
+            others: 1.0 / (2.0 * (n + lambda)),
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn mean(&self, index: usize) -> f32 {
+        if index == 0 {
+            self.mean_center
+        } else {
+            self.others
+        }
+    }
+
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn covariance(&self, index: usize) -> f32 {
+        if index == 0 {
+            self.covariance_center
+        } else {
+            self.others
+        }
+    }
+}
+
+/// Calculates the sigma points around a zero mean with the given covariance.
+///
+/// Returns a matrix with one sigma point per column, the first one being
+/// the mean.
+///
+/// # Panics
+///
+/// Panics if the covariance is not positive semi-definite.
+#[tracing::instrument(level = "trace", skip_all)]
+fn calculate_sigma_points(covariance: &DMatrix<f32>, weights: &SigmaWeights) -> DMatrix<f32> {
+    trace!("Calculating sigma points");
+    let n = covariance.nrows();
+    let square_root = covariance
+        .clone()
+        .cholesky()
+        .or_else(|| (covariance + DMatrix::<f32>::identity(n, n) * JITTER).cholesky())
+        .expect("Covariance to be positive semi-definite.")
+        .l()
+        * weights.scaling;
+    let mut sigma_points = DMatrix::<f32>::zeros(n, 2 * n + 1);
+    for column in 0..n {
+        sigma_points
+            .column_mut(1 + column)
+            .copy_from(&square_root.column(column));
+        sigma_points
+            .column_mut(1 + n + column)
+            .copy_from(&(-square_root.column(column)));
+    }
+    sigma_points
+}
+
+/// Calculates the weighted mean and covariance of the given sigma points.
+#[tracing::instrument(level = "trace", skip_all)]
+fn calculate_moments(
+    points: &DMatrix<f32>,
+    weights: &SigmaWeights,
+) -> (DMatrix<f32>, DMatrix<f32>) {
+    trace!("Calculating sigma point moments");
+    let mut mean = DMatrix::<f32>::zeros(points.nrows(), 1);
+    for (index, column) in points.column_iter().enumerate() {
+        mean += column * weights.mean(index);
+    }
+    let mut centered = points.clone();
+    centered
+        .column_iter_mut()
+        .enumerate()
+        .for_each(|(index, mut column)| {
+            column -= mean.column(0);
+            column *= weights.covariance(index).sqrt();
+        });
+    (mean, centered)
+}
+
+/// Calculates the Kalman gain of an unscented Kalman filter for the given
+/// step and writes it to the functional description.
+///
+/// The mean is propagated by the allpass model in
+/// `calculate_system_prediction`. The covariance is propagated with sigma
+/// points through the allpass model, see [`innovate_last_step_deviations`],
+/// and through the measurement matrix. The process covariance of the
+/// functional description is added after the propagation, see
+/// [`process_noise_factor`]. The sparse state covariances in the
+/// estimations are filled from the dense covariance before and after the
+/// update. If the innovation covariance is singular, its pseudo inverse is
+/// used.
+///
+/// The dense covariance and the `2n + 1` sigma points make this backend
+/// only feasible for small models.
+///
+/// # Panics
+///
+/// Panics if the covariances are not positive semi-definite.
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_unscented_kalman_gain(
+    functional_description: &mut FunctionalDescription,
+    estimations: &mut Estimations,
+    beat: usize,
+) {
+    trace!("Calculating unscented Kalman gain");
+    let num_states = estimations.system_states.num_states();
+    let process_noise = process_noise_factor(
+        &functional_description.process_covariance,
+        &functional_description.ap_params,
+    );
+    let process_covariance = &process_noise * process_noise.transpose();
+    let state = estimations
+        .unscented
+        .get_or_insert_with(|| UnscentedCovariance::from_process_covariance(&process_covariance));
+    let weights = SigmaWeights::new(num_states);
+
+    // prediction
+    let sigma_points = calculate_sigma_points(&state.covariance, &weights);
+    let (_, centered) = calculate_moments(
+        &innovate_last_step_deviations(&functional_description.ap_params, &sigma_points),
+        &weights,
+    );
+    let mut covariance_pred = &centered * centered.transpose();
+    covariance_pred += &process_covariance;
+    assign_sparse_covariance(
+        &mut estimations.state_covariance_pred,
+        &functional_description.ap_params,
+        |a, b| covariance_pred[(a, b)],
+    );
+
+    // update
+    let measurement_matrix = functional_description.measurement_matrix.at_beat(beat);
+    let measurement_matrix = DMatrix::from_row_slice(
+        measurement_matrix.shape()[0],
+        measurement_matrix.shape()[1],
+        measurement_matrix
+            .as_standard_layout()
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+    let measurement_covariance = &functional_description.measurement_covariance;
+    let measurement_covariance = DMatrix::from_row_slice(
+        measurement_covariance.shape()[0],
+        measurement_covariance.shape()[1],
+        measurement_covariance
+            .as_slice()
+            .expect("Slice to be some."),
+    );
+    let sigma_points = calculate_sigma_points(&covariance_pred, &weights);
+    let (_, centered_states) = calculate_moments(&sigma_points, &weights);
+    let (_, centered_measurements) =
+        calculate_moments(&(&measurement_matrix * &sigma_points), &weights);
+    let cross_covariance = &centered_states * centered_measurements.transpose();
+    let innovation_covariance =
+        &centered_measurements * centered_measurements.transpose() + &measurement_covariance;
+    estimations
+        .innovation_covariance
+        .copy_from(&invert_or_pseudo_invert(innovation_covariance.clone()));
+    let kalman_gain = &cross_covariance * &estimations.innovation_covariance;
+    functional_description
+        .kalman_gain
+        .indexed_iter_mut()
+        .for_each(|(index, value)| *value = kalman_gain[index]);
+
+    state.covariance =
+        covariance_pred - &kalman_gain * innovation_covariance * kalman_gain.transpose();
+    // keep the covariance symmetric
+    state.covariance = (&state.covariance + state.covariance.transpose()) * 0.5;
+    assign_sparse_covariance(
+        &mut estimations.state_covariance_est,
+        &functional_description.ap_params,
+        |a, b| state.covariance[(a, b)],
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use nalgebra::DMatrix;
+    use ndarray::Dim;
+
+    use super::*;
+    use crate::core::model::functional::allpass::{offset_to_gain_index, APParameters};
+
+    #[test]
+    fn sigma_points_reproduce_covariance() {
+        let covariance = DMatrix::from_row_slice(2, 2, &[2.0, 0.5, 0.5, 1.0]);
+        let weights = SigmaWeights::new(2);
+
+        let sigma_points = calculate_sigma_points(&covariance, &weights);
+        let (mean, centered) = calculate_moments(&sigma_points, &weights);
+        let reproduced = &centered * centered.transpose();
+
+        mean.iter()
+            .for_each(|value| assert_relative_eq!(*value, 0.0, epsilon = 1e-5));
+        covariance
+            .iter()
+            .zip(reproduced.iter())
+            .for_each(|(expected, actual)| assert_relative_eq!(expected, actual, epsilon = 1e-5));
+    }
+
+    #[test]
+    fn sigma_points_follow_allpass_delays() {
+        let mut ap_params = APParameters::empty(9, Dim([3, 1, 1]));
+        for (x_offset, y_offset, input_state, delay) in [(1, 0, 3, 1), (0, 1, 6, 2)] {
+            let offset_index = offset_to_gain_index(x_offset, y_offset, 0, 0).unwrap();
+            ap_params.output_state_indices[(0, offset_index)] = Some(input_state);
+            ap_params.gains[(0, offset_index)] = 2.0;
+            ap_params.coefs[(0, offset_index / 3)] = 0.5;
+            ap_params.delays[(0, offset_index / 3)] = delay;
+        }
+        let deviations = DMatrix::from_element(9, 3, 1.0);
+
+        let innovated = innovate_last_step_deviations(&ap_params, &deviations);
+
+        // only the connection with a delay of one sample sees the last step
+        for column in 0..3 {
+            assert_relative_eq!(innovated[(0, column)], 1.0);
+        }
+        assert_relative_eq!(innovated.row_sum().sum(), 3.0);
+    }
+}
@@ -4,7 +4,7 @@ use super::GPU;
 use crate::core::{
     algorithm::{estimation::EstimationsGPU, refinement::derivation::DerivativesGPU},
     config::algorithm::Algorithm,
-    model::ModelGPU,
+    model::{functional::measurement::SensorWeights, ModelGPU},
 };
 
 pub struct DerivationKernel {
@@ -16,8 +16,10 @@ pub struct DerivationKernel {
     fir_kernel: Kernel,
     iir_kernel: Kernel,
     coefs_kernel: Kernel,
+    control_function_kernel: Kernel,
     freeze_gains: bool,
     freeze_delays: bool,
+    learn_control_function: bool,
 }
 
 impl DerivationKernel {
@@ -45,6 +47,9 @@ impl DerivationKernel {
         let queue = &gpu.queue;
         let device = &gpu.device;
         let number_of_voxels = number_of_states / 3;
+        let loss_scaling = config.mse_strength
+            / SensorWeights::from_gpu(&model.functional_description.sensor_weights)
+                .active_weight_sum(number_of_sensors as usize);
 
         let residual_src =
             std::fs::read_to_string("src/core/algorithm/gpu/kernels/calculate_residuals.cl")
@@ -58,6 +63,7 @@ impl DerivationKernel {
             .arg(&estimations.residuals)
             .arg(&estimations.measurements)
             .arg(actual_measurements)
+            .arg(&model.functional_description.sensor_weights)
             .arg(&estimations.step)
             .arg(&estimations.beat)
             .arg(number_of_sensors)
@@ -97,6 +103,7 @@ impl DerivationKernel {
             .arg(&derivatives.mapped_residuals)
             .arg(&model.functional_description.measurement_matrix)
             .arg(&estimations.residuals)
+            .arg(&model.functional_description.sensor_weights)
             .arg(&estimations.beat)
             .arg_local::<f32>(work_group_size)
             .arg(number_of_states)
@@ -151,7 +158,7 @@ impl DerivationKernel {
             .arg(&estimations.ap_outputs_now)
             .arg(&derivatives.maximum_regularization)
             .arg(&derivatives.mapped_residuals)
-            .arg(config.mse_strength / number_of_sensors as f32)
+            .arg(loss_scaling)
             .arg(config.maximum_regularization_strength)
             .arg(number_of_states)
             .build()
@@ -209,11 +216,37 @@ impl DerivationKernel {
             .arg(&model.functional_description.ap_params.coefs)
             .arg(&model.functional_description.ap_params.delays)
             .arg_local::<f32>(9) // 4x4 local memory
-            .arg(config.mse_strength / number_of_sensors as f32)
+            .arg(loss_scaling)
             .arg(number_of_states)
             .build()
             .unwrap();
 
+        let derivatives_control_src = std::fs::read_to_string(
+            "src/core/algorithm/gpu/kernels/calculate_derivatives_control.cl",
+        )
+        .unwrap();
+        let derivatives_control_program = Program::builder()
+            .src(format!("{atomic_src}\n{derivatives_control_src}"))
+            .build(context)
+            .unwrap();
+
+        let control_function_kernel = Kernel::builder()
+            .program(&derivatives_control_program)
+            .name("calculate_derivatives_control_function")
+            .queue(queue.clone())
+            .global_work_size(number_of_states)
+            .arg(&derivatives.control_function)
+            .arg(&model.functional_description.control_matrix)
+            .arg(&derivatives.mapped_residuals)
+            .arg(&estimations.beat)
+            .arg(&estimations.step)
+            .arg(&model.functional_description.time_offsets)
+            .arg(loss_scaling)
+            .arg(number_of_states)
+            .arg(number_of_steps)
+            .build()
+            .unwrap();
+
         Self {
             residual_kernel,
             reset_mapped_residual_kernel,
@@ -223,8 +256,10 @@ impl DerivationKernel {
             fir_kernel,
             iir_kernel,
             coefs_kernel,
+            control_function_kernel,
             freeze_gains: config.freeze_gains,
             freeze_delays: config.freeze_delays,
+            learn_control_function: config.learn_control_function,
         }
     }
 
@@ -235,7 +270,7 @@ impl DerivationKernel {
         // See prediction.rs for implementation details.
         unsafe {
             self.residual_kernel.enq().unwrap();
-            if !(self.freeze_gains && self.freeze_delays) {
+            if !(self.freeze_gains && self.freeze_delays) || self.learn_control_function {
                 self.reset_mapped_residual_kernel.enq().unwrap();
                 self.mapped_residual_kernel.enq().unwrap();
             }
@@ -243,6 +278,9 @@ impl DerivationKernel {
             if !self.freeze_gains {
                 self.gains_kernel.enq().unwrap();
             }
+            if self.learn_control_function {
+                self.control_function_kernel.enq().unwrap();
+            }
             if !self.freeze_delays {
                 self.fir_kernel.enq().unwrap();
                 self.iir_kernel.enq().unwrap();
@@ -270,8 +308,9 @@ mod tests {
             estimation::{calculate_residuals, prediction::calculate_system_prediction},
             gpu::{derivation::DerivationKernel, prediction::PredictionKernel, GPU},
             refinement::derivation::{
-                calculate_derivatives_coefs_textbook, calculate_derivatives_gains,
-                calculate_mapped_residuals, calculate_maximum_regularization,
+                calculate_derivatives_coefs_textbook, calculate_derivatives_control_function,
+                calculate_derivatives_gains, calculate_mapped_residuals,
+                calculate_maximum_regularization,
             },
         },
         config::Config,
@@ -288,6 +327,7 @@ mod tests {
     fn test_derivation() {
         let mut config = Config::default();
         config.algorithm.freeze_delays = false;
+        config.algorithm.learn_control_function = true;
         let mut results_cpu = Results::get_default();
         let gpu = GPU::new();
         let results_gpu = results_cpu.to_gpu(&gpu.queue);
@@ -301,6 +341,13 @@ mod tests {
             .spatial_description
             .sensors
             .count();
+        let sensor_weight_sum = results_cpu
+            .model
+            .as_ref()
+            .unwrap()
+            .functional_description
+            .sensor_weights
+            .active_weight_sum(number_of_sensors);
 
         let prediction_kernel = PredictionKernel::new(
             &gpu,
@@ -350,7 +397,18 @@ mod tests {
                 0,
                 step,
             );
-            calculate_residuals(&mut results_cpu.estimations, &data, 0, step);
+            calculate_residuals(
+                &mut results_cpu.estimations,
+                &data,
+                &results_cpu
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .sensor_weights,
+                0,
+                step,
+            );
             calculate_mapped_residuals(
                 &mut results_cpu.derivatives.mapped_residuals,
                 &results_cpu.estimations.residuals,
@@ -374,7 +432,7 @@ mod tests {
                 &results_cpu.derivatives.maximum_regularization,
                 &results_cpu.derivatives.mapped_residuals,
                 &config.algorithm,
-                number_of_sensors,
+                sensor_weight_sum,
             );
             calculate_derivatives_coefs_textbook(
                 &mut results_cpu.derivatives,
@@ -383,6 +441,31 @@ mod tests {
                 step,
                 &config.algorithm,
             );
+            calculate_derivatives_control_function(
+                &mut results_cpu.derivatives.control_function,
+                &results_cpu.derivatives.mapped_residuals,
+                &results_cpu
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .control_matrix,
+                &results_cpu
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .time_offsets,
+                &results_cpu
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .stimulus_sites,
+                0,
+                step,
+                sensor_weight_sum,
+            );
             results_gpu
                 .estimations
                 .step
@@ -444,6 +527,15 @@ mod tests {
                 results_from_gpu.derivatives.coefs.as_slice().unwrap(),
                 epsilon = 1e-6
             );
+            assert_relative_eq!(
+                results_cpu.derivatives.control_function.as_slice().unwrap(),
+                results_from_gpu
+                    .derivatives
+                    .control_function
+                    .as_slice()
+                    .unwrap(),
+                epsilon = 1e-5
+            );
         }
     }
     #[test]
@@ -505,6 +597,14 @@ mod tests {
             .build()
             .unwrap();
 
+        let sensor_weights_buffer = Buffer::builder()
+            .queue(gpu.queue.clone())
+            .flags(MemFlags::new().read_only().copy_host_ptr())
+            .len(num_sensors as usize)
+            .copy_host_slice(&vec![1.0f32; num_sensors as usize])
+            .build()
+            .unwrap();
+
         // Set up kernel
         let atomic_src =
             std::fs::read_to_string("src/core/algorithm/gpu/kernels/atomic.cl").unwrap();
@@ -529,6 +629,7 @@ mod tests {
             .arg(&mapped_residuals_buffer)
             .arg(&measurement_matrix_buffer)
             .arg(&residuals_buffer)
+            .arg(&sensor_weights_buffer)
             .arg(&beat_buffer)
             .arg_local::<f32>(work_group_size)
             .arg(num_states)
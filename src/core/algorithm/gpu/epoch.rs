@@ -34,6 +34,7 @@ impl EpochKernel {
         number_of_states: i32,
         number_of_sensors: i32,
         number_of_steps: i32,
+        sample_rate_hz: f32,
     ) -> Self {
         let reset_kernel = ResetKernel::new(
             gpu,
@@ -69,6 +70,7 @@ impl EpochKernel {
             &results.model,
             number_of_states,
             number_of_steps,
+            sample_rate_hz,
             config,
         );
         let metrics_kernel = MetricsKernel::new(
@@ -76,6 +78,7 @@ impl EpochKernel {
             &results.estimations,
             &results.derivatives,
             &results.metrics,
+            &results.model,
             number_of_sensors,
             number_of_steps,
             config,
@@ -171,6 +174,7 @@ mod tests {
             number_of_states as i32,
             number_of_sensors as i32,
             results_cpu.estimations.measurements.num_steps() as i32,
+            data.simulation.sample_rate_hz,
         );
         let mut results_from_gpu = results_cpu.clone();
 
@@ -1,14 +1,28 @@
 __kernel void add_control_function(
     __global float* system_states,
     __global const float* control_matrix,
+    __global const int* beat,
     __global const int* step,
     __global float* control_values,
-    const int num_states
+    __global const float* time_offsets,
+    const int num_states,
+    const int num_steps
 ) {
     int state_idx = get_global_id(0);
     if (state_idx >= num_states) return;
     int step_idx = step[0];
-    
-    system_states[step_idx * num_states + state_idx] += 
-        control_values[step_idx] * control_matrix[state_idx];
-}
\ No newline at end of file
+
+    // shift the control function by the time offset of the beat
+    float position = (float)step_idx - time_offsets[beat[0]];
+    float control_value = 0.0f;
+    if (position >= 0.0f) {
+        int index = (int)floor(position);
+        float fraction = position - (float)index;
+        float value = index < num_steps ? control_values[index] : 0.0f;
+        float next_value = index + 1 < num_steps ? control_values[index + 1] : 0.0f;
+        control_value = fraction == 0.0f ? value : (1.0f - fraction) * value + fraction * next_value;
+    }
+
+    system_states[step_idx * num_states + state_idx] +=
+        control_value * control_matrix[state_idx];
+}
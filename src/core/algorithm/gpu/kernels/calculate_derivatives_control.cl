@@ -0,0 +1,32 @@
+__kernel void calculate_derivatives_control_function(
+    __global float* derivatives_control_function,
+    __global const float* control_matrix,
+    __global const float* mapped_residuals,
+    __global const int* beat,
+    __global const int* step,
+    __global const float* time_offsets,
+    float mse_scaling,
+    int num_states,
+    int num_steps
+) {
+    int state_index = get_global_id(0);
+    if (state_index >= num_states) return;
+
+    float control = control_matrix[state_index];
+    if (control == 0.0f) return;
+
+    // the control function is shifted by the time offset of the beat, so the
+    // derivative is split onto the two interpolated values
+    float position = (float)step[0] - time_offsets[beat[0]];
+    if (position < 0.0f) return;
+    int index = (int)floor(position);
+    float fraction = position - (float)index;
+    float derivative = control * mapped_residuals[state_index] * mse_scaling;
+
+    if (index < num_steps) {
+        atomic_add_float(&derivatives_control_function[index], (1.0f - fraction) * derivative);
+    }
+    if (fraction != 0.0f && index + 1 < num_steps) {
+        atomic_add_float(&derivatives_control_function[index + 1], fraction * derivative);
+    }
+}
@@ -2,6 +2,7 @@ __kernel void calculate_residuals(
     __global float* residuals,
     __global const float* predicted_measurements,
     __global const float* actual_measurements,
+    __global const float* sensor_weights,
     __global int* step,
     __global int* beat,
     int num_sensors,
@@ -11,6 +12,13 @@ __kernel void calculate_residuals(
     if (sensor_idx >= num_sensors) return;
     int step_idx = step[0];
     int beat_idx = beat[0];
-    
-    residuals[sensor_idx] = predicted_measurements[beat_idx * num_sensors * num_steps + step_idx * num_sensors + sensor_idx] - actual_measurements[beat_idx * num_sensors * num_steps + step_idx * num_sensors + sensor_idx];
+    int idx = beat_idx * num_sensors * num_steps + step_idx * num_sensors + sensor_idx;
+
+    // masked sensors and missing samples do not contribute
+    float actual = actual_measurements[idx];
+    if (isnan(actual) || sensor_weights[sensor_idx] == 0.0f) {
+        residuals[sensor_idx] = 0.0f;
+    } else {
+        residuals[sensor_idx] = predicted_measurements[idx] - actual;
+    }
 }
\ No newline at end of file
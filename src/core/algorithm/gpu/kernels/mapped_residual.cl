@@ -4,6 +4,7 @@ __kernel void calculate_mapped_residuals(
     __global float* mapped_residuals,
     __global const float* measurement_matrix,
     __global const float* residuals,
+    __global const float* sensor_weights,
     __global const int* beat,
     __local float* partial_sums,
     int num_states,
@@ -22,7 +23,7 @@ __kernel void calculate_mapped_residuals(
     if (sensor_idx < num_sensors){
         int idx = beat_idx * num_sensors * num_states + sensor_idx * num_states + state_idx;
         float mat_entry = measurement_matrix[idx];
-        float res = residuals[sensor_idx];
+        float res = sensor_weights[sensor_idx] * residuals[sensor_idx];
         contribution = mat_entry * res;
     }
     
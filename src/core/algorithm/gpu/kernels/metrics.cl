@@ -2,10 +2,12 @@ void atomic_add_float(volatile __global float *addr, float val);
 
 __kernel void calculate_mse_step(
     __global const float* residuals,
+    __global const float* sensor_weights,
     __global float* loss_mse,
     __local float* partial_sums,
     __global const int* step,
-    const int num_sensors
+    const int num_sensors,
+    const float sensor_weight_sum
 ) {
     int idx = get_global_id(0);
     int lid = get_local_id(0);
@@ -15,7 +17,7 @@ __kernel void calculate_mse_step(
     float contribution = 0.0f;
     if (idx < num_sensors) {
         float val = residuals[idx];
-        contribution = val * val;
+        contribution = sensor_weights[idx] * val * val;
     }
     partial_sums[lid] = contribution;
     
@@ -31,7 +33,7 @@ __kernel void calculate_mse_step(
     
     // Write result to global memory
     if (lid == 0) {
-        atomic_add_float(&loss_mse[step_idx], partial_sums[0] / num_sensors);
+        atomic_add_float(&loss_mse[step_idx], partial_sums[0] / sensor_weight_sum);
     }
 }
 
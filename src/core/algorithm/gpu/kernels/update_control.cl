@@ -0,0 +1,28 @@
+__kernel void calculate_derivatives_control_function_smoothness(
+    __global float* derivatives_control_function,
+    __global const float* control_function_values,
+    float strength,
+    int num_steps
+) {
+    int step = get_global_id(0);
+    if (step >= num_steps) return;
+
+    float value = control_function_values[step];
+    float difference = 0.0f;
+    if (step > 0) difference += value - control_function_values[step - 1];
+    if (step + 1 < num_steps) difference += value - control_function_values[step + 1];
+
+    derivatives_control_function[step] += strength * difference;
+}
+
+__kernel void update_control_function(
+    __global float* control_function_values,
+    __global const float* derivatives_control_function,
+    float learning_rate_over_batch_size,
+    int num_steps
+) {
+    int step = get_global_id(0);
+    if (step >= num_steps) return;
+
+    control_function_values[step] -= derivatives_control_function[step] * learning_rate_over_batch_size;
+}
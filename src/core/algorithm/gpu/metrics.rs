@@ -6,13 +6,14 @@ use crate::core::{
         estimation::EstimationsGPU, metrics::MetricsGPU, refinement::derivation::DerivativesGPU,
     },
     config::algorithm::Algorithm,
+    model::{functional::measurement::SensorWeights, ModelGPU},
 };
 
 pub struct MetricsKernel {
-    mse_step_kernel: Kernel,
-    max_reg_step_kernel: Kernel,
-    loss_step_kernel: Kernel,
-    batch_kernel: Kernel,
+    mse_step: Kernel,
+    max_reg_step: Kernel,
+    loss_step: Kernel,
+    batch: Kernel,
 }
 
 impl MetricsKernel {
@@ -30,6 +31,7 @@ impl MetricsKernel {
         estimations: &EstimationsGPU,
         derivatives: &DerivativesGPU,
         metrics: &MetricsGPU,
+        model: &ModelGPU,
         number_of_sensors: i32,
         number_of_steps: i32,
         config: &Algorithm,
@@ -47,25 +49,31 @@ impl MetricsKernel {
             .build(context)
             .unwrap();
 
+        let sensor_weight_sum =
+            SensorWeights::from_gpu(&model.functional_description.sensor_weights)
+                .active_weight_sum(number_of_sensors as usize);
+
         let max_size = device.max_wg_size().unwrap();
         let work_group_size = max_size.min(number_of_sensors as usize).next_power_of_two();
         let sensors_work_group_size =
             (number_of_sensors as usize).next_multiple_of(work_group_size) as i32;
-        let mse_step_kernel = Kernel::builder()
+        let mse_step = Kernel::builder()
             .program(&metrics_program)
             .name("calculate_mse_step")
             .queue(queue.clone())
             .global_work_size(sensors_work_group_size)
             .local_work_size(work_group_size)
             .arg(&estimations.residuals)
+            .arg(&model.functional_description.sensor_weights)
             .arg(&metrics.loss_mse)
             .arg_local::<f32>(work_group_size)
             .arg(&estimations.step)
             .arg(number_of_sensors)
+            .arg(sensor_weight_sum)
             .build()
             .unwrap();
 
-        let max_reg_step_kernel = Kernel::builder()
+        let max_reg_step = Kernel::builder()
             .program(&metrics_program)
             .name("store_max_reg")
             .queue(queue.clone())
@@ -76,7 +84,7 @@ impl MetricsKernel {
             .build()
             .unwrap();
 
-        let loss_step_kernel = Kernel::builder()
+        let loss_step = Kernel::builder()
             .program(&metrics_program)
             .name("calculate_final_loss")
             .queue(queue.clone())
@@ -93,7 +101,7 @@ impl MetricsKernel {
         let work_group_size = max_size.min(number_of_steps as usize).next_power_of_two();
         let steps_work_group_size =
             (number_of_steps as usize).next_multiple_of(work_group_size) as i32;
-        let batch_kernel = Kernel::builder()
+        let batch = Kernel::builder()
             .program(&metrics_program)
             .name("calculate_metrics_batch")
             .queue(queue.clone())
@@ -114,10 +122,10 @@ impl MetricsKernel {
             .unwrap();
 
         Self {
-            mse_step_kernel,
-            max_reg_step_kernel,
-            loss_step_kernel,
-            batch_kernel,
+            mse_step,
+            max_reg_step,
+            loss_step,
+            batch,
         }
     }
 
@@ -127,9 +135,9 @@ impl MetricsKernel {
         // This would allow better GPU utilization by processing independent beats simultaneously.
         // See prediction.rs for implementation details.
         unsafe {
-            self.mse_step_kernel.enq().unwrap();
-            self.max_reg_step_kernel.enq().unwrap();
-            self.loss_step_kernel.enq().unwrap();
+            self.mse_step.enq().unwrap();
+            self.max_reg_step.enq().unwrap();
+            self.loss_step.enq().unwrap();
         }
     }
     #[allow(clippy::missing_panics_doc)]
@@ -138,7 +146,7 @@ impl MetricsKernel {
         // This would allow better GPU utilization by processing independent beats simultaneously.
         // See prediction.rs for implementation details.
         unsafe {
-            self.batch_kernel.enq().unwrap();
+            self.batch.enq().unwrap();
         }
     }
 }
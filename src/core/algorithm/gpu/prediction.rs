@@ -73,12 +73,15 @@ impl PredictionKernel {
                 "control_matrix",
                 &model.functional_description.control_matrix,
             )
+            .arg_named("beat", &estimations.beat)
             .arg_named("step", &estimations.step)
             .arg_named(
                 "control_values",
                 &model.functional_description.control_function_values,
             )
+            .arg_named("time_offsets", &model.functional_description.time_offsets)
             .arg_named("num_states", number_of_states)
+            .arg_named("num_steps", number_of_steps)
             .build()
             .unwrap();
 
@@ -14,6 +14,7 @@ pub struct ResetKernel {
     coefs_kernel: Kernel,
     iir_kernel: Kernel,
     fir_kernel: Kernel,
+    control_function_kernel: Kernel,
     maximum_regularization_sum_kernel: Kernel,
     step_kernel: Kernel,
 }
@@ -107,6 +108,14 @@ impl ResetKernel {
             .arg(&derivatives.coefs_fir)
             .build()
             .unwrap();
+        let control_function_kernel = Kernel::builder()
+            .program(&reset_program)
+            .name("reset_float")
+            .queue(queue.clone())
+            .global_work_size(number_of_steps)
+            .arg(&derivatives.control_function)
+            .build()
+            .unwrap();
         let maximum_regularization_sum_kernel = Kernel::builder()
             .program(&reset_program)
             .name("reset_float")
@@ -133,6 +142,7 @@ impl ResetKernel {
             coefs_kernel,
             iir_kernel,
             fir_kernel,
+            control_function_kernel,
             maximum_regularization_sum_kernel,
             step_kernel,
         }
@@ -152,6 +162,7 @@ impl ResetKernel {
             self.coefs_kernel.enq().unwrap();
             self.iir_kernel.enq().unwrap();
             self.fir_kernel.enq().unwrap();
+            self.control_function_kernel.enq().unwrap();
             self.maximum_regularization_sum_kernel.enq().unwrap();
             self.step_kernel.enq().unwrap();
         }
@@ -1,16 +1,26 @@
-use ocl::{Kernel, Program};
+use ocl::{Buffer, Kernel, Program};
 
 use super::GPU;
 use crate::core::{
-    algorithm::refinement::derivation::DerivativesGPU, config::algorithm::Algorithm,
-    model::ModelGPU,
+    algorithm::refinement::{
+        derivation::DerivativesGPU,
+        update::{batch_size, limit_bandwidth},
+    },
+    config::algorithm::Algorithm,
+    model::{functional::control::ControlFunction, ModelGPU},
 };
 
 pub struct UpdateKernel {
     gains_kernel: Kernel,
     coefs_kernel: Kernel,
+    control_function_smoothness_kernel: Kernel,
+    control_function_kernel: Kernel,
+    control_function_values: Buffer<f32>,
     freeze_gains: bool,
     freeze_delays: bool,
+    learn_control_function: bool,
+    control_function_bandwidth_hz: f32,
+    sample_rate_hz: f32,
 }
 
 impl UpdateKernel {
@@ -29,11 +39,15 @@ impl UpdateKernel {
         model: &ModelGPU,
         number_of_states: i32,
         number_of_steps: i32,
+        sample_rate_hz: f32,
         config: &Algorithm,
     ) -> Self {
         let context = &gpu.context;
         let queue = &gpu.queue;
         let number_of_voxels = number_of_states / 3;
+        // the gpu fits one beat per epoch
+        let learning_rate =
+            config.learning_rate / batch_size(config, number_of_steps as usize, 1) as f32;
 
         let gains_src =
             std::fs::read_to_string("src/core/algorithm/gpu/kernels/update_gains.cl").unwrap();
@@ -45,7 +59,7 @@ impl UpdateKernel {
             .global_work_size([number_of_states, 78])
             .arg(&model.functional_description.ap_params.gains)
             .arg(&derivatives.gains)
-            .arg(config.learning_rate / number_of_steps as f32) // not accounting for batch size at the moment. might want to fix that later
+            .arg(learning_rate)
             .arg(number_of_states)
             .build()
             .unwrap();
@@ -62,16 +76,50 @@ impl UpdateKernel {
             .arg(&model.functional_description.ap_params.coefs)
             .arg(&model.functional_description.ap_params.delays)
             .arg(&derivatives.coefs)
-            .arg(config.learning_rate / number_of_steps as f32) // not accounting for batch size at the moment. might want to fix that later
+            .arg(learning_rate)
             .arg(number_of_states)
             .build()
             .unwrap();
 
+        let control_src =
+            std::fs::read_to_string("src/core/algorithm/gpu/kernels/update_control.cl").unwrap();
+        let control_program = Program::builder().src(control_src).build(context).unwrap();
+
+        let control_function_smoothness_kernel = Kernel::builder()
+            .program(&control_program)
+            .name("calculate_derivatives_control_function_smoothness")
+            .queue(queue.clone())
+            .global_work_size(number_of_steps)
+            .arg(&derivatives.control_function)
+            .arg(&model.functional_description.control_function_values)
+            .arg(config.control_function_smoothness_strength)
+            .arg(number_of_steps)
+            .build()
+            .unwrap();
+
+        let control_function_kernel = Kernel::builder()
+            .program(&control_program)
+            .name("update_control_function")
+            .queue(queue.clone())
+            .global_work_size(number_of_steps)
+            .arg(&model.functional_description.control_function_values)
+            .arg(&derivatives.control_function)
+            .arg(learning_rate)
+            .arg(number_of_steps)
+            .build()
+            .unwrap();
+
         Self {
             gains_kernel,
             coefs_kernel,
+            control_function_smoothness_kernel,
+            control_function_kernel,
+            control_function_values: model.functional_description.control_function_values.clone(),
             freeze_gains: config.freeze_gains,
             freeze_delays: config.freeze_delays,
+            learn_control_function: config.learn_control_function,
+            control_function_bandwidth_hz: config.control_function_bandwidth_hz,
+            sample_rate_hz,
         }
     }
 
@@ -87,8 +135,34 @@ impl UpdateKernel {
             if !self.freeze_delays {
                 self.coefs_kernel.enq().unwrap();
             }
+            if self.learn_control_function {
+                self.control_function_smoothness_kernel.enq().unwrap();
+                self.control_function_kernel.enq().unwrap();
+            }
+        }
+        if self.learn_control_function && self.control_function_bandwidth_hz > 0.0 {
+            self.limit_bandwidth();
         }
     }
+
+    /// Removes the frequencies above the configured bandwidth from the
+    /// control function on the host, like the update on the cpu.
+    fn limit_bandwidth(&self) {
+        let mut control_function = ControlFunction::empty(self.control_function_values.len());
+        self.control_function_values
+            .read(control_function.as_slice_mut().unwrap())
+            .enq()
+            .unwrap();
+        limit_bandwidth(
+            &mut control_function,
+            self.control_function_bandwidth_hz,
+            self.sample_rate_hz,
+        );
+        self.control_function_values
+            .write(control_function.as_slice().unwrap())
+            .enq()
+            .unwrap();
+    }
     pub fn set_freeze_delays(&mut self, value: bool) {
         self.freeze_delays = value;
     }
@@ -142,6 +216,13 @@ mod tests {
             .spatial_description
             .sensors
             .count();
+        let sensor_weight_sum = results_cpu
+            .model
+            .as_ref()
+            .unwrap()
+            .functional_description
+            .sensor_weights
+            .active_weight_sum(number_of_sensors);
 
         let prediction_kernel = PredictionKernel::new(
             &gpu,
@@ -188,6 +269,7 @@ mod tests {
             &results_gpu.model,
             number_of_states as i32,
             results_cpu.estimations.measurements.num_steps() as i32,
+            data.simulation.sample_rate_hz,
             &config.algorithm,
         );
 
@@ -200,7 +282,18 @@ mod tests {
                 0,
                 step,
             );
-            calculate_residuals(&mut results_cpu.estimations, &data, 0, step);
+            calculate_residuals(
+                &mut results_cpu.estimations,
+                &data,
+                &results_cpu
+                    .model
+                    .as_ref()
+                    .unwrap()
+                    .functional_description
+                    .sensor_weights,
+                0,
+                step,
+            );
             calculate_mapped_residuals(
                 &mut results_cpu.derivatives.mapped_residuals,
                 &results_cpu.estimations.residuals,
@@ -224,7 +317,7 @@ mod tests {
                 &results_cpu.derivatives.maximum_regularization,
                 &results_cpu.derivatives.mapped_residuals,
                 &config.algorithm,
-                number_of_sensors,
+                sensor_weight_sum,
             );
             calculate_derivatives_coefs_textbook(
                 &mut results_cpu.derivatives,
@@ -4,15 +4,28 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-use ndarray::Array1;
+use ndarray::{Array1, Array2, Array3};
 use ndarray_npy::WriteNpyExt;
 use ndarray_stats::QuantileExt;
 use ocl::Buffer;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use super::estimation::Estimations;
-use crate::core::model::spatial::voxels::{VoxelNumbers, VoxelType, VoxelTypes};
+use super::{
+    estimation::Estimations,
+    refinement::{derivation::AverageDelays, loss::calculate_pointwise_losses},
+};
+use crate::core::{
+    config::algorithm::Algorithm,
+    data::shapes::ActivationTimePerStateMs,
+    model::{
+        functional::measurement::SensorWeights,
+        spatial::voxels::{
+            PathologyRegions, PathologySeverity, VoxelNumbers, VoxelPositions, VoxelType,
+            VoxelTypes,
+        },
+    },
+};
 
 #[allow(clippy::unsafe_derive_deserialize)]
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -25,6 +38,27 @@ pub struct Metrics {
     pub loss_maximum_regularization: SampleWiseMetric,
     pub loss_maximum_regularization_batch: BatchWiseMetric,
 
+    #[serde(default)]
+    pub loss_huber: SampleWiseMetric,
+    #[serde(default)]
+    pub loss_huber_batch: BatchWiseMetric,
+    #[serde(default)]
+    pub loss_l1: SampleWiseMetric,
+    #[serde(default)]
+    pub loss_l1_batch: BatchWiseMetric,
+    #[serde(default)]
+    pub loss_cauchy: SampleWiseMetric,
+    #[serde(default)]
+    pub loss_cauchy_batch: BatchWiseMetric,
+    #[serde(default)]
+    pub loss_ncc: SampleWiseMetric,
+    #[serde(default)]
+    pub loss_ncc_batch: BatchWiseMetric,
+    #[serde(default)]
+    pub loss_spectral: SampleWiseMetric,
+    #[serde(default)]
+    pub loss_spectral_batch: BatchWiseMetric,
+
     #[serde(default)]
     pub dice_score_over_threshold: Array1<f32>,
     #[serde(default)]
@@ -33,6 +67,30 @@ pub struct Metrics {
     pub precision_over_threshold: Array1<f32>,
     #[serde(default)]
     pub recall_over_threshold: Array1<f32>,
+    #[serde(default)]
+    // recall of every ground truth pathology region (regions x thresholds)
+    pub lesion_recall_over_threshold: Array2<f32>,
+    #[serde(default)]
+    // between the ground truth and the predicted severity of every voxel
+    pub severity_mean_absolute_error: f32,
+    #[serde(default)]
+    pub severity_correlation: f32,
+    #[serde(default)]
+    // estimated minus ground truth average propagation speed of every voxel
+    pub propagation_speed_error_m_per_s: Array3<f32>,
+    #[serde(default)]
+    pub propagation_speed_mean_absolute_error_m_per_s: f32,
+    #[serde(default)]
+    pub propagation_speed_root_mean_square_error_m_per_s: f32,
+    #[serde(default)]
+    // mean of the absolute errors relative to the ground truth speeds
+    pub propagation_speed_mean_relative_error: f32,
+    #[serde(default)]
+    // estimated position of the earliest activation
+    pub earliest_activation_site_mm: [f32; 3],
+    #[serde(default)]
+    // distance between the estimated and the ground truth earliest activation
+    pub earliest_activation_error_mm: f32,
 }
 
 pub struct MetricsGPU {
@@ -66,10 +124,30 @@ impl Metrics {
                 number_of_batches,
             ),
 
+            loss_huber: SampleWiseMetric::new(number_of_steps),
+            loss_huber_batch: BatchWiseMetric::new(number_of_epochs, number_of_batches),
+            loss_l1: SampleWiseMetric::new(number_of_steps),
+            loss_l1_batch: BatchWiseMetric::new(number_of_epochs, number_of_batches),
+            loss_cauchy: SampleWiseMetric::new(number_of_steps),
+            loss_cauchy_batch: BatchWiseMetric::new(number_of_epochs, number_of_batches),
+            loss_ncc: SampleWiseMetric::new(number_of_steps),
+            loss_ncc_batch: BatchWiseMetric::new(number_of_epochs, number_of_batches),
+            loss_spectral: SampleWiseMetric::new(number_of_steps),
+            loss_spectral_batch: BatchWiseMetric::new(number_of_epochs, number_of_batches),
+
             dice_score_over_threshold: Array1::zeros(101),
             iou_over_threshold: Array1::zeros(101),
             precision_over_threshold: Array1::zeros(101),
             recall_over_threshold: Array1::zeros(101),
+            lesion_recall_over_threshold: Array2::zeros((0, 101)),
+            severity_mean_absolute_error: 0.0,
+            severity_correlation: 0.0,
+            propagation_speed_error_m_per_s: Array3::zeros((0, 0, 0)),
+            propagation_speed_mean_absolute_error_m_per_s: 0.0,
+            propagation_speed_root_mean_square_error_m_per_s: 0.0,
+            propagation_speed_mean_relative_error: 0.0,
+            earliest_activation_site_mm: [0.0; 3],
+            earliest_activation_error_mm: 0.0,
         }
     }
 
@@ -90,6 +168,19 @@ impl Metrics {
         self.loss_maximum_regularization_batch
             .save_npy(path, "loss_maximum_regularization_epoch.npy");
 
+        self.loss_huber.save_npy(path, "loss_huber.npy");
+        self.loss_huber_batch.save_npy(path, "loss_huber_epoch.npy");
+        self.loss_l1.save_npy(path, "loss_l1.npy");
+        self.loss_l1_batch.save_npy(path, "loss_l1_epoch.npy");
+        self.loss_cauchy.save_npy(path, "loss_cauchy.npy");
+        self.loss_cauchy_batch
+            .save_npy(path, "loss_cauchy_epoch.npy");
+        self.loss_ncc.save_npy(path, "loss_ncc.npy");
+        self.loss_ncc_batch.save_npy(path, "loss_ncc_epoch.npy");
+        self.loss_spectral.save_npy(path, "loss_spectral.npy");
+        self.loss_spectral_batch
+            .save_npy(path, "loss_spectral_epoch.npy");
+
         let writer = BufWriter::new(File::create(path.join("dice.npy")).unwrap());
         self.dice_score_over_threshold.write_npy(writer).unwrap();
 
@@ -101,6 +192,15 @@ impl Metrics {
 
         let writer = BufWriter::new(File::create(path.join("recall.npy")).unwrap());
         self.recall_over_threshold.write_npy(writer).unwrap();
+
+        let writer = BufWriter::new(File::create(path.join("lesion_recall.npy")).unwrap());
+        self.lesion_recall_over_threshold.write_npy(writer).unwrap();
+
+        let writer =
+            BufWriter::new(File::create(path.join("propagation_speed_error.npy")).unwrap());
+        self.propagation_speed_error_m_per_s
+            .write_npy(writer)
+            .unwrap();
     }
 
     pub(crate) fn to_gpu(&self, queue: &ocl::Queue) -> MetricsGPU {
@@ -129,7 +229,8 @@ impl Metrics {
 /// Calculates metrics for the current step.
 ///
 /// Updates the metrics fields with calculations for the current step:
-/// - MSE loss
+/// - MSE loss, weighted per sensor and normalized by the summed sensor weights
+/// - Huber, L1 and Cauchy loss
 /// - Maximum regularization loss
 /// - Total loss
 /// - Mean and max of absolute deltas for:
@@ -138,6 +239,8 @@ impl Metrics {
 ///   - Gains
 ///   - Delays
 ///
+/// Steps outside of the fit windows contribute no loss.
+///
 /// # Panics
 ///
 /// Panics if any array is None.
@@ -147,81 +250,388 @@ pub fn calculate_step(
     metrics: &mut Metrics,
     estimations: &Estimations,
     maximum_regularization_sum: f32,
-    regularization_strength: f32,
+    sensor_weights: &SensorWeights,
+    config: &Algorithm,
     step: usize,
+    in_fit_window: bool,
 ) {
     trace!("Calculating metrics for step {}", step);
 
-    metrics.loss_mse[step] = estimations.residuals.mapv(|v| v.powi(2)).sum()
-        / estimations.measurements.num_sensors() as f32;
+    if !in_fit_window {
+        metrics.loss_mse[step] = 0.0;
+        metrics.loss_maximum_regularization[step] = 0.0;
+        metrics.loss_huber[step] = 0.0;
+        metrics.loss_l1[step] = 0.0;
+        metrics.loss_cauchy[step] = 0.0;
+        metrics.loss[step] = 0.0;
+        return;
+    }
+
+    metrics.loss_mse[step] = estimations
+        .residuals
+        .iter()
+        .enumerate()
+        .map(|(sensor, residual)| sensor_weights.weight(sensor) * residual.powi(2))
+        .sum::<f32>()
+        / sensor_weights.active_weight_sum(estimations.residuals.len());
     metrics.loss_maximum_regularization[step] = maximum_regularization_sum;
-    metrics.loss[step] = regularization_strength.mul_add(
+    metrics.loss[step] = config.maximum_regularization_strength.mul_add(
         metrics.loss_maximum_regularization[step],
         metrics.loss_mse[step],
     );
+
+    let (huber, l1, cauchy) =
+        calculate_pointwise_losses(&estimations.residuals, sensor_weights, config);
+    metrics.loss_huber[step] = huber;
+    metrics.loss_l1[step] = l1;
+    metrics.loss_cauchy[step] = cauchy;
+    metrics.loss[step] += config.cauchy_strength.mul_add(
+        cauchy,
+        config
+            .huber_strength
+            .mul_add(huber, config.l1_strength * l1),
+    );
 }
 
 /// Calculates epoch metrics by taking the mean of step metrics.
 ///
-/// # Panics
-///
-/// Panics if any loss array is None.
-#[tracing::instrument(level = "debug")]
-pub fn calculate_batch(metrics: &mut Metrics, epoch_index: usize) {
+/// Steps outside of the fit windows hold no loss, so the sums are divided
+/// by the number of fitted steps instead of the number of steps.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "debug", skip(metrics))]
+pub fn calculate_batch(metrics: &mut Metrics, epoch_index: usize, number_of_fitted_steps: usize) {
     debug!("Calculating metrics for epoch {}", epoch_index);
-    metrics.loss_mse_batch[epoch_index] = metrics.loss_mse.mean().unwrap();
+    let number_of_fitted_steps = number_of_fitted_steps.max(1) as f32;
+    metrics.loss_mse_batch[epoch_index] = metrics.loss_mse.sum() / number_of_fitted_steps;
     metrics.loss_maximum_regularization_batch[epoch_index] =
-        metrics.loss_maximum_regularization.mean().unwrap();
-    metrics.loss_batch[epoch_index] = metrics.loss.mean().unwrap();
+        metrics.loss_maximum_regularization.sum() / number_of_fitted_steps;
+    metrics.loss_batch[epoch_index] = metrics.loss.sum() / number_of_fitted_steps;
+    metrics.loss_huber_batch[epoch_index] = metrics.loss_huber.sum() / number_of_fitted_steps;
+    metrics.loss_l1_batch[epoch_index] = metrics.loss_l1.sum() / number_of_fitted_steps;
+    metrics.loss_cauchy_batch[epoch_index] = metrics.loss_cauchy.sum() / number_of_fitted_steps;
+    metrics.loss_ncc_batch[epoch_index] = metrics.loss_ncc.sum() / number_of_fitted_steps;
+    metrics.loss_spectral_batch[epoch_index] = metrics.loss_spectral.sum() / number_of_fitted_steps;
 }
 
+/// Share of a pathology region that has to be predicted as pathological for
+/// the region to count as detected.
+pub const LESION_DETECTION_RECALL: f32 = 0.5;
+
 /// Calculates metrics over the full range of thresholds from 0 to 1 by incrementing
 /// in steps of 0.01. Stores the dice score, `IoU`, precision, and recall for each
-/// threshold value in the given metric arrays.
+/// threshold value in the given metric arrays, as well as the recall of every
+/// ground truth pathology region and the errors of the predicted severity.
 #[allow(clippy::cast_precision_loss)]
 #[tracing::instrument(level = "debug", skip_all)]
 pub fn calculate_final(
     metrics: &mut Metrics,
     estimations: &Estimations,
     ground_truth: &VoxelTypes,
+    ground_truth_regions: &PathologyRegions,
+    ground_truth_severity: &PathologySeverity,
     voxel_numbers: &VoxelNumbers,
 ) {
     debug!("Calculating final metrics");
+    metrics.lesion_recall_over_threshold =
+        Array2::zeros((ground_truth_regions.number_of_regions(), 101));
     for i in 0..=100 {
         let threshold = i as f32 / 100.0;
-        let (dice, iou, precision, recall) =
-            calculate_for_threshold(estimations, ground_truth, voxel_numbers, threshold);
+        let predictions = predict_voxeltype(estimations, ground_truth, voxel_numbers, threshold);
+        let (dice, iou, precision, recall) = calculate_for_threshold(&predictions, ground_truth);
         metrics.dice_score_over_threshold[i] = dice;
         metrics.iou_over_threshold[i] = iou;
         metrics.precision_over_threshold[i] = precision;
         metrics.recall_over_threshold[i] = recall;
+        metrics
+            .lesion_recall_over_threshold
+            .column_mut(i)
+            .assign(&calculate_lesion_recalls(
+                &predictions,
+                ground_truth,
+                ground_truth_regions,
+            ));
     }
+    let (mean_absolute_error, correlation) =
+        calculate_severity_errors(estimations, ground_truth_severity, voxel_numbers);
+    metrics.severity_mean_absolute_error = mean_absolute_error;
+    metrics.severity_correlation = correlation;
 }
-/// Calculates Dice score, `IoU`, precision, and recall for the given estimations, ground truth, and voxel numbers at the specified threshold.
+
+/// Predicts the severity of every voxel from the estimations as one minus the
+/// maximum absolute value of its system states, normalized to the largest
+/// one of all voxels. Voxels without states have a severity of zero.
 ///
-/// The estimations, ground truth, and voxel numbers are used to generate voxel type predictions at the given threshold.
-/// These predictions are then compared to the ground truth to calculate the metrics.
-#[tracing::instrument(level = "trace")]
-fn calculate_for_threshold(
+/// This is a heuristic: weak currents are taken as a sign of pathological
+/// tissue, but the estimations carry no calibrated severity. The resulting
+/// errors are only meant to compare runs against each other.
+#[must_use]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn predict_severity(estimations: &Estimations, voxel_numbers: &VoxelNumbers) -> Array3<f32> {
+    trace!("Predicting severity");
+    let system_states = estimations.analysis_system_states();
+    let maximum = voxel_numbers.mapv(|number| {
+        number.map_or(0.0, |voxel_index| {
+            system_states
+                .rows()
+                .into_iter()
+                .map(|states| {
+                    states[voxel_index].abs()
+                        + states[voxel_index + 1].abs()
+                        + states[voxel_index + 2].abs()
+                })
+                .fold(0.0, f32::max)
+        })
+    });
+    let largest = maximum.iter().copied().fold(0.0, f32::max);
+    let mut severity = Array3::zeros(maximum.raw_dim());
+    severity
+        .iter_mut()
+        .zip(maximum.iter())
+        .zip(voxel_numbers.iter())
+        .filter(|(_, number)| number.is_some() && largest > 0.0)
+        .for_each(|((severity, maximum), _)| *severity = 1.0 - maximum / largest);
+    severity
+}
+
+/// Calculates the mean absolute error and the pearson correlation between
+/// the predicted and the ground truth severity over all voxels with states.
+///
+/// The correlation is zero if either severity is constant.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn calculate_severity_errors(
     estimations: &Estimations,
-    ground_truth: &VoxelTypes,
+    ground_truth_severity: &PathologySeverity,
     voxel_numbers: &VoxelNumbers,
-    threshold: f32,
-) -> (f32, f32, f32, f32) {
-    trace!(
-        "Calculating segmentation metrics for threshold {}",
-        threshold
+) -> (f32, f32) {
+    trace!("Calculating severity errors");
+    let predictions = predict_severity(estimations, voxel_numbers);
+    let pairs: Vec<(f32, f32)> = predictions
+        .iter()
+        .zip(ground_truth_severity.iter())
+        .zip(voxel_numbers.iter())
+        .filter(|(_, number)| number.is_some())
+        .map(|((prediction, ground_truth), _)| (*prediction, *ground_truth))
+        .collect();
+    if pairs.is_empty() {
+        return (0.0, 0.0);
+    }
+    let count = pairs.len() as f32;
+    let mean_absolute_error = pairs.iter().map(|(p, g)| (p - g).abs()).sum::<f32>() / count;
+    let mean_prediction = pairs.iter().map(|(p, _)| p).sum::<f32>() / count;
+    let mean_ground_truth = pairs.iter().map(|(_, g)| g).sum::<f32>() / count;
+    let (covariance, variance_prediction, variance_ground_truth) = pairs.iter().fold(
+        (0.0, 0.0, 0.0),
+        |(covariance, variance_prediction, variance_ground_truth), (p, g)| {
+            let p = p - mean_prediction;
+            let g = g - mean_ground_truth;
+            (
+                p.mul_add(g, covariance),
+                p.mul_add(p, variance_prediction),
+                g.mul_add(g, variance_ground_truth),
+            )
+        },
+    );
+    let denominator = (variance_prediction * variance_ground_truth).sqrt();
+    let correlation = if denominator == 0.0 {
+        0.0
+    } else {
+        covariance / denominator
+    };
+    (mean_absolute_error, correlation)
+}
+
+/// Calculates the error of the estimated average propagation speed of every
+/// voxel against the ground truth one, which follows the velocities the data
+/// was simulated with, including a velocity map.
+///
+/// The mean absolute, root mean square and mean relative error are taken
+/// over all voxels that have a speed in both. Voxels without one have an
+/// error of zero in the error map.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_propagation_speed_errors(
+    metrics: &mut Metrics,
+    estimated_delays: &AverageDelays,
+    ground_truth_delays: &AverageDelays,
+    voxel_numbers: &VoxelNumbers,
+    voxel_size_mm: f32,
+    sample_rate_hz: f32,
+) {
+    debug!("Calculating propagation speed errors");
+    let estimated_speeds =
+        estimated_delays.propagation_speeds_m_per_s(voxel_numbers, voxel_size_mm, sample_rate_hz);
+    let ground_truth_speeds = ground_truth_delays.propagation_speeds_m_per_s(
+        voxel_numbers,
+        voxel_size_mm,
+        sample_rate_hz,
     );
-    let predictions = predict_voxeltype(estimations, ground_truth, voxel_numbers, threshold);
+    let mut errors = Array3::zeros(voxel_numbers.raw_dim());
+    let mut pairs = Vec::new();
+    errors
+        .iter_mut()
+        .zip(estimated_speeds.iter())
+        .zip(ground_truth_speeds.iter())
+        .for_each(|((error, estimated), ground_truth)| {
+            if let (Some(estimated), Some(ground_truth)) = (estimated, ground_truth) {
+                *error = estimated - ground_truth;
+                pairs.push((*estimated, *ground_truth));
+            }
+        });
+    metrics.propagation_speed_error_m_per_s = errors;
+    if pairs.is_empty() {
+        metrics.propagation_speed_mean_absolute_error_m_per_s = 0.0;
+        metrics.propagation_speed_root_mean_square_error_m_per_s = 0.0;
+        metrics.propagation_speed_mean_relative_error = 0.0;
+        return;
+    }
+    let count = pairs.len() as f32;
+    metrics.propagation_speed_mean_absolute_error_m_per_s =
+        pairs.iter().map(|(e, g)| (e - g).abs()).sum::<f32>() / count;
+    metrics.propagation_speed_root_mean_square_error_m_per_s =
+        (pairs.iter().map(|(e, g)| (e - g).powi(2)).sum::<f32>() / count).sqrt();
+    metrics.propagation_speed_mean_relative_error =
+        pairs.iter().map(|(e, g)| ((e - g) / g).abs()).sum::<f32>() / count;
+}
 
-    let dice = calculate_dice(&predictions, ground_truth);
-    let iou = calculate_iou(&predictions, ground_truth);
-    let precision = calculate_precision(&predictions, ground_truth);
-    let recall = calculate_recall(&predictions, ground_truth);
+/// Returns the earliest activation site, i.e. the mean position of all
+/// voxels activated at the earliest activation time, or `None` if no voxel
+/// has states.
+#[must_use]
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn earliest_activation_site_mm(
+    activation_times: &ActivationTimePerStateMs,
+    voxel_numbers: &VoxelNumbers,
+    voxel_positions_mm: &VoxelPositions,
+) -> Option<[f32; 3]> {
+    trace!("Finding earliest activation site");
+    let earliest_ms = activation_times
+        .iter()
+        .copied()
+        .fold(f32::INFINITY, f32::min);
+    let mut site_mm = [0.0; 3];
+    let mut count = 0;
+    for ((x, y, z), number) in voxel_numbers.indexed_iter() {
+        let Some(number) = number else {
+            continue;
+        };
+        if activation_times[number / 3] > earliest_ms {
+            continue;
+        }
+        for (axis, position) in site_mm.iter_mut().enumerate() {
+            *position += voxel_positions_mm[(x, y, z, axis)];
+        }
+        count += 1;
+    }
+    if count == 0 {
+        return None;
+    }
+    Some(site_mm.map(|position| position / count as f32))
+}
+
+/// Localizes the earliest activation site from the estimated activation
+/// times and calculates its distance to the ground truth one.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_earliest_activation_error(
+    metrics: &mut Metrics,
+    estimated_activation_times: &ActivationTimePerStateMs,
+    ground_truth_activation_times: &ActivationTimePerStateMs,
+    voxel_numbers: &VoxelNumbers,
+    voxel_positions_mm: &VoxelPositions,
+) {
+    debug!("Calculating earliest activation error");
+    let estimated = earliest_activation_site_mm(
+        estimated_activation_times,
+        voxel_numbers,
+        voxel_positions_mm,
+    );
+    let ground_truth = earliest_activation_site_mm(
+        ground_truth_activation_times,
+        voxel_numbers,
+        voxel_positions_mm,
+    );
+    let (Some(estimated), Some(ground_truth)) = (estimated, ground_truth) else {
+        return;
+    };
+    metrics.earliest_activation_site_mm = estimated;
+    metrics.earliest_activation_error_mm = estimated
+        .iter()
+        .zip(ground_truth.iter())
+        .map(|(e, g)| (e - g).powi(2))
+        .sum::<f32>()
+        .sqrt();
+}
+
+/// Returns the number of ground truth pathology regions detected at the
+/// given threshold index.
+#[must_use]
+#[tracing::instrument(level = "trace")]
+pub fn count_detected_lesions(metrics: &Metrics, threshold_index: usize) -> usize {
+    trace!("Counting detected lesions");
+    metrics
+        .lesion_recall_over_threshold
+        .column(threshold_index)
+        .iter()
+        .filter(|recall| **recall >= LESION_DETECTION_RECALL)
+        .count()
+}
+
+/// Calculates Dice score, `IoU`, precision, and recall for the given
+/// voxel type predictions and ground truth.
+#[tracing::instrument(level = "trace")]
+fn calculate_for_threshold(
+    predictions: &VoxelTypes,
+    ground_truth: &VoxelTypes,
+) -> (f32, f32, f32, f32) {
+    trace!("Calculating segmentation metrics");
+    let dice = calculate_dice(predictions, ground_truth);
+    let iou = calculate_iou(predictions, ground_truth);
+    let precision = calculate_precision(predictions, ground_truth);
+    let recall = calculate_recall(predictions, ground_truth);
 
     (dice, iou, precision, recall)
 }
 
+/// Calculates the recall of every ground truth pathology region, i.e. the
+/// share of its pathological voxels predicted as pathological. Border zone
+/// voxels are not counted.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace")]
+fn calculate_lesion_recalls(
+    predictions: &VoxelTypes,
+    ground_truth: &VoxelTypes,
+    ground_truth_regions: &PathologyRegions,
+) -> Array1<f32> {
+    trace!("Calculating lesion recalls");
+    let number_of_regions = ground_truth_regions.number_of_regions();
+    let mut positives = Array1::<f32>::zeros(number_of_regions);
+    let mut true_positives = Array1::<f32>::zeros(number_of_regions);
+    predictions
+        .iter()
+        .zip(ground_truth.iter())
+        .zip(ground_truth_regions.iter())
+        .filter(|((_, ground_truth), _)| **ground_truth == VoxelType::Pathological)
+        .filter_map(|((prediction, _), region)| region.map(|region| (prediction, region)))
+        .for_each(|(prediction, region)| {
+            positives[region] += 1.0;
+            if *prediction == VoxelType::Pathological {
+                true_positives[region] += 1.0;
+            }
+        });
+    true_positives
+        .iter()
+        .zip(positives.iter())
+        .map(|(true_positives, positives)| {
+            if *positives == 0.0 {
+                1.0
+            } else {
+                true_positives / positives
+            }
+        })
+        .collect()
+}
+
 /// Calculates the recall for the given predictions and ground truth voxel types.
 ///
 /// Recall is defined as the ratio of true positives to total positives.
@@ -376,8 +786,8 @@ pub fn predict_voxeltype(
         ground_truth.shape()[2],
     ]);
 
-    let mut abs = Array1::zeros(estimations.system_states.shape()[0]);
-    let system_states = &estimations.system_states;
+    let system_states = estimations.analysis_system_states();
+    let mut abs = Array1::zeros(system_states.shape()[0]);
 
     predictions
         .iter_mut()
@@ -403,7 +813,7 @@ pub fn predict_voxeltype(
     predictions
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
 pub struct SampleWiseMetric(Array1<f32>);
 
 impl SampleWiseMetric {
@@ -456,7 +866,7 @@ impl DerefMut for SampleWiseMetric {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
 pub struct BatchWiseMetric(Array1<f32>);
 
 impl BatchWiseMetric {
@@ -2,7 +2,10 @@ use std::fmt::Display;
 
 use serde::{Deserialize, Serialize};
 pub mod derivation;
+pub mod loss;
+pub mod time_offset;
 pub mod update;
+pub mod window;
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Default, Copy)]
 pub enum Optimizer {
@@ -1,24 +1,30 @@
 use std::ops::{Deref, DerefMut, Sub};
 
 use approx::AbsDiffEq;
-use ndarray::Array1;
+use ndarray::{Array1, Array3};
 use ocl::Buffer;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use super::Optimizer;
+use super::{loss::calculate_loss_residuals, window::FitWindows, Optimizer};
 use crate::core::{
     algorithm::estimation::Estimations,
     config::algorithm::{APDerivative, Algorithm},
-    data::shapes::{Residuals, SystemStatesAtStep},
-    model::functional::{
-        allpass::{
-            delay_index_to_offset, from_coef_to_samples,
-            shapes::{Coefs, Gains},
-            APParameters,
+    data::shapes::{Measurements, Residuals, SystemStatesAtStep},
+    model::{
+        functional::{
+            allpass::{
+                delay_index_to_offset, from_coef_to_samples,
+                shapes::{Coefs, Gains},
+                APParameters,
+            },
+            control::{ControlFunction, ControlMatrix},
+            measurement::MeasurementMatrixAtBeat,
+            stimulus::StimulusSites,
+            time_offset::TimeOffsets,
+            FunctionalDescription,
         },
-        measurement::MeasurementMatrixAtBeat,
-        FunctionalDescription,
+        spatial::voxels::VoxelNumbers,
     },
 };
 
@@ -40,6 +46,9 @@ pub struct Derivatives {
     pub coefs_first_moment: Option<Coefs>,
     /// Second moment of the coeficients derivatives
     pub coefs_second_moment: Option<Coefs>,
+    /// Derivatives of the control function values
+    #[serde(default)]
+    pub control_function: ControlFunction,
     pub step: usize,
     /// IIR component of the coeficients derivatives
     /// only used for internal computation
@@ -54,6 +63,19 @@ pub struct Derivatives {
     /// Stored internally to avoid redundant computation
     pub maximum_regularization: MaximumRegularization,
     pub maximum_regularization_sum: f32,
+    /// Derivatives of the data-fit loss with regards to the
+    /// estimated measurements of the current step.
+    /// Stored internally to avoid redundant computation
+    #[serde(skip)]
+    pub loss_residuals: Option<Residuals>,
+    /// Derivatives of the beat-wise losses (NCC, spectral) with
+    /// regards to the estimated measurements.
+    /// Updated after every beat and used in the next epoch.
+    #[serde(skip)]
+    pub beat_loss_gradients: Option<Measurements>,
+    /// Samples of every beat that contribute to the loss.
+    #[serde(skip)]
+    pub fit_windows: FitWindows,
 }
 
 pub struct DerivativesGPU {
@@ -61,6 +83,7 @@ pub struct DerivativesGPU {
     pub coefs: Buffer<f32>,
     pub coefs_iir: Buffer<f32>,
     pub coefs_fir: Buffer<f32>,
+    pub control_function: Buffer<f32>,
     pub mapped_residuals: Buffer<f32>,
     pub maximum_regularization: Buffer<f32>,
     pub maximum_regularization_sum: Buffer<f32>,
@@ -68,10 +91,10 @@ pub struct DerivativesGPU {
 
 impl Derivatives {
     /// Creates a new Derivatives struct with empty arrays initialized to
-    /// the given number of states.
+    /// the given number of states and steps.
     #[must_use]
     #[tracing::instrument(level = "debug")]
-    pub fn new(number_of_states: usize, optimizer: Optimizer) -> Self {
+    pub fn new(number_of_states: usize, number_of_steps: usize, optimizer: Optimizer) -> Self {
         debug!("Creating empty derivatives");
         let gains_first_moment = match optimizer {
             Optimizer::Sgd => None,
@@ -96,12 +119,16 @@ impl Derivatives {
             coefs: Coefs::empty(number_of_states),
             coefs_first_moment,
             coefs_second_moment,
+            control_function: ControlFunction::empty(number_of_steps),
             step: 1,
             coefs_iir: Gains::empty(number_of_states),
             coefs_fir: Gains::empty(number_of_states),
             mapped_residuals: MappedResiduals::new(number_of_states),
             maximum_regularization: MaximumRegularization::new(number_of_states),
             maximum_regularization_sum: 0.0,
+            loss_residuals: None,
+            beat_loss_gradients: None,
+            fit_windows: FitWindows::default(),
         }
     }
 
@@ -116,6 +143,7 @@ impl Derivatives {
         self.coefs.fill(0.0);
         self.coefs_iir.fill(0.0);
         self.coefs_fir.fill(0.0);
+        self.control_function.fill(0.0);
         self.maximum_regularization.fill(0.0);
         self.maximum_regularization_sum = 0.0;
     }
@@ -126,6 +154,7 @@ impl Derivatives {
             coefs: self.coefs.to_gpu(queue),
             coefs_iir: self.coefs_iir.to_gpu(queue),
             coefs_fir: self.coefs_fir.to_gpu(queue),
+            control_function: self.control_function.to_gpu(queue),
             mapped_residuals: self.mapped_residuals.to_gpu(queue),
             maximum_regularization: self.maximum_regularization.to_gpu(queue),
             maximum_regularization_sum: ocl::Buffer::builder()
@@ -142,6 +171,8 @@ impl Derivatives {
         self.coefs.update_from_gpu(&derivatives.coefs);
         self.coefs_iir.update_from_gpu(&derivatives.coefs_iir);
         self.coefs_fir.update_from_gpu(&derivatives.coefs_fir);
+        self.control_function
+            .update_from_gpu(&derivatives.control_function);
         self.mapped_residuals
             .update_from_gpu(&derivatives.mapped_residuals);
         self.maximum_regularization
@@ -161,6 +192,10 @@ impl Derivatives {
 /// CAUTION: adds to old values. use "reset" after using the
 /// derivatives to update the parameters.
 ///
+/// Steps outside of the fit windows contribute neither data-fit nor
+/// regularization gradients. The data-fit gradients are normalized by the
+/// summed sensor weights, like the losses of the step metrics.
+///
 /// # Panics
 ///
 /// Panics if `ap_params` is not set.
@@ -176,18 +211,42 @@ pub fn calculate_step_derivatives(
     number_of_sensors: usize,
 ) {
     debug!("Calculating derivatives");
+    let in_fit_window = derivates.fit_windows.contains(beat, step);
+    let sensor_weight_sum = functional_description
+        .sensor_weights
+        .active_weight_sum(number_of_sensors);
+    let loss_residuals = derivates
+        .loss_residuals
+        .get_or_insert_with(|| Residuals::empty(number_of_sensors));
+    if in_fit_window {
+        calculate_loss_residuals(
+            loss_residuals,
+            &estimations.residuals,
+            derivates.beat_loss_gradients.as_ref(),
+            &functional_description.sensor_weights,
+            config,
+            beat,
+            step,
+        );
+    } else {
+        loss_residuals.fill(0.0);
+    }
     calculate_mapped_residuals(
         &mut derivates.mapped_residuals,
-        &estimations.residuals,
+        loss_residuals,
         &functional_description.measurement_matrix.at_beat(beat),
     );
 
-    calculate_maximum_regularization(
-        &mut derivates.maximum_regularization,
-        &mut derivates.maximum_regularization_sum,
-        &estimations.system_states.at_step(step),
-        config.maximum_regularization_threshold,
-    );
+    if in_fit_window {
+        calculate_maximum_regularization(
+            &mut derivates.maximum_regularization,
+            &mut derivates.maximum_regularization_sum,
+            &estimations.system_states.at_step(step),
+            config.maximum_regularization_threshold,
+        );
+    } else {
+        derivates.maximum_regularization.fill(0.0);
+    }
 
     if !config.freeze_gains {
         calculate_derivatives_gains(
@@ -196,7 +255,19 @@ pub fn calculate_step_derivatives(
             &derivates.maximum_regularization,
             &derivates.mapped_residuals,
             config,
-            number_of_sensors,
+            sensor_weight_sum,
+        );
+    }
+    if config.learn_control_function {
+        calculate_derivatives_control_function(
+            &mut derivates.control_function,
+            &derivates.mapped_residuals,
+            &functional_description.control_matrix,
+            &functional_description.time_offsets,
+            &functional_description.stimulus_sites,
+            beat,
+            step,
+            sensor_weight_sum,
         );
     }
     if !config.freeze_delays {
@@ -247,6 +318,17 @@ pub fn calculate_batch_derivatives(
     {
         calculate_smoothness_derivatives(derivatives, estimations, functional_description, config);
     }
+    if config.learn_control_function
+        && config
+            .control_function_smoothness_strength
+            .abs_diff_ne(&0.0, f32::EPSILON)
+    {
+        calculate_control_function_smoothness_derivatives(
+            &mut derivatives.control_function,
+            &functional_description.control_function_values,
+            config.control_function_smoothness_strength,
+        );
+    }
 }
 
 #[allow(clippy::cast_precision_loss)]
@@ -296,7 +378,6 @@ pub fn calculate_smoothness_derivatives(
 }
 /// Calculates the derivatives for the allpass filter gains.
 #[inline]
-#[allow(clippy::cast_precision_loss)]
 #[tracing::instrument(level = "trace")]
 pub fn calculate_derivatives_gains(
     derivatives_gains: &mut Gains,
@@ -304,9 +385,9 @@ pub fn calculate_derivatives_gains(
     maximum_regularization: &MaximumRegularization,
     mapped_residuals: &MappedResiduals,
     config: &Algorithm,
-    number_of_sensors: usize,
+    sensor_weight_sum: f32,
 ) {
-    let mse_scaling = 1.0 / number_of_sensors as f32 * config.mse_strength;
+    let loss_scaling = 1.0 / sensor_weight_sum;
     let regularization_scaling = config.maximum_regularization_strength;
 
     for gain_index in 0..derivatives_gains.shape()[0] {
@@ -317,10 +398,88 @@ pub fn calculate_derivatives_gains(
             let derivative = unsafe { derivatives_gains.uget_mut((gain_index, offset_index)) };
 
             *derivative +=
-                ap_output * residual.mul_add(mse_scaling, max_reg * regularization_scaling);
+                ap_output * residual.mul_add(loss_scaling, max_reg * regularization_scaling);
         }
     }
 }
+/// Calculates the derivatives for the control function values.
+///
+/// The control function is added to the system states via the control
+/// matrix, so the derivative is the mapped residual of the controlled
+/// states. If the beat is shifted by a fractional time offset, the
+/// derivative is split onto the two interpolated values. Stimulus sites with
+/// onsets contribute to the values their delayed control function was taken
+/// from.
+#[inline]
+#[allow(clippy::too_many_arguments)]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_derivatives_control_function(
+    derivatives_control_function: &mut ControlFunction,
+    mapped_residuals: &MappedResiduals,
+    control_matrix: &ControlMatrix,
+    time_offsets: &TimeOffsets,
+    stimulus_sites: &StimulusSites,
+    beat: usize,
+    step: usize,
+    sensor_weight_sum: f32,
+) {
+    let loss_scaling = 1.0 / sensor_weight_sum;
+    let mut add_derivative = |position: Option<(usize, f32)>, derivative: f32| {
+        let Some((index, fraction)) = position else {
+            return;
+        };
+        if let Some(value) = derivatives_control_function.get_mut(index) {
+            *value += (1.0 - fraction) * derivative;
+        }
+        if fraction != 0.0 {
+            if let Some(value) = derivatives_control_function.get_mut(index + 1) {
+                *value += fraction * derivative;
+            }
+        }
+    };
+    if stimulus_sites.has_onsets() {
+        for (site, state) in stimulus_sites.states.iter().enumerate() {
+            add_derivative(
+                time_offsets.delayed_control_position(
+                    beat,
+                    step,
+                    stimulus_sites.onset_samples(site, beat),
+                ),
+                loss_scaling * control_matrix[*state] * mapped_residuals[*state],
+            );
+        }
+        return;
+    }
+    add_derivative(
+        time_offsets.control_position(beat, step),
+        loss_scaling * control_matrix.dot(&**mapped_residuals),
+    );
+}
+
+/// Calculates the derivatives of the smoothness regularization of the
+/// control function, which penalizes the squared differences between
+/// neighbouring values.
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_control_function_smoothness_derivatives(
+    derivatives_control_function: &mut ControlFunction,
+    control_function: &ControlFunction,
+    strength: f32,
+) {
+    trace!("Calculating control function smoothness derivatives");
+    let number_of_steps = control_function.len();
+    for step in 0..number_of_steps {
+        let value = control_function[step];
+        let mut difference = 0.0;
+        if step > 0 {
+            difference += value - control_function[step - 1];
+        }
+        if step + 1 < number_of_steps {
+            difference += value - control_function[step + 1];
+        }
+        derivatives_control_function[step] += strength * difference;
+    }
+}
+
 /// Calculates the derivatives for the allpass filter coefficients using a simplified form for the AP derivative.
 #[inline]
 #[allow(clippy::cast_precision_loss)]
@@ -332,7 +491,10 @@ pub fn calculate_derivatives_coefs_simple(
     step: usize,
     config: &Algorithm,
 ) {
-    let mse_scaling = 1.0 / estimations.measurements.num_sensors() as f32 * config.mse_strength;
+    let loss_scaling = 1.0
+        / functional_description
+            .sensor_weights
+            .active_weight_sum(estimations.measurements.num_sensors());
     for state_index in 0..derivatives.coefs_iir.shape()[0] {
         for offset_index in 0..derivatives.coefs_iir.shape()[1] {
             let coef_index = (state_index / 3, offset_index / 3);
@@ -378,7 +540,7 @@ pub fn calculate_derivatives_coefs_simple(
                 let coef_derivative = unsafe { derivatives.coefs.uget_mut(coef_index) };
                 *coef_derivative += ((state_val - ap_output_last) * ap_gain * mapped_residual)
                     .mul_add(
-                        mse_scaling,
+                        loss_scaling,
                         config.difference_regularization_strength * delay_delta,
                     );
             }
@@ -397,7 +559,10 @@ pub fn calculate_derivatives_coefs_textbook(
     step: usize,
     config: &Algorithm,
 ) {
-    let mse_scaling = 1.0 / estimations.measurements.num_sensors() as f32 * config.mse_strength;
+    let loss_scaling = 1.0
+        / functional_description
+            .sensor_weights
+            .active_weight_sum(estimations.measurements.num_sensors());
 
     // FIR derivatives calculation
     for state_index in 0..derivatives.coefs_fir.shape()[0] {
@@ -477,7 +642,7 @@ pub fn calculate_derivatives_coefs_textbook(
 
             let coef_derivative = unsafe { derivatives.coefs.uget_mut(coef_index) };
             *coef_derivative += ((fir - iir) * ap_gain * mapped_residual).mul_add(
-                mse_scaling,
+                loss_scaling,
                 config.difference_regularization_strength * delay_delta,
             );
         }
@@ -581,7 +746,8 @@ pub fn calculate_average_delays(average_delays: &mut AverageDelays, ap_params: &
 /// These values are then used for the calcualtion of the derivatives
 ///
 /// The mapped residuals are calculated as
-/// `H_T` * y
+/// `H_T` * y, where y are the derivatives of the
+/// data-fit loss with regards to the estimated measurements
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct MappedResiduals(Array1<f32>);
 
@@ -642,6 +808,26 @@ impl AverageDelays {
         trace!("Creating AverageDelays");
         Self(Array1::from_elem(number_of_states / 3, None))
     }
+
+    /// Returns the average propagation speed of every voxel in m/s, i.e. the
+    /// voxel size divided by its average delay. Voxels without states or
+    /// without an average delay have no speed.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip_all)]
+    pub fn propagation_speeds_m_per_s(
+        &self,
+        voxel_numbers: &VoxelNumbers,
+        voxel_size_mm: f32,
+        sample_rate_hz: f32,
+    ) -> Array3<Option<f32>> {
+        trace!("Calculating average propagation speeds");
+        voxel_numbers.mapv(|number| {
+            number.and_then(|number| {
+                self[number / 3]
+                    .map(|delay_samples| voxel_size_mm / 1000.0 / (delay_samples / sample_rate_hz))
+            })
+        })
+    }
 }
 
 impl<'a, 'b> Sub<&'b AverageDelays> for &'a AverageDelays {
@@ -753,7 +939,7 @@ mod tests {
         let number_of_sensors = 10;
         let number_of_beats = 1;
         let step = 10;
-        let mut derivatives = Derivatives::new(number_of_states, Optimizer::Sgd);
+        let mut derivatives = Derivatives::new(number_of_states, number_of_steps, Optimizer::Sgd);
         let estimations = Estimations::empty(
             number_of_states,
             number_of_sensors,
@@ -782,6 +968,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn gains_derivatives_are_normalized_by_sensor_weight_sum() {
+        let number_of_states = 3;
+        let sensor_weight_sum = 4.0;
+        let config = Algorithm {
+            maximum_regularization_strength: 0.0,
+            ..Default::default()
+        };
+
+        let mut derivatives_gains = Gains::empty(number_of_states);
+        let mut ap_outputs = Gains::empty(number_of_states);
+        ap_outputs.fill(1.0);
+        let mut mapped_residuals = MappedResiduals::new(number_of_states);
+        mapped_residuals.fill(2.0);
+
+        calculate_derivatives_gains(
+            &mut derivatives_gains,
+            &ap_outputs,
+            &MaximumRegularization::new(number_of_states),
+            &mapped_residuals,
+            &config,
+            sensor_weight_sum,
+        );
+
+        derivatives_gains
+            .iter()
+            .for_each(|derivative| assert_relative_eq!(*derivative, 0.5));
+    }
+
     #[test]
     fn calculate_no_crash() {
         let number_of_states = 1500;
@@ -796,7 +1011,7 @@ mod tests {
             ..Default::default()
         };
 
-        let mut derivates = Derivatives::new(number_of_states, config.optimizer);
+        let mut derivates = Derivatives::new(number_of_states, number_of_steps, config.optimizer);
         let functional_description = FunctionalDescription::empty(
             number_of_states,
             number_of_sensors,
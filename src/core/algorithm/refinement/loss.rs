@@ -0,0 +1,478 @@
+use ndarray::{s, ArrayView1, ArrayViewMut1};
+use realfft::{num_complex::Complex, RealFftPlanner};
+use tracing::{debug, trace};
+
+use crate::core::{
+    algorithm::{estimation::Estimations, metrics::Metrics, refinement::derivation::Derivatives},
+    config::algorithm::Algorithm,
+    data::{
+        shapes::{Measurements, Residuals},
+        Data,
+    },
+    model::functional::measurement::SensorWeights,
+};
+
+/// Calculates the derivative of the data-fit loss with respect to the
+/// estimated measurements of the current step.
+///
+/// All terms follow the convention of the MSE loss `r^2`, whose derivative
+/// is taken as `r`, i.e. half the actual derivative. The pointwise terms
+/// (MSE, Huber, L1, Cauchy) are evaluated on the current residuals. The
+/// beat-wise terms (NCC, spectral) need the whole beat and are taken from
+/// `beat_loss_gradients`, which are calculated from an estimation of the
+/// current beat before its steps are refined.
+/// The derivative of every sensor is scaled by its weight.
+#[inline]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_loss_residuals(
+    loss_residuals: &mut Residuals,
+    residuals: &Residuals,
+    beat_loss_gradients: Option<&Measurements>,
+    sensor_weights: &SensorWeights,
+    config: &Algorithm,
+    beat: usize,
+    step: usize,
+) {
+    trace!("Calculating loss residuals");
+    for (sensor, (loss_residual, residual)) in
+        loss_residuals.iter_mut().zip(residuals.iter()).enumerate()
+    {
+        let mut value = config.mse_strength * residual;
+        if config.huber_strength != 0.0 {
+            value += config.huber_strength * huber_derivative(*residual, config.huber_delta);
+        }
+        // missing samples have a residual of exactly zero
+        if config.l1_strength != 0.0 && *residual != 0.0 {
+            value += config.l1_strength * 0.5 * residual.signum();
+        }
+        if config.cauchy_strength != 0.0 {
+            value += config.cauchy_strength * cauchy_derivative(*residual, config.cauchy_scale);
+        }
+        if let Some(gradients) = beat_loss_gradients {
+            value += gradients[(beat, step, sensor)];
+        }
+        *loss_residual = sensor_weights.weight(sensor) * value;
+    }
+}
+
+/// Calculates the pointwise losses of the current step, weighted per sensor
+/// and normalized by the summed sensor weights.
+///
+/// Returns the Huber, L1 and Cauchy loss.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+pub fn calculate_pointwise_losses(
+    residuals: &Residuals,
+    sensor_weights: &SensorWeights,
+    config: &Algorithm,
+) -> (f32, f32, f32) {
+    trace!("Calculating pointwise losses");
+    let weight_sum = sensor_weights.active_weight_sum(residuals.len());
+    let weighted_mean = |loss: &dyn Fn(f32) -> f32| {
+        residuals
+            .iter()
+            .enumerate()
+            .map(|(sensor, residual)| sensor_weights.weight(sensor) * loss(*residual))
+            .sum::<f32>()
+            / weight_sum
+    };
+    let huber = weighted_mean(&|residual| huber(residual, config.huber_delta));
+    let l1 = weighted_mean(&f32::abs);
+    let cauchy = weighted_mean(&|residual| cauchy(residual, config.cauchy_scale));
+    (huber, l1, cauchy)
+}
+
+/// Returns true if a beat-wise loss (NCC or spectral) is enabled.
+#[must_use]
+pub fn uses_beat_losses(config: &Algorithm) -> bool {
+    config.ncc_strength != 0.0 || config.spectral_strength != 0.0
+}
+
+/// Calculates the derivatives of the beat-wise losses (NCC and spectral)
+/// of the given beat and stores them in `derivatives.beat_loss_gradients`.
+///
+/// Needs the estimated measurements of all steps of the beat. Returns the
+/// NCC and the spectral loss, normalized by the summed sensor weights.
+///
+/// Masked sensors are skipped and the remaining ones are weighted. Missing
+/// (NaN) samples and samples outside of the fit windows are replaced by the
+/// estimated value, so they do not contribute to the loss of the beat. The
+/// gradients outside of the fit windows are set to zero.
+///
+/// # Panics
+///
+/// Panics if the measurements of the estimations and the data differ in
+/// shape.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_beat_loss_gradients(
+    derivatives: &mut Derivatives,
+    estimations: &Estimations,
+    data: &Data,
+    sensor_weights: &SensorWeights,
+    config: &Algorithm,
+    beat: usize,
+) -> (f32, f32) {
+    debug!("Calculating beat loss gradients");
+    let estimated = estimations.measurements.at_beat(beat);
+    let actual = data.simulation.measurements.at_beat(beat);
+    let number_of_steps = estimated.shape()[0];
+    let number_of_sensors = estimated.shape()[1];
+    let fit_windows = &derivatives.fit_windows;
+    let gradients = derivatives.beat_loss_gradients.get_or_insert_with(|| {
+        Measurements::empty(
+            estimations.measurements.num_beats(),
+            number_of_steps,
+            number_of_sensors,
+        )
+    });
+    let mut gradients = gradients.slice_mut(s![beat, .., ..]);
+    gradients.fill(0.0);
+
+    let mut loss_ncc = 0.0;
+    let mut loss_spectral = 0.0;
+    let mut planner = RealFftPlanner::<f32>::new();
+    for sensor in 0..number_of_sensors {
+        let weight = sensor_weights.weight(sensor);
+        if weight == 0.0 {
+            continue;
+        }
+        let estimated = estimated.slice(s![.., sensor]);
+        let mut actual = actual.slice(s![.., sensor]).to_owned();
+        for (step, (actual, estimated)) in actual.iter_mut().zip(estimated.iter()).enumerate() {
+            if actual.is_nan() || !fit_windows.contains(beat, step) {
+                *actual = *estimated;
+            }
+        }
+        let actual = actual.view();
+        let mut gradient = gradients.slice_mut(s![.., sensor]);
+        if config.ncc_strength != 0.0 {
+            loss_ncc += weight
+                * calculate_ncc(
+                    &estimated,
+                    &actual,
+                    &mut gradient,
+                    weight * config.ncc_strength,
+                );
+        }
+        if config.spectral_strength != 0.0 {
+            loss_spectral += weight
+                * calculate_spectral(
+                    &estimated,
+                    &actual,
+                    &mut gradient,
+                    weight * config.spectral_strength,
+                    &mut planner,
+                );
+        }
+    }
+    for (step, mut gradient) in gradients.outer_iter_mut().enumerate() {
+        if !fit_windows.contains(beat, step) {
+            gradient.fill(0.0);
+        }
+    }
+    let weight_sum = sensor_weights.active_weight_sum(number_of_sensors);
+    loss_ncc /= weight_sum;
+    loss_spectral /= weight_sum;
+
+    (loss_ncc, loss_spectral)
+}
+
+/// Calculates the beat-wise losses (NCC and spectral) of the given beat.
+///
+/// Writes the losses into the metrics of every step, adds them to the total
+/// loss and updates their derivatives, see
+/// [`calculate_beat_loss_gradients`]. Needs to be called after all steps of
+/// the beat have been estimated.
+///
+/// The loss of a beat is spread evenly over its steps, so that the mean over
+/// the steps equals `1 - ncc` for the NCC loss. The spectral loss is
+/// normalized such that it is comparable to the MSE.
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_beat_losses(
+    metrics: &mut Metrics,
+    derivatives: &mut Derivatives,
+    estimations: &Estimations,
+    data: &Data,
+    sensor_weights: &SensorWeights,
+    config: &Algorithm,
+    beat: usize,
+) {
+    if !uses_beat_losses(config) {
+        return;
+    }
+    debug!("Calculating beat losses");
+    let (loss_ncc, loss_spectral) =
+        calculate_beat_loss_gradients(derivatives, estimations, data, sensor_weights, config, beat);
+    let number_of_steps = estimations.measurements.num_steps();
+    let fit_windows = &derivatives.fit_windows;
+    let beat_loss = config
+        .ncc_strength
+        .mul_add(loss_ncc, config.spectral_strength * loss_spectral);
+    for step in 0..number_of_steps {
+        if fit_windows.contains(beat, step) {
+            metrics.loss_ncc[step] = loss_ncc;
+            metrics.loss_spectral[step] = loss_spectral;
+            metrics.loss[step] += beat_loss;
+        } else {
+            metrics.loss_ncc[step] = 0.0;
+            metrics.loss_spectral[step] = 0.0;
+        }
+    }
+}
+
+/// Calculates `1 - ncc` for one sensor and adds the weighted derivative to
+/// the gradient.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn calculate_ncc(
+    estimated: &ArrayView1<f32>,
+    actual: &ArrayView1<f32>,
+    gradient: &mut ArrayViewMut1<f32>,
+    strength: f32,
+) -> f32 {
+    let number_of_steps = estimated.len() as f32;
+    let estimated = estimated - estimated.mean().unwrap_or_default();
+    let actual = actual - actual.mean().unwrap_or_default();
+    let estimated_norm = estimated.dot(&estimated).sqrt();
+    let actual_norm = actual.dot(&actual).sqrt();
+    if estimated_norm == 0.0 || actual_norm == 0.0 {
+        return 1.0;
+    }
+    let ncc = estimated.dot(&actual) / (estimated_norm * actual_norm);
+    // the beat loss is number_of_steps * (1 - ncc), half of its derivative
+    let scaling = -0.5 * strength * number_of_steps;
+    gradient.zip_mut_with(&estimated, |gradient, estimated| {
+        *gradient -= scaling * ncc * estimated / estimated_norm.powi(2);
+    });
+    gradient.zip_mut_with(&actual, |gradient, actual| {
+        *gradient += scaling * actual / (estimated_norm * actual_norm);
+    });
+    1.0 - ncc
+}
+
+/// Calculates the spectral loss for one sensor and adds the weighted
+/// derivative to the gradient.
+///
+/// The beat loss is `sum_f (|A_f| - |B_f|)^2 / N` over the full spectrum,
+/// which equals the summed squared error if only the phases match. Half of
+/// its derivative is the inverse transform of `(|A_f| - |B_f|) A_f / |A_f|`.
+/// The returned value is the beat loss divided by the number of steps.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn calculate_spectral(
+    estimated: &ArrayView1<f32>,
+    actual: &ArrayView1<f32>,
+    gradient: &mut ArrayViewMut1<f32>,
+    strength: f32,
+    planner: &mut RealFftPlanner<f32>,
+) -> f32 {
+    let number_of_steps = estimated.len();
+    let forward = planner.plan_fft_forward(number_of_steps);
+    let inverse = planner.plan_fft_inverse(number_of_steps);
+
+    let mut estimated_spectrum = forward.make_output_vec();
+    let mut actual_spectrum = forward.make_output_vec();
+    let mut input = estimated.to_vec();
+    forward
+        .process(&mut input, &mut estimated_spectrum)
+        .expect("Buffer sizes to match.");
+    let mut input = actual.to_vec();
+    forward
+        .process(&mut input, &mut actual_spectrum)
+        .expect("Buffer sizes to match.");
+
+    let mut loss = 0.0;
+    let mut difference = inverse.make_input_vec();
+    let last_bin = estimated_spectrum.len() - 1;
+    let has_nyquist_bin = 2 * last_bin == number_of_steps;
+    for (bin, (estimated, actual)) in estimated_spectrum
+        .iter()
+        .zip(actual_spectrum.iter())
+        .enumerate()
+    {
+        let estimated_magnitude = estimated.norm();
+        let delta = estimated_magnitude - actual.norm();
+        // bins other than DC and nyquist appear twice in the full spectrum
+        let multiplicity = if bin == 0 || (bin == last_bin && has_nyquist_bin) {
+            1.0
+        } else {
+            2.0
+        };
+        loss += multiplicity * delta * delta;
+        difference[bin] = if estimated_magnitude > 0.0 {
+            estimated * (delta / estimated_magnitude)
+        } else {
+            Complex::new(0.0, 0.0)
+        };
+    }
+    // the inverse of a real signal needs real DC and nyquist bins
+    difference[0].im = 0.0;
+    if has_nyquist_bin {
+        difference[last_bin].im = 0.0;
+    }
+    let mut output = inverse.make_output_vec();
+    inverse
+        .process(&mut difference, &mut output)
+        .expect("Buffer sizes to match.");
+    let scaling = strength / number_of_steps as f32;
+    gradient
+        .iter_mut()
+        .zip(output)
+        .for_each(|(gradient, value)| *gradient += scaling * value);
+
+    loss / (number_of_steps * number_of_steps) as f32
+}
+
+/// Huber loss, equal to the squared residual below `delta`.
+#[inline]
+fn huber(residual: f32, delta: f32) -> f32 {
+    let magnitude = residual.abs();
+    if magnitude <= delta {
+        residual * residual
+    } else {
+        delta * 2.0f32.mul_add(magnitude, -delta)
+    }
+}
+
+/// Half the derivative of the Huber loss.
+#[inline]
+fn huber_derivative(residual: f32, delta: f32) -> f32 {
+    residual.clamp(-delta, delta)
+}
+
+/// Cauchy loss, approximately equal to the squared residual below `scale`.
+#[inline]
+fn cauchy(residual: f32, scale: f32) -> f32 {
+    scale * scale * (residual / scale).powi(2).ln_1p()
+}
+
+/// Half the derivative of the Cauchy loss.
+#[inline]
+fn cauchy_derivative(residual: f32, scale: f32) -> f32 {
+    residual / (residual / scale).mul_add(residual / scale, 1.0)
+}
+
+#[cfg(test)]
+#[allow(clippy::cast_precision_loss)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Array1;
+
+    use super::*;
+
+    /// Calculates the derivative of the beat-wise losses numerically, used to
+    /// check the analytic derivatives.
+    fn numeric_gradient(
+        estimated: &Array1<f32>,
+        actual: &Array1<f32>,
+        loss: impl Fn(&ArrayView1<f32>, &ArrayView1<f32>) -> f32,
+    ) -> Array1<f32> {
+        let epsilon = 1e-2;
+        let mut gradient = Array1::zeros(estimated.len());
+        for step in 0..estimated.len() {
+            let mut plus = estimated.clone();
+            plus[step] += epsilon;
+            let mut minus = estimated.clone();
+            minus[step] -= epsilon;
+            gradient[step] = (loss(&plus.view(), &actual.view())
+                - loss(&minus.view(), &actual.view()))
+                / (2.0 * epsilon);
+        }
+        gradient
+    }
+
+    fn create_signals() -> (Array1<f32>, Array1<f32>) {
+        let estimated = Array1::from_shape_fn(32, |step| (step as f32 * 0.3).sin() + 0.1);
+        let actual = Array1::from_shape_fn(32, |step| (step as f32 * 0.35).sin() * 2.0);
+        (estimated, actual)
+    }
+
+    #[test]
+    fn pointwise_losses_match_mse_for_small_residuals() {
+        assert_relative_eq!(huber(0.5, 1.0), 0.25);
+        assert_relative_eq!(huber(3.0, 1.0), 5.0);
+        assert_relative_eq!(huber_derivative(3.0, 1.0), 1.0);
+        assert_relative_eq!(cauchy(1e-2, 1.0), 1e-4, epsilon = 1e-7);
+        assert_relative_eq!(cauchy_derivative(1e-2, 1.0), 1e-2, epsilon = 1e-5);
+    }
+
+    #[test]
+    fn ncc_gradient_matches_numeric() {
+        let (estimated, actual) = create_signals();
+        let mut gradient = Array1::zeros(estimated.len());
+
+        calculate_ncc(
+            &estimated.view(),
+            &actual.view(),
+            &mut gradient.view_mut(),
+            1.0,
+        );
+
+        let number_of_steps = estimated.len() as f32;
+        let numeric = numeric_gradient(&estimated, &actual, |estimated, actual| {
+            0.5 * number_of_steps
+                * calculate_ncc(
+                    estimated,
+                    actual,
+                    &mut Array1::zeros(estimated.len()).view_mut(),
+                    0.0,
+                )
+        });
+        for (analytic, numeric) in gradient.iter().zip(numeric.iter()) {
+            assert_relative_eq!(analytic, numeric, epsilon = 1e-2);
+        }
+    }
+
+    #[test]
+    fn spectral_gradient_matches_numeric() {
+        let (estimated, actual) = create_signals();
+        let mut gradient = Array1::zeros(estimated.len());
+        let mut planner = RealFftPlanner::<f32>::new();
+
+        let loss = calculate_spectral(
+            &estimated.view(),
+            &actual.view(),
+            &mut gradient.view_mut(),
+            1.0,
+            &mut planner,
+        );
+        assert!(loss > 0.0);
+
+        let number_of_steps = estimated.len() as f32;
+        let numeric = numeric_gradient(&estimated, &actual, |estimated, actual| {
+            0.5 * number_of_steps
+                * calculate_spectral(
+                    estimated,
+                    actual,
+                    &mut Array1::zeros(estimated.len()).view_mut(),
+                    0.0,
+                    &mut RealFftPlanner::<f32>::new(),
+                )
+        });
+        for (analytic, numeric) in gradient.iter().zip(numeric.iter()) {
+            assert_relative_eq!(analytic, numeric, epsilon = 1e-2);
+        }
+    }
+
+    #[test]
+    fn spectral_loss_equals_mse_for_equal_phases() {
+        let (estimated, _) = create_signals();
+        let actual = &estimated * 2.0;
+        let mut planner = RealFftPlanner::<f32>::new();
+
+        let loss = calculate_spectral(
+            &estimated.view(),
+            &actual.view(),
+            &mut Array1::zeros(estimated.len()).view_mut(),
+            0.0,
+            &mut planner,
+        );
+
+        let mse = (&estimated - &actual)
+            .mapv(|value| value * value)
+            .mean()
+            .unwrap();
+        assert_relative_eq!(loss, mse, epsilon = 1e-4);
+    }
+}
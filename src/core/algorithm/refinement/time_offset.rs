@@ -0,0 +1,187 @@
+use ndarray::ArrayView2;
+use tracing::{debug, trace};
+
+use super::window::FitWindows;
+use crate::core::{
+    algorithm::estimation::Estimations,
+    config::algorithm::Algorithm,
+    data::Data,
+    model::functional::{time_offset::TimeOffsets, FunctionalDescription},
+};
+
+/// Updates the time offset of the given beat.
+///
+/// Needs to be called after all steps of the beat have been estimated.
+/// Before the offsets are initialized, the offset is shifted by the lag that
+/// maximizes the cross-correlation between the predicted and the measured
+/// signals. Afterwards a damped Gauss-Newton step is taken, using that a
+/// shift of the beat by `tau` changes the prediction by `-tau` times its time
+/// derivative.
+///
+/// Masked sensors, missing samples and samples outside of the fit windows
+/// are ignored. The offset is limited to `maximum_time_offset_ms`.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn update_time_offset(
+    functional_description: &mut FunctionalDescription,
+    estimations: &Estimations,
+    data: &Data,
+    fit_windows: &FitWindows,
+    config: &Algorithm,
+    beat: usize,
+) {
+    debug!("Updating time offset of beat {beat}");
+    let number_of_beats = data.simulation.measurements.num_beats();
+    let maximum_offset = config.maximum_time_offset_ms * data.simulation.sample_rate_hz / 1000.0;
+    let FunctionalDescription {
+        time_offsets,
+        sensor_weights,
+        ..
+    } = functional_description;
+    if time_offsets.samples.len() != number_of_beats {
+        *time_offsets = TimeOffsets::zeros(number_of_beats);
+    }
+
+    let estimated = estimations.measurements.at_beat(beat);
+    let actual = data.simulation.measurements.at_beat(beat);
+    let weight = |step: usize, sensor: usize| {
+        if actual[(step, sensor)].is_nan() || !fit_windows.contains(beat, step) {
+            0.0
+        } else {
+            sensor_weights.weight(sensor)
+        }
+    };
+
+    let change = if time_offsets.initialized {
+        config.time_offset_step_size * gauss_newton_step(&estimated, &actual, weight)
+    } else {
+        cross_correlation_lag(
+            &estimated,
+            &actual,
+            weight,
+            maximum_offset.max(0.0) as usize,
+        ) as f32
+    };
+    let offset = &mut time_offsets.samples[beat];
+    *offset = (*offset + change).clamp(-maximum_offset, maximum_offset);
+    trace!("Time offset of beat {beat}: {offset} samples");
+}
+
+/// Returns the lag in samples that maximizes the weighted cross-correlation
+/// between the estimated and the actual measurements.
+///
+/// A positive lag means that the actual measurements are delayed.
+#[allow(clippy::cast_possible_wrap, clippy::cast_sign_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn cross_correlation_lag(
+    estimated: &ArrayView2<f32>,
+    actual: &ArrayView2<f32>,
+    weight: impl Fn(usize, usize) -> f32,
+    maximum_lag: usize,
+) -> isize {
+    trace!("Calculating cross-correlation lag");
+    let number_of_steps = estimated.nrows();
+    let maximum_lag = maximum_lag.min(number_of_steps.saturating_sub(1)) as isize;
+    let mut best_lag = 0;
+    let mut best_correlation = f32::NEG_INFINITY;
+    for lag in -maximum_lag..=maximum_lag {
+        let mut correlation = 0.0;
+        for step in 0..number_of_steps {
+            let shifted = step as isize + lag;
+            if shifted < 0 || shifted >= number_of_steps as isize {
+                continue;
+            }
+            let shifted = shifted as usize;
+            for (sensor, value) in estimated.row(step).iter().enumerate() {
+                let weight = weight(shifted, sensor);
+                if weight != 0.0 {
+                    correlation += weight * value * actual[(shifted, sensor)];
+                }
+            }
+        }
+        if correlation > best_correlation {
+            best_correlation = correlation;
+            best_lag = lag;
+        }
+    }
+    best_lag
+}
+
+/// Returns the Gauss-Newton step of the offset in samples.
+///
+/// The time derivative of the estimated measurements is approximated by
+/// central differences.
+#[tracing::instrument(level = "trace", skip_all)]
+fn gauss_newton_step(
+    estimated: &ArrayView2<f32>,
+    actual: &ArrayView2<f32>,
+    weight: impl Fn(usize, usize) -> f32,
+) -> f32 {
+    trace!("Calculating gauss-newton step of time offset");
+    let number_of_steps = estimated.nrows();
+    if number_of_steps < 2 {
+        return 0.0;
+    }
+    let mut numerator = 0.0;
+    let mut denominator = 0.0;
+    for step in 0..number_of_steps {
+        let before = step.saturating_sub(1);
+        let after = (step + 1).min(number_of_steps - 1);
+        #[allow(clippy::cast_precision_loss)]
+        let distance = (after - before) as f32;
+        for sensor in 0..estimated.ncols() {
+            let weight = weight(step, sensor);
+            if weight == 0.0 {
+                continue;
+            }
+            let derivative = (estimated[(after, sensor)] - estimated[(before, sensor)]) / distance;
+            let residual = estimated[(step, sensor)] - actual[(step, sensor)];
+            numerator += weight * derivative * residual;
+            denominator += weight * derivative * derivative;
+        }
+    }
+    if denominator > 0.0 {
+        numerator / denominator
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Array2;
+
+    use super::*;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn pulse(number_of_steps: usize, center: f32) -> Array2<f32> {
+        Array2::from_shape_fn((number_of_steps, 2), |(step, sensor)| {
+            let time = step as f32 - center;
+            (sensor as f32 + 1.0) * (-time * time / 50.0).exp()
+        })
+    }
+
+    #[test]
+    fn cross_correlation_finds_integer_lag() {
+        let estimated = pulse(100, 40.0);
+        let actual = pulse(100, 47.0);
+
+        let lag = cross_correlation_lag(&estimated.view(), &actual.view(), |_, _| 1.0, 20);
+
+        assert_eq!(lag, 7);
+    }
+
+    #[test]
+    fn gauss_newton_approaches_fractional_offset() {
+        let estimated = pulse(100, 40.0);
+        let actual = pulse(100, 40.4);
+
+        let step = gauss_newton_step(&estimated.view(), &actual.view(), |_, _| 1.0);
+
+        assert!((step - 0.4).abs() < 0.05, "step: {step}");
+    }
+}
@@ -1,12 +1,16 @@
+use realfft::{num_complex::Complex, RealFftPlanner};
 use tracing::debug;
 
 use super::derivation::Derivatives;
 use crate::core::{
     algorithm::refinement::Optimizer,
     config::algorithm::Algorithm,
-    model::functional::allpass::{
-        shapes::{Coefs, Gains, UnitDelays},
-        APParameters,
+    model::functional::{
+        allpass::{
+            shapes::{Coefs, Gains, UnitDelays},
+            APParameters,
+        },
+        control::ControlFunction,
     },
 };
 
@@ -27,10 +31,7 @@ impl APParameters {
         number_of_beats: usize,
     ) {
         debug!("Updating allpass filter parameters");
-        let batch_size = match config.batch_size {
-            0 => number_of_steps * number_of_beats,
-            _ => number_of_steps * config.batch_size,
-        };
+        let batch_size = batch_size(config, number_of_steps, number_of_beats);
 
         if !config.freeze_gains {
             match config.optimizer {
@@ -81,6 +82,93 @@ impl APParameters {
     }
 }
 
+impl ControlFunction {
+    /// Updates the control function values based on the provided derivatives.
+    ///
+    /// The values are updated by gradient descent with the learning rate of
+    /// the allpass parameters. If a bandwidth is configured, all frequencies
+    /// above it are removed afterwards.
+    #[allow(clippy::cast_precision_loss)]
+    #[inline]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn update(
+        &mut self,
+        derivatives: &Derivatives,
+        config: &Algorithm,
+        number_of_steps: usize,
+        number_of_beats: usize,
+        sample_rate_hz: f32,
+    ) {
+        debug!("Updating control function");
+        let batch_size = batch_size(config, number_of_steps, number_of_beats);
+        **self -= &(config.learning_rate / batch_size as f32 * &*derivatives.control_function);
+        if config.control_function_bandwidth_hz > 0.0 {
+            limit_bandwidth(self, config.control_function_bandwidth_hz, sample_rate_hz);
+        }
+    }
+}
+
+/// Returns the number of samples the derivatives of one update are
+/// accumulated over, which scales the learning rate.
+///
+/// Without a configured batch size, all beats of the epoch form one batch.
+#[must_use]
+pub const fn batch_size(
+    config: &Algorithm,
+    number_of_steps: usize,
+    number_of_beats: usize,
+) -> usize {
+    match config.batch_size {
+        0 => number_of_steps * number_of_beats,
+        _ => number_of_steps * config.batch_size,
+    }
+}
+
+/// Removes all frequencies above the given bandwidth from the control
+/// function by zeroing them in its spectrum.
+///
+/// # Panics
+///
+/// Panics if the control function is not contiguous.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn limit_bandwidth(
+    control_function: &mut ControlFunction,
+    bandwidth_hz: f32,
+    sample_rate_hz: f32,
+) {
+    debug!("Limiting bandwidth of control function");
+    let number_of_steps = control_function.len();
+    if number_of_steps < 2 {
+        return;
+    }
+    let mut planner = RealFftPlanner::<f32>::new();
+    let forward = planner.plan_fft_forward(number_of_steps);
+    let inverse = planner.plan_fft_inverse(number_of_steps);
+
+    let mut spectrum = forward.make_output_vec();
+    let mut input = control_function.to_vec();
+    forward
+        .process(&mut input, &mut spectrum)
+        .expect("Buffer sizes to match.");
+    let resolution_hz = sample_rate_hz / number_of_steps as f32;
+    for (bin, value) in spectrum.iter_mut().enumerate() {
+        if bin as f32 * resolution_hz > bandwidth_hz {
+            *value = Complex::new(0.0, 0.0);
+        }
+    }
+    // the inverse of a real signal needs real DC and nyquist bins
+    spectrum[0].im = 0.0;
+    if number_of_steps.is_multiple_of(2) {
+        let last_bin = spectrum.len() - 1;
+        spectrum[last_bin].im = 0.0;
+    }
+    inverse
+        .process(&mut spectrum, control_function.as_slice_mut().unwrap())
+        .expect("Buffer sizes to match.");
+    **control_function /= number_of_steps as f32;
+}
+
 /// Updates the gains based on the provided derivatives, learning rate,
 /// batch size, and gradient clamping threshold. The gains are updated
 /// by subtracting the scaled and clamped derivatives.
@@ -243,4 +331,28 @@ mod tests {
 
         assert_eq!(-&*derivatives, &*ap_coefs);
     }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn limit_bandwidth_removes_high_frequencies() {
+        let sample_rate_hz = 1000.0;
+        let number_of_steps = 1000;
+        let mut control_function = ControlFunction::empty(number_of_steps);
+        let slow =
+            |step: usize| (2.0 * std::f32::consts::PI * 5.0 * step as f32 / sample_rate_hz).sin();
+        control_function
+            .iter_mut()
+            .enumerate()
+            .for_each(|(step, value)| {
+                let fast =
+                    (2.0 * std::f32::consts::PI * 200.0 * step as f32 / sample_rate_hz).sin();
+                *value = slow(step) + fast;
+            });
+
+        limit_bandwidth(&mut control_function, 50.0, sample_rate_hz);
+
+        for (step, value) in control_function.iter().enumerate() {
+            assert!((value - slow(step)).abs() < 1e-3, "step {step}: {value}");
+        }
+    }
 }
@@ -0,0 +1,173 @@
+use ndarray::Array2;
+use tracing::{debug, trace};
+
+use crate::core::{
+    config::algorithm::{Algorithm, FitWindowReference},
+    data::{beats::calculate_global_field_power, Data},
+};
+
+/// Samples of every beat that contribute to the loss and the gradients.
+///
+/// Has dimensions (`number_of_beats`, `number_of_steps`). An empty array
+/// means that the whole beat is fitted.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct FitWindows(Array2<bool>);
+
+impl FitWindows {
+    /// Creates the fit windows of every beat from the algorithm config.
+    ///
+    /// The windows are given in ms relative to the start of the beat or to
+    /// its R-peak, which is taken as the maximum of the global field power
+    /// of the measurements of the beat.
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_config(config: &Algorithm, data: &Data) -> Self {
+        if config.fit_windows_ms.is_empty() {
+            return Self::default();
+        }
+        debug!("Creating fit windows");
+        let measurements = &data.simulation.measurements;
+        let sample_rate_hz = data.simulation.sample_rate_hz;
+        let mut windows =
+            Array2::from_elem((measurements.num_beats(), measurements.num_steps()), false);
+        for (beat, mut window) in windows.outer_iter_mut().enumerate() {
+            let reference_ms = match config.fit_window_reference {
+                FitWindowReference::BeatStart => 0.0,
+                FitWindowReference::RPeak => {
+                    let power = calculate_global_field_power(&measurements.at_beat(beat));
+                    let peak = power
+                        .iter()
+                        .enumerate()
+                        .fold((0, f32::NEG_INFINITY), |(peak, maximum), (step, value)| {
+                            if *value > maximum {
+                                (step, *value)
+                            } else {
+                                (peak, maximum)
+                            }
+                        })
+                        .0;
+                    peak as f32 * 1000.0 / sample_rate_hz
+                }
+            };
+            for (step, inside) in window.iter_mut().enumerate() {
+                let time_ms = (step as f32).mul_add(1000.0 / sample_rate_hz, -reference_ms);
+                *inside = config
+                    .fit_windows_ms
+                    .iter()
+                    .any(|[start_ms, end_ms]| *start_ms <= time_ms && time_ms <= *end_ms);
+            }
+        }
+        Self(windows)
+    }
+
+    /// Returns true if the step of the beat is fitted.
+    #[inline]
+    #[must_use]
+    pub fn contains(&self, beat: usize, step: usize) -> bool {
+        self.0.get((beat, step)).copied().unwrap_or(true)
+    }
+
+    /// Returns the number of fitted steps of the beat.
+    #[must_use]
+    pub fn number_of_fitted_steps(&self, beat: usize, number_of_steps: usize) -> usize {
+        if self.0.is_empty() {
+            return number_of_steps;
+        }
+        self.0.row(beat).iter().filter(|inside| **inside).count()
+    }
+
+    /// Returns the number of fitted steps per beat, averaged over all beats.
+    ///
+    /// Used to normalize the gradients, which only accumulate inside of the
+    /// fit windows.
+    #[must_use]
+    pub fn mean_number_of_fitted_steps(&self, number_of_steps: usize) -> usize {
+        if self.0.is_empty() {
+            return number_of_steps;
+        }
+        let number_of_beats = self.0.nrows().max(1);
+        (self.0.iter().filter(|inside| **inside).count() / number_of_beats).max(1)
+    }
+
+    /// Returns the fitted parts of the beat as (start, end) in seconds.
+    ///
+    /// Returns an empty vector if the whole beat is fitted.
+    #[allow(clippy::cast_precision_loss)]
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn regions_s(&self, beat: usize, sample_rate_hz: f32) -> Vec<(f32, f32)> {
+        trace!("Collecting fit window regions");
+        if self.0.is_empty() {
+            return Vec::new();
+        }
+        let mut regions = Vec::new();
+        let mut start = None;
+        let window = self.0.row(beat);
+        for (step, inside) in window.iter().enumerate() {
+            match (start, *inside) {
+                (None, true) => start = Some(step),
+                (Some(first), false) => {
+                    regions.push((
+                        first as f32 / sample_rate_hz,
+                        (step - 1) as f32 / sample_rate_hz,
+                    ));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        if let Some(first) = start {
+            regions.push((
+                first as f32 / sample_rate_hz,
+                (window.len() - 1) as f32 / sample_rate_hz,
+            ));
+        }
+        regions
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ndarray::Dim;
+
+    use super::*;
+
+    #[test]
+    fn windows_relative_to_r_peak() {
+        let number_of_steps = 100;
+        let mut data = Data::empty(2, 3, number_of_steps, Dim([1, 1, 1]), 2);
+        data.simulation.sample_rate_hz = 1000.0;
+        data.simulation.measurements[(0, 30, 0)] = 1.0;
+        data.simulation.measurements[(1, 60, 0)] = 1.0;
+        let config = Algorithm {
+            fit_windows_ms: vec![[-5.0, 10.0]],
+            fit_window_reference: FitWindowReference::RPeak,
+            ..Default::default()
+        };
+
+        let windows = FitWindows::from_config(&config, &data);
+
+        assert!(!windows.contains(0, 24));
+        assert!(windows.contains(0, 25));
+        assert!(windows.contains(0, 40));
+        assert!(!windows.contains(0, 41));
+        assert!(windows.contains(1, 55));
+        assert!(!windows.contains(1, 30));
+        assert_eq!(windows.regions_s(0, 1000.0), vec![(0.025, 0.04)]);
+        assert_eq!(windows.number_of_fitted_steps(0, number_of_steps), 16);
+        assert_eq!(windows.mean_number_of_fitted_steps(number_of_steps), 16);
+    }
+
+    #[test]
+    fn no_windows_fit_everything() {
+        let data = Data::empty(2, 3, 10, Dim([1, 1, 1]), 1);
+
+        let windows = FitWindows::from_config(&Algorithm::default(), &data);
+
+        assert!(windows.contains(0, 5));
+        assert!(windows.regions_s(0, 1.0).is_empty());
+        assert_eq!(windows.number_of_fitted_steps(0, 10), 10);
+        assert_eq!(windows.mean_number_of_fitted_steps(10), 10);
+    }
+}
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+
+use nalgebra::DMatrix;
+use tracing::{debug, trace, warn};
+
+use crate::core::model::functional::allpass::APParameters;
+
+/// Maximum number of conjugate gradient iterations.
+const MAX_ITERATIONS: usize = 1000;
+/// The conjugate gradient iteration stops once the residual norm dropped
+/// below this fraction of the norm of the right hand side.
+const RELATIVE_TOLERANCE: f32 = 1e-5;
+
+/// Solver for the spatio-temporally regularized inverse problem.
+///
+/// Minimizes
+/// `Σ_t ||y_t - H x_t||² + λ_s ||L x_t||² + λ_t Σ_t ||x_t - x_{t-1}||²`
+/// jointly over all time steps of a beat, where `L` is the graph laplacian
+/// of the voxel connections and the temporal term is the Twomey
+/// regularization. Every state is coupled to both of its neighbouring time
+/// steps.
+///
+/// The states are not restricted to the row space of `H`, so the spatial
+/// term also regularizes the states `H` can not observe. The normal
+/// equations of the full block system are solved matrix free with
+/// conjugate gradients.
+#[derive(Debug)]
+pub struct SpatioTemporalSolver {
+    measurement_matrix: DMatrix<f32>,
+    laplacian: SpatialLaplacian,
+    spatial_strength: f32,
+    temporal_strength: f32,
+}
+
+impl SpatioTemporalSolver {
+    /// Creates a new solver for the measurement matrix of a single beat.
+    ///
+    /// The spatial laplacian is built from the connections of the allpass
+    /// model, see [`calculate_spatial_laplacian`].
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn new(
+        measurement_matrix: DMatrix<f32>,
+        ap_params: &APParameters,
+        spatial_strength: f32,
+        temporal_strength: f32,
+    ) -> Self {
+        debug!("Creating spatio-temporal solver");
+        Self {
+            measurement_matrix,
+            laplacian: calculate_spatial_laplacian(ap_params),
+            spatial_strength,
+            temporal_strength,
+        }
+    }
+
+    /// Estimates the system states of all time steps of a beat.
+    ///
+    /// The measurements have dimensions (`number_of_sensors`,
+    /// `number_of_steps`), the returned states (`number_of_states`,
+    /// `number_of_steps`).
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn solve(&self, measurements: &DMatrix<f32>) -> DMatrix<f32> {
+        debug!("Solving spatio-temporal regularized system");
+        let right_hand_side = self.measurement_matrix.tr_mul(measurements);
+        let tolerance = RELATIVE_TOLERANCE * right_hand_side.norm();
+        let mut states =
+            DMatrix::<f32>::zeros(self.measurement_matrix.ncols(), measurements.ncols());
+        let mut residual = right_hand_side;
+        let mut direction = residual.clone();
+        let mut residual_norm_squared = residual.norm_squared();
+        for iteration in 0..MAX_ITERATIONS {
+            if residual_norm_squared.sqrt() <= tolerance {
+                trace!("Conjugate gradients converged after {iteration} iterations");
+                return states;
+            }
+            let applied_direction = self.apply(&direction);
+            let step_size = residual_norm_squared / direction.dot(&applied_direction);
+            states += &direction * step_size;
+            residual -= applied_direction * step_size;
+            let new_residual_norm_squared = residual.norm_squared();
+            direction = &residual + direction * (new_residual_norm_squared / residual_norm_squared);
+            residual_norm_squared = new_residual_norm_squared;
+        }
+        warn!("Conjugate gradients did not converge after {MAX_ITERATIONS} iterations");
+        states
+    }
+
+    /// Applies the system matrix of the normal equations,
+    /// `(HᵀH + λ_s LᵀL) X + λ_t X D`, where `D` is the laplacian of the time
+    /// steps.
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn apply(&self, states: &DMatrix<f32>) -> DMatrix<f32> {
+        trace!("Applying regularized normal equations");
+        let mut result = self
+            .measurement_matrix
+            .tr_mul(&(&self.measurement_matrix * states));
+        if self.spatial_strength > 0.0 {
+            result += self.laplacian.apply(&self.laplacian.apply(states)) * self.spatial_strength;
+        }
+        if self.temporal_strength > 0.0 {
+            for step in 1..states.ncols() {
+                let difference =
+                    (states.column(step) - states.column(step - 1)) * self.temporal_strength;
+                let mut column = result.column_mut(step);
+                column += &difference;
+                let mut column = result.column_mut(step - 1);
+                column -= &difference;
+            }
+        }
+        result
+    }
+}
+
+/// Sparse graph laplacian `L = D - A`, stored as a list of edges.
+#[derive(Debug, Default)]
+pub struct SpatialLaplacian {
+    num_states: usize,
+    edges: Vec<(usize, usize)>,
+}
+
+impl SpatialLaplacian {
+    /// Calculates `L * matrix` without building the dense laplacian.
+    ///
+    /// # Panics
+    ///
+    /// - the number of rows of `matrix` does not match the number of states
+    #[must_use]
+    pub fn apply(&self, matrix: &DMatrix<f32>) -> DMatrix<f32> {
+        assert_eq!(matrix.nrows(), self.num_states);
+        let mut result = DMatrix::<f32>::zeros(matrix.nrows(), matrix.ncols());
+        for &(first, second) in &self.edges {
+            for column in 0..matrix.ncols() {
+                let difference = matrix[(first, column)] - matrix[(second, column)];
+                result[(first, column)] += difference;
+                result[(second, column)] -= difference;
+            }
+        }
+        result
+    }
+}
+
+/// Calculates the graph laplacian `L = D - A` over all system states.
+///
+/// Two voxels are considered adjacent if the allpass model connected
+/// them in either direction, i.e. if any of the gains between them is
+/// non-zero. The adjacency is applied per state component, so only
+/// states with the same direction are coupled.
+#[must_use]
+#[tracing::instrument(level = "debug", skip_all)]
+pub fn calculate_spatial_laplacian(ap_params: &APParameters) -> SpatialLaplacian {
+    debug!("Calculating spatial laplacian");
+    let num_states = ap_params.gains.shape()[0];
+    // connections might be stored in both directions, only count them once
+    let mut edges = HashSet::new();
+
+    for input_voxel_start in (0..num_states).step_by(3) {
+        for offset_index in (0..ap_params.gains.shape()[1]).step_by(3) {
+            let Some(output_voxel_start) =
+                ap_params.output_state_indices[(input_voxel_start, offset_index)]
+            else {
+                continue;
+            };
+            let connected = (0..3).any(|input_dimension| {
+                (0..3).any(|output_dimension| {
+                    ap_params.gains[(
+                        input_voxel_start + input_dimension,
+                        offset_index + output_dimension,
+                    )] != 0.0
+                })
+            });
+            if !connected || input_voxel_start == output_voxel_start {
+                continue;
+            }
+            for dimension in 0..3 {
+                let input_state = input_voxel_start + dimension;
+                let output_state = output_voxel_start + dimension;
+                edges.insert((input_state.min(output_state), input_state.max(output_state)));
+            }
+        }
+    }
+    let mut edges: Vec<_> = edges.into_iter().collect();
+    edges.sort_unstable();
+    SpatialLaplacian { num_states, edges }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use nalgebra::DMatrix;
+    use ndarray::Dim;
+
+    use super::*;
+    use crate::core::model::functional::allpass::offset_to_gain_index;
+
+    #[test]
+    fn laplacian_of_two_connected_voxels() {
+        let mut ap_params = APParameters::empty(6, Dim([2, 1, 1]));
+        let offset_index = offset_to_gain_index(1, 0, 0, 0).unwrap();
+        ap_params.output_state_indices[(0, offset_index)] = Some(3);
+        ap_params.gains[(0, offset_index)] = 1.0;
+
+        let laplacian = calculate_spatial_laplacian(&ap_params).apply(&DMatrix::identity(6, 6));
+
+        for dimension in 0..3 {
+            assert_relative_eq!(laplacian[(dimension, dimension)], 1.0);
+            assert_relative_eq!(laplacian[(dimension + 3, dimension + 3)], 1.0);
+            assert_relative_eq!(laplacian[(dimension, dimension + 3)], -1.0);
+            assert_relative_eq!(laplacian[(dimension + 3, dimension)], -1.0);
+        }
+        assert_relative_eq!(laplacian.row_sum().norm(), 0.0);
+    }
+
+    #[test]
+    fn laplacian_ignores_unconnected_neighbours() {
+        let mut ap_params = APParameters::empty(6, Dim([2, 1, 1]));
+        let offset_index = offset_to_gain_index(1, 0, 0, 0).unwrap();
+        ap_params.output_state_indices[(0, offset_index)] = Some(3);
+
+        let laplacian = calculate_spatial_laplacian(&ap_params).apply(&DMatrix::identity(6, 6));
+
+        assert_relative_eq!(laplacian.norm(), 0.0);
+    }
+
+    #[test]
+    fn temporal_regularization_couples_both_neighbours() {
+        let ap_params = APParameters::empty(3, Dim([1, 1, 1]));
+        let solver = SpatioTemporalSolver::new(DMatrix::identity(3, 3), &ap_params, 0.0, 1.0);
+        let measurements = DMatrix::from_fn(3, 3, |_, step| if step == 1 { 3.0 } else { 0.0 });
+
+        let states = solver.solve(&measurements);
+
+        for state in 0..3 {
+            assert_relative_eq!(states[(state, 0)], 0.75, epsilon = 1e-4);
+            assert_relative_eq!(states[(state, 1)], 1.5, epsilon = 1e-4);
+            assert_relative_eq!(states[(state, 2)], 0.75, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn spatial_regularization_reaches_unobservable_states() {
+        let mut ap_params = APParameters::empty(6, Dim([2, 1, 1]));
+        let offset_index = offset_to_gain_index(1, 0, 0, 0).unwrap();
+        ap_params.output_state_indices[(0, offset_index)] = Some(3);
+        ap_params.gains[(0, offset_index)] = 1.0;
+        // only the states of the first voxel are measured
+        let measurement_matrix = DMatrix::from_fn(3, 6, |i, j| if i == j { 1.0 } else { 0.0 });
+        let solver = SpatioTemporalSolver::new(measurement_matrix, &ap_params, 1.0, 0.0);
+
+        let states = solver.solve(&DMatrix::from_element(3, 1, 2.0));
+
+        for state in 0..6 {
+            assert_relative_eq!(states[state], 2.0, epsilon = 1e-4);
+        }
+    }
+
+    #[test]
+    fn unobservable_states_stay_zero_without_spatial_regularization() {
+        let ap_params = APParameters::empty(3, Dim([1, 1, 1]));
+        let measurement_matrix =
+            DMatrix::from_diagonal(&nalgebra::DVector::from_vec(vec![1.0, 1.0, 0.0]));
+        let solver = SpatioTemporalSolver::new(measurement_matrix, &ap_params, 0.0, 1.0);
+
+        let states = solver.solve(&DMatrix::from_element(3, 2, 2.0));
+
+        assert_relative_eq!(states[(0, 0)], 2.0, epsilon = 1e-4);
+        assert_relative_eq!(states[(0, 1)], 2.0, epsilon = 1e-4);
+        assert_relative_eq!(states[(2, 0)], 0.0);
+        assert_relative_eq!(states[(2, 1)], 0.0);
+    }
+}
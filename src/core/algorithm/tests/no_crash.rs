@@ -52,6 +52,69 @@ fn run_epoch_no_crash() {
     run_epoch(&mut results, &mut batch_index, &data, &config);
 }
 
+#[test]
+fn run_epoch_with_rts_smoothing_no_crash() {
+    let number_of_states = 30;
+    let number_of_sensors = 5;
+    let number_of_steps = 4;
+    let number_of_epochs = 1;
+    let number_of_snapshots = 0;
+    let mut config = AlgorithmConfig {
+        update_kalman_gain: true,
+        rts_smoothing: true,
+        ..Default::default()
+    };
+    config.model.common.apply_system_update = true;
+    let voxels_in_dims = Dim([10, 1, 1]);
+    let number_of_beats = 2;
+
+    let model = Model::empty(
+        number_of_states,
+        number_of_sensors,
+        number_of_steps,
+        voxels_in_dims,
+        number_of_beats,
+    );
+
+    let mut results = Results::new(
+        number_of_epochs,
+        number_of_steps,
+        number_of_sensors,
+        number_of_states,
+        number_of_beats,
+        number_of_snapshots,
+        config.batch_size,
+        config.optimizer,
+    );
+    results.model = Some(model);
+    let data = Data::empty(
+        number_of_sensors,
+        number_of_states,
+        number_of_steps,
+        voxels_in_dims,
+        number_of_beats,
+    );
+
+    let mut batch_index = 0;
+    run_epoch(&mut results, &mut batch_index, &data, &config);
+
+    let estimations = &results.estimations;
+    assert!(estimations
+        .system_states_smoothed
+        .as_ref()
+        .unwrap()
+        .iter()
+        .all(|value| value.is_finite()));
+    assert_eq!(
+        estimations
+            .state_covariances_smoothed
+            .as_ref()
+            .unwrap()
+            .len(),
+        number_of_steps
+    );
+}
+
 #[test]
 fn run_no_crash() {
     let number_of_states = 3000;
@@ -1,11 +1,12 @@
 pub mod algorithm;
 pub mod model;
+pub mod preprocessing;
 pub mod simulation;
 
 use serde::{Deserialize, Serialize};
 use tracing::info;
 
-use self::{algorithm::Algorithm, simulation::Simulation};
+use self::{algorithm::Algorithm, preprocessing::Preprocessing, simulation::Simulation};
 
 /// Struct to hold the configuration for a simulation run.
 ///
@@ -14,10 +15,13 @@ use self::{algorithm::Algorithm, simulation::Simulation};
 /// - `measurement`: Path to the measurement data file.
 /// - `simulation`: Simulation parameters.
 /// - `algorithm`: Algorithm parameters.
+/// - `preprocessing`: Preprocessing applied to the measurements.
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Config {
     pub simulation: Simulation,
     pub algorithm: Algorithm,
+    #[serde(default)]
+    pub preprocessing: Preprocessing,
 }
 
 impl Default for Config {
@@ -29,6 +33,7 @@ impl Default for Config {
         Self {
             simulation: Simulation::default(),
             algorithm: Algorithm::default(),
+            preprocessing: Preprocessing::default(),
         }
     }
 }
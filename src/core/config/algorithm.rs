@@ -20,6 +20,29 @@ pub enum APDerivative {
     Textbook,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum EstimationBackend {
+    #[default]
+    Kalman,
+    EnsembleKalman,
+    UnscentedKalman,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum FitWindowReference {
+    #[default]
+    BeatStart,
+    RPeak,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum CovarianceAdaptation {
+    #[default]
+    None,
+    Innovation,
+    ExpectationMaximization,
+}
+
 #[allow(clippy::struct_excessive_bools)]
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Algorithm {
@@ -40,6 +63,42 @@ pub struct Algorithm {
     #[serde(default)]
     pub mse_strength: f32,
     #[serde(default)]
+    // the robust and beat-wise data-fit terms below are model based (CPU) only.
+    pub huber_strength: f32,
+    #[serde(default = "default_huber_delta")]
+    // residuals larger than delta are penalized linearly by the huber loss.
+    pub huber_delta: f32,
+    #[serde(default)]
+    pub l1_strength: f32,
+    #[serde(default)]
+    pub cauchy_strength: f32,
+    #[serde(default = "default_cauchy_scale")]
+    // residuals around this scale start to be down-weighted by the cauchy loss.
+    pub cauchy_scale: f32,
+    #[serde(default)]
+    // one minus the normalized cross-correlation of every sensor over a beat.
+    pub ncc_strength: f32,
+    #[serde(default)]
+    // squared difference of the magnitude spectra of every sensor over a beat.
+    pub spectral_strength: f32,
+    #[serde(default)]
+    // [start, end] in ms of the parts of each beat that contribute to the loss,
+    // empty means the whole beat is fitted.
+    pub fit_windows_ms: Vec<[f32; 2]>,
+    #[serde(default)]
+    // time the fit windows are relative to, e.g. the r-peak for the qrs complex.
+    pub fit_window_reference: FitWindowReference,
+    #[serde(default)]
+    // estimates a time shift between the model and the measurements of every
+    // beat, initialized by cross-correlation (model based, CPU only).
+    pub estimate_time_offsets: bool,
+    #[serde(default)]
+    // damping of the gauss-newton steps on the time offsets, 1.0 is undamped.
+    pub time_offset_step_size: f32,
+    #[serde(default)]
+    // largest offset in ms searched by the cross-correlation and allowed afterwards.
+    pub maximum_time_offset_ms: f32,
+    #[serde(default)]
     // used for SGD optimization of ap coefficients to ensure convergence.
     pub slow_down_stregth: f32,
     #[serde(default)]
@@ -51,12 +110,148 @@ pub struct Algorithm {
     #[serde(default)]
     pub smoothness_regularization_strength: f32,
     #[serde(default)]
+    // optimizes the control function values jointly with the ap parameters.
+    pub learn_control_function: bool,
+    #[serde(default)]
+    // penalizes the squared differences of neighbouring control function values.
+    pub control_function_smoothness_strength: f32,
+    #[serde(default)]
+    // frequencies above this are removed from the learned control function,
+    // zero keeps all frequencies (model based, CPU only).
+    pub control_function_bandwidth_hz: f32,
+    #[serde(default)]
     pub freeze_gains: bool,
     pub freeze_delays: bool,
     pub update_kalman_gain: bool,
     #[serde(default)]
     pub ap_derivative: APDerivative,
+    #[serde(default)]
+    // couples neighbouring time steps in the pseudo inverse (Twomey).
+    pub pseudo_inverse_temporal_regularization_strength: f32,
+    #[serde(default)]
+    // penalizes the laplacian over the connected voxels in the pseudo inverse.
+    pub pseudo_inverse_spatial_regularization_strength: f32,
+    #[serde(default)]
+    // runs a backward RTS pass after every beat (model based cpu only).
+    pub rts_smoothing: bool,
+    #[serde(default)]
+    // solves the riccati equation for the kalman gain once per beat instead of step by step.
+    pub steady_state_kalman_gain: bool,
+    #[serde(default = "default_steady_state_kalman_gain_tolerance")]
+    // maximum change of the ap parameters or covariances before cached steady-state kalman gains are recomputed.
+    pub steady_state_kalman_gain_tolerance: f32,
+    #[serde(default)]
+    pub estimation_backend: EstimationBackend,
+    #[serde(default = "default_ensemble_size")]
+    // number of members used by the ensemble kalman filter.
+    pub ensemble_size: usize,
+    #[serde(default = "default_ensemble_seed")]
+    // seed of the random number generator of the ensemble kalman filter.
+    pub ensemble_seed: u64,
+    #[serde(default)]
+    pub covariance_adaptation: CovarianceAdaptation,
+    #[serde(default)]
+    // weight of the old covariances when adapting them.
+    pub covariance_adaptation_forgetting_factor: f32,
 }
+impl Algorithm {
+    /// Checks that the configuration can be run.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error describing the first invalid parameter.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn validate(&self) -> Result<(), String> {
+        debug!("Validating algorithm config");
+        if self.estimation_backend == EstimationBackend::EnsembleKalman && self.ensemble_size < 2 {
+            return Err(format!(
+                "The ensemble kalman filter needs at least two members, got {}.",
+                self.ensemble_size
+            ));
+        }
+        if self.huber_delta <= 0.0 {
+            return Err(format!(
+                "The huber delta has to be positive, got {}.",
+                self.huber_delta
+            ));
+        }
+        if self.cauchy_scale <= 0.0 {
+            return Err(format!(
+                "The cauchy scale has to be positive, got {}.",
+                self.cauchy_scale
+            ));
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU
+            && self.estimation_backend != EstimationBackend::Kalman
+        {
+            return Err(format!(
+                "The estimation backend {:?} is not supported on the gpu.",
+                self.estimation_backend
+            ));
+        }
+        if self.rts_smoothing
+            && self.model.common.apply_system_update
+            && self.estimation_backend == EstimationBackend::Kalman
+            && (!self.update_kalman_gain || self.steady_state_kalman_gain)
+        {
+            return Err(
+                "RTS smoothing needs the state covariances of every step, the kalman gain has to be updated step by step."
+                    .to_string(),
+            );
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU && self.rts_smoothing {
+            return Err("RTS smoothing is not supported on the gpu.".to_string());
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU
+            && self.learn_control_function
+            && [
+                self.huber_strength,
+                self.l1_strength,
+                self.cauchy_strength,
+                self.ncc_strength,
+                self.spectral_strength,
+            ]
+            .iter()
+            .any(|strength| *strength != 0.0)
+        {
+            return Err(
+                "The gpu derives the control function from the mse loss only, it can not be learned with robust or beat-wise losses."
+                    .to_string(),
+            );
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU && self.optimizer != Optimizer::Sgd {
+            return Err(format!(
+                "The optimizer {} is not supported on the gpu.",
+                self.optimizer
+            ));
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU && self.batch_size > 1 {
+            return Err(
+                "The gpu updates the parameters after every beat, the batch size has to be 0 or 1."
+                    .to_string(),
+            );
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU && self.estimate_time_offsets {
+            return Err("Time offsets can not be estimated on the gpu.".to_string());
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU && !self.fit_windows_ms.is_empty() {
+            return Err("Fit windows are not supported on the gpu.".to_string());
+        }
+        if self.algorithm_type == AlgorithmType::ModelBasedGPU
+            && self
+                .model
+                .common
+                .stimuli
+                .iter()
+                .flat_map(|stimulus| &stimulus.onsets_s)
+                .any(|onset_s| *onset_s != 0.0)
+        {
+            return Err("Stimulus onsets are not supported on the gpu.".to_string());
+        }
+        Ok(())
+    }
+}
+
 impl Default for Algorithm {
     /// Returns a default `Algorithm` configuration with reasonable defaults for most use cases.
     #[must_use]
@@ -73,16 +268,61 @@ impl Default for Algorithm {
             learning_rate_reduction_factor: 0.0,
             learning_rate_reduction_interval: 0,
             mse_strength: 1.0,
+            huber_strength: 0.0,
+            huber_delta: default_huber_delta(),
+            l1_strength: 0.0,
+            cauchy_strength: 0.0,
+            cauchy_scale: default_cauchy_scale(),
+            ncc_strength: 0.0,
+            spectral_strength: 0.0,
+            fit_windows_ms: Vec::new(),
+            fit_window_reference: FitWindowReference::default(),
+            estimate_time_offsets: false,
+            time_offset_step_size: 0.5,
+            maximum_time_offset_ms: 50.0,
             slow_down_stregth: 0.,
             maximum_regularization_strength: 1.0,
             maximum_regularization_threshold: 1.01,
             difference_regularization_strength: 0.0,
             smoothness_regularization_strength: 0.0,
+            learn_control_function: false,
+            control_function_smoothness_strength: 0.0,
+            control_function_bandwidth_hz: 0.0,
             model: Model::default(),
             freeze_gains: false,
             freeze_delays: true,
             update_kalman_gain: false,
             ap_derivative: APDerivative::default(),
+            pseudo_inverse_temporal_regularization_strength: 0.0,
+            pseudo_inverse_spatial_regularization_strength: 0.0,
+            rts_smoothing: false,
+            steady_state_kalman_gain: false,
+            steady_state_kalman_gain_tolerance: default_steady_state_kalman_gain_tolerance(),
+            estimation_backend: EstimationBackend::default(),
+            ensemble_size: default_ensemble_size(),
+            ensemble_seed: default_ensemble_seed(),
+            covariance_adaptation: CovarianceAdaptation::default(),
+            covariance_adaptation_forgetting_factor: 0.95,
         }
     }
 }
+
+const fn default_steady_state_kalman_gain_tolerance() -> f32 {
+    1e-3
+}
+
+const fn default_ensemble_size() -> usize {
+    32
+}
+
+const fn default_ensemble_seed() -> u64 {
+    42
+}
+
+const fn default_huber_delta() -> f32 {
+    1.0
+}
+
+const fn default_cauchy_scale() -> f32 {
+    1.0
+}
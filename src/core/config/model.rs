@@ -1,12 +1,13 @@
 use std::{
-    collections::HashMap,
+    collections::{BTreeSet, HashMap},
     path::{Path, PathBuf},
 };
 
 use serde::{Deserialize, Serialize};
-use tracing::debug;
+use strum::IntoEnumIterator;
+use tracing::{debug, warn};
 
-use crate::core::model::spatial::voxels::VoxelType;
+use crate::core::model::spatial::voxels::{self, VoxelType};
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Model {
@@ -34,6 +35,40 @@ impl Default for Model {
     }
 }
 
+impl Model {
+    /// Checks the model config for values that can not yield a valid model.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the segmentation label table of an MRI model is
+    /// invalid, if the transverse velocity ratio of the fibers is not in
+    /// (0, 1] or if an MRI model uses rule based fibers.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn validate(&self) -> Result<(), String> {
+        debug!("Validating model config");
+        if let Some(mri) = &self.mri {
+            mri.labels.validate()?;
+        }
+        if let Some(fibers) = &self.common.fibers {
+            if !(fibers.transverse_velocity_ratio > 0.0 && fibers.transverse_velocity_ratio <= 1.0)
+            {
+                return Err(format!(
+                    "The transverse velocity ratio of the fibers has to be in (0, 1], got {}.",
+                    fibers.transverse_velocity_ratio
+                ));
+            }
+            if self.mri.is_some() && matches!(fibers.orientation, FiberOrientation::Rule { .. }) {
+                return Err(
+                    "Rule based fibers follow the layers of handcrafted models, \
+                            MRI models need a fiber file."
+                        .to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Handcrafted {
     pub heart_size_mm: [f32; 3],
@@ -96,6 +131,9 @@ impl Default for Handcrafted {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
 pub struct Mri {
     pub path: PathBuf,
+    #[serde(default)]
+    // maps the label ids of the segmentation to voxel types
+    pub labels: SegmentationLabels,
 }
 
 impl Default for Mri {
@@ -105,15 +143,79 @@ impl Default for Mri {
 
         Self {
             path: Path::new("assets/segmentation.nii").to_path_buf(),
+            labels: SegmentationLabels::default(),
         }
     }
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub struct SegmentationLabel {
+    pub label: usize,
+    pub voxel_type: VoxelType,
+}
+
+/// Table mapping the label ids of a segmentation to voxel types.
+///
+/// Labels that are not in the table are treated as `VoxelType::None`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(transparent)]
+pub struct SegmentationLabels(pub Vec<SegmentationLabel>);
+
+impl Default for SegmentationLabels {
+    /// Returns the label table of the segmentation in `assets/`.
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default segmentation labels");
+        Self(
+            [
+                (1, VoxelType::Atrium),
+                (2, VoxelType::Vessel),
+                (3, VoxelType::Torso),
+                (5, VoxelType::Chamber),
+                (6, VoxelType::Sinoatrial),
+            ]
+            .into_iter()
+            .map(|(label, voxel_type)| SegmentationLabel { label, voxel_type })
+            .collect(),
+        )
+    }
+}
+
+impl SegmentationLabels {
+    /// Returns the voxel type of the given label, `VoxelType::None` if the
+    /// label is not in the table.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn voxel_type(&self, label: usize) -> VoxelType {
+        self.0
+            .iter()
+            .find(|entry| entry.label == label)
+            .map_or(VoxelType::None, |entry| entry.voxel_type)
+    }
+
+    /// Checks that no label is mapped more than once.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error naming the first label that is mapped twice.
+    #[tracing::instrument(level = "debug")]
+    pub fn validate(&self) -> Result<(), String> {
+        let mut mapped = BTreeSet::new();
+        for entry in &self.0 {
+            if !mapped.insert(entry.label) {
+                return Err(format!("Label {} is mapped more than once.", entry.label));
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
 pub enum ControlFunction {
     Ohara,
     Triangle,
     Ramp,
+    Custom,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
@@ -132,6 +234,18 @@ pub enum SensorArrayMotion {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
 pub struct Common {
     pub control_function: ControlFunction,
+    #[serde(default)]
+    // waveform (.npy or .csv) of the custom control function
+    pub control_function_path: Option<PathBuf>,
+    #[serde(default)]
+    // sample rate of the custom control function waveform, zero means it
+    // already has the sample rate of the simulation
+    pub control_function_sample_rate_hz: f32,
+    #[serde(default)]
+    // if set, the control function is treated as a single beat that is cut or
+    // zero-padded to this length and repeated, otherwise the triangle repeats
+    // every second and the waveforms repeat after their own length
+    pub control_function_cycle_length_s: Option<f32>,
     pub pathological: bool,
     pub sensor_array_geometry: SensorArrayGeometry,
     pub sensor_array_motion: SensorArrayMotion,
@@ -151,6 +265,31 @@ pub struct Common {
     // otherwise the elements along the main diagonal will be drawn from a
     // normal distribution
     pub measurement_covariance_std: f32,
+    #[serde(default)]
+    // if larger than zero, sensors with the same orientation get correlated
+    // noise that decays exponentially with their distance
+    pub measurement_covariance_correlation_length_mm: f32,
+    #[serde(default)]
+    // full measurement noise covariance matrix (.npy), overrides mean and std
+    pub measurement_covariance_path: Option<PathBuf>,
+    #[serde(default)]
+    // empty-room recording (.npy, samples x sensors) the measurement noise
+    // covariance is estimated from, overrides mean and std
+    pub empty_room_recording_path: Option<PathBuf>,
+    #[serde(default)]
+    // weights of the sensors in the loss, empty means all sensors are
+    // weighted equally
+    pub sensor_weights: Vec<f32>,
+    #[serde(default)]
+    // one weight per sensor (.npy), overrides sensor_weights
+    pub sensor_weights_path: Option<PathBuf>,
+    #[serde(default)]
+    // bad channels, ignored by the loss, the kalman update and the pseudo
+    // inverse
+    pub masked_sensors: Vec<usize>,
+    #[serde(default)]
+    // one entry per sensor (.npy), non-zero entries mark bad channels
+    pub sensor_mask_path: Option<PathBuf>,
     pub process_covariance_mean: f32,
     // the covariance noise covariance matrix will be a diagonal matrix
     // if std is set to zero, every value will be set to mean
@@ -159,7 +298,544 @@ pub struct Common {
     pub process_covariance_std: f32,
     pub apply_system_update: bool,
     pub propagation_velocities_m_per_s: HashMap<VoxelType, f32>,
+    #[serde(default)]
+    // per-voxel propagation velocities (.npy or .nii, float32) on the grid of
+    // the model, positive values replace the velocity of the voxel type
+    pub propagation_velocity_map_path: Option<PathBuf>,
     pub current_factor_in_pathology: f32,
+    #[serde(default)]
+    // pathological regions placed if pathological is set, replace the
+    // handcrafted rectangle if not empty and are the only pathologies of mri
+    // anatomies
+    pub pathologies: Vec<Pathology>,
+    #[serde(default)]
+    // fiber orientation of the tissue, conduction is isotropic if not set
+    pub fibers: Option<Fibers>,
+    #[serde(default)]
+    // sites the control function is injected at, replace the sinoatrial node
+    // if not empty
+    pub stimuli: Vec<Stimulus>,
+    #[serde(default)]
+    // lines of block and non-conducting scar, no connections are made across
+    // them
+    pub barriers: Vec<Barrier>,
+    #[serde(default)]
+    // neighbours every voxel can be connected to
+    pub neighbourhood: Neighbourhood,
+    #[serde(default)]
+    // voxel types every voxel type can activate, empty means the anatomical
+    // rules of the original model
+    pub allowed_connections: HashMap<VoxelType, Vec<VoxelType>>,
+}
+
+impl Common {
+    /// Checks if a voxel of the output type may activate a voxel of the
+    /// input type, using `allowed_connections` if configured.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn is_connection_allowed(
+        &self,
+        output_voxel_type: &VoxelType,
+        input_voxel_type: &VoxelType,
+    ) -> bool {
+        if self.allowed_connections.is_empty() {
+            return voxels::is_connection_allowed(output_voxel_type, input_voxel_type);
+        }
+        output_voxel_type.is_connectable()
+            && self
+                .allowed_connections
+                .get(output_voxel_type)
+                .is_some_and(|input_voxel_types| input_voxel_types.contains(input_voxel_type))
+    }
+
+    /// Validates the neighbourhood and the allowed connections.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the neighbourhood radius does not reach the face neighbours
+    /// or reaches beyond the corner neighbours, or if a voxel type that can not be connected appears in the allowed
+    /// connections. Connectable voxel types that can not activate any other
+    /// voxel are reported as warnings.
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn validate_connections(&self) -> Result<(), String> {
+        debug!("Validating connection rules");
+        if let Neighbourhood::Radius { radius_mm } = self.neighbourhood {
+            if radius_mm.is_nan() || radius_mm < self.voxel_size_mm {
+                return Err(format!(
+                    "Neighbourhood radius of {radius_mm} mm does not reach the face \
+                    neighbours at {} mm.",
+                    self.voxel_size_mm
+                ));
+            }
+            if radius_mm > 3f32.sqrt() * self.voxel_size_mm {
+                return Err(format!(
+                    "Neighbourhood radius of {radius_mm} mm reaches beyond the \
+                    corner neighbours at {} mm, only direct neighbours can be \
+                    connected.",
+                    3f32.sqrt() * self.voxel_size_mm
+                ));
+            }
+        }
+        if self.allowed_connections.is_empty() {
+            return Ok(());
+        }
+        for (output_voxel_type, input_voxel_types) in &self.allowed_connections {
+            if let Some(voxel_type) = std::iter::once(output_voxel_type)
+                .chain(input_voxel_types)
+                .find(|voxel_type| !voxel_type.is_connectable())
+            {
+                return Err(format!(
+                    "Voxel type {voxel_type:?} can not be connected, but appears \
+                    in the allowed connections of {output_voxel_type:?}."
+                ));
+            }
+        }
+        for voxel_type in VoxelType::iter().filter(|voxel_type| voxel_type.is_connectable()) {
+            if self
+                .allowed_connections
+                .get(&voxel_type)
+                .is_none_or(Vec::is_empty)
+            {
+                warn!("Voxel type {voxel_type:?} can not activate any other voxel.");
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the propagation velocity of a voxel.
+    ///
+    /// The velocity of the voxel from the velocity map takes precedence over
+    /// the one of the voxel type. Inside of one of `pathologies` the velocity
+    /// is interpolated between this velocity and the one of the pathology by
+    /// the severity of the voxel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no velocity is configured for the voxel type.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn propagation_velocity_m_per_s(
+        &self,
+        voxel_type: VoxelType,
+        voxel_velocity_m_per_s: Option<f32>,
+        region: Option<usize>,
+        severity: f32,
+    ) -> f32 {
+        let velocity_m_per_s = voxel_velocity_m_per_s.unwrap_or_else(|| {
+            *self
+                .propagation_velocities_m_per_s
+                .get(&voxel_type)
+                .unwrap()
+        });
+        region
+            .and_then(|region| self.pathologies.get(region))
+            .map_or(velocity_m_per_s, |pathology| {
+                severity.mul_add(
+                    pathology.propagation_velocity_m_per_s - velocity_m_per_s,
+                    velocity_m_per_s,
+                )
+            })
+    }
+
+    /// Returns the factor the current is scaled with when entering a voxel
+    /// of the given pathology region, interpolated from one for healthy
+    /// tissue by the severity of the voxel.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self))]
+    pub fn current_factor(&self, region: Option<usize>, severity: f32) -> f32 {
+        region.map_or(1.0, |region| {
+            let factor = self
+                .pathologies
+                .get(region)
+                .map_or(self.current_factor_in_pathology, |pathology| {
+                    pathology.current_factor
+                });
+            severity.mul_add(factor - 1.0, 1.0)
+        })
+    }
+}
+
+/// Site at which the control function starts an activation, e.g. a pacing
+/// electrode or an ectopic focus.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Stimulus {
+    // position in mm relative to the heart corner, the closest heart voxel
+    // is stimulated
+    pub position_mm: [f32; 3],
+    // onset of the stimulus in every beat, the last onset is used for all
+    // following beats
+    pub onsets_s: Vec<f32>,
+}
+
+impl Default for Stimulus {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default stimulus");
+        Self {
+            position_mm: [10.0, 10.0, 0.0],
+            onsets_s: vec![0.0],
+        }
+    }
+}
+
+/// Geometry conduction can not pass, e.g. a line of block or a scar.
+///
+/// Positions are given like the ones of a [`PathologyShape`].
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum Barrier {
+    /// Disc in the plane through `point_mm` with the given normal. A radius
+    /// of zero blocks the whole plane.
+    Plane {
+        point_mm: [f32; 3],
+        normal: [f32; 3],
+        radius_mm: f32,
+    },
+    /// Line of block through the given points in the x-y plane, extruded
+    /// along z.
+    Polyline { points_mm: Vec<[f32; 2]> },
+    /// Voxel mask (.npy or .nii, float32) on the grid of the model, non-zero
+    /// voxels are non-conducting scar.
+    Mask { path: PathBuf },
+}
+
+impl Default for Barrier {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default barrier");
+        Self::Polyline {
+            points_mm: vec![[10.0, 0.0], [10.0, 15.0]],
+        }
+    }
+}
+
+impl Barrier {
+    /// Returns true if the straight connection between the given positions
+    /// relative to the heart corner crosses the barrier. Masks are handled on
+    /// the voxel grid and never block here.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn blocks(&self, from_mm: [f32; 3], to_mm: [f32; 3]) -> bool {
+        match self {
+            Self::Plane {
+                point_mm,
+                normal,
+                radius_mm,
+            } => {
+                let side = |position_mm: &[f32; 3]| -> f32 {
+                    (0..3)
+                        .map(|axis| (position_mm[axis] - point_mm[axis]) * normal[axis])
+                        .sum()
+                };
+                let (side_from, side_to) = (side(&from_mm), side(&to_mm));
+                if side_from * side_to >= 0.0 {
+                    return false;
+                }
+                if *radius_mm <= 0.0 {
+                    return true;
+                }
+                let fraction = side_from / (side_from - side_to);
+                let squared_distance: f32 = (0..3)
+                    .map(|axis| {
+                        (fraction.mul_add(to_mm[axis] - from_mm[axis], from_mm[axis])
+                            - point_mm[axis])
+                            .powi(2)
+                    })
+                    .sum();
+                squared_distance <= radius_mm.powi(2)
+            }
+            Self::Polyline { points_mm } => points_mm.windows(2).any(|line| {
+                segments_intersect(
+                    [from_mm[0], from_mm[1]],
+                    [to_mm[0], to_mm[1]],
+                    line[0],
+                    line[1],
+                )
+            }),
+            Self::Mask { .. } => false,
+        }
+    }
+}
+
+/// Returns true if the segments a-b and c-d in the plane intersect or touch.
+#[tracing::instrument(level = "trace")]
+fn segments_intersect(a: [f32; 2], b: [f32; 2], c: [f32; 2], d: [f32; 2]) -> bool {
+    let orientation = |p: [f32; 2], q: [f32; 2], r: [f32; 2]| -> f32 {
+        (q[0] - p[0]).mul_add(r[1] - p[1], -((q[1] - p[1]) * (r[0] - p[0])))
+    };
+    let on_segment = |p: [f32; 2], q: [f32; 2], r: [f32; 2]| -> bool {
+        r[0] >= p[0].min(q[0])
+            && r[0] <= p[0].max(q[0])
+            && r[1] >= p[1].min(q[1])
+            && r[1] <= p[1].max(q[1])
+    };
+    let (o1, o2) = (orientation(a, b, c), orientation(a, b, d));
+    let (o3, o4) = (orientation(c, d, a), orientation(c, d, b));
+    if o1 * o2 < 0.0 && o3 * o4 < 0.0 {
+        return true;
+    }
+    (o1 == 0.0 && on_segment(a, b, c))
+        || (o2 == 0.0 && on_segment(a, b, d))
+        || (o3 == 0.0 && on_segment(c, d, a))
+        || (o4 == 0.0 && on_segment(c, d, b))
+}
+
+/// Neighbours a voxel can be connected to.
+///
+/// The allpass model stores gains and delays for the 26 direct neighbours,
+/// so a radius only selects among those.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub enum Neighbourhood {
+    /// Neighbours sharing a face.
+    Six,
+    /// Neighbours sharing a face or an edge.
+    Eighteen,
+    /// Neighbours sharing a face, an edge or a corner.
+    TwentySix,
+    /// Direct neighbours with their center within the radius.
+    ///
+    /// Only prunes the 26 direct neighbours, so the radius has to lie
+    /// between the voxel size and `sqrt(3)` times the voxel size.
+    Radius { radius_mm: f32 },
+}
+
+impl Default for Neighbourhood {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default neighbourhood");
+        Self::TwentySix
+    }
+}
+
+impl Neighbourhood {
+    /// Returns true if the voxel at the given offset in voxels is a
+    /// neighbour.
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "trace")]
+    pub fn contains(&self, offset: [i32; 3], voxel_size_mm: f32) -> bool {
+        if offset.iter().any(|o| o.abs() > 1) {
+            return false;
+        }
+        let shared_axes = offset.iter().filter(|o| **o != 0).count();
+        match self {
+            Self::Six => shared_axes == 1,
+            Self::Eighteen => (1..=2).contains(&shared_axes),
+            Self::TwentySix => shared_axes > 0,
+            Self::Radius { radius_mm } => {
+                shared_axes > 0 && (shared_axes as f32).sqrt() * voxel_size_mm <= *radius_mm
+            }
+        }
+    }
+}
+
+/// Fiber orientation of the heart tissue, making the conduction faster along
+/// the fibers than across them.
+///
+/// The velocities configured per voxel type and pathology are the
+/// longitudinal velocities along the fibers.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Fibers {
+    pub orientation: FiberOrientation,
+    // velocity across the fibers as a fraction of the longitudinal velocity
+    pub transverse_velocity_ratio: f32,
+}
+
+impl Default for Fibers {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default fibers");
+        Self {
+            orientation: FiberOrientation::default(),
+            transverse_velocity_ratio: 0.4,
+        }
+    }
+}
+
+/// Source of the fiber direction of every voxel.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum FiberOrientation {
+    /// Fibers in the x-y plane whose helix angle to the x-axis rotates
+    /// linearly along the z-axis, from the endocardial angle in the lowest
+    /// layer to the epicardial angle in the highest one. A single layer uses
+    /// the endocardial angle.
+    Rule {
+        endocardial_angle_deg: f32,
+        epicardial_angle_deg: f32,
+    },
+    /// Fiber directions (.npy, float32, x × y × z × 3) on the grid of the
+    /// model. Zero vectors mark isotropic voxels.
+    File { path: PathBuf },
+}
+
+impl Default for FiberOrientation {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default fiber orientation");
+        Self::Rule {
+            endocardial_angle_deg: 60.0,
+            epicardial_angle_deg: -60.0,
+        }
+    }
+}
+
+/// Pathological region of the heart with its own tissue properties.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Pathology {
+    pub shape: PathologyShape,
+    pub current_factor: f32,
+    pub propagation_velocity_m_per_s: f32,
+    #[serde(default)]
+    // width of the border zone around the region in which the severity falls
+    // off from one in the dense core to zero in healthy tissue
+    pub border_zone_mm: f32,
+    #[serde(default)]
+    pub severity_falloff: SeverityFalloff,
+}
+
+/// How the severity decreases with the distance from the core of a pathology.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SeverityFalloff {
+    #[default]
+    Linear,
+    /// Gaussian with a standard deviation of a third of the border zone.
+    Gaussian,
+}
+
+impl SeverityFalloff {
+    /// Returns the severity at the given distance from the core.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn severity(self, distance_mm: f32, border_zone_mm: f32) -> f32 {
+        if distance_mm <= 0.0 {
+            return 1.0;
+        }
+        if distance_mm > border_zone_mm {
+            return 0.0;
+        }
+        match self {
+            Self::Linear => 1.0 - distance_mm / border_zone_mm,
+            Self::Gaussian => {
+                let sigma_mm = border_zone_mm / 3.0;
+                (-distance_mm.powi(2) / (2.0 * sigma_mm.powi(2))).exp()
+            }
+        }
+    }
+}
+
+impl Default for Pathology {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default pathology");
+        Self {
+            shape: PathologyShape::default(),
+            current_factor: 0.0,
+            propagation_velocity_m_per_s: 0.1,
+            border_zone_mm: 0.0,
+            severity_falloff: SeverityFalloff::default(),
+        }
+    }
+}
+
+/// Shape of a pathology region.
+///
+/// Positions are given in mm relative to the lower corner of the bounding box
+/// of the heart, i.e. the first voxel of the model.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub enum PathologyShape {
+    Box {
+        min_mm: [f32; 3],
+        max_mm: [f32; 3],
+    },
+    Sphere {
+        center_mm: [f32; 3],
+        radius_mm: f32,
+    },
+    Ellipsoid {
+        center_mm: [f32; 3],
+        radii_mm: [f32; 3],
+    },
+    /// Cylinder between the centers of its two caps.
+    Cylinder {
+        start_mm: [f32; 3],
+        end_mm: [f32; 3],
+        radius_mm: f32,
+    },
+    /// Voxel mask (.npy or .nii, float32) on the grid of the model, non-zero
+    /// voxels belong to the region.
+    Mask {
+        path: PathBuf,
+    },
+    /// Region grown from the seed through connected heart voxels until it
+    /// reaches the given volume.
+    Seed {
+        seed_mm: [f32; 3],
+        volume_ml: f32,
+    },
+}
+
+impl Default for PathologyShape {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default pathology shape");
+        Self::Sphere {
+            center_mm: [10.0, 10.0, 0.0],
+            radius_mm: 5.0,
+        }
+    }
+}
+
+impl PathologyShape {
+    /// Returns true if the given position relative to the heart corner lies
+    /// inside of the shape. Masks and seeds are handled on the voxel grid and
+    /// are never inside here.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn contains(&self, position_mm: [f32; 3]) -> bool {
+        let squared_distance = |a: &[f32; 3], b: &[f32; 3]| -> f32 {
+            a.iter().zip(b).map(|(a, b)| (a - b).powi(2)).sum()
+        };
+        match self {
+            Self::Box { min_mm, max_mm } => (0..3)
+                .all(|axis| min_mm[axis] <= position_mm[axis] && position_mm[axis] <= max_mm[axis]),
+            Self::Sphere {
+                center_mm,
+                radius_mm,
+            } => squared_distance(&position_mm, center_mm) <= radius_mm.powi(2),
+            Self::Ellipsoid {
+                center_mm,
+                radii_mm,
+            } => {
+                (0..3)
+                    .map(|axis| ((position_mm[axis] - center_mm[axis]) / radii_mm[axis]).powi(2))
+                    .sum::<f32>()
+                    <= 1.0
+            }
+            Self::Cylinder {
+                start_mm,
+                end_mm,
+                radius_mm,
+            } => {
+                let length_squared = squared_distance(start_mm, end_mm);
+                if length_squared == 0.0 {
+                    return false;
+                }
+                let along = (0..3)
+                    .map(|axis| {
+                        (position_mm[axis] - start_mm[axis]) * (end_mm[axis] - start_mm[axis])
+                    })
+                    .sum::<f32>()
+                    / length_squared;
+                if !(0.0..=1.0).contains(&along) {
+                    return false;
+                }
+                let closest: [f32; 3] = std::array::from_fn(|axis| {
+                    along.mul_add(end_mm[axis] - start_mm[axis], start_mm[axis])
+                });
+                squared_distance(&position_mm, &closest) <= radius_mm.powi(2)
+            }
+            Self::Mask { .. } | Self::Seed { .. } => false,
+        }
+    }
 }
 
 pub const DEFAULT_HEART_OFFSET_HANDCRAFTED: [f32; 3] = [25.0, -250.0, 150.0];
@@ -181,6 +857,9 @@ impl Default for Common {
 
         let mut config = Self {
             control_function: ControlFunction::Ohara,
+            control_function_path: None,
+            control_function_sample_rate_hz: 0.0,
+            control_function_cycle_length_s: None,
             pathological: false,
             sensor_array_geometry: SensorArrayGeometry::Cube,
             sensor_array_motion: SensorArrayMotion::Static,
@@ -196,11 +875,25 @@ impl Default for Common {
             heart_offset_mm: [25.0, -250.0, 150.0],
             measurement_covariance_mean: 1e-3,
             measurement_covariance_std: 0.0,
+            measurement_covariance_correlation_length_mm: 0.0,
+            measurement_covariance_path: None,
+            empty_room_recording_path: None,
+            sensor_weights: Vec::new(),
+            sensor_weights_path: None,
+            masked_sensors: Vec::new(),
+            sensor_mask_path: None,
             process_covariance_mean: 1e-5,
             process_covariance_std: 0.0,
             apply_system_update: false,
             propagation_velocities_m_per_s,
+            propagation_velocity_map_path: None,
             current_factor_in_pathology: 0.00,
+            pathologies: Vec::new(),
+            fibers: None,
+            stimuli: Vec::new(),
+            barriers: Vec::new(),
+            neighbourhood: Neighbourhood::default(),
+            allowed_connections: HashMap::new(),
         };
         match config.sensor_array_geometry {
             SensorArrayGeometry::Cube | SensorArrayGeometry::SparseCube => {
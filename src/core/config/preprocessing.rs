@@ -0,0 +1,137 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Detrend {
+    #[default]
+    None,
+    Constant,
+    Linear,
+}
+
+/// Preprocessing applied to the measurements before the algorithm runs.
+///
+/// Every step is disabled by default. The steps are applied in the order
+/// beat averaging, channel rejection, detrending, filtering, signal-space projection and
+/// resampling.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct Preprocessing {
+    #[serde(default)]
+    // zero disables the high-pass filter
+    pub highpass_cutoff_hz: f32,
+    #[serde(default)]
+    // zero disables the low-pass filter
+    pub lowpass_cutoff_hz: f32,
+    #[serde(default)]
+    pub notch_frequencies_hz: Vec<f32>,
+    #[serde(default)]
+    pub notch_quality_factor: f32,
+    #[serde(default)]
+    pub detrend: Detrend,
+    #[serde(default)]
+    // if set, the measurements are resampled to this rate
+    pub target_sample_rate_hz: Option<f32>,
+    #[serde(default)]
+    pub rejected_channels: Vec<usize>,
+    #[serde(default)]
+    // channels with a standard deviation above this multiple of the median
+    // standard deviation are rejected, zero disables the rejection
+    pub channel_rejection_threshold: f32,
+    #[serde(default)]
+    // number of noise components removed by signal-space projection
+    pub ssp_components: usize,
+    #[serde(default)]
+    // noise-only recording (.npy, samples x sensors) used to calculate the
+    // projection, required if ssp components are removed
+    pub ssp_reference_path: Option<PathBuf>,
+    #[serde(default)]
+    pub beat_averaging: BeatAveraging,
+}
+
+impl Default for Preprocessing {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default preprocessing config");
+        Self {
+            highpass_cutoff_hz: 0.0,
+            lowpass_cutoff_hz: 0.0,
+            notch_frequencies_hz: Vec::new(),
+            notch_quality_factor: 30.0,
+            detrend: Detrend::default(),
+            target_sample_rate_hz: None,
+            rejected_channels: Vec::new(),
+            channel_rejection_threshold: 0.0,
+            ssp_components: 0,
+            ssp_reference_path: None,
+            beat_averaging: BeatAveraging::default(),
+        }
+    }
+}
+
+impl Preprocessing {
+    /// Returns true if any preprocessing step is enabled.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn is_enabled(&self) -> bool {
+        self.highpass_cutoff_hz > 0.0
+            || self.lowpass_cutoff_hz > 0.0
+            || !self.notch_frequencies_hz.is_empty()
+            || self.detrend != Detrend::None
+            || self.target_sample_rate_hz.is_some()
+            || !self.rejected_channels.is_empty()
+            || self.channel_rejection_threshold > 0.0
+            || self.ssp_components > 0
+            || self.beat_averaging.is_enabled()
+    }
+}
+
+/// Averaging of the beats of continuous recordings into the measurements.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct BeatAveraging {
+    #[serde(default)]
+    // continuous recordings (.npy, samples x sensors), one per array
+    // position, an empty list disables the averaging
+    pub recording_paths: Vec<PathBuf>,
+    #[serde(default)]
+    // continuous system states (.npy, samples x states), one per recording,
+    // averaged over the same beats, an empty list keeps the simulated ones
+    pub ground_truth_paths: Vec<PathBuf>,
+    #[serde(default)]
+    // start of the window relative to the R-peak
+    pub window_before_ms: f32,
+    #[serde(default)]
+    // fraction of the largest peak a peak has to exceed
+    pub detection_threshold: f32,
+    #[serde(default)]
+    // minimum distance between two R-peaks
+    pub refractory_period_ms: f32,
+    #[serde(default)]
+    // beats with a lower correlation to the mean beat are rejected
+    pub minimum_correlation: f32,
+}
+
+impl Default for BeatAveraging {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default beat averaging config");
+        Self {
+            recording_paths: Vec::new(),
+            ground_truth_paths: Vec::new(),
+            window_before_ms: 100.0,
+            detection_threshold: 0.5,
+            refractory_period_ms: 250.0,
+            minimum_correlation: 0.8,
+        }
+    }
+}
+
+impl BeatAveraging {
+    /// Returns true if recordings to average are given.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn is_enabled(&self) -> bool {
+        !self.recording_paths.is_empty()
+    }
+}
@@ -8,6 +8,17 @@ pub struct Simulation {
     pub model: Model,
     pub sample_rate_hz: f32,
     pub duration_s: f32,
+    #[serde(default)]
+    // additional noise sources, all disabled by default
+    pub noise: Noise,
+    #[serde(default)]
+    // standard deviation of the beat onsets in ms, zero means all beats
+    // start with the control function.
+    pub onset_jitter_ms: f32,
+    #[serde(default = "default_seed")]
+    // seed of the random number generators used for the noise and the
+    // onset jitter
+    pub seed: u64,
 }
 impl Default for Simulation {
     /// Returns a default `Simulation` struct with sample rate 2000 Hz,
@@ -19,6 +30,115 @@ impl Default for Simulation {
             model: Model::default(),
             sample_rate_hz: 2000.0,
             duration_s: 1.0,
+            noise: Noise::default(),
+            onset_jitter_ms: 0.0,
+            seed: default_seed(),
         }
     }
 }
+
+/// Noise sources added to the simulated measurements on top of the white
+/// measurement noise. A source with an amplitude of zero is disabled.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Default)]
+pub struct Noise {
+    #[serde(default)]
+    pub pink: PinkNoise,
+    #[serde(default)]
+    pub powerline: PowerlineNoise,
+    #[serde(default)]
+    pub drift: DriftNoise,
+    #[serde(default)]
+    pub spikes: SpikeNoise,
+}
+
+/// Noise with a power spectral density proportional to `1 / f^exponent`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PinkNoise {
+    // standard deviation of the noise in pT
+    pub amplitude: f32,
+    // 1.0 is pink noise, 2.0 brown noise
+    pub exponent: f32,
+}
+
+impl Default for PinkNoise {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default pink noise");
+        Self {
+            amplitude: 0.0,
+            exponent: 1.0,
+        }
+    }
+}
+
+/// Sinusoidal power-line interference including its harmonics.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct PowerlineNoise {
+    // amplitude of the fundamental in pT
+    pub amplitude: f32,
+    pub frequency_hz: f32,
+    // number of harmonics in addition to the fundamental
+    pub harmonics: usize,
+    // amplitude ratio between consecutive harmonics
+    pub harmonic_decay: f32,
+}
+
+impl Default for PowerlineNoise {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default powerline noise");
+        Self {
+            amplitude: 0.0,
+            frequency_hz: 50.0,
+            harmonics: 3,
+            harmonic_decay: 0.5,
+        }
+    }
+}
+
+/// Slow baseline drift, modelled as an Ornstein-Uhlenbeck process.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct DriftNoise {
+    // stationary standard deviation of the drift in pT
+    pub amplitude: f32,
+    // the corner frequency of the drift is 1 / (2 pi time constant)
+    pub time_constant_s: f32,
+}
+
+impl Default for DriftNoise {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default drift noise");
+        Self {
+            amplitude: 0.0,
+            time_constant_s: 1.0,
+        }
+    }
+}
+
+/// Randomly occurring single sample spikes and baseline jumps.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone)]
+pub struct SpikeNoise {
+    // height of spikes and jumps in pT
+    pub amplitude: f32,
+    // expected number of events per second and sensor
+    pub rate_hz: f32,
+    // fraction of events that are jumps instead of spikes
+    pub jump_probability: f32,
+}
+
+impl Default for SpikeNoise {
+    #[tracing::instrument(level = "debug")]
+    fn default() -> Self {
+        debug!("Creating default spike noise");
+        Self {
+            amplitude: 0.0,
+            rate_hz: 1.0,
+            jump_probability: 0.2,
+        }
+    }
+}
+
+const fn default_seed() -> u64 {
+    42
+}
@@ -1,3 +1,5 @@
+pub mod beats;
+pub mod preprocessing;
 pub mod shapes;
 pub mod simulation;
 
@@ -7,12 +9,15 @@ use ndarray::Dim;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use self::simulation::Simulation;
+use self::{beats::BeatStatistics, preprocessing::Preprocessed, simulation::Simulation};
 use crate::core::{config::simulation::Simulation as SimulationConfig, data::shapes::Measurements};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[allow(clippy::unsafe_derive_deserialize)]
 pub struct Data {
     pub simulation: Simulation,
+    pub preprocessed: Option<Preprocessed>,
+    pub beat_statistics: Option<BeatStatistics>,
 }
 
 impl Data {
@@ -35,6 +40,8 @@ impl Data {
                 voxels_in_dims,
                 number_of_beats,
             ),
+            preprocessed: None,
+            beat_statistics: None,
         }
     }
 
@@ -49,9 +56,13 @@ impl Data {
     pub fn from_simulation_config(config: &SimulationConfig) -> Result<Self, Box<dyn Error>> {
         debug!("Creating data from simulation config");
         let mut simulation = Simulation::from_config(config)?;
-        simulation.run();
+        simulation.run(config);
         simulation.update_activation_time();
-        Ok(Self { simulation })
+        Ok(Self {
+            simulation,
+            preprocessed: None,
+            beat_statistics: None,
+        })
     }
 
     /// # Panics
@@ -61,6 +72,9 @@ impl Data {
     pub fn save_npy(&self, path: &std::path::Path) {
         trace!("Saving data to npy");
         self.simulation.save_npy(&path.join("simulation"));
+        if let Some(beat_statistics) = &self.beat_statistics {
+            beat_statistics.save_npy(&path.join("beats"));
+        }
     }
 
     #[allow(dead_code)]
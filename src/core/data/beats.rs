@@ -0,0 +1,447 @@
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::BufWriter,
+};
+
+use ndarray::{s, Array1, Array2, Array3, ArrayView2, Axis};
+use ndarray_npy::{read_npy, WriteNpyExt};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+use super::{shapes::Measurements, Data};
+use crate::core::config::preprocessing::BeatAveraging;
+
+/// Metadata of the beat averaging.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[allow(clippy::unsafe_derive_deserialize)]
+pub struct BeatStatistics {
+    /// Detected R-peaks (sample indices) per array position.
+    pub peaks: Vec<Vec<usize>>,
+    /// Number of beats that entered the average per array position.
+    pub number_of_beats: Vec<usize>,
+    /// Variance of the averaged beats, with the same layout as the
+    /// measurements.
+    pub variance: Measurements,
+}
+
+impl BeatStatistics {
+    /// Saves the number of averaged beats and their variance to .npy files.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the files can not be written.
+    #[tracing::instrument(level = "trace")]
+    pub fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving beat statistics to npy");
+        fs::create_dir_all(path).unwrap();
+        let number_of_beats: Array1<u64> = self
+            .number_of_beats
+            .iter()
+            .map(|beats| *beats as u64)
+            .collect();
+        let writer = BufWriter::new(File::create(path.join("number_of_beats.npy")).unwrap());
+        number_of_beats.write_npy(writer).unwrap();
+        self.variance.save_npy(&path.join("variance"));
+    }
+}
+
+/// Beats averaged per array position.
+#[derive(Debug, PartialEq, Clone)]
+pub struct AveragedBeats {
+    pub measurements: Measurements,
+    /// System states averaged over all accepted beats of all positions with
+    /// dimensions (`number_of_steps`, `number_of_states`), if a ground truth
+    /// was given.
+    pub system_states: Option<Array2<f32>>,
+    pub statistics: BeatStatistics,
+}
+
+impl Data {
+    /// Replaces the measurements with beats averaged from the continuous
+    /// recordings in `config.recording_paths`, one recording per array
+    /// position.
+    ///
+    /// If `config.ground_truth_paths` are given, the simulated system states
+    /// are replaced with the ground truth averaged over the same beats.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a recording or ground truth can not be read, if
+    /// their number, sensors or states do not match the measurements, or if
+    /// no beat is left for a position.
+    #[tracing::instrument(level = "info", skip(self))]
+    pub fn average_beats(&mut self, config: &BeatAveraging) -> Result<(), Box<dyn Error>> {
+        debug!("Replacing measurements with averaged beats");
+        let recordings = config
+            .recording_paths
+            .iter()
+            .map(read_npy)
+            .collect::<Result<Vec<Array2<f32>>, _>>()?;
+        let recordings: Vec<ArrayView2<f32>> = recordings.iter().map(Array2::view).collect();
+        let ground_truths = config
+            .ground_truth_paths
+            .iter()
+            .map(read_npy)
+            .collect::<Result<Vec<Array2<f32>>, _>>()?;
+        let ground_truths: Vec<ArrayView2<f32>> = ground_truths.iter().map(Array2::view).collect();
+        let averaged = average_beats(
+            &recordings,
+            &ground_truths,
+            self.simulation.sample_rate_hz,
+            self.simulation.measurements.num_steps(),
+            config,
+        )?;
+        if averaged.measurements.shape() != self.simulation.measurements.shape() {
+            return Err(format!(
+                "Averaged beats have shape {:?}, measurements have shape {:?}.",
+                averaged.measurements.shape(),
+                self.simulation.measurements.shape()
+            )
+            .into());
+        }
+        if let Some(system_states) = averaged.system_states {
+            if system_states.shape() != self.simulation.system_states.shape() {
+                return Err(format!(
+                    "Averaged ground truth has shape {:?}, system states have shape {:?}.",
+                    system_states.shape(),
+                    self.simulation.system_states.shape()
+                )
+                .into());
+            }
+            self.simulation.system_states.assign(&system_states);
+            self.simulation.calculate_plotting_arrays();
+        } else {
+            warn!("No ground truth given, the simulated system states are not averaged");
+        }
+        self.simulation.measurements = averaged.measurements;
+        self.beat_statistics = Some(averaged.statistics);
+        Ok(())
+    }
+}
+
+/// Detects, segments, rejects and averages the beats of continuous
+/// recordings.
+///
+/// Every recording has dimensions (`number_of_samples`, `number_of_sensors`)
+/// and belongs to one array position, i.e. one beat of the
+/// [`Measurements`] layout. Each window starts `window_before_ms` before
+/// the R-peak and is `number_of_steps` samples long.
+///
+/// The ground truths are either empty or hold one continuous recording of
+/// the system states per recording, with dimensions (`number_of_samples`,
+/// `number_of_states`). They are cut at the same peaks and averaged over
+/// the same accepted beats as the recordings.
+///
+/// # Errors
+///
+/// Returns an error if the recordings have different numbers of sensors,
+/// if the ground truths do not match the recordings or if no beat is left
+/// for a position.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss,
+    clippy::cast_precision_loss
+)]
+#[tracing::instrument(level = "debug", skip(recordings, ground_truths))]
+pub fn average_beats(
+    recordings: &[ArrayView2<f32>],
+    ground_truths: &[ArrayView2<f32>],
+    sample_rate_hz: f32,
+    number_of_steps: usize,
+    config: &BeatAveraging,
+) -> Result<AveragedBeats, Box<dyn Error>> {
+    debug!("Averaging beats");
+    let number_of_sensors = recordings.first().map_or(0, ArrayView2::ncols);
+    if recordings
+        .iter()
+        .any(|recording| recording.ncols() != number_of_sensors)
+    {
+        return Err("Recordings have different numbers of sensors.".into());
+    }
+    if !ground_truths.is_empty() && ground_truths.len() != recordings.len() {
+        return Err(format!(
+            "Got {} ground truths for {} recordings.",
+            ground_truths.len(),
+            recordings.len()
+        )
+        .into());
+    }
+    if let Some((position, _)) = ground_truths
+        .iter()
+        .zip(recordings)
+        .enumerate()
+        .find(|(_, (ground_truth, recording))| ground_truth.nrows() != recording.nrows())
+    {
+        return Err(format!(
+            "Ground truth and recording of array position {position} have different lengths."
+        )
+        .into());
+    }
+    let number_of_states = ground_truths.first().map_or(0, ArrayView2::ncols);
+    if ground_truths
+        .iter()
+        .any(|ground_truth| ground_truth.ncols() != number_of_states)
+    {
+        return Err("Ground truths have different numbers of states.".into());
+    }
+    let samples_before =
+        ((config.window_before_ms / 1000.0 * sample_rate_hz) as usize).min(number_of_steps);
+    let samples_after = number_of_steps - samples_before;
+
+    let mut measurements =
+        Measurements::empty(recordings.len(), number_of_steps, number_of_sensors);
+    let mut variance = Measurements::empty(recordings.len(), number_of_steps, number_of_sensors);
+    let mut peaks = Vec::with_capacity(recordings.len());
+    let mut number_of_beats = Vec::with_capacity(recordings.len());
+    let mut system_states = Array2::<f32>::zeros((number_of_steps, number_of_states));
+
+    for (position, recording) in recordings.iter().enumerate() {
+        let detected = detect_r_peaks(recording, sample_rate_hz, config);
+        let segments = segment_beats(recording, &detected, samples_before, samples_after);
+        let accepted = find_accepted_beats(&segments, config.minimum_correlation);
+        let accepted_count = accepted.iter().filter(|accepted| **accepted).count();
+        if accepted_count == 0 {
+            return Err(format!("No beats left for array position {position}.").into());
+        }
+        if accepted_count < segments.shape()[0] {
+            warn!(
+                "Rejected {} of {} beats for array position {position}",
+                segments.shape()[0] - accepted_count,
+                segments.shape()[0]
+            );
+        }
+        let accepted_indices: Vec<usize> = accepted
+            .iter()
+            .enumerate()
+            .filter_map(|(index, accepted)| accepted.then_some(index))
+            .collect();
+        let accepted_segments = segments.select(Axis(0), &accepted_indices);
+        measurements
+            .slice_mut(s![position, .., ..])
+            .assign(&(accepted_segments.sum_axis(Axis(0)) / accepted_count as f32));
+        if let Some(ground_truth) = ground_truths.get(position) {
+            let ground_truth_segments =
+                segment_beats(ground_truth, &detected, samples_before, samples_after);
+            system_states += &ground_truth_segments
+                .select(Axis(0), &accepted_indices)
+                .sum_axis(Axis(0));
+        }
+        if accepted_count > 1 {
+            variance
+                .slice_mut(s![position, .., ..])
+                .assign(&accepted_segments.var_axis(Axis(0), 1.0));
+        }
+        peaks.push(detected);
+        number_of_beats.push(accepted_count);
+    }
+
+    let total_number_of_beats: usize = number_of_beats.iter().sum();
+    Ok(AveragedBeats {
+        measurements,
+        system_states: (!ground_truths.is_empty())
+            .then(|| system_states / total_number_of_beats as f32),
+        statistics: BeatStatistics {
+            peaks,
+            number_of_beats,
+            variance,
+        },
+    })
+}
+
+/// Detects R-peaks in a continuous recording.
+///
+/// The detection signal is the global field power, i.e. the root mean
+/// square over all (mean-free) channels. Peaks are accepted in order of
+/// decreasing amplitude if they exceed `detection_threshold` times the
+/// maximum and are further than the refractory period from all accepted
+/// peaks. The returned indices are sorted.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+#[tracing::instrument(level = "debug", skip(recording))]
+pub fn detect_r_peaks(
+    recording: &ArrayView2<f32>,
+    sample_rate_hz: f32,
+    config: &BeatAveraging,
+) -> Vec<usize> {
+    debug!("Detecting R-peaks");
+    let global_field_power = calculate_global_field_power(recording);
+    let maximum = global_field_power.iter().copied().fold(0.0, f32::max);
+    let threshold = config.detection_threshold * maximum;
+    let refractory_samples = (config.refractory_period_ms / 1000.0 * sample_rate_hz) as usize;
+
+    let number_of_samples = global_field_power.len();
+    let mut candidates: Vec<usize> = (0..number_of_samples)
+        .filter(|index| {
+            let value = global_field_power[*index];
+            value >= threshold
+                && (*index == 0 || global_field_power[index - 1] <= value)
+                && (*index + 1 == number_of_samples || global_field_power[index + 1] < value)
+        })
+        .collect();
+    candidates.sort_unstable_by(|a, b| global_field_power[*b].total_cmp(&global_field_power[*a]));
+
+    let mut peaks: Vec<usize> = Vec::new();
+    for candidate in candidates {
+        if peaks
+            .iter()
+            .all(|peak| peak.abs_diff(candidate) > refractory_samples)
+        {
+            peaks.push(candidate);
+        }
+    }
+    peaks.sort_unstable();
+    trace!("Detected {} R-peaks", peaks.len());
+    peaks
+}
+
+/// Root mean square over all mean-free channels for every sample.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+pub(crate) fn calculate_global_field_power(recording: &ArrayView2<f32>) -> Array1<f32> {
+    trace!("Calculating global field power");
+    let mean = recording
+        .mean_axis(Axis(0))
+        .unwrap_or_else(|| Array1::zeros(recording.ncols()));
+    let centered = recording - &mean;
+    centered.map_axis(Axis(1), |sample| {
+        (sample.mapv(|value| value * value).sum() / sample.len().max(1) as f32).sqrt()
+    })
+}
+
+/// Cuts windows around the peaks out of the recording.
+///
+/// Peaks whose window exceeds the recording are skipped. The result has
+/// dimensions (`number_of_beats`, `samples_before + samples_after`,
+/// `number_of_sensors`).
+#[tracing::instrument(level = "debug", skip(recording, peaks))]
+pub fn segment_beats(
+    recording: &ArrayView2<f32>,
+    peaks: &[usize],
+    samples_before: usize,
+    samples_after: usize,
+) -> Array3<f32> {
+    debug!("Segmenting beats");
+    let windows: Vec<usize> = peaks
+        .iter()
+        .copied()
+        .filter(|peak| *peak >= samples_before && peak + samples_after <= recording.nrows())
+        .collect();
+    let mut segments = Array3::zeros((
+        windows.len(),
+        samples_before + samples_after,
+        recording.ncols(),
+    ));
+    for (mut segment, peak) in segments.outer_iter_mut().zip(windows) {
+        segment.assign(&recording.slice(s![peak - samples_before..peak + samples_after, ..]));
+    }
+    segments
+}
+
+/// Marks the beats whose correlation with the mean beat is at least
+/// `minimum_correlation`.
+#[tracing::instrument(level = "debug", skip(segments))]
+pub fn find_accepted_beats(segments: &Array3<f32>, minimum_correlation: f32) -> Vec<bool> {
+    debug!("Finding outlier beats");
+    let Some(template) = segments.mean_axis(Axis(0)) else {
+        return Vec::new();
+    };
+    segments
+        .outer_iter()
+        .map(|segment| correlation(&segment, &template.view()) >= minimum_correlation)
+        .collect()
+}
+
+/// Pearson correlation of two equally shaped arrays.
+#[tracing::instrument(level = "trace", skip_all)]
+fn correlation(a: &ArrayView2<f32>, b: &ArrayView2<f32>) -> f32 {
+    let a_centered = a - a.mean().unwrap_or_default();
+    let b_centered = b - b.mean().unwrap_or_default();
+    let norm = (a_centered.mapv(|v| v * v).sum() * b_centered.mapv(|v| v * v).sum()).sqrt();
+    if norm > 0.0 {
+        (&a_centered * &b_centered).sum() / norm
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::cast_precision_loss)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+
+    /// Recording with a triangular "QRS" every `interval` samples.
+    fn create_recording(number_of_samples: usize, interval: usize, sensors: usize) -> Array2<f32> {
+        let mut recording = Array2::zeros((number_of_samples, sensors));
+        for peak in (interval / 2..number_of_samples).step_by(interval) {
+            for offset in 0..10usize {
+                let value = 10.0 - offset as f32;
+                for sensor in 0..sensors {
+                    let gain = (sensor + 1) as f32;
+                    recording[(peak + offset, sensor)] = gain * value;
+                    if offset > 0 {
+                        recording[(peak - offset, sensor)] = gain * value;
+                    }
+                }
+            }
+        }
+        recording
+    }
+
+    #[test]
+    fn detects_all_peaks() {
+        let recording = create_recording(5000, 800, 3);
+        let config = BeatAveraging::default();
+
+        let peaks = detect_r_peaks(&recording.view(), 1000.0, &config);
+
+        assert_eq!(peaks, vec![400, 1200, 2000, 2800, 3600, 4400]);
+    }
+
+    #[test]
+    fn averaging_rejects_outliers() {
+        let mut recording = create_recording(5000, 800, 3);
+        // corrupt the third beat
+        recording
+            .slice_mut(s![1950..2050, ..])
+            .mapv_inplace(|value| -value);
+        let config = BeatAveraging {
+            window_before_ms: 100.0,
+            ..Default::default()
+        };
+
+        let averaged = average_beats(&[recording.view()], &[], 1000.0, 300, &config).unwrap();
+
+        assert_eq!(averaged.measurements.shape(), &[1, 300, 3]);
+        assert_eq!(averaged.statistics.number_of_beats, vec![5]);
+        assert_relative_eq!(averaged.measurements[(0, 100, 2)], 30.0);
+        assert_relative_eq!(averaged.statistics.variance.sum(), 0.0);
+    }
+
+    #[test]
+    fn ground_truth_is_averaged_over_accepted_beats() {
+        let mut recording = create_recording(5000, 800, 3);
+        recording
+            .slice_mut(s![1950..2050, ..])
+            .mapv_inplace(|value| -value);
+        let mut ground_truth = Array2::ones((5000, 2));
+        ground_truth.slice_mut(s![1950..2050, ..]).fill(100.0);
+        let config = BeatAveraging::default();
+
+        let averaged = average_beats(
+            &[recording.view()],
+            &[ground_truth.view()],
+            1000.0,
+            300,
+            &config,
+        )
+        .unwrap();
+
+        let system_states = averaged.system_states.unwrap();
+        assert_eq!(system_states.shape(), &[300, 2]);
+        system_states
+            .iter()
+            .for_each(|value| assert_relative_eq!(*value, 1.0));
+    }
+}
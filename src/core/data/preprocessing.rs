@@ -0,0 +1,682 @@
+use std::{error::Error, f32::consts::PI};
+
+use nalgebra::{DMatrix, SymmetricEigen};
+use ndarray::{s, Array2, ArrayView2, ArrayViewMut1, Axis, Zip};
+use ndarray_npy::read_npy;
+use rubato::{Resampler, SincFixedIn, SincInterpolationParameters};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use super::{shapes::Measurements, Data};
+use crate::core::config::preprocessing::{Detrend, Preprocessing};
+
+/// Keeps the raw measurements and the information needed to apply the
+/// preprocessing to the model.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[allow(clippy::unsafe_derive_deserialize)]
+pub struct Preprocessed {
+    pub raw_measurements: Measurements,
+    pub raw_sample_rate_hz: f32,
+    pub rejected_channels: Vec<usize>,
+    /// Orthogonal projector of channel rejection and signal-space
+    /// projection with dimensions (`number_of_sensors`, `number_of_sensors`).
+    pub projector: Option<Array2<f32>>,
+}
+
+impl Data {
+    /// Runs the preprocessing pipeline on the measurements.
+    ///
+    /// The processed measurements replace `simulation.measurements`, the
+    /// raw ones are kept in `preprocessed`. If the measurements are
+    /// resampled, the simulated states and control function are resampled
+    /// as well so all time series of the data share one sample rate.
+    ///
+    /// The duration is needed to match the number of resampled steps with
+    /// the number of steps of a model created at the target sample rate.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configuration is invalid, SSP components are
+    /// requested without a reference recording or the SSP reference can not
+    /// be read.
+    #[tracing::instrument(level = "info", skip(self))]
+    pub fn preprocess(
+        &mut self,
+        config: &Preprocessing,
+        duration_s: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        if !config.is_enabled() {
+            return Ok(());
+        }
+        debug!("Preprocessing measurements");
+        if config.beat_averaging.is_enabled() {
+            self.average_beats(&config.beat_averaging)?;
+        }
+        let sample_rate_hz = self.simulation.sample_rate_hz;
+        let raw_measurements = self.simulation.measurements.clone();
+        let measurements = &mut self.simulation.measurements;
+        let number_of_sensors = measurements.num_sensors();
+
+        let mut rejected_channels = config.rejected_channels.clone();
+        if let Some(channel) = rejected_channels
+            .iter()
+            .find(|channel| **channel >= number_of_sensors)
+        {
+            return Err(format!(
+                "Can not reject channel {channel}, there are only {number_of_sensors} sensors."
+            )
+            .into());
+        }
+        if config.channel_rejection_threshold > 0.0 {
+            rejected_channels.extend(find_noisy_channels(
+                measurements,
+                config.channel_rejection_threshold,
+            ));
+        }
+        rejected_channels.sort_unstable();
+        rejected_channels.dedup();
+
+        // missing samples are excluded from the trend and set to zero while
+        // filtering and projecting, so they do not spread to other samples
+        let missing = measurements.mapv(f32::is_nan);
+        let filters = create_filters(config, sample_rate_hz)?;
+        for mut signal in measurements.lanes_mut(Axis(1)) {
+            detrend(&mut signal, config.detrend);
+            fill_missing(&mut signal);
+            for filter in &filters {
+                filter.filtfilt(&mut signal);
+            }
+        }
+
+        let projector = if rejected_channels.is_empty() && config.ssp_components == 0 {
+            None
+        } else {
+            let reference = match (&config.ssp_reference_path, config.ssp_components) {
+                (_, 0) => Array2::zeros((0, number_of_sensors)),
+                (Some(path), _) => read_npy(path)?,
+                (None, _) => {
+                    return Err(
+                        "SSP needs a noise-only reference recording, set the SSP reference path."
+                            .into(),
+                    );
+                }
+            };
+            Some(calculate_projector(
+                &reference.view(),
+                number_of_sensors,
+                &rejected_channels,
+                config.ssp_components,
+            )?)
+        };
+        if let Some(projector) = &projector {
+            for mut beat in measurements.outer_iter_mut() {
+                let projected = beat.dot(&projector.t());
+                beat.assign(&projected);
+            }
+        }
+        Zip::from(&mut **measurements)
+            .and(&missing)
+            .for_each(|value, missing| {
+                if *missing {
+                    *value = f32::NAN;
+                }
+            });
+
+        if let Some(target_sample_rate_hz) = config.target_sample_rate_hz {
+            self.resample(target_sample_rate_hz, duration_s)?;
+        }
+
+        self.preprocessed = Some(Preprocessed {
+            raw_measurements,
+            raw_sample_rate_hz: sample_rate_hz,
+            rejected_channels,
+            projector,
+        });
+        Ok(())
+    }
+
+    /// Resamples measurements, noise, simulated states and control function
+    /// to the given sample rate and recalculates the derived arrays.
+    ///
+    /// The delays and time offsets of the simulated model are given in
+    /// samples and are rescaled accordingly.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    #[tracing::instrument(level = "debug", skip(self))]
+    fn resample(
+        &mut self,
+        target_sample_rate_hz: f32,
+        duration_s: f32,
+    ) -> Result<(), Box<dyn Error>> {
+        debug!("Resampling data");
+        let simulation = &mut self.simulation;
+        let from_sample_rate_hz = simulation.sample_rate_hz;
+        let number_of_steps = (target_sample_rate_hz * duration_s) as usize;
+        let resample = |measurements: &Measurements| {
+            resample_measurements(
+                measurements,
+                from_sample_rate_hz,
+                target_sample_rate_hz,
+                number_of_steps,
+            )
+        };
+
+        simulation.measurements = resample(&simulation.measurements)?;
+        let noise = &mut simulation.noise;
+        noise.white = resample(&noise.white)?;
+        for component in [
+            &mut noise.pink,
+            &mut noise.powerline,
+            &mut noise.drift,
+            &mut noise.spikes,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *component = resample(component)?;
+        }
+
+        let system_states = resample_columns(
+            &simulation.system_states.view(),
+            from_sample_rate_hz,
+            target_sample_rate_hz,
+            number_of_steps,
+        )?;
+        *simulation.system_states = system_states;
+
+        let control_function_values = &mut simulation
+            .model
+            .functional_description
+            .control_function_values;
+        let resampled = resample_columns(
+            &control_function_values.view().insert_axis(Axis(1)),
+            from_sample_rate_hz,
+            target_sample_rate_hz,
+            number_of_steps,
+        )?;
+        **control_function_values = resampled.column(0).to_owned();
+
+        let factor = target_sample_rate_hz / from_sample_rate_hz;
+        let functional_description = &mut simulation.model.functional_description;
+        functional_description.ap_params.rescale_delays(factor);
+        functional_description
+            .time_offsets
+            .samples
+            .mapv_inplace(|samples| samples * factor);
+
+        simulation.system_states_spherical = super::shapes::SystemStatesSpherical::empty(
+            number_of_steps,
+            simulation.system_states.num_states(),
+        );
+        simulation.sample_rate_hz = target_sample_rate_hz;
+        simulation.calculate_plotting_arrays();
+        Ok(())
+    }
+}
+
+/// Returns the channels whose standard deviation exceeds `threshold` times
+/// the median standard deviation of all channels.
+#[tracing::instrument(level = "debug", skip(measurements))]
+fn find_noisy_channels(measurements: &Measurements, threshold: f32) -> Vec<usize> {
+    debug!("Finding noisy channels");
+    let stds: Vec<f32> = measurements
+        .axis_iter(Axis(2))
+        .map(|channel| channel.std(0.0))
+        .collect();
+    let mut sorted = stds.clone();
+    sorted.sort_unstable_by(f32::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    stds.iter()
+        .enumerate()
+        .filter(|(_, std)| **std > threshold * median)
+        .map(|(channel, _)| channel)
+        .collect()
+}
+
+/// Removes the mean or a least squares line from the signal.
+///
+/// Missing (NaN) samples are ignored when fitting the trend.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip(signal))]
+fn detrend(signal: &mut ArrayViewMut1<f32>, mode: Detrend) {
+    trace!("Detrending signal");
+    let samples: Vec<(f32, f32)> = signal
+        .iter()
+        .enumerate()
+        .filter(|(_, value)| !value.is_nan())
+        .map(|(step, value)| (step as f32, *value))
+        .collect();
+    if samples.is_empty() {
+        return;
+    }
+    let n = samples.len() as f32;
+    let mean = samples.iter().map(|(_, value)| value).sum::<f32>() / n;
+    match mode {
+        Detrend::None => {}
+        Detrend::Constant => {
+            signal.mapv_inplace(|value| value - mean);
+        }
+        Detrend::Linear => {
+            let time_mean = samples.iter().map(|(time, _)| time).sum::<f32>() / n;
+            let (covariance, variance) =
+                samples
+                    .iter()
+                    .fold((0.0, 0.0), |(covariance, variance), (time, value)| {
+                        let time = time - time_mean;
+                        (
+                            time.mul_add(value - mean, covariance),
+                            time.mul_add(time, variance),
+                        )
+                    });
+            let slope = if variance > 0.0 {
+                covariance / variance
+            } else {
+                0.0
+            };
+            signal.iter_mut().enumerate().for_each(|(step, value)| {
+                *value -= slope.mul_add(step as f32 - time_mean, mean);
+            });
+        }
+    }
+}
+
+/// Sets the missing (NaN) samples of the signal to zero.
+#[tracing::instrument(level = "trace", skip_all)]
+fn fill_missing(signal: &mut ArrayViewMut1<f32>) {
+    signal
+        .iter_mut()
+        .filter(|value| value.is_nan())
+        .for_each(|value| *value = 0.0);
+}
+
+/// Second order IIR filter section with the coefficients normalized by `a0`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+}
+
+impl Biquad {
+    /// Creates a filter from the RBJ audio EQ cookbook coefficients.
+    #[tracing::instrument(level = "trace")]
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+        }
+    }
+
+    /// Second order Butterworth high-pass filter.
+    #[tracing::instrument(level = "trace")]
+    fn highpass(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate_hz, 1.0 / 2.0f32.sqrt());
+        let gain = (1.0 + cos) * 0.5;
+        Self::new(
+            [gain, -2.0 * gain, gain],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Second order Butterworth low-pass filter.
+    #[tracing::instrument(level = "trace")]
+    fn lowpass(cutoff_hz: f32, sample_rate_hz: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(cutoff_hz, sample_rate_hz, 1.0 / 2.0f32.sqrt());
+        let gain = (1.0 - cos) * 0.5;
+        Self::new(
+            [gain, 2.0 * gain, gain],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    /// Notch filter with the given quality factor.
+    #[tracing::instrument(level = "trace")]
+    fn notch(frequency_hz: f32, sample_rate_hz: f32, quality_factor: f32) -> Self {
+        let (cos, alpha) = Self::prewarp(frequency_hz, sample_rate_hz, quality_factor);
+        Self::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn prewarp(frequency_hz: f32, sample_rate_hz: f32, quality_factor: f32) -> (f32, f32) {
+        let omega = 2.0 * PI * frequency_hz / sample_rate_hz;
+        (omega.cos(), omega.sin() / (2.0 * quality_factor))
+    }
+
+    /// Filters the signal in place (direct form II transposed).
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn filter<'a>(&self, signal: impl Iterator<Item = &'a mut f32>) {
+        let mut z1 = 0.0;
+        let mut z2 = 0.0;
+        for value in signal {
+            let input = *value;
+            let output = self.b0.mul_add(input, z1);
+            z1 = self.b1.mul_add(input, (-self.a1).mul_add(output, z2));
+            z2 = self.b2.mul_add(input, -self.a2 * output);
+            *value = output;
+        }
+    }
+
+    /// Filters the signal forward and backward, which results in zero
+    /// phase shift and squares the magnitude response.
+    #[tracing::instrument(level = "trace", skip_all)]
+    fn filtfilt(&self, signal: &mut ArrayViewMut1<f32>) {
+        self.filter(signal.iter_mut());
+        self.filter(signal.iter_mut().rev());
+    }
+}
+
+/// Creates the band-pass and notch filters of the configuration.
+#[tracing::instrument(level = "debug")]
+fn create_filters(
+    config: &Preprocessing,
+    sample_rate_hz: f32,
+) -> Result<Vec<Biquad>, Box<dyn Error>> {
+    debug!("Creating filters");
+    let nyquist_hz = sample_rate_hz / 2.0;
+    let mut filters = Vec::new();
+    if config.highpass_cutoff_hz > 0.0 {
+        if config.highpass_cutoff_hz >= nyquist_hz {
+            return Err("High-pass cutoff has to be below the Nyquist frequency.".into());
+        }
+        filters.push(Biquad::highpass(config.highpass_cutoff_hz, sample_rate_hz));
+    }
+    if config.lowpass_cutoff_hz > 0.0 {
+        if config.lowpass_cutoff_hz >= nyquist_hz {
+            return Err("Low-pass cutoff has to be below the Nyquist frequency.".into());
+        }
+        filters.push(Biquad::lowpass(config.lowpass_cutoff_hz, sample_rate_hz));
+    }
+    for frequency_hz in &config.notch_frequencies_hz {
+        if *frequency_hz <= 0.0 || *frequency_hz >= nyquist_hz {
+            return Err(format!(
+                "Notch frequency {frequency_hz} Hz has to be between 0 Hz and the \
+                Nyquist frequency."
+            )
+            .into());
+        }
+        filters.push(Biquad::notch(
+            *frequency_hz,
+            sample_rate_hz,
+            config.notch_quality_factor,
+        ));
+    }
+    Ok(filters)
+}
+
+/// Calculates the orthogonal projector that removes the rejected channels
+/// and the strongest noise components of the reference.
+///
+/// The reference has dimensions (`number_of_samples`, `number_of_sensors`).
+/// The signal-space projection vectors are the principal components of the
+/// reference restricted to the kept channels.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "debug", skip(reference))]
+fn calculate_projector(
+    reference: &ArrayView2<f32>,
+    number_of_sensors: usize,
+    rejected_channels: &[usize],
+    ssp_components: usize,
+) -> Result<Array2<f32>, Box<dyn Error>> {
+    debug!("Calculating projector");
+    if reference.ncols() != number_of_sensors {
+        return Err(format!(
+            "SSP reference has {} channels, expected {number_of_sensors}.",
+            reference.ncols()
+        )
+        .into());
+    }
+    let mut keep = DMatrix::<f32>::identity(number_of_sensors, number_of_sensors);
+    for channel in rejected_channels {
+        keep[(*channel, *channel)] = 0.0;
+    }
+    let number_of_kept = number_of_sensors - rejected_channels.len();
+    if ssp_components > number_of_kept {
+        return Err(format!(
+            "Can not remove {ssp_components} SSP components from {number_of_kept} channels."
+        )
+        .into());
+    }
+    let mut projector = keep.clone();
+    if ssp_components > 0 {
+        let reference = DMatrix::from_row_iterator(
+            reference.nrows(),
+            number_of_sensors,
+            reference.iter().copied(),
+        ) * &keep;
+        let covariance = reference.transpose() * &reference / reference.nrows().max(1) as f32;
+        let eigen = SymmetricEigen::new(covariance);
+        let mut order: Vec<usize> = (0..number_of_sensors).collect();
+        order.sort_unstable_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+        for index in order.into_iter().take(ssp_components) {
+            let vector = eigen.eigenvectors.column(index);
+            projector -= vector * vector.transpose();
+        }
+    }
+    Ok(Array2::from_shape_fn(
+        (number_of_sensors, number_of_sensors),
+        |index| projector[index],
+    ))
+}
+
+/// Resamples every beat of the measurements to the target sample rate.
+///
+/// Missing (NaN) samples are set to zero for the interpolation. A resampled
+/// step is missing if the closest original step was missing.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+#[tracing::instrument(level = "debug", skip(measurements))]
+fn resample_measurements(
+    measurements: &Measurements,
+    from_sample_rate_hz: f32,
+    target_sample_rate_hz: f32,
+    number_of_steps: usize,
+) -> Result<Measurements, Box<dyn Error>> {
+    debug!("Resampling measurements");
+    let missing = measurements.mapv(f32::is_nan);
+    let filled = measurements.mapv(|value| if value.is_nan() { 0.0 } else { value });
+    let mut resampled = Measurements::empty(
+        measurements.num_beats(),
+        number_of_steps,
+        measurements.num_sensors(),
+    );
+    let last_step = measurements.num_steps().saturating_sub(1);
+    for (beat, mut beat_values) in resampled.outer_iter_mut().enumerate() {
+        beat_values.assign(&resample_columns(
+            &filled.slice(s![beat, .., ..]),
+            from_sample_rate_hz,
+            target_sample_rate_hz,
+            number_of_steps,
+        )?);
+        for (step, mut values) in beat_values.outer_iter_mut().enumerate() {
+            let closest = ((step as f32 * from_sample_rate_hz / target_sample_rate_hz).round()
+                as usize)
+                .min(last_step);
+            Zip::from(&mut values)
+                .and(&missing.slice(s![beat, closest, ..]))
+                .for_each(|value, missing| {
+                    if *missing {
+                        *value = f32::NAN;
+                    }
+                });
+        }
+    }
+    Ok(resampled)
+}
+
+/// Resamples every column of the signal to the target sample rate.
+///
+/// The resampler is flushed to get the end of the signal and the result is
+/// padded with its last value or truncated to the given number of steps.
+#[tracing::instrument(level = "debug", skip(signal))]
+fn resample_columns(
+    signal: &ArrayView2<f32>,
+    from_sample_rate_hz: f32,
+    target_sample_rate_hz: f32,
+    number_of_steps: usize,
+) -> Result<Array2<f32>, Box<dyn Error>> {
+    debug!("Resampling signal");
+    let params = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        oversampling_factor: 256,
+        interpolation: rubato::SincInterpolationType::Cubic,
+        window: rubato::WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = SincFixedIn::<f32>::new(
+        f64::from(target_sample_rate_hz) / f64::from(from_sample_rate_hz),
+        10.0,
+        params,
+        signal.nrows(),
+        signal.ncols(),
+    )?;
+    let input_frames: Vec<Vec<f32>> = signal.columns().into_iter().map(|c| c.to_vec()).collect();
+    let mut output_frames = resampler.process(&input_frames, None)?;
+    let flushed = resampler.process_partial::<Vec<f32>>(None, None)?;
+
+    let mut output_signal = Array2::<f32>::zeros((number_of_steps, signal.ncols()));
+    for ((mut column, output), tail) in output_signal
+        .columns_mut()
+        .into_iter()
+        .zip(output_frames.iter_mut())
+        .zip(flushed)
+    {
+        output.extend(tail);
+        let last = output.last().copied().unwrap_or_default();
+        column.iter_mut().enumerate().for_each(|(step, value)| {
+            *value = output.get(step).copied().unwrap_or(last);
+        });
+    }
+    Ok(output_signal)
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Array1;
+
+    use super::*;
+
+    #[allow(clippy::cast_precision_loss)]
+    fn sine(frequency_hz: f32, sample_rate_hz: f32, number_of_steps: usize) -> Array1<f32> {
+        Array1::from_shape_fn(number_of_steps, |step| {
+            (2.0 * PI * frequency_hz * step as f32 / sample_rate_hz).sin()
+        })
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn linear_detrend_removes_line() {
+        let mut signal = Array1::from_shape_fn(100, |step| 0.5f32.mul_add(step as f32, 3.0));
+
+        detrend(&mut signal.view_mut(), Detrend::Linear);
+
+        signal
+            .iter()
+            .for_each(|value| assert_relative_eq!(*value, 0.0, epsilon = 1e-3));
+    }
+
+    #[test]
+    fn notch_removes_powerline() {
+        let sample_rate_hz = 1000.0;
+        let mut signal = sine(50.0, sample_rate_hz, 2000);
+        let passband = sine(5.0, sample_rate_hz, 2000);
+        signal += &passband;
+
+        Biquad::notch(50.0, sample_rate_hz, 5.0).filtfilt(&mut signal.view_mut());
+
+        // ignore the edges where the filter settles
+        let error = (&signal - &passband).slice(s![500..1500]).std(0.0);
+        assert!(error < 0.05, "Residual error {error} too large.");
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn projector_removes_rejected_channel_and_component() {
+        let reference = Array2::from_shape_fn((200, 3), |(sample, channel)| {
+            let common = (sample as f32 * 0.3).sin();
+            if channel == 2 {
+                0.0
+            } else {
+                common
+            }
+        });
+
+        let projector = calculate_projector(&reference.view(), 3, &[2], 1).unwrap();
+
+        let noise = Array1::from(vec![1.0, 1.0, 5.0]);
+        projector
+            .dot(&noise)
+            .iter()
+            .for_each(|value| assert_relative_eq!(*value, 0.0, epsilon = 1e-5));
+        // projectors are idempotent
+        let squared = projector.dot(&projector);
+        squared
+            .iter()
+            .zip(projector.iter())
+            .for_each(|(a, b)| assert_relative_eq!(a, b, epsilon = 1e-5));
+    }
+
+    #[test]
+    fn resampling_keeps_sine() {
+        let signal = sine(5.0, 2000.0, 2000).insert_axis(Axis(1));
+
+        let resampled = resample_columns(&signal.view(), 2000.0, 1000.0, 1000).unwrap();
+
+        assert_eq!(resampled.shape(), &[1000, 1]);
+        let expected = sine(5.0, 1000.0, 1000);
+        let error = (&resampled.column(0) - &expected)
+            .slice(s![100..900])
+            .std(0.0);
+        assert!(error < 0.05, "Residual error {error} too large.");
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn linear_detrend_ignores_missing_samples() {
+        let mut signal = Array1::from_shape_fn(100, |step| 0.5f32.mul_add(step as f32, 3.0));
+        signal[10] = f32::NAN;
+
+        detrend(&mut signal.view_mut(), Detrend::Linear);
+
+        assert!(signal[10].is_nan());
+        signal
+            .iter()
+            .filter(|value| !value.is_nan())
+            .for_each(|value| assert_relative_eq!(*value, 0.0, epsilon = 1e-3));
+    }
+
+    #[test]
+    fn resampling_keeps_missing_samples() {
+        let mut measurements = Measurements::empty(1, 2000, 2);
+        measurements
+            .slice_mut(s![0, .., 0])
+            .assign(&sine(5.0, 2000.0, 2000));
+        measurements[[0, 1000, 1]] = f32::NAN;
+
+        let resampled = resample_measurements(&measurements, 2000.0, 1000.0, 1000).unwrap();
+
+        assert!(resampled[[0, 500, 1]].is_nan());
+        assert_eq!(resampled.iter().filter(|value| value.is_nan()).count(), 1);
+    }
+
+    #[test]
+    fn ssp_without_reference_is_rejected() {
+        let mut data = Data::empty(3, 3, 100, ndarray::Dim([1, 1, 1]), 1);
+        let config = Preprocessing {
+            ssp_components: 1,
+            ..Default::default()
+        };
+
+        assert!(data.preprocess(&config, 0.05).is_err());
+    }
+}
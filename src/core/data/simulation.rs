@@ -1,15 +1,18 @@
+pub mod noise;
 #[cfg(test)]
 mod tests;
 
 use std::error::Error;
 
+use nalgebra::DVector;
 use ndarray::Dim;
 use rand::prelude::*;
 use rand_chacha::ChaCha8Rng;
-use rand_distr::{Distribution, Normal};
+use rand_distr::{Distribution, Normal, StandardNormal};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, info, trace};
 
+use self::noise::NoiseComponents;
 use super::shapes::{
     ActivationTimePerStateMs, SystemStates, SystemStatesSpherical, SystemStatesSphericalMax,
 };
@@ -20,7 +23,7 @@ use crate::core::{
     },
     config::{model::SensorArrayMotion, simulation::Simulation as SimulationConfig},
     data::Measurements,
-    model::Model,
+    model::{functional::time_offset::TimeOffsets, Model},
 };
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
@@ -33,6 +36,7 @@ pub struct Simulation {
     pub average_delays: AverageDelays,
     pub sample_rate_hz: f32,
     pub model: Model,
+    pub noise: NoiseComponents,
 }
 impl Simulation {
     /// Creates an empty Simulation with the given dimensions and number of
@@ -69,6 +73,7 @@ impl Simulation {
                 voxels_in_dims,
                 sensor_motion_steps,
             ),
+            noise: NoiseComponents::empty(sensor_motion_steps, number_of_steps, number_of_sensors),
         }
     }
 
@@ -84,7 +89,7 @@ impl Simulation {
     #[tracing::instrument(level = "debug")]
     pub fn from_config(config: &SimulationConfig) -> Result<Self, Box<dyn Error>> {
         debug!("Creating simulation from config");
-        let model =
+        let mut model =
             Model::from_model_config(&config.model, config.sample_rate_hz, config.duration_s)?;
         let number_of_sensors = model.spatial_description.sensors.count();
         let number_of_states = model.spatial_description.voxels.count_states();
@@ -99,6 +104,17 @@ impl Simulation {
                 .iter()
                 .product(),
         };
+        if config.onset_jitter_ms > 0.0 {
+            // the onsets are drawn from their own stream, so they do not change
+            // the noise of the simulation
+            let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+            rng.set_stream(1);
+            model.functional_description.time_offsets = TimeOffsets::random(
+                number_of_beats,
+                config.onset_jitter_ms * config.sample_rate_hz / 1000.0,
+                &mut rng,
+            );
+        }
 
         let measurements = Measurements::empty(number_of_beats, number_of_steps, number_of_sensors);
         let system_states = SystemStates::empty(number_of_steps, number_of_states);
@@ -117,17 +133,28 @@ impl Simulation {
             average_delays,
             sample_rate_hz: config.sample_rate_hz,
             model,
+            noise: NoiseComponents::empty(number_of_beats, number_of_steps, number_of_sensors),
         })
     }
 
     /// Runs a simulation by calculating system predictions, adding measurement
     /// noise, and storing results in the measurements and `system_states` fields.
     ///
+    /// The white noise of each sensor is drawn independently, using the
+    /// diagonal of the measurement covariance as its standard deviation. If
+    /// correlated noise is configured, it is instead drawn with the Cholesky
+    /// factor of the measurement covariance, so correlations between sensors
+    /// are reproduced. The
+    /// additional noise sources are generated according to the noise config.
+    /// All noise components are kept in the `noise` field. The random numbers
+    /// are drawn with the seed of the config.
+    ///
     /// # Panics
     ///
-    /// if there are negative values in the measurement covariance matrix.
+    /// if the measurement covariance matrix is not positive semi-definite or
+    /// contains negative variances.
     #[tracing::instrument(level = "info", skip_all)]
-    pub fn run(&mut self) {
+    pub fn run(&mut self, config: &SimulationConfig) {
         info!("Running simulation");
 
         let mut estimations = Estimations::empty(
@@ -152,21 +179,39 @@ impl Simulation {
         self.measurements.assign(&*estimations.measurements);
         self.system_states.assign(&*estimations.system_states);
 
-        let mut rng = ChaCha8Rng::seed_from_u64(42);
-        for sensor_index in 0..self.measurements.num_sensors() {
-            let dist = Normal::new(
-                0.0,
-                self.model.functional_description.measurement_covariance
-                    [[sensor_index, sensor_index]],
-            )
-            .unwrap();
+        let mut rng = ChaCha8Rng::seed_from_u64(config.seed);
+        let measurement_covariance = &self.model.functional_description.measurement_covariance;
+        if measurement_covariance.is_correlated() {
+            let noise_factor = measurement_covariance
+                .cholesky_factor()
+                .expect("Measurement covariance to be positive semi-definite.");
             for beat_index in 0..self.measurements.num_beats() {
                 for time_index in 0..self.measurements.num_steps() {
-                    self.measurements[[beat_index, time_index, sensor_index]] +=
-                        dist.sample(&mut rng);
+                    let white_noise =
+                        DVector::<f32>::from_fn(self.measurements.num_sensors(), |_, _| {
+                            StandardNormal.sample(&mut rng)
+                        });
+                    let noise = &noise_factor * white_noise;
+                    for (sensor_index, value) in noise.iter().enumerate() {
+                        self.noise.white[[beat_index, time_index, sensor_index]] = *value;
+                    }
+                }
+            }
+        } else {
+            for sensor_index in 0..self.measurements.num_sensors() {
+                let dist =
+                    Normal::new(0.0, measurement_covariance[[sensor_index, sensor_index]]).unwrap();
+                for beat_index in 0..self.measurements.num_beats() {
+                    for time_index in 0..self.measurements.num_steps() {
+                        self.noise.white[[beat_index, time_index, sensor_index]] =
+                            dist.sample(&mut rng);
+                    }
                 }
             }
         }
+        self.noise
+            .generate(&config.noise, self.sample_rate_hz, &mut rng);
+        self.noise.add_to(&mut self.measurements);
         self.calculate_plotting_arrays();
     }
 
@@ -192,6 +237,7 @@ impl Simulation {
         self.measurements.save_npy(path);
         self.system_states.save_npy(path);
         self.model.save_npy(path);
+        self.noise.save_npy(&path.join("noise"));
     }
 
     #[tracing::instrument(level = "trace", skip_all)]
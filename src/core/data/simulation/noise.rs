@@ -0,0 +1,330 @@
+use std::f32::consts::PI;
+
+use ndarray::{s, Array1, Array2, ArrayViewMut1};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use rand_distr::{Distribution, StandardNormal};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use crate::core::{
+    config::simulation::{DriftNoise, Noise, PinkNoise, PowerlineNoise, SpikeNoise},
+    data::shapes::Measurements,
+};
+
+/// Longest impulse response used to shape the pink noise.
+const MAX_PINK_FILTER_LENGTH: usize = 2048;
+
+/// The individual noise components added to the simulated measurements.
+///
+/// Every component has the same shape as the measurements, so the clean
+/// signal can be recovered by subtracting their sum. The optional
+/// components are `None` if the corresponding source is disabled.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[allow(clippy::unsafe_derive_deserialize)]
+pub struct NoiseComponents {
+    pub white: Measurements,
+    pub pink: Option<Measurements>,
+    pub powerline: Option<Measurements>,
+    pub drift: Option<Measurements>,
+    pub spikes: Option<Measurements>,
+}
+
+impl NoiseComponents {
+    /// Creates empty noise components with the given dimensions.
+    ///
+    /// Only the white noise is allocated, all other sources are disabled.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn empty(number_of_beats: usize, number_of_steps: usize, number_of_sensors: usize) -> Self {
+        debug!("Creating empty noise components");
+        Self {
+            white: Measurements::empty(number_of_beats, number_of_steps, number_of_sensors),
+            pink: None,
+            powerline: None,
+            drift: None,
+            spikes: None,
+        }
+    }
+
+    /// Generates the configured noise sources (all but the white noise).
+    ///
+    /// Every beat and sensor gets an independent realization. Disabled
+    /// sources are set to `None`.
+    #[tracing::instrument(level = "debug", skip(rng))]
+    pub fn generate(&mut self, config: &Noise, sample_rate_hz: f32, rng: &mut ChaCha8Rng) {
+        debug!("Generating noise components");
+        let (number_of_beats, number_of_steps, number_of_sensors) = self.white.dim();
+        let allocate = |amplitude: f32| {
+            (amplitude > 0.0)
+                .then(|| Measurements::empty(number_of_beats, number_of_steps, number_of_sensors))
+        };
+        self.pink = allocate(config.pink.amplitude);
+        self.powerline = allocate(config.powerline.amplitude);
+        self.drift = allocate(config.drift.amplitude);
+        self.spikes = allocate(config.spikes.amplitude);
+        for beat in 0..number_of_beats {
+            for sensor in 0..number_of_sensors {
+                if let Some(pink) = &mut self.pink {
+                    generate_pink(pink.slice_mut(s![beat, .., sensor]), &config.pink, rng);
+                }
+                if let Some(powerline) = &mut self.powerline {
+                    generate_powerline(
+                        powerline.slice_mut(s![beat, .., sensor]),
+                        &config.powerline,
+                        sample_rate_hz,
+                        rng,
+                    );
+                }
+                if let Some(drift) = &mut self.drift {
+                    generate_drift(
+                        drift.slice_mut(s![beat, .., sensor]),
+                        &config.drift,
+                        sample_rate_hz,
+                        rng,
+                    );
+                }
+                if let Some(spikes) = &mut self.spikes {
+                    generate_spikes(
+                        spikes.slice_mut(s![beat, .., sensor]),
+                        &config.spikes,
+                        sample_rate_hz,
+                        rng,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the enabled noise components, starting with the white noise.
+    fn enabled(&self) -> impl Iterator<Item = &Measurements> {
+        std::iter::once(&self.white).chain(
+            [&self.pink, &self.powerline, &self.drift, &self.spikes]
+                .into_iter()
+                .flatten(),
+        )
+    }
+
+    /// Adds all noise components to the given measurements.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn add_to(&self, measurements: &mut Measurements) {
+        debug!("Adding noise components to measurements");
+        for component in self.enabled() {
+            **measurements += &**component;
+        }
+    }
+
+    /// Returns the sum of all noise components with the beats concatenated,
+    /// i.e. with dimensions (`number_of_beats * number_of_steps`,
+    /// `number_of_sensors`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if the summed noise is not contiguous.
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn total(&self) -> Array2<f32> {
+        debug!("Summing noise components");
+        let mut total = (*self.white).to_owned();
+        for component in self.enabled().skip(1) {
+            total += &**component;
+        }
+        let (number_of_beats, number_of_steps, number_of_sensors) = total.dim();
+        total
+            .into_shape((number_of_beats * number_of_steps, number_of_sensors))
+            .expect("Noise to be contiguous.")
+    }
+
+    /// Saves the noise components to .npy files, one folder per enabled
+    /// component.
+    #[tracing::instrument(level = "trace")]
+    pub(crate) fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving noise components to npy");
+        self.white.save_npy(&path.join("white"));
+        let optional = [
+            ("pink", &self.pink),
+            ("powerline", &self.powerline),
+            ("drift", &self.drift),
+            ("spikes", &self.spikes),
+        ];
+        for (name, component) in optional {
+            if let Some(component) = component {
+                component.save_npy(&path.join(name));
+            }
+        }
+    }
+}
+
+/// Fills the signal with noise with a `1 / f^exponent` spectrum.
+///
+/// White noise is shaped with the truncated impulse response of the
+/// fractional integrator `(1 - z^-1)^(-exponent / 2)` (Kasdin, 1995) and
+/// scaled to the configured standard deviation.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn generate_pink(mut signal: ArrayViewMut1<f32>, config: &PinkNoise, rng: &mut ChaCha8Rng) {
+    trace!("Generating pink noise");
+    let number_of_steps = signal.len();
+    let filter_length = number_of_steps.min(MAX_PINK_FILTER_LENGTH);
+    let mut impulse_response = Array1::<f32>::zeros(filter_length);
+    impulse_response[0] = 1.0;
+    for k in 1..filter_length {
+        impulse_response[k] =
+            impulse_response[k - 1] * (0.5f32.mul_add(config.exponent, k as f32 - 1.0)) / k as f32;
+    }
+    // white noise including the warm-up of the filter
+    let white: Array1<f32> = (0..number_of_steps + filter_length)
+        .map(|_| StandardNormal.sample(rng))
+        .collect();
+    for (step, value) in signal.iter_mut().enumerate() {
+        let end = step + filter_length;
+        *value = impulse_response
+            .iter()
+            .zip(white.slice(s![step + 1..=end; -1]).iter())
+            .map(|(h, w)| h * w)
+            .sum();
+    }
+    normalize(&mut signal, config.amplitude);
+}
+
+/// Fills the signal with power-line interference.
+///
+/// Harmonic `h` has the amplitude `amplitude * harmonic_decay^(h - 1)` and
+/// a random phase.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn generate_powerline(
+    mut signal: ArrayViewMut1<f32>,
+    config: &PowerlineNoise,
+    sample_rate_hz: f32,
+    rng: &mut ChaCha8Rng,
+) {
+    trace!("Generating powerline noise");
+    let mut amplitude = config.amplitude;
+    for harmonic in 1..=config.harmonics + 1 {
+        let frequency_hz = config.frequency_hz * harmonic as f32;
+        let phase = rng.gen_range(0.0..2.0 * PI);
+        for (step, value) in signal.iter_mut().enumerate() {
+            let time_s = step as f32 / sample_rate_hz;
+            *value += amplitude * (2.0 * PI * frequency_hz).mul_add(time_s, phase).sin();
+        }
+        amplitude *= config.harmonic_decay;
+    }
+}
+
+/// Fills the signal with an Ornstein-Uhlenbeck process with the configured
+/// stationary standard deviation and time constant.
+#[tracing::instrument(level = "trace", skip_all)]
+fn generate_drift(
+    mut signal: ArrayViewMut1<f32>,
+    config: &DriftNoise,
+    sample_rate_hz: f32,
+    rng: &mut ChaCha8Rng,
+) {
+    trace!("Generating drift");
+    let decay = (-1.0 / (config.time_constant_s * sample_rate_hz)).exp();
+    let innovation_std = config.amplitude * decay.mul_add(-decay, 1.0).sqrt();
+    let mut drift = config.amplitude * Distribution::<f32>::sample(&StandardNormal, rng);
+    for value in &mut signal {
+        *value = drift;
+        drift = decay.mul_add(
+            drift,
+            innovation_std * Distribution::<f32>::sample(&StandardNormal, rng),
+        );
+    }
+}
+
+/// Fills the signal with randomly occurring spikes and jumps.
+///
+/// Events follow a Poisson process. A spike only affects a single sample,
+/// a jump shifts the baseline for the rest of the signal. The sign of each
+/// event is random.
+#[tracing::instrument(level = "trace", skip_all)]
+fn generate_spikes(
+    mut signal: ArrayViewMut1<f32>,
+    config: &SpikeNoise,
+    sample_rate_hz: f32,
+    rng: &mut ChaCha8Rng,
+) {
+    trace!("Generating spikes");
+    let event_probability = (config.rate_hz / sample_rate_hz).clamp(0.0, 1.0);
+    let mut baseline = 0.0;
+    for value in &mut signal {
+        *value = baseline;
+        if rng.gen::<f32>() < event_probability {
+            let height = if rng.gen::<bool>() {
+                config.amplitude
+            } else {
+                -config.amplitude
+            };
+            if rng.gen::<f32>() < config.jump_probability {
+                baseline += height;
+            }
+            *value += height;
+        }
+    }
+}
+
+/// Scales the signal to zero mean and the given standard deviation.
+#[tracing::instrument(level = "trace", skip_all)]
+fn normalize(signal: &mut ArrayViewMut1<f32>, std: f32) {
+    let mean = signal.mean().unwrap_or_default();
+    let current_std = signal.std(0.0);
+    if current_std > 0.0 {
+        signal.mapv_inplace(|value| (value - mean) / current_std * std);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn disabled_sources_are_zero() {
+        let mut components = NoiseComponents::empty(2, 100, 3);
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+
+        components.generate(&Noise::default(), 1000.0, &mut rng);
+
+        assert!(components.pink.is_none());
+        assert!(components.powerline.is_none());
+        assert!(components.drift.is_none());
+        assert!(components.spikes.is_none());
+    }
+
+    #[test]
+    fn pink_noise_has_configured_std() {
+        let mut components = NoiseComponents::empty(1, 1000, 2);
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let mut config = Noise::default();
+        config.pink.amplitude = 2.0;
+
+        components.generate(&config, 1000.0, &mut rng);
+
+        let signal = components.pink.as_ref().unwrap().slice(s![0, .., 1]);
+        assert_relative_eq!(signal.std(0.0), 2.0, epsilon = 1e-3);
+        assert_relative_eq!(signal.mean().unwrap(), 0.0, epsilon = 1e-3);
+    }
+
+    #[test]
+    fn powerline_amplitude() {
+        let mut components = NoiseComponents::empty(1, 1000, 1);
+        let mut rng = ChaCha8Rng::seed_from_u64(42);
+        let mut config = Noise::default();
+        config.powerline.amplitude = 1.0;
+        config.powerline.harmonics = 0;
+
+        components.generate(&config, 1000.0, &mut rng);
+
+        let maximum = components
+            .powerline
+            .as_ref()
+            .unwrap()
+            .iter()
+            .fold(0.0f32, |maximum, value| maximum.max(value.abs()));
+        assert_relative_eq!(maximum, 1.0, epsilon = 2e-2);
+    }
+}
@@ -36,13 +36,35 @@ fn create_simulation_no_crash() {
 fn run_simulation_default() {
     let config = &SimulationConfig::default();
     let mut simulation = Simulation::from_config(config).unwrap();
-    simulation.run();
+    simulation.run(config);
     let max = *simulation.system_states.max_skipnan();
     assert!(max.relative_eq(&1.0, 0.001, 0.001));
     let max = *simulation.measurements.max_skipnan();
     assert!(max > 0.0);
 }
 
+#[test]
+fn onset_jitter_uses_config_seed() {
+    let mut config = SimulationConfig {
+        onset_jitter_ms: 10.0,
+        ..Default::default()
+    };
+    let offsets = |config: &SimulationConfig| {
+        Simulation::from_config(config)
+            .unwrap()
+            .model
+            .functional_description
+            .time_offsets
+    };
+
+    let first = offsets(&config);
+    config.seed += 1;
+    let second = offsets(&config);
+
+    assert_ne!(first, second);
+    assert_eq!(second, offsets(&config));
+}
+
 #[test]
 #[ignore]
 #[allow(clippy::too_many_lines)]
@@ -51,7 +73,7 @@ fn run_simulation_default_and_plot() {
     setup_folder(&folder);
     let config = &SimulationConfig::default();
     let mut simulation = Simulation::from_config(config).unwrap();
-    simulation.run();
+    simulation.run(config);
     let max = *simulation.system_states.max_skipnan();
     assert!(max.relative_eq(&1.0, 0.001, 0.001));
     let max = *simulation.measurements.max_skipnan();
@@ -176,7 +198,7 @@ fn run_simulation_pathological() {
     let mut config = SimulationConfig::default();
     config.model.common.pathological = true;
     let mut simulation = Simulation::from_config(&config).unwrap();
-    simulation.run();
+    simulation.run(&config);
     let max = *simulation.system_states.max_skipnan();
     assert!(max.relative_eq(&1.0, 0.001, 0.001));
     let max = *simulation.measurements.max_skipnan();
@@ -192,7 +214,7 @@ fn run_simulation_pathological_and_plot() {
     let mut config = SimulationConfig::default();
     config.model.common.pathological = true;
     let mut simulation = Simulation::from_config(&config).unwrap();
-    simulation.run();
+    simulation.run(&config);
     let max = *simulation.system_states.max_skipnan();
     assert!(max.relative_eq(&1.0, 0.001, 0.001));
     let max = *simulation.measurements.max_skipnan();
@@ -317,7 +339,7 @@ fn run_simulation_mri() {
     config.model.handcrafted = None;
     config.model.mri = Some(Mri::default());
     let mut simulation = Simulation::from_config(&config).unwrap();
-    simulation.run();
+    simulation.run(&config);
     let max = *simulation.measurements.max_skipnan();
     assert!(max > 0.0);
     // make sure the max in each voxel is one
@@ -385,7 +407,7 @@ fn run_simulation_mri_and_plot() {
     config.model.handcrafted = None;
     config.model.mri = Some(Mri::default());
     let mut simulation = Simulation::from_config(&config).unwrap();
-    simulation.run();
+    simulation.run(&config);
     let max = *simulation.system_states.max_skipnan();
     assert!(max.relative_eq(&1.0, 0.002, 0.002));
     let max = *simulation.measurements.max_skipnan();
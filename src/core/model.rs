@@ -5,7 +5,7 @@ mod tests;
 
 use std::error::Error;
 
-use ndarray::Dim;
+use ndarray::{Array2, Dim};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
@@ -74,7 +74,7 @@ impl Model {
         duration_s: f32,
     ) -> Result<Self, Box<dyn Error>> {
         debug!("Creating model from config");
-        let spatial_description = SpatialDescription::from_model_config(config);
+        let spatial_description = SpatialDescription::from_model_config(config)?;
         let functional_description = FunctionalDescription::from_model_config(
             config,
             &spatial_description,
@@ -118,6 +118,36 @@ impl Model {
                 .sensors
                 .positions_mm,
         );
+        if let Some(projector) = data
+            .preprocessed
+            .as_ref()
+            .and_then(|preprocessed| preprocessed.projector.as_ref())
+        {
+            self.apply_projector(projector);
+        }
+        self.functional_description.apply_sensor_mask();
+    }
+
+    /// Applies the projector of the preprocessing to the measurement matrix
+    /// and covariance, so the model predicts projected measurements.
+    ///
+    /// The removed subspace keeps the mean measurement variance, which keeps
+    /// the innovation covariance invertible. The residuals vanish in this
+    /// subspace, so it does not affect the estimation.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn apply_projector(&mut self, projector: &Array2<f32>) {
+        debug!("Applying projector to model");
+        let functional_description = &mut self.functional_description;
+        for mut measurement_matrix in functional_description.measurement_matrix.outer_iter_mut() {
+            let projected = projector.dot(&measurement_matrix);
+            measurement_matrix.assign(&projected);
+        }
+        let measurement_covariance = &mut functional_description.measurement_covariance;
+        let mean_variance = measurement_covariance.diag().mean().unwrap_or_default();
+        let complement = Array2::<f32>::eye(projector.nrows()) - projector;
+        let projected = projector.dot(&**measurement_covariance).dot(&projector.t())
+            + complement * mean_variance;
+        measurement_covariance.assign(&projected);
     }
 
     /// Saves the functional and spatial descriptions of the model
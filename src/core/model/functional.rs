@@ -2,11 +2,13 @@ pub mod allpass;
 pub mod control;
 pub mod kalman;
 pub mod measurement;
+pub mod stimulus;
+pub mod time_offset;
 
 use std::error::Error;
 
 use approx::relative_eq;
-use ndarray::Dim;
+use ndarray::{Axis, Dim};
 use ocl::{Buffer, Queue};
 use rand_distr::{Distribution, Normal};
 use serde::{Deserialize, Serialize};
@@ -16,7 +18,9 @@ use self::{
     allpass::{shapes::Gains, APParameters, APParametersGPU},
     control::{ControlFunction, ControlMatrix},
     kalman::KalmanGain,
-    measurement::{MeasurementCovariance, MeasurementMatrix},
+    measurement::{MeasurementCovariance, MeasurementMatrix, SensorWeights},
+    stimulus::StimulusSites,
+    time_offset::TimeOffsets,
 };
 use super::spatial::SpatialDescription;
 use crate::core::config::model::Model;
@@ -31,6 +35,12 @@ pub struct FunctionalDescription {
     pub measurement_covariance: MeasurementCovariance,
     pub kalman_gain: KalmanGain,
     pub control_function_values: ControlFunction,
+    #[serde(default)]
+    pub sensor_weights: SensorWeights,
+    #[serde(default)]
+    pub time_offsets: TimeOffsets,
+    #[serde(default)]
+    pub stimulus_sites: StimulusSites,
 }
 
 pub struct FunctionalDescriptionGPU {
@@ -41,6 +51,8 @@ pub struct FunctionalDescriptionGPU {
     pub measurement_covariance: Buffer<f32>,
     pub kalman_gain: Buffer<f32>,
     pub control_function_values: Buffer<f32>,
+    pub sensor_weights: Buffer<f32>,
+    pub time_offsets: Buffer<f32>,
 }
 
 impl FunctionalDescription {
@@ -70,6 +82,9 @@ impl FunctionalDescription {
             measurement_covariance: MeasurementCovariance::empty(number_of_sensors),
             kalman_gain: KalmanGain::empty(number_of_states, number_of_sensors),
             control_function_values: ControlFunction::empty(number_of_steps),
+            sensor_weights: SensorWeights::ones(number_of_sensors),
+            time_offsets: TimeOffsets::default(),
+            stimulus_sites: StimulusSites::default(),
         }
     }
     /// Constructs a `FunctionalDescription` from the given Model config, `SpatialDescription`,
@@ -92,22 +107,33 @@ impl FunctionalDescription {
         duration_s: f32,
     ) -> Result<Self, Box<dyn Error>> {
         debug!("Creating functional description from model config");
-        let ap_params =
-            APParameters::from_model_config(config, spatial_description, sample_rate_hz)?;
+        let stimulus_sites =
+            StimulusSites::from_model_config(config, spatial_description, sample_rate_hz);
+        let ap_params = APParameters::from_model_config(
+            config,
+            spatial_description,
+            &stimulus_sites,
+            sample_rate_hz,
+        )?;
         let process_covariance =
             process_covariance_from_model_config(config, spatial_description, &ap_params);
         let measurement_matrix =
             MeasurementMatrix::from_model_spatial_description(spatial_description);
-        let control_matrix = ControlMatrix::from_model_config(config, spatial_description);
+        let control_matrix = ControlMatrix::from_stimulus_sites(
+            &stimulus_sites,
+            spatial_description.voxels.count_states(),
+        );
         let measurement_covariance =
-            MeasurementCovariance::from_model_config(config, spatial_description);
+            MeasurementCovariance::from_model_config(config, spatial_description)?;
         //        let kalman_gain = Gain::from_model_config(config, &measurement_matrix);
         let kalman_gain = KalmanGain::empty(
             spatial_description.voxels.count_states(),
             spatial_description.sensors.count(),
         );
         let control_function_values =
-            ControlFunction::from_model_config(config, sample_rate_hz, duration_s);
+            ControlFunction::from_model_config(config, sample_rate_hz, duration_s)?;
+        let sensor_weights =
+            SensorWeights::from_model_config(config, spatial_description.sensors.count())?;
 
         Ok(Self {
             ap_params,
@@ -117,9 +143,38 @@ impl FunctionalDescription {
             measurement_covariance,
             kalman_gain,
             control_function_values,
+            sensor_weights,
+            time_offsets: TimeOffsets::default(),
+            stimulus_sites,
         })
     }
 
+    /// Removes the masked sensors from the measurement model.
+    ///
+    /// Only the model of the estimation is masked, when it is synchronized
+    /// with the data. The simulation keeps predicting all sensors.
+    ///
+    /// The rows of the measurement matrix and the correlations of masked
+    /// sensors are set to zero, so they neither contribute to the Kalman
+    /// update nor to the pseudo inverse. Their variance is kept, which keeps
+    /// the innovation covariance invertible.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn apply_sensor_mask(&mut self) {
+        debug!("Applying sensor mask");
+        for sensor in 0..self.measurement_covariance.nrows() {
+            if !self.sensor_weights.is_masked(sensor) {
+                continue;
+            }
+            self.measurement_matrix
+                .index_axis_mut(Axis(1), sensor)
+                .fill(0.0);
+            let variance = self.measurement_covariance[(sensor, sensor)];
+            self.measurement_covariance.row_mut(sensor).fill(0.0);
+            self.measurement_covariance.column_mut(sensor).fill(0.0);
+            self.measurement_covariance[(sensor, sensor)] = variance;
+        }
+    }
+
     /// Saves the internal state of the `FunctionalDescription` to .npy files.
     ///
     /// This exports the allpass filter parameters, process covariance,
@@ -137,6 +192,9 @@ impl FunctionalDescription {
         self.measurement_covariance.save_npy(path);
         self.kalman_gain.save_npy(path);
         self.control_function_values.save_npy(path);
+        self.sensor_weights.save_npy(path);
+        self.time_offsets.save_npy(path);
+        self.stimulus_sites.save_npy(path);
     }
 
     #[allow(clippy::missing_panics_doc)]
@@ -150,6 +208,12 @@ impl FunctionalDescription {
             measurement_covariance: self.measurement_covariance.to_gpu(queue),
             kalman_gain: self.kalman_gain.to_gpu(queue),
             control_function_values: self.control_function_values.to_gpu(queue),
+            sensor_weights: self
+                .sensor_weights
+                .to_gpu(queue, self.measurement_covariance.nrows()),
+            time_offsets: self
+                .time_offsets
+                .to_gpu(queue, self.measurement_matrix.shape()[0]),
         }
     }
 
@@ -245,7 +309,7 @@ mod tests {
     #[test]
     fn from_handcrafted_model_config_no_crash() {
         let config = Model::default();
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
         let sample_rate_hz = 2000.0;
         let duration_s = 2.0;
         let _functional_description = FunctionalDescription::from_model_config(
@@ -264,7 +328,7 @@ mod tests {
             handcrafted: None,
             mri: Some(Mri::default()),
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
         let sample_rate_hz = 2000.0;
         let duration_s = 2.0;
         let _functional_description = FunctionalDescription::from_model_config(
@@ -7,22 +7,23 @@ use std::error::Error;
 
 use approx::relative_eq;
 use itertools::Itertools;
-use ndarray::{arr1, s, Array1, Array3, Array4, Dim};
+use ndarray::{arr1, s, Array1, Array3, Array4, Dim, Zip};
 use ndarray_stats::QuantileExt;
 use ocl::{Buffer, Queue};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use self::{
     delay::calculate_delay_samples_array,
     shapes::{ActivationTimeMs, Coefs, Gains, Indices, UnitDelays},
 };
+use super::stimulus::StimulusSites;
 use crate::core::{
-    config::model::Model,
-    model::spatial::{
-        voxels::{self, VoxelType},
-        SpatialDescription,
+    config::{
+        algorithm::Algorithm,
+        model::{Common, Model},
     },
+    model::spatial::{voxels::VoxelType, SpatialDescription},
 };
 
 #[allow(clippy::module_name_repetitions)]
@@ -72,23 +73,30 @@ impl APParameters {
     pub fn from_model_config(
         config: &Model,
         spatial_description: &SpatialDescription,
+        stimulus_sites: &StimulusSites,
         sample_rate_hz: f32,
     ) -> Result<Self, Box<dyn Error>> {
         debug!("Creating AP parameters from model config");
+        config.common.validate_connections()?;
         let mut ap_params = Self::empty(
             spatial_description.voxels.count_states(),
             spatial_description.voxels.types.raw_dim(),
         );
 
-        connect_voxels(spatial_description, config, &mut ap_params);
-
-        let delays_samples = calculate_delay_samples_array(
+        connect_voxels(
             spatial_description,
-            &config.common.propagation_velocities_m_per_s,
+            config,
+            stimulus_sites,
             sample_rate_hz,
-        )?;
+            &mut ap_params,
+        );
 
-        ap_params.output_state_indices = init_output_state_indicies(spatial_description);
+        let delays_samples =
+            calculate_delay_samples_array(spatial_description, &config.common, sample_rate_hz)?;
+
+        ap_params.output_state_indices =
+            init_output_state_indicies(spatial_description, &config.common);
+        debug!("Connected {} neighbours", ap_params.number_of_connections());
 
         ap_params
             .delays
@@ -107,6 +115,47 @@ impl APParameters {
         Ok(ap_params)
     }
 
+    /// Returns the number of neighbour connections, i.e. the pairs of input
+    /// and output voxels with an output state.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn number_of_connections(&self) -> usize {
+        trace!("Counting connections");
+        // every connection maps the three input states to three output states
+        self.output_state_indices
+            .iter()
+            .filter(|index| index.is_some())
+            .count()
+            / 9
+    }
+
+    /// Returns the number of parameters the refinement calculates gradients
+    /// for, nine gains and one delay per connection unless they are frozen.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(config))]
+    pub fn number_of_gradients(&self, config: &Algorithm) -> usize {
+        trace!("Counting gradients");
+        let gains = if config.freeze_gains { 0 } else { 9 };
+        let delays = usize::from(!config.freeze_delays);
+        self.number_of_connections() * (gains + delays)
+    }
+
+    /// Scales all delays by the given factor, e.g. after the data was
+    /// resampled by it.
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "debug", skip(self))]
+    pub fn rescale_delays(&mut self, factor: f32) {
+        debug!("Rescaling delays");
+        Zip::from(&mut *self.delays)
+            .and(&mut *self.coefs)
+            .for_each(|delay, coef| {
+                let samples = (*delay as f32 + from_coef_to_samples(*coef)) * factor;
+                *delay = from_samples_to_usize(samples);
+                *coef = from_samples_to_coef(samples);
+            });
+        self.initial_delays.mapv_inplace(|samples| samples * factor);
+    }
+
     /// Saves the allpass filter parameters to .npy files.
     #[tracing::instrument(level = "debug")]
     pub(crate) fn save_npy(&self, path: &std::path::Path) {
@@ -185,9 +234,14 @@ impl APParameters {
 /// spatial description. It finds neighboring output voxels for each input
 /// voxel and maps the input states to the corresponding output states. This
 /// allows signals to propagate from input voxels to neighboring output voxels
-/// through the allpass filter.
+/// through the allpass filter. Only voxels within the configured
+/// neighbourhood whose connection is allowed and not blocked by a barrier are
+/// neighbours.
 #[tracing::instrument(level = "debug", skip_all)]
-fn init_output_state_indicies(spatial_description: &SpatialDescription) -> Indices {
+fn init_output_state_indicies(
+    spatial_description: &SpatialDescription,
+    common: &Common,
+) -> Indices {
     debug!("Initializing output state indices");
     let mut output_state_indices = Indices::empty(spatial_description.voxels.count_states());
     let v_types = &spatial_description.voxels.types;
@@ -201,7 +255,10 @@ fn init_output_state_indicies(spatial_description: &SpatialDescription) -> Indic
             for ((x_offset, y_offset), z_offset) in
                 (-1..=1).cartesian_product(-1..=1).cartesian_product(-1..=1)
             {
-                if x_offset == 0 && y_offset == 0 && z_offset == 0 {
+                if !common
+                    .neighbourhood
+                    .contains([x_offset, y_offset, z_offset], common.voxel_size_mm)
+                {
                     continue;
                 }
                 let ouput_voxel_index_candidate = [
@@ -220,6 +277,19 @@ fn init_output_state_indicies(spatial_description: &SpatialDescription) -> Indic
                     usize::try_from(ouput_voxel_index_candidate[1]).unwrap(),
                     usize::try_from(ouput_voxel_index_candidate[2]).unwrap(),
                 ];
+                if (!common.allowed_connections.is_empty()
+                    && !common.is_connection_allowed(
+                        &v_types[output_voxel_index],
+                        &v_types[input_voxel_index],
+                    ))
+                    || spatial_description.voxels.is_conduction_blocked(
+                        &common.barriers,
+                        [x_in, y_in, z_in],
+                        output_voxel_index,
+                    )
+                {
+                    continue;
+                }
                 for input_direction in 0..3 {
                     let input_state_number =
                         v_numbers[input_voxel_index].unwrap() + input_direction;
@@ -241,41 +311,67 @@ fn init_output_state_indicies(spatial_description: &SpatialDescription) -> Indic
 /// Connects voxels in the model based on voxel type and proximity.
 /// Iteratively activates voxels by updating `activation_time_s` and `current_directions`.
 /// Stops when no more voxels can be connected at the current time step.
+///
+/// The activation starts at the stimulus sites at their onsets in the first
+/// beat. A site that is reached by an earlier activation before its onset is
+/// connected like any other voxel. Later beats use the same connections, so
+/// a warning is logged if their sites are stimulated in another sequence.
 #[tracing::instrument(level = "debug", skip_all)]
 fn connect_voxels(
     spatial_description: &SpatialDescription,
     config: &Model,
+    stimulus_sites: &StimulusSites,
+    sample_rate_hz: f32,
     ap_params: &mut APParameters,
 ) {
     debug!("Connecting voxels");
+    if !stimulus_sites.beats_share_sequence() {
+        warn!(
+            "The stimulus onsets change the activation sequence between beats, \
+             the connections follow the sequence of the first beat."
+        );
+    }
     let mut activation_time_s =
         Array3::<Option<f32>>::from_elem(spatial_description.voxels.types.raw_dim(), None);
     let mut current_directions =
         Array4::<f32>::zeros(spatial_description.voxels.positions_mm.raw_dim());
 
-    let v_types = &spatial_description.voxels.types;
-
+    let mut pending_sites: Vec<((usize, usize, usize), f32)> = stimulus_sites
+        .voxels
+        .iter()
+        .enumerate()
+        .map(|(site, index)| {
+            (
+                *index,
+                stimulus_sites.onset_samples(site, 0) / sample_rate_hz,
+            )
+        })
+        .collect();
     let mut current_time_s: f32 = 0.0;
-    // Handle Sinoatrial node
-    v_types
-        .indexed_iter()
-        .filter(|(_, v_type)| **v_type == VoxelType::Sinoatrial)
-        .for_each(|(index, _)| {
-            activation_time_s[index] = Some(current_time_s);
-            current_directions
-                .slice_mut(s![index.0, index.1, index.2, ..])
-                .assign(&arr1(&[1.0, 0.0, 0.0]));
-        });
     let mut connected_something = true;
 
     while connected_something {
+        // Handle stimulus sites that are not activated yet
+        pending_sites.retain(|(index, onset_s)| {
+            if *onset_s > current_time_s {
+                return true;
+            }
+            if activation_time_s[*index].is_none() {
+                activation_time_s[*index] = Some(current_time_s);
+                current_directions
+                    .slice_mut(s![index.0, index.1, index.2, ..])
+                    .assign(&arr1(&[1.0, 0.0, 0.0]));
+            }
+            false
+        });
         // reset the connected something variable so we don't get stuck here forever
         // have to check the activation times because there might be some connection possible
         // with a voxel that is not yet activated.
-        if !activation_time_s
-            .iter()
-            .filter(|time_s| time_s.is_some())
-            .any(|time_s| time_s.unwrap() > current_time_s)
+        if pending_sites.is_empty()
+            && !activation_time_s
+                .iter()
+                .filter(|time_s| time_s.is_some())
+                .any(|time_s| time_s.unwrap() > current_time_s)
         {
             connected_something = false;
         }
@@ -304,6 +400,7 @@ fn connect_voxels(
             .iter()
             .filter(|t| t.is_some() && t.unwrap() > current_time_s)
             .map(|t| t.unwrap())
+            .chain(pending_sites.iter().map(|(_, onset_s)| *onset_s))
             .collect();
         let candidate_times_s = Array1::from_vec(candidate_times_s);
         current_time_s = *candidate_times_s.min_skipnan();
@@ -338,8 +435,12 @@ fn try_to_connect(
     let v_numbers = &spatial_description.voxels.numbers;
     let (x_offset, y_offset, z_offset) = voxel_offset;
 
-    // no self connection allowed
-    if x_offset == 0 && y_offset == 0 && z_offset == 0 {
+    // only connect within the neighbourhood, which excludes the voxel itself
+    if !config
+        .common
+        .neighbourhood
+        .contains([x_offset, y_offset, z_offset], config.common.voxel_size_mm)
+    {
         return false;
     }
     let (x_out, y_out, z_out) = output_voxel_index;
@@ -364,13 +465,31 @@ fn try_to_connect(
     let output_voxel_type = &v_types[output_voxel_index];
     let input_voxel_type = &v_types[input_voxel_index];
     // Skip if connection is not alowed
-    if !voxels::is_connection_allowed(output_voxel_type, input_voxel_type) {
+    if !config
+        .common
+        .is_connection_allowed(output_voxel_type, input_voxel_type)
+    {
+        return false;
+    }
+    // Skip scar and connections across lines of block
+    if spatial_description.voxels.is_conduction_blocked(
+        &config.common.barriers,
+        input_voxel_index,
+        [x_out, y_out, z_out],
+    ) {
         return false;
     }
+    let v_regions = &spatial_description.voxels.pathology_regions;
+    let v_severity = &spatial_description.voxels.pathology_severity;
+    let input_region = v_regions[input_voxel_index];
+    let input_severity = v_severity[input_voxel_index];
+    let input_current_factor = config.common.current_factor(input_region, input_severity);
+    let output_current_factor = config.common.current_factor(
+        v_regions[output_voxel_index],
+        v_severity[output_voxel_index],
+    );
     // Skip pathologies if the propagation factor is zero
-    if input_voxel_type == &VoxelType::Pathological
-        && relative_eq!(config.common.current_factor_in_pathology, 0.0)
-    {
+    if input_voxel_type == &VoxelType::Pathological && relative_eq!(input_current_factor, 0.0) {
         return false;
     }
     // Now we finally found something that we want to connect.
@@ -378,16 +497,35 @@ fn try_to_connect(
     let output_position_mm = &v_position_mm.slice(s![x_out, y_out, z_out, ..]);
     let [x_in, y_in, z_in] = input_voxel_index;
     let input_position_mm = &v_position_mm.slice(s![x_in, y_in, z_in, ..]);
-    let propagation_velocity_m_per_s = config
-        .common
-        .propagation_velocities_m_per_s
-        .get(input_voxel_type)
-        .unwrap();
-    let delay_s = delay::calculate_delay_s(
-        input_position_mm,
-        output_position_mm,
-        *propagation_velocity_m_per_s,
+    let propagation_velocity_m_per_s = config.common.propagation_velocity_m_per_s(
+        *input_voxel_type,
+        spatial_description.voxels.velocities_m_per_s[input_voxel_index],
+        input_region,
+        input_severity,
     );
+    let delay_s = spatial_description
+        .voxels
+        .fibers
+        .as_ref()
+        .zip(config.common.fibers.as_ref())
+        .map_or_else(
+            || {
+                delay::calculate_delay_s(
+                    input_position_mm,
+                    output_position_mm,
+                    propagation_velocity_m_per_s,
+                )
+            },
+            |(v_fibers, fibers)| {
+                delay::calculate_anisotropic_delay_s(
+                    input_position_mm,
+                    output_position_mm,
+                    &v_fibers.slice(s![x_in, y_in, z_in, ..]),
+                    propagation_velocity_m_per_s,
+                    fibers.transverse_velocity_ratio,
+                )
+            },
+        );
     // update activation time of input voxel, marking them as connected
     activation_time_s[input_voxel_index] =
         Some(activation_time_s[output_voxel_index].unwrap() + delay_s);
@@ -399,13 +537,10 @@ fn try_to_connect(
         &direction,
         current_directions.slice(s![x_out, y_out, z_out, ..]),
     );
-    if *input_voxel_type == VoxelType::Pathological && *output_voxel_type != VoxelType::Pathological
-    {
-        gain *= config.common.current_factor_in_pathology;
-    }
-    if *output_voxel_type == VoxelType::Pathological && *input_voxel_type != VoxelType::Pathological
-    {
-        gain *= 1.0 / config.common.current_factor_in_pathology;
+    // scale the current when it crosses into, out of or within a graded
+    // pathology region
+    if !relative_eq!(input_current_factor, output_current_factor) {
+        gain *= input_current_factor / output_current_factor;
     }
     assign_gain(
         ap_params,
@@ -593,10 +728,103 @@ pub fn from_coef_to_samples(coef: f32) -> f32 {
 mod test {
     use approx::assert_relative_eq;
 
-    use crate::core::model::functional::allpass::{
-        from_samples_to_coef, from_samples_to_usize, offset_to_gain_index,
+    use super::APParameters;
+    use crate::{
+        core::{
+            config::{
+                algorithm::Algorithm,
+                model::{Common, Neighbourhood},
+            },
+            model::{
+                functional::{
+                    allpass::{from_samples_to_coef, from_samples_to_usize, offset_to_gain_index},
+                    stimulus::StimulusSites,
+                },
+                spatial::{voxels::VoxelType, SpatialDescription},
+            },
+        },
+        tests::handcrafted_model,
     };
 
+    fn ap_params(common: Common) -> Result<APParameters, String> {
+        let mut config = handcrafted_model([10.0, 10.0, 2.5]);
+        config.common = common;
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
+        let stimulus_sites =
+            StimulusSites::from_model_config(&config, &spatial_description, 2000.0);
+        APParameters::from_model_config(&config, &spatial_description, &stimulus_sites, 2000.0)
+            .map_err(|error| error.to_string())
+    }
+
+    fn number_of_connections(common: Common) -> Result<usize, String> {
+        ap_params(common).map(|ap_params| ap_params.number_of_connections())
+    }
+
+    #[test]
+    fn neighbourhood_limits_connections() {
+        let connections = |neighbourhood| {
+            number_of_connections(Common {
+                neighbourhood,
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let six = connections(Neighbourhood::Six);
+        let eighteen = connections(Neighbourhood::Eighteen);
+        let twenty_six = connections(Neighbourhood::TwentySix);
+
+        assert!(six > 0);
+        assert!(six < eighteen);
+        assert!(eighteen <= twenty_six);
+        assert_eq!(connections(Neighbourhood::Radius { radius_mm: 2.5 }), six);
+        assert!(number_of_connections(Common {
+            neighbourhood: Neighbourhood::Radius { radius_mm: 1.0 },
+            ..Default::default()
+        })
+        .is_err());
+        assert!(number_of_connections(Common {
+            neighbourhood: Neighbourhood::Radius { radius_mm: 100.0 },
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn allowed_connections_are_validated() {
+        let mut common = Common::default();
+        common
+            .allowed_connections
+            .insert(VoxelType::Ventricle, vec![VoxelType::Torso]);
+        assert!(number_of_connections(common).is_err());
+
+        let mut common = Common::default();
+        common
+            .allowed_connections
+            .insert(VoxelType::Sinoatrial, vec![VoxelType::Atrium]);
+        assert!(
+            number_of_connections(common.clone()).unwrap()
+                < number_of_connections(Common::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn frozen_parameters_have_no_gradients() {
+        let ap_params = ap_params(Common::default()).unwrap();
+        let connections = ap_params.number_of_connections();
+        let gradients = |freeze_gains, freeze_delays| {
+            ap_params.number_of_gradients(&Algorithm {
+                freeze_gains,
+                freeze_delays,
+                ..Default::default()
+            })
+        };
+
+        assert_eq!(gradients(false, false), connections * 10);
+        assert_eq!(gradients(false, true), connections * 9);
+        assert_eq!(gradients(true, false), connections);
+        assert_eq!(gradients(true, true), 0);
+    }
+
     #[test]
     fn from_samples_to_usize_1() {
         assert_eq!(1, from_samples_to_usize(1.0));
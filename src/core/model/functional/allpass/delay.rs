@@ -1,11 +1,11 @@
-use std::{collections::HashMap, error::Error};
+use std::error::Error;
 
 use itertools::Itertools;
 use ndarray::{s, ArrayBase, Dim, ViewRepr};
 use tracing::trace;
 
 use super::{offset_to_delay_index, shapes::Coefs};
-use crate::core::model::spatial::{voxels::VoxelType, SpatialDescription};
+use crate::core::{config::model::Common, model::spatial::SpatialDescription};
 
 /// Calculates the delay in seconds for a given input and output position,
 /// based on the propagation velocity. Takes the Euclidean distance between
@@ -23,6 +23,38 @@ pub fn calculate_delay_s(
     distance_norm_m / propagation_velocity_m_per_s
 }
 
+/// Calculates the delay in seconds for a given input and output position,
+/// with the conduction being faster along the fiber direction of the input
+/// voxel than across it.
+///
+/// The Euclidean distance is scaled by the slowness of the conduction
+/// ellipsoid, `sqrt(cos² / v_l² + sin² / v_t²)`, where the angle is taken
+/// between the connection and the fiber and the transverse velocity `v_t` is
+/// the given fraction of the longitudinal velocity `v_l`. A zero fiber
+/// vector gives isotropic conduction with the longitudinal velocity.
+#[tracing::instrument(level = "trace")]
+pub fn calculate_anisotropic_delay_s(
+    input_position_mm: &ArrayBase<ViewRepr<&f32>, Dim<[usize; 1]>>,
+    output_position_mm: &ArrayBase<ViewRepr<&f32>, Dim<[usize; 1]>>,
+    fiber: &ArrayBase<ViewRepr<&f32>, Dim<[usize; 1]>>,
+    longitudinal_velocity_m_per_s: f32,
+    transverse_velocity_ratio: f32,
+) -> f32 {
+    trace!("Calculating anisotropic delay in seconds");
+    let distance_m = (input_position_mm - output_position_mm) / 1000.0;
+    let distance_norm_m = distance_m.mapv(|v| v.powi(2)).sum().sqrt();
+    let fiber_norm = fiber.mapv(|v| v.powi(2)).sum().sqrt();
+    if fiber_norm == 0.0 || distance_norm_m == 0.0 {
+        return distance_norm_m / longitudinal_velocity_m_per_s;
+    }
+    let cos_squared = (distance_m.dot(fiber) / (distance_norm_m * fiber_norm)).powi(2);
+    let transverse_velocity_m_per_s = longitudinal_velocity_m_per_s * transverse_velocity_ratio;
+    distance_norm_m
+        * (cos_squared / longitudinal_velocity_m_per_s.powi(2)
+            + (1.0 - cos_squared) / transverse_velocity_m_per_s.powi(2))
+        .sqrt()
+}
+
 /// Calculates an array of delay values in samples for each voxel and its neighborhood,
 /// based on the spatial description, material propagation velocities, and sample rate.
 ///
@@ -35,7 +67,7 @@ pub fn calculate_delay_s(
 #[tracing::instrument(level = "trace")]
 pub fn calculate_delay_samples_array(
     spatial_description: &SpatialDescription,
-    propagation_velocities_m_per_s: &HashMap<VoxelType, f32>,
+    common: &Common,
     sample_rate_hz: f32,
 ) -> Result<Coefs, Box<dyn Error>> {
     trace!("Calculating delay samples array");
@@ -44,6 +76,10 @@ pub fn calculate_delay_samples_array(
     let v_types = &spatial_description.voxels.types;
     let v_position_mm = &spatial_description.voxels.positions_mm;
     let v_numbers = &spatial_description.voxels.numbers;
+    let v_regions = &spatial_description.voxels.pathology_regions;
+    let v_severity = &spatial_description.voxels.pathology_severity;
+    let v_fibers = &spatial_description.voxels.fibers;
+    let v_velocities = &spatial_description.voxels.velocities_m_per_s;
 
     // Fill the delays_samples tensor
     for (input_voxel_index, v_type) in v_types.indexed_iter() {
@@ -52,10 +88,19 @@ pub fn calculate_delay_samples_array(
         }
         let (x_in, y_in, z_in) = input_voxel_index;
         let input_position_mm = &v_position_mm.slice(s![x_in, y_in, z_in, ..]);
+        let propagation_velocity_m_per_s = common.propagation_velocity_m_per_s(
+            *v_type,
+            v_velocities[input_voxel_index],
+            v_regions[input_voxel_index],
+            v_severity[input_voxel_index],
+        );
         for ((x_offset, y_offset), z_offset) in
             (-1..=1).cartesian_product(-1..=1).cartesian_product(-1..=1)
         {
-            if x_offset == 0 && y_offset == 0 && z_offset == 0 {
+            if !common
+                .neighbourhood
+                .contains([x_offset, y_offset, z_offset], common.voxel_size_mm)
+            {
                 continue;
             }
             let ouput_voxel_index = [
@@ -73,10 +118,23 @@ pub fn calculate_delay_samples_array(
             ];
             let output_position_mm = &v_position_mm.slice(s![x_out, y_out, z_out, ..]);
 
-            let delay_s = calculate_delay_s(
-                input_position_mm,
-                output_position_mm,
-                *propagation_velocities_m_per_s.get(v_type).unwrap(),
+            let delay_s = v_fibers.as_ref().zip(common.fibers.as_ref()).map_or_else(
+                || {
+                    calculate_delay_s(
+                        input_position_mm,
+                        output_position_mm,
+                        propagation_velocity_m_per_s,
+                    )
+                },
+                |(v_fibers, fibers)| {
+                    calculate_anisotropic_delay_s(
+                        input_position_mm,
+                        output_position_mm,
+                        &v_fibers.slice(s![x_in, y_in, z_in, ..]),
+                        propagation_velocity_m_per_s,
+                        fibers.transverse_velocity_ratio,
+                    )
+                },
             );
             let delay_samples = delay_s * sample_rate_hz;
 
@@ -105,7 +163,7 @@ mod test {
     use ndarray::{arr1, Array1};
     use ndarray_stats::QuantileExt;
 
-    use super::{calculate_delay_s, calculate_delay_samples_array};
+    use super::{calculate_anisotropic_delay_s, calculate_delay_s, calculate_delay_samples_array};
     use crate::core::{
         config::model::Model,
         model::spatial::{voxels::VoxelType, SpatialDescription},
@@ -141,18 +199,39 @@ mod test {
         assert_relative_eq!(delay_s, 2.5);
     }
 
+    #[test]
+    fn calculate_anisotropic_delay_s_depends_on_direction() {
+        let input_position_mm: Array1<f32> = arr1(&[0.0, 0.0, 0.0]);
+        let fiber: Array1<f32> = arr1(&[1.0, 0.0, 0.0]);
+        let delay = |output_position_mm: [f32; 3], fiber: &Array1<f32>| {
+            calculate_anisotropic_delay_s(
+                &input_position_mm.view(),
+                &arr1(&output_position_mm).view(),
+                &fiber.view(),
+                2.0,
+                0.5,
+            )
+        };
+
+        assert_relative_eq!(delay([1000.0, 0.0, 0.0], &fiber), 0.5);
+        assert_relative_eq!(delay([0.0, 1000.0, 0.0], &fiber), 1.0);
+        assert_relative_eq!(
+            delay([1000.0, 1000.0, 0.0], &fiber),
+            2.0_f32.sqrt() * 0.625_f32.sqrt(),
+            epsilon = 1e-6
+        );
+        assert_relative_eq!(delay([0.0, 1000.0, 0.0], &Array1::zeros(3)), 0.5);
+    }
+
     #[test]
     fn calculate_delay_samples_array_1() {
         let config = &Model::default();
-        let spatial_description = &SpatialDescription::from_model_config(config);
+        let spatial_description = &SpatialDescription::from_model_config(config).unwrap();
         let sample_rate_hz = 2000.0;
 
-        let delay_samples = calculate_delay_samples_array(
-            spatial_description,
-            &config.common.propagation_velocities_m_per_s,
-            sample_rate_hz,
-        )
-        .unwrap();
+        let delay_samples =
+            calculate_delay_samples_array(spatial_description, &config.common, sample_rate_hz)
+                .unwrap();
 
         let max = delay_samples.max_skipnan();
         let expected = (spatial_description.voxels.size_mm / 1000.0)
@@ -1,7 +1,10 @@
 use std::{
+    error::Error,
+    ffi::OsStr,
     fs::{self, File},
     io::BufWriter,
     ops::{Deref, DerefMut},
+    path::Path,
 };
 
 use approx::RelativeEq;
@@ -12,10 +15,8 @@ use rubato::{Resampler, SincFixedIn, SincInterpolationParameters};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use crate::core::{
-    config::{self, model::Model},
-    model::spatial::{voxels::VoxelType, SpatialDescription},
-};
+use super::stimulus::StimulusSites;
+use crate::core::config::{self, model::Model};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[allow(clippy::module_name_repetitions)]
@@ -31,28 +32,18 @@ impl ControlMatrix {
         Self(Array1::zeros(number_of_states))
     }
 
-    /// Creates a `ControlMatrix` from the given `Model` configuration and
-    /// `SpatialDescription`. Initializes the control matrix by setting the value
-    /// for the state of the sinoatrial voxel to 1.0, and all other states to 0.
-    ///
-    /// # Panics
-    ///
-    /// Panics if the `v_number` of the sinoatrial node is None.
+    /// Creates a `ControlMatrix` from the given stimulus sites. Initializes
+    /// the control matrix by setting the value for the first state of every
+    /// stimulus site, by default the sinoatrial voxels, to 1.0, and all other
+    /// states to 0.
     #[must_use]
-    #[tracing::instrument(level = "debug")]
-    pub fn from_model_config(config: &Model, spatial_description: &SpatialDescription) -> Self {
-        debug!("Creating control matrix from model config");
-        let mut control_matrix = Self::empty(spatial_description.voxels.count_states());
-        spatial_description
-            .voxels
-            .types
-            .iter()
-            .zip(spatial_description.voxels.numbers.iter())
-            .for_each(|(v_type, v_number)| {
-                if *v_type == VoxelType::Sinoatrial {
-                    control_matrix[v_number.unwrap()] = 1.0;
-                }
-            });
+    #[tracing::instrument(level = "debug", skip(stimulus_sites))]
+    pub fn from_stimulus_sites(stimulus_sites: &StimulusSites, number_of_states: usize) -> Self {
+        debug!("Creating control matrix from stimulus sites");
+        let mut control_matrix = Self::empty(number_of_states);
+        for state in &stimulus_sites.states {
+            control_matrix[*state] = 1.0;
+        }
         control_matrix
     }
 
@@ -99,7 +90,7 @@ impl DerefMut for ControlMatrix {
     }
 }
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
 #[allow(clippy::module_name_repetitions)]
 pub struct ControlFunction(Array1<f32>);
 
@@ -111,95 +102,92 @@ impl ControlFunction {
         Self(Array1::zeros(number_of_steps))
     }
 
-    /// Creates a new `ControlFunction` by reading a control function .npy file,
-    /// resampling it to match the given sample rate and duration, and returning
-    /// the resampled values as a new `ControlFunction`.
+    /// Creates a new `ControlFunction` from the given `Model` configuration,
+    /// matching the given sample rate and duration.
     ///
-    /// The control function .npy file is assumed to be located in `assets/`.
-    /// The resampling is done by looping through the target number of samples
-    /// based on sample rate and duration, and taking values from the .npy file
-    /// using modulo to wrap the index.
+    /// The Ohara waveform is read from `assets/control_function_ohara.npy`,
+    /// which is recorded at 2 kHz. Custom waveforms are read from the .npy or
+    /// .csv file at `control_function_path` with their own sample rate. Both
+    /// are resampled to the given sample rate and repeated until the duration
+    /// is reached, either after their own length or, if
+    /// `control_function_cycle_length_s` is set, as a single beat that is cut
+    /// or zero-padded to the cycle length. The cycle length also replaces the
+    /// one second period of the triangle.
     ///
-    /// This allows creating a `ControlFunction` of arbitrary duration from a fixed
-    /// length control function file.
+    /// # Errors
     ///
-    /// # Panics
-    ///
-    /// Panics if the control function input file is missing.
-    #[must_use]
+    /// Returns an error if the waveform can not be read or resampled, or if a
+    /// custom control function has no path.
     #[tracing::instrument(level = "debug")]
     #[allow(
         clippy::cast_possible_truncation,
         clippy::cast_precision_loss,
         clippy::cast_sign_loss
     )]
-
-    pub fn from_model_config(config: &Model, sample_rate_hz: f32, duration_s: f32) -> Self {
+    pub fn from_model_config(
+        config: &Model,
+        sample_rate_hz: f32,
+        duration_s: f32,
+    ) -> Result<Self, Box<dyn Error>> {
         debug!("Creating control function from model config");
         let desired_length_samples = (duration_s * sample_rate_hz) as usize;
+        let cycle_length_samples = config
+            .common
+            .control_function_cycle_length_s
+            .map(|cycle_length_s| (cycle_length_s * sample_rate_hz) as usize);
 
         match config.common.control_function {
             config::model::ControlFunction::Ohara => {
-                let mut control_function_raw: Array1<f32> =
-                    read_npy("assets/control_function_ohara.npy").unwrap();
-
-                let from_sample_rate_hz = 2000.0;
-
-                if !from_sample_rate_hz.relative_eq(&sample_rate_hz, 1e-3, 1e-3) {
-                    let params = SincInterpolationParameters {
-                        sinc_len: 256,
-                        f_cutoff: 0.95,
-                        oversampling_factor: 256,
-                        interpolation: rubato::SincInterpolationType::Cubic,
-                        window: rubato::WindowFunction::BlackmanHarris2,
-                    };
-                    let mut resampler = SincFixedIn::<f32>::new(
-                        f64::from(sample_rate_hz) / f64::from(from_sample_rate_hz),
-                        10.0,
-                        params,
-                        control_function_raw.len(),
-                        1,
-                    )
-                    .unwrap();
-
-                    let input_frames: Vec<Vec<f32>> = vec![control_function_raw.to_vec()];
-
-                    let output_frames = resampler.process(&input_frames, None).unwrap();
-
-                    control_function_raw = output_frames[0].clone().into();
-                }
-
-                let control_function_values: Vec<f32> = (0..desired_length_samples)
-                    .map(|i| {
-                        let index = i % control_function_raw.len();
-                        control_function_raw[index]
-                    })
-                    .collect();
-
-                return Self(Array1::from(control_function_values));
+                let control_function_raw: Array1<f32> =
+                    read_npy("assets/control_function_ohara.npy")?;
+                let control_function_raw = resample(control_function_raw, 2000.0, sample_rate_hz)?;
+
+                Ok(Self(repeat(
+                    &control_function_raw,
+                    cycle_length_samples,
+                    desired_length_samples,
+                )))
+            }
+            config::model::ControlFunction::Custom => {
+                let path = config
+                    .common
+                    .control_function_path
+                    .as_ref()
+                    .ok_or("A custom control function needs a path.")?;
+                let control_function_raw = read_waveform(path)?;
+                let from_sample_rate_hz = if config.common.control_function_sample_rate_hz > 0.0 {
+                    config.common.control_function_sample_rate_hz
+                } else {
+                    sample_rate_hz
+                };
+                let control_function_raw =
+                    resample(control_function_raw, from_sample_rate_hz, sample_rate_hz)?;
+
+                Ok(Self(repeat(
+                    &control_function_raw,
+                    cycle_length_samples,
+                    desired_length_samples,
+                )))
             }
             config::model::ControlFunction::Triangle => {
-                let mut control_function_values = Array1::<f32>::zeros(desired_length_samples);
+                let period_samples = cycle_length_samples.unwrap_or(sample_rate_hz as usize);
+                let mut triangle = Array1::<f32>::zeros(period_samples);
 
-                let triangle_half_length = (0.5 * sample_rate_hz) as i32;
+                let triangle_half_length = period_samples / 2;
 
                 let increase_per_step = 1.0 / (triangle_half_length + 1) as f32;
 
                 for i in 0..triangle_half_length {
                     let value = (i + 1) as f32 * increase_per_step;
-                    control_function_values[i as usize] = value;
-                    control_function_values[2 * triangle_half_length as usize - i as usize - 1] =
-                        value;
+                    triangle[i] = value;
+                    triangle[2 * triangle_half_length - i - 1] = value;
                 }
 
-                control_function_values[triangle_half_length as usize] = 1.0;
-
-                for i in sample_rate_hz as usize..desired_length_samples {
-                    control_function_values[i] =
-                        control_function_values[i % sample_rate_hz as usize];
+                if triangle_half_length < period_samples {
+                    triangle[triangle_half_length] = 1.0;
                 }
 
-                return Self(control_function_values);
+                Ok(Self(repeat(&triangle, None, desired_length_samples)))
             }
             config::model::ControlFunction::Ramp => {
                 let mut control_function_values = Array1::<f32>::zeros(desired_length_samples);
@@ -210,7 +198,7 @@ impl ControlFunction {
                     let value = i as f32 * increase_per_step;
                     control_function_values[i] = -value;
                 }
-                return Self(control_function_values);
+                Ok(Self(control_function_values))
             }
         }
     }
@@ -244,6 +232,101 @@ impl ControlFunction {
     }
 }
 
+/// Reads a waveform from a .npy or .csv file.
+///
+/// CSV files hold one sample per line. The value is taken from the last
+/// column, so a leading time column is ignored. The first line is skipped if
+/// it is a header and empty lines are ignored, any other line that can not
+/// be parsed is an error.
+#[tracing::instrument(level = "debug")]
+fn read_waveform(path: &Path) -> Result<Array1<f32>, Box<dyn Error>> {
+    debug!("Reading control function waveform");
+    match path.extension().and_then(OsStr::to_str) {
+        Some("npy") => Ok(read_npy(path)?),
+        Some("csv") => {
+            let mut values = Vec::new();
+            for (index, line) in fs::read_to_string(path)?.lines().enumerate() {
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let value = line.rsplit([',', ';']).next().unwrap_or_default().trim();
+                match value.parse() {
+                    Ok(value) => values.push(value),
+                    Err(_) if index == 0 => {}
+                    Err(error) => {
+                        return Err(format!(
+                            "Could not parse line {} of {}: {error}.",
+                            index + 1,
+                            path.display()
+                        )
+                        .into());
+                    }
+                }
+            }
+            if values.is_empty() {
+                return Err(format!("No samples found in {}.", path.display()).into());
+            }
+            Ok(Array1::from(values))
+        }
+        _ => Err(format!(
+            "Unsupported control function file {}, expected .npy or .csv.",
+            path.display()
+        )
+        .into()),
+    }
+}
+
+/// Resamples a waveform to the given sample rate using sinc interpolation.
+#[tracing::instrument(level = "debug", skip(waveform))]
+fn resample(
+    waveform: Array1<f32>,
+    from_sample_rate_hz: f32,
+    to_sample_rate_hz: f32,
+) -> Result<Array1<f32>, Box<dyn Error>> {
+    if from_sample_rate_hz.relative_eq(&to_sample_rate_hz, 1e-3, 1e-3) {
+        return Ok(waveform);
+    }
+    debug!("Resampling control function waveform");
+    let params = SincInterpolationParameters {
+        sinc_len: 256,
+        f_cutoff: 0.95,
+        oversampling_factor: 256,
+        interpolation: rubato::SincInterpolationType::Cubic,
+        window: rubato::WindowFunction::BlackmanHarris2,
+    };
+    let mut resampler = SincFixedIn::<f32>::new(
+        f64::from(to_sample_rate_hz) / f64::from(from_sample_rate_hz),
+        10.0,
+        params,
+        waveform.len(),
+        1,
+    )?;
+
+    let input_frames: Vec<Vec<f32>> = vec![waveform.to_vec()];
+
+    let output_frames = resampler.process(&input_frames, None)?;
+
+    Ok(output_frames[0].clone().into())
+}
+
+/// Repeats a waveform until it has the desired length.
+///
+/// If a cycle length is given, the waveform is treated as a single beat that
+/// is cut or zero-padded to the cycle length before it is repeated.
+fn repeat(
+    waveform: &Array1<f32>,
+    cycle_length_samples: Option<usize>,
+    desired_length_samples: usize,
+) -> Array1<f32> {
+    let period_samples = cycle_length_samples.unwrap_or(waveform.len());
+    if period_samples == 0 {
+        return Array1::zeros(desired_length_samples);
+    }
+    (0..desired_length_samples)
+        .map(|i| waveform.get(i % period_samples).copied().unwrap_or(0.0))
+        .collect()
+}
+
 impl Deref for ControlFunction {
     type Target = Array1<f32>;
 
@@ -268,7 +351,10 @@ mod test {
     use approx::assert_relative_eq;
 
     use super::*;
-    use crate::{core::config::model::Model, vis::plotting::png::line::standard_time_plot};
+    use crate::{
+        core::{config::model::Model, model::spatial::SpatialDescription},
+        vis::plotting::png::line::standard_time_plot,
+    };
 
     const COMMON_PATH: &str = "tests/core/model/functional/control/";
 
@@ -285,11 +371,16 @@ mod test {
     }
 
     #[test]
-    fn matrix_from_model_config_no_crash() {
+    fn matrix_from_stimulus_sites_no_crash() {
         let config = Model::default();
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
+        let stimulus_sites =
+            StimulusSites::from_model_config(&config, &spatial_description, 2000.0);
 
-        let control_matrix = ControlMatrix::from_model_config(&config, &spatial_description);
+        let control_matrix = ControlMatrix::from_stimulus_sites(
+            &stimulus_sites,
+            spatial_description.voxels.count_states(),
+        );
         let sum = control_matrix.sum();
         assert_relative_eq!(sum, 1.0);
     }
@@ -307,7 +398,7 @@ mod test {
         let config = Model::default();
 
         let control_function =
-            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s);
+            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s).unwrap();
         assert_eq!(expected_length_samples, control_function.shape()[0]);
     }
 
@@ -326,7 +417,7 @@ mod test {
         config.common.control_function = config::model::ControlFunction::Ohara;
 
         let control_function =
-            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s);
+            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s).unwrap();
         assert_eq!(expected_length_samples, control_function.shape()[0]);
 
         let path = Path::new(COMMON_PATH).join("control_function_ohara.png");
@@ -355,7 +446,7 @@ mod test {
         config.common.control_function = config::model::ControlFunction::Triangle;
 
         let control_function =
-            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s);
+            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s).unwrap();
         assert_eq!(expected_length_samples, control_function.shape()[0]);
 
         let path = Path::new(COMMON_PATH).join("control_function_triangle.png");
@@ -384,7 +475,7 @@ mod test {
         config.common.control_function = config::model::ControlFunction::Ramp;
 
         let control_function =
-            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s);
+            ControlFunction::from_model_config(&config, sample_rate_hz, duration_s).unwrap();
         assert_eq!(expected_length_samples, control_function.shape()[0]);
 
         let path = Path::new(COMMON_PATH).join("control_function_ramp.png");
@@ -397,4 +488,44 @@ mod test {
         )
         .unwrap();
     }
+
+    #[test]
+    fn custom_function_from_csv_repeated_with_cycle_length() {
+        setup(Some("custom"));
+        let path = Path::new(COMMON_PATH).join("custom").join("waveform.csv");
+        std::fs::write(&path, "time,value\n0.000,1.0\n0.001,2.0\n0.002,3.0\n").unwrap();
+        let mut config = Model::default();
+        config.common.control_function = config::model::ControlFunction::Custom;
+        config.common.control_function_path = Some(path);
+        config.common.control_function_sample_rate_hz = 1000.0;
+        config.common.control_function_cycle_length_s = Some(0.005);
+
+        let control_function = ControlFunction::from_model_config(&config, 1000.0, 0.01).unwrap();
+
+        assert_relative_eq!(
+            control_function.as_slice().unwrap(),
+            [1.0, 2.0, 3.0, 0.0, 0.0, 1.0, 2.0, 3.0, 0.0, 0.0].as_slice()
+        );
+    }
+
+    #[test]
+    fn custom_function_from_csv_with_invalid_line_fails() {
+        setup(Some("custom"));
+        let path = Path::new(COMMON_PATH).join("custom").join("invalid.csv");
+        std::fs::write(&path, "time,value\n0.000,1.0\n0.001,n/a\n0.002,3.0\n").unwrap();
+        let mut config = Model::default();
+        config.common.control_function = config::model::ControlFunction::Custom;
+        config.common.control_function_path = Some(path);
+        config.common.control_function_sample_rate_hz = 1000.0;
+
+        assert!(ControlFunction::from_model_config(&config, 1000.0, 0.01).is_err());
+    }
+
+    #[test]
+    fn custom_function_without_path_fails() {
+        let mut config = Model::default();
+        config.common.control_function = config::model::ControlFunction::Custom;
+
+        assert!(ControlFunction::from_model_config(&config, 1000.0, 1.0).is_err());
+    }
 }
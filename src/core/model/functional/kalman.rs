@@ -147,7 +147,7 @@ mod tests {
     #[test]
     fn from_model_config_no_crash() {
         let config = Model::default();
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
         let measurement_matrix =
             MeasurementMatrix::from_model_spatial_description(&spatial_description);
 
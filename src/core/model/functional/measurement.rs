@@ -1,20 +1,26 @@
 use std::{
+    error::Error,
     f32::consts::PI,
     fs::{self, File},
     io::BufWriter,
     ops::{Deref, DerefMut},
+    path::Path,
 };
 
 use approx::relative_eq;
-use ndarray::{s, Array2, Array3, ArrayView2};
-use ndarray_npy::WriteNpyExt;
+use nalgebra::DMatrix;
+use ndarray::{s, Array1, Array2, Array3, ArrayView2, Axis};
+use ndarray_npy::{read_npy, WriteNpyExt};
 use ocl::{Buffer, Queue};
 use physical_constants::VACUUM_MAG_PERMEABILITY;
 use rand_distr::{Distribution, Normal};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace};
 
-use crate::core::{config::model::Model, model::spatial::SpatialDescription};
+use crate::core::{
+    config::model::Model,
+    model::spatial::{sensors::Sensors, SpatialDescription},
+};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 #[allow(clippy::module_name_repetitions, clippy::unsafe_derive_deserialize)]
@@ -181,18 +187,40 @@ impl MeasurementCovariance {
     }
 
     /// Creates a new `MeasurementCovariance` initialized from the model
-    /// configuration. The diagonal is filled with random values drawn from
-    /// a normal distribution with the configured mean and standard deviation.
-    /// If the standard deviation is 0, the diagonal is filled with the mean.
-    //
+    /// configuration.
+    ///
+    /// If a covariance file or an empty-room recording is configured, the
+    /// covariance is loaded or estimated from it. Otherwise the diagonal is
+    /// filled with random values drawn from a normal distribution with the
+    /// configured mean and standard deviation. If the standard deviation is
+    /// 0, the diagonal is filled with the mean. With a correlation length
+    /// larger than zero, the off-diagonal elements are set by
+    /// [`MeasurementCovariance::correlate`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the configured file can not be read or does not
+    /// match the number of sensors.
+    ///
     /// # Panics
     ///
     /// Panics if voxel numbers are not initialized correctly.
-    #[must_use]
     #[tracing::instrument(level = "debug")]
-    pub fn from_model_config(config: &Model, spatial_description: &SpatialDescription) -> Self {
+    pub fn from_model_config(
+        config: &Model,
+        spatial_description: &SpatialDescription,
+    ) -> Result<Self, Box<dyn Error>> {
         debug!("Creating measurement covariance from model config");
-        let mut measurement_covariance = Self::empty(spatial_description.sensors.count());
+        let number_of_sensors = spatial_description.sensors.count();
+        if let Some(path) = &config.common.measurement_covariance_path {
+            return Self::from_npy(path, number_of_sensors);
+        }
+        if let Some(path) = &config.common.empty_room_recording_path {
+            let recording: Array2<f32> = read_npy(path)?;
+            return Self::from_empty_room_recording(&recording.view(), number_of_sensors);
+        }
+
+        let mut measurement_covariance = Self::empty(number_of_sensors);
 
         if relative_eq!(config.common.measurement_covariance_std, 0.0) {
             measurement_covariance
@@ -209,16 +237,148 @@ impl MeasurementCovariance {
             });
         }
 
-        measurement_covariance
+        if config.common.measurement_covariance_correlation_length_mm > 0.0 {
+            measurement_covariance.correlate(
+                &spatial_description.sensors,
+                config.common.measurement_covariance_correlation_length_mm,
+            );
+        }
+
+        Ok(measurement_covariance)
+    }
+
+    /// Loads a full measurement covariance matrix from a .npy file.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file can not be read or the matrix is not
+    /// a symmetric (`number_of_sensors`, `number_of_sensors`) matrix.
+    #[tracing::instrument(level = "debug")]
+    pub fn from_npy(path: &Path, number_of_sensors: usize) -> Result<Self, Box<dyn Error>> {
+        debug!("Loading measurement covariance from npy");
+        let covariance: Array2<f32> = read_npy(path)?;
+        if covariance.shape() != [number_of_sensors, number_of_sensors] {
+            return Err(format!(
+                "Measurement covariance has shape {:?}, expected {:?}.",
+                covariance.shape(),
+                [number_of_sensors, number_of_sensors]
+            )
+            .into());
+        }
+        if covariance
+            .iter()
+            .zip(covariance.t().iter())
+            .any(|(a, b)| !relative_eq!(a, b, epsilon = 1e-6, max_relative = 1e-4))
+        {
+            return Err("Measurement covariance is not symmetric.".into());
+        }
+        Ok(Self(covariance))
+    }
+
+    /// Estimates the measurement covariance from an empty-room recording,
+    /// i.e. a recording without a source present.
+    ///
+    /// The recording has dimensions (`number_of_samples`, `number_of_sensors`).
+    /// The mean of every sensor is removed before the sample covariance is
+    /// calculated.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the number of sensors does not match or if the
+    /// recording contains less than two samples.
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "debug", skip(recording))]
+    pub fn from_empty_room_recording(
+        recording: &ArrayView2<f32>,
+        number_of_sensors: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        debug!("Estimating measurement covariance from empty-room recording");
+        let (number_of_samples, recorded_sensors) = recording.dim();
+        if recorded_sensors != number_of_sensors {
+            return Err(format!(
+                "Empty-room recording has {recorded_sensors} sensors, \
+                expected {number_of_sensors}."
+            )
+            .into());
+        }
+        if number_of_samples < 2 {
+            return Err("Empty-room recording needs at least two samples.".into());
+        }
+        let mean = recording
+            .mean_axis(Axis(0))
+            .ok_or("Empty-room recording is empty.")?;
+        let centered = recording - &mean;
+        let covariance = centered.t().dot(&centered) / (number_of_samples - 1) as f32;
+        Ok(Self(covariance))
     }
 
-    /// Saves the process covariance matrix to a .npy file at the given path.
+    /// Sets the off-diagonal elements to model spatially correlated noise.
+    ///
+    /// The covariance of two sensors is the geometric mean of their
+    /// variances, scaled by the dot product of their orientations and by
+    /// `exp(-distance / correlation_length_mm)`. Both factors are positive
+    /// semi-definite kernels, so the result stays a valid covariance.
+    #[tracing::instrument(level = "debug", skip(sensors))]
+    pub fn correlate(&mut self, sensors: &Sensors, correlation_length_mm: f32) {
+        debug!("Correlating measurement covariance");
+        let std = self.diag().mapv(|variance| variance.max(0.0).sqrt());
+        self.indexed_iter_mut()
+            .filter(|((i, j), _)| i != j)
+            .for_each(|((i, j), value)| {
+                let distance_mm = (&sensors.positions_mm.row(i) - &sensors.positions_mm.row(j))
+                    .mapv(|d| d * d)
+                    .sum()
+                    .sqrt();
+                let orientation = sensors
+                    .orientations_xyz
+                    .row(i)
+                    .dot(&sensors.orientations_xyz.row(j));
+                *value =
+                    std[i] * std[j] * orientation * (-distance_mm / correlation_length_mm).exp();
+            });
+    }
+
+    /// Returns true if any two sensors have a non-zero covariance.
+    ///
+    /// This is only the case if correlated noise was configured, either via
+    /// a correlation length, a covariance file or an empty-room recording.
+    #[must_use]
+    pub fn is_correlated(&self) -> bool {
+        self.indexed_iter()
+            .any(|((i, j), value)| i != j && *value != 0.0)
+    }
+
+    /// Calculates the lower triangular Cholesky factor `L` with `R = L L^T`.
+    ///
+    /// If the covariance is only positive semi-definite, a small jitter is
+    /// added to the diagonal. Returns `None` if the covariance is not
+    /// positive semi-definite.
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn cholesky_factor(&self) -> Option<DMatrix<f32>> {
+        debug!("Calculating Cholesky factor of measurement covariance");
+        let number_of_sensors = self.nrows();
+        let covariance =
+            DMatrix::from_row_iterator(number_of_sensors, number_of_sensors, self.iter().copied());
+        covariance
+            .clone()
+            .cholesky()
+            .or_else(|| {
+                let jitter = 1e-6 * covariance.diagonal().max().max(f32::EPSILON);
+                (covariance
+                    + DMatrix::<f32>::identity(number_of_sensors, number_of_sensors) * jitter)
+                    .cholesky()
+            })
+            .map(|cholesky| cholesky.l())
+    }
+
+    /// Saves the measurement covariance matrix to a .npy file at the given path.
     /// Creates the directory if it does not exist.
     #[tracing::instrument(level = "trace")]
     pub(crate) fn save_npy(&self, path: &std::path::Path) {
-        trace!("Saving process covariance matrix to npy file");
+        trace!("Saving measurement covariance matrix to npy file");
         fs::create_dir_all(path).unwrap();
-        let writer = BufWriter::new(File::create(path.join("process_covariance.npy")).unwrap());
+        let writer = BufWriter::new(File::create(path.join("measurement_covariance.npy")).unwrap());
         self.write_npy(writer).unwrap();
     }
 
@@ -255,6 +415,169 @@ impl DerefMut for MeasurementCovariance {
     }
 }
 
+/// Weights of the sensors in the loss.
+///
+/// Has dimensions (`number_of_sensors`). Masked (bad) channels have a weight
+/// of zero. An empty array weights all sensors equally.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+#[allow(clippy::module_name_repetitions, clippy::unsafe_derive_deserialize)]
+pub struct SensorWeights(Array1<f32>);
+
+impl SensorWeights {
+    /// Creates new `SensorWeights` with all sensors weighted equally.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn ones(number_of_sensors: usize) -> Self {
+        debug!("Creating sensor weights");
+        Self(Array1::ones(number_of_sensors))
+    }
+
+    /// Creates new `SensorWeights` from the model configuration.
+    ///
+    /// The weights are loaded from `sensor_weights_path` or taken from
+    /// `sensor_weights`, if set. Afterwards the sensors in `masked_sensors`
+    /// and the non-zero entries of the file at `sensor_mask_path` are masked.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a file can not be read, the number of weights
+    /// does not match the number of sensors, a weight is negative or a
+    /// masked sensor does not exist.
+    #[tracing::instrument(level = "debug")]
+    pub fn from_model_config(
+        config: &Model,
+        number_of_sensors: usize,
+    ) -> Result<Self, Box<dyn Error>> {
+        debug!("Creating sensor weights from model config");
+        let mut weights = Self::ones(number_of_sensors);
+        let configured = match &config.common.sensor_weights_path {
+            Some(path) => Some(read_npy::<_, Array1<f32>>(path)?),
+            None => (!config.common.sensor_weights.is_empty())
+                .then(|| Array1::from(config.common.sensor_weights.clone())),
+        };
+        if let Some(configured) = configured {
+            if configured.len() != number_of_sensors {
+                return Err(format!(
+                    "Got {} sensor weights for {number_of_sensors} sensors.",
+                    configured.len()
+                )
+                .into());
+            }
+            if configured
+                .iter()
+                .any(|weight| weight.is_nan() || *weight < 0.0)
+            {
+                return Err("Sensor weights have to be non-negative.".into());
+            }
+            weights.assign(&configured);
+        }
+
+        let mut masked_sensors = config.common.masked_sensors.clone();
+        if let Some(path) = &config.common.sensor_mask_path {
+            let mask: Array1<f32> = read_npy(path)?;
+            if mask.len() != number_of_sensors {
+                return Err(format!(
+                    "Got a mask with {} entries for {number_of_sensors} sensors.",
+                    mask.len()
+                )
+                .into());
+            }
+            masked_sensors.extend(
+                mask.iter()
+                    .enumerate()
+                    .filter_map(|(sensor, masked)| (*masked != 0.0).then_some(sensor)),
+            );
+        }
+        for sensor in masked_sensors {
+            if sensor >= number_of_sensors {
+                return Err(format!(
+                    "Can not mask sensor {sensor}, there are only {number_of_sensors} sensors."
+                )
+                .into());
+            }
+            weights[sensor] = 0.0;
+        }
+        Ok(weights)
+    }
+
+    /// Returns the weight of the given sensor.
+    #[inline]
+    #[must_use]
+    pub fn weight(&self, sensor: usize) -> f32 {
+        self.get(sensor).copied().unwrap_or(1.0)
+    }
+
+    /// Returns true if the given sensor is masked.
+    #[inline]
+    #[must_use]
+    pub fn is_masked(&self, sensor: usize) -> bool {
+        self.weight(sensor) == 0.0
+    }
+
+    /// Returns the summed weight of the given sensors, which normalizes
+    /// the weighted losses and their derivatives.
+    ///
+    /// Returns one if all sensors are masked.
+    #[must_use]
+    pub fn active_weight_sum(&self, number_of_sensors: usize) -> f32 {
+        let sum = (0..number_of_sensors)
+            .map(|sensor| self.weight(sensor))
+            .sum::<f32>();
+        if sum > 0.0 {
+            sum
+        } else {
+            1.0
+        }
+    }
+
+    pub(crate) fn to_gpu(&self, queue: &ocl::Queue, number_of_sensors: usize) -> Buffer<f32> {
+        let weights: Vec<f32> = (0..number_of_sensors)
+            .map(|sensor| self.weight(sensor))
+            .collect();
+        Buffer::builder()
+            .queue(queue.clone())
+            .len(number_of_sensors)
+            .copy_host_slice(&weights)
+            .build()
+            .unwrap()
+    }
+
+    pub(crate) fn from_gpu(sensor_weights: &Buffer<f32>) -> Self {
+        let mut weights = vec![0.0; sensor_weights.len()];
+        sensor_weights.read(&mut weights).enq().unwrap();
+        Self(Array1::from(weights))
+    }
+
+    /// Saves the sensor weights to a .npy file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file can not be written.
+    #[tracing::instrument(level = "trace")]
+    pub fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving sensor weights to npy");
+        fs::create_dir_all(path).unwrap();
+        let writer = BufWriter::new(File::create(path.join("sensor_weights.npy")).unwrap());
+        self.write_npy(writer).unwrap();
+    }
+}
+
+impl Deref for SensorWeights {
+    type Target = Array1<f32>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for SensorWeights {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::path::Path;
@@ -289,7 +612,7 @@ mod tests {
             },
             ..Default::default()
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let measurement_matrix =
             MeasurementMatrix::from_model_spatial_description(&spatial_description);
@@ -308,7 +631,7 @@ mod tests {
             },
             ..Default::default()
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let measurement_matrix =
             MeasurementMatrix::from_model_spatial_description(&spatial_description);
@@ -345,7 +668,7 @@ mod tests {
             },
             ..Default::default()
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let measurement_matrix =
             MeasurementMatrix::from_model_spatial_description(&spatial_description);
@@ -391,14 +714,101 @@ mod tests {
             ..Default::default()
         };
 
-        let spatial_description_full = SpatialDescription::from_model_config(&config_full);
+        let spatial_description_full = SpatialDescription::from_model_config(&config_full).unwrap();
         let measurement_matrix_full =
             MeasurementMatrix::from_model_spatial_description(&spatial_description_full);
 
-        let spatial_description_sparse = SpatialDescription::from_model_config(&config_sparse);
+        let spatial_description_sparse =
+            SpatialDescription::from_model_config(&config_sparse).unwrap();
         let measurement_matrix_sparse =
             MeasurementMatrix::from_model_spatial_description(&spatial_description_sparse);
 
         assert_eq!(measurement_matrix_full, measurement_matrix_sparse);
     }
+
+    #[test]
+    fn correlated_covariance_is_positive_definite() {
+        let config = Model {
+            common: Common {
+                sensors_per_axis: [3, 3, 3],
+                voxel_size_mm: 20.0,
+                measurement_covariance_correlation_length_mm: 100.0,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
+
+        let measurement_covariance =
+            MeasurementCovariance::from_model_config(&config, &spatial_description).unwrap();
+
+        assert!(measurement_covariance
+            .iter()
+            .zip(measurement_covariance.t().iter())
+            .all(|(a, b)| relative_eq!(a, b)));
+        assert!(measurement_covariance
+            .indexed_iter()
+            .any(|((i, j), value)| i != j && *value > 0.0));
+        assert!(measurement_covariance.cholesky_factor().is_some());
+    }
+
+    #[test]
+    fn empty_room_recording_covariance() {
+        let recording =
+            ndarray::arr2(&[[1.0, 2.0], [3.0, 2.0], [1.0, 4.0], [3.0, 4.0], [2.0, 3.0]]);
+
+        let measurement_covariance =
+            MeasurementCovariance::from_empty_room_recording(&recording.view(), 2).unwrap();
+
+        assert!(relative_eq!(measurement_covariance[(0, 0)], 1.0));
+        assert!(relative_eq!(measurement_covariance[(1, 1)], 1.0));
+        assert!(relative_eq!(measurement_covariance[(0, 1)], 0.0));
+        assert!(MeasurementCovariance::from_empty_room_recording(&recording.view(), 3).is_err());
+    }
+
+    #[test]
+    fn sensor_weights_masked_sensors() {
+        let config = Model {
+            common: Common {
+                sensor_weights: vec![1.0, 2.0, 0.5, 1.0],
+                masked_sensors: vec![2],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let weights = SensorWeights::from_model_config(&config, 4).unwrap();
+
+        assert_eq!(weights.to_vec(), vec![1.0, 2.0, 0.0, 1.0]);
+        assert!(weights.is_masked(2));
+        assert!(!weights.is_masked(1));
+        assert!(relative_eq!(weights.active_weight_sum(4), 4.0));
+        assert!(relative_eq!(
+            SensorWeights::default().active_weight_sum(3),
+            3.0
+        ));
+        assert!(relative_eq!(
+            SensorWeights(Array1::zeros(2)).active_weight_sum(2),
+            1.0
+        ));
+    }
+
+    #[test]
+    fn sensor_weights_invalid_config() {
+        let mut config = Model {
+            common: Common {
+                masked_sensors: vec![4],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(SensorWeights::from_model_config(&config, 4).is_err());
+
+        config.common.masked_sensors.clear();
+        config.common.sensor_weights = vec![1.0, -1.0, 1.0, 1.0];
+        assert!(SensorWeights::from_model_config(&config, 4).is_err());
+
+        config.common.sensor_weights = vec![1.0; 3];
+        assert!(SensorWeights::from_model_config(&config, 4).is_err());
+    }
 }
@@ -0,0 +1,223 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+};
+
+use ndarray::{arr1, s, Array2};
+use ndarray_npy::WriteNpyExt;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace, warn};
+
+use crate::core::{
+    config::model::Model,
+    model::spatial::{voxels::VoxelType, SpatialDescription},
+};
+
+/// Voxels the control function is injected at, each with its own onset in
+/// every beat.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+pub struct StimulusSites {
+    /// Voxel index of every site.
+    pub voxels: Vec<(usize, usize, usize)>,
+    /// First state of the voxel of every site.
+    pub states: Vec<usize>,
+    /// Onsets of every site in samples, one per beat. The last onset is used
+    /// for all following beats.
+    pub onsets_samples: Vec<Vec<f32>>,
+}
+
+impl StimulusSites {
+    /// Creates the stimulus sites from the `stimuli` of the model config.
+    ///
+    /// Without configured stimuli every sinoatrial voxel is a site with an
+    /// onset of zero, as in the original model.
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(
+        config: &Model,
+        spatial_description: &SpatialDescription,
+        sample_rate_hz: f32,
+    ) -> Self {
+        debug!("Creating stimulus sites from model config");
+        let voxels = site_voxels(config, spatial_description);
+        if voxels.is_empty() {
+            return Self::default();
+        }
+        let states = voxels
+            .iter()
+            .map(|index| {
+                spatial_description.voxels.numbers[*index].expect("Stimulus voxel to have states.")
+            })
+            .collect();
+        let onsets_samples = if config.common.stimuli.is_empty() {
+            vec![vec![0.0]; voxels.len()]
+        } else {
+            config
+                .common
+                .stimuli
+                .iter()
+                .map(|stimulus| {
+                    stimulus
+                        .onsets_s
+                        .iter()
+                        .map(|onset_s| onset_s * sample_rate_hz)
+                        .collect()
+                })
+                .collect()
+        };
+        Self {
+            voxels,
+            states,
+            onsets_samples,
+        }
+    }
+
+    /// Returns the onset of the given site in the given beat in samples.
+    #[inline]
+    #[must_use]
+    pub fn onset_samples(&self, site: usize, beat: usize) -> f32 {
+        let onsets = &self.onsets_samples[site];
+        onsets
+            .get(beat)
+            .or_else(|| onsets.last())
+            .copied()
+            .unwrap_or(0.0)
+    }
+
+    /// Returns true if any site is stimulated later than at the start of a
+    /// beat, in which case the control function has to be shifted per site.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn has_onsets(&self) -> bool {
+        self.onsets_samples
+            .iter()
+            .flatten()
+            .any(|onset_samples| *onset_samples != 0.0)
+    }
+
+    /// Returns true if the onsets of all sites shift by the same amount from
+    /// the first beat to every following one, i.e. all beats share the
+    /// activation sequence of the first beat.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn beats_share_sequence(&self) -> bool {
+        let number_of_beats = self.onsets_samples.iter().map(Vec::len).max().unwrap_or(0);
+        (1..number_of_beats).all(|beat| {
+            let shift = |site: usize| self.onset_samples(site, beat) - self.onset_samples(site, 0);
+            (1..self.voxels.len()).all(|site| (shift(site) - shift(0)).abs() < f32::EPSILON)
+        })
+    }
+
+    /// Saves the voxel indices of the sites to a .npy file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file can not be written.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    #[tracing::instrument(level = "trace")]
+    pub fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving stimulus sites to npy");
+        fs::create_dir_all(path).unwrap();
+        let mut voxels = Array2::<i64>::zeros((self.voxels.len(), 3));
+        for (mut row, (x, y, z)) in voxels.rows_mut().into_iter().zip(&self.voxels) {
+            row.assign(&arr1(&[*x as i64, *y as i64, *z as i64]));
+        }
+        let writer = BufWriter::new(File::create(path.join("stimulus_voxels.npy")).unwrap());
+        voxels.write_npy(writer).unwrap();
+    }
+}
+
+/// Returns the voxel of every stimulus site.
+///
+/// Without configured stimuli every sinoatrial voxel is a site. Otherwise
+/// every stimulus is placed at the connectable voxel outside of scar closest
+/// to its position, which is given relative to the heart corner like the
+/// pathologies.
+#[tracing::instrument(level = "debug", skip_all)]
+fn site_voxels(
+    config: &Model,
+    spatial_description: &SpatialDescription,
+) -> Vec<(usize, usize, usize)> {
+    debug!("Finding stimulus site voxels");
+    let voxels = &spatial_description.voxels;
+    if config.common.stimuli.is_empty() {
+        return voxels
+            .types
+            .indexed_iter()
+            .filter(|(_, voxel_type)| **voxel_type == VoxelType::Sinoatrial)
+            .map(|(index, _)| index)
+            .collect();
+    }
+    let voxel_size_mm = config.common.voxel_size_mm;
+    let corner_mm = voxels.positions_mm.corner_mm(voxel_size_mm);
+    config
+        .common
+        .stimuli
+        .iter()
+        .enumerate()
+        .filter_map(|(number, stimulus)| {
+            let target_mm = &corner_mm + &arr1(&stimulus.position_mm);
+            let closest = voxels
+                .types
+                .indexed_iter()
+                .filter(|(index, voxel_type)| voxel_type.is_connectable() && !voxels.scar[*index])
+                .map(|((x, y, z), _)| {
+                    let distance_mm = (&voxels.positions_mm.slice(s![x, y, z, ..]) - &target_mm)
+                        .mapv(|d| d.powi(2))
+                        .sum()
+                        .sqrt();
+                    ((x, y, z), distance_mm)
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            if closest.is_none() {
+                warn!("Stimulus {number} could not be placed, the model has no heart voxels.");
+            }
+            if let Some((_, distance_mm)) = closest.filter(|(_, d)| *d > voxel_size_mm) {
+                warn!("Stimulus {number} lies {distance_mm} mm away from the closest heart voxel.");
+            }
+            closest.map(|(index, _)| index)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+
+    use super::*;
+    use crate::{core::config::model::Stimulus, tests::handcrafted_model};
+
+    #[test]
+    fn stimuli_are_placed_at_closest_voxel() {
+        let mut config = handcrafted_model([10.0, 10.0, 1.0]);
+        config.common.stimuli = vec![
+            Stimulus::default(),
+            Stimulus {
+                position_mm: [2.4, 7.6, 0.5],
+                onsets_s: vec![0.1, 0.2],
+            },
+        ];
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
+        let sites = StimulusSites::from_model_config(&config, &spatial_description, 1000.0);
+
+        assert_eq!(sites.voxels, vec![(9, 9, 0), (2, 7, 0)]);
+        assert!(sites.has_onsets());
+        assert_relative_eq!(sites.onset_samples(0, 3), 0.0);
+        assert_relative_eq!(sites.onset_samples(1, 0), 100.0);
+        assert_relative_eq!(sites.onset_samples(1, 5), 200.0);
+        assert!(!sites.beats_share_sequence());
+    }
+
+    #[test]
+    fn shifted_onsets_share_the_sequence() {
+        let mut sites = StimulusSites {
+            voxels: vec![(0, 0, 0), (1, 0, 0)],
+            states: vec![0, 3],
+            onsets_samples: vec![vec![0.0, 10.0], vec![5.0, 15.0, 15.0]],
+        };
+        assert!(sites.beats_share_sequence());
+
+        sites.onsets_samples[1][2] = 5.0;
+        assert!(!sites.beats_share_sequence());
+    }
+}
@@ -0,0 +1,198 @@
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+};
+
+use ndarray::Array1;
+use ndarray_npy::WriteNpyExt;
+use rand::Rng;
+use rand_distr::{Distribution, Normal};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use super::control::ControlFunction;
+
+/// Time shift between the model and the measurements of every beat.
+///
+/// The offsets are given in samples and may be fractional. A positive offset
+/// means that the beat starts later in the measurements than in the model,
+/// i.e. the control function is delayed. Empty offsets shift no beat.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+#[allow(clippy::module_name_repetitions)]
+pub struct TimeOffsets {
+    pub samples: Array1<f32>,
+    /// Set once the offsets were initialized by cross-correlation.
+    pub initialized: bool,
+}
+
+impl TimeOffsets {
+    /// Creates new `TimeOffsets` without a shift for the given number of beats.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn zeros(number_of_beats: usize) -> Self {
+        debug!("Creating time offsets");
+        Self {
+            samples: Array1::zeros(number_of_beats),
+            initialized: false,
+        }
+    }
+
+    /// Draws normally distributed offsets, used to jitter the beat onset of
+    /// simulations.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the standard deviation is negative or not finite.
+    #[tracing::instrument(level = "debug", skip(rng))]
+    pub fn random<R: Rng>(
+        number_of_beats: usize,
+        standard_deviation_samples: f32,
+        rng: &mut R,
+    ) -> Self {
+        debug!("Creating random time offsets");
+        let distribution =
+            Normal::new(0.0, standard_deviation_samples).expect("Standard deviation to be valid.");
+        Self {
+            samples: (0..number_of_beats)
+                .map(|_| distribution.sample(rng))
+                .collect(),
+            initialized: true,
+        }
+    }
+
+    /// Returns the offset of the given beat in samples.
+    #[inline]
+    #[must_use]
+    pub fn at_beat(&self, beat: usize) -> f32 {
+        self.samples.get(beat).copied().unwrap_or(0.0)
+    }
+
+    /// Returns the control function index of the given step shifted by the
+    /// offset of the beat, together with the fraction towards the next index.
+    ///
+    /// Returns `None` if the shifted step lies before the control function.
+    #[inline]
+    #[must_use]
+    pub fn control_position(&self, beat: usize, step: usize) -> Option<(usize, f32)> {
+        self.delayed_control_position(beat, step, 0.0)
+    }
+
+    /// Returns the control function index like `control_position` for a
+    /// stimulus site whose onset is delayed by the given number of samples.
+    #[allow(
+        clippy::cast_precision_loss,
+        clippy::cast_possible_truncation,
+        clippy::cast_sign_loss
+    )]
+    #[inline]
+    #[must_use]
+    pub fn delayed_control_position(
+        &self,
+        beat: usize,
+        step: usize,
+        onset_samples: f32,
+    ) -> Option<(usize, f32)> {
+        let offset = self.at_beat(beat) + onset_samples;
+        if offset == 0.0 {
+            return Some((step, 0.0));
+        }
+        let position = step as f32 - offset;
+        if position < 0.0 {
+            return None;
+        }
+        Some((position.floor() as usize, position - position.floor()))
+    }
+
+    /// Returns the control function value of the given step shifted by the
+    /// offset of the beat.
+    ///
+    /// Fractional offsets are linearly interpolated, values outside of the
+    /// control function are zero.
+    #[inline]
+    #[must_use]
+    pub fn control_value(
+        &self,
+        control_function: &ControlFunction,
+        beat: usize,
+        step: usize,
+    ) -> f32 {
+        self.delayed_control_value(control_function, beat, step, 0.0)
+    }
+
+    /// Returns the control function value like `control_value` for a
+    /// stimulus site whose onset is delayed by the given number of samples.
+    #[inline]
+    #[must_use]
+    pub fn delayed_control_value(
+        &self,
+        control_function: &ControlFunction,
+        beat: usize,
+        step: usize,
+        onset_samples: f32,
+    ) -> f32 {
+        let Some((index, fraction)) = self.delayed_control_position(beat, step, onset_samples)
+        else {
+            return 0.0;
+        };
+        let value = |index: usize| control_function.get(index).copied().unwrap_or(0.0);
+        if fraction == 0.0 {
+            return value(index);
+        }
+        (1.0 - fraction).mul_add(value(index), fraction * value(index + 1))
+    }
+
+    /// Copies the offsets of the given number of beats to the gpu, beats
+    /// without an offset are not shifted.
+    pub(crate) fn to_gpu(&self, queue: &ocl::Queue, number_of_beats: usize) -> ocl::Buffer<f32> {
+        let samples: Vec<f32> = (0..number_of_beats.max(1))
+            .map(|beat| self.at_beat(beat))
+            .collect();
+        ocl::Buffer::builder()
+            .queue(queue.clone())
+            .len(samples.len())
+            .copy_host_slice(&samples)
+            .build()
+            .unwrap()
+    }
+
+    /// Saves the offsets in samples to a .npy file.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the file can not be written.
+    #[tracing::instrument(level = "trace")]
+    pub fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving time offsets to npy");
+        fs::create_dir_all(path).unwrap();
+        let writer = BufWriter::new(File::create(path.join("time_offsets_samples.npy")).unwrap());
+        self.samples.write_npy(writer).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use approx::assert_relative_eq;
+    use ndarray::Array1;
+
+    use super::*;
+
+    #[test]
+    fn control_value_interpolates_shift() {
+        let mut control_function = ControlFunction::empty(4);
+        control_function.assign(&Array1::from(vec![0.0, 1.0, 2.0, 3.0]));
+        let offsets = TimeOffsets {
+            samples: Array1::from(vec![0.0, 1.5, -1.0]),
+            initialized: true,
+        };
+
+        assert_relative_eq!(offsets.control_value(&control_function, 0, 2), 2.0);
+        assert_relative_eq!(offsets.control_value(&control_function, 1, 0), 0.0);
+        assert_relative_eq!(offsets.control_value(&control_function, 1, 3), 1.5);
+        assert_relative_eq!(offsets.control_value(&control_function, 2, 1), 2.0);
+        assert_relative_eq!(offsets.control_value(&control_function, 2, 3), 0.0);
+        assert_relative_eq!(
+            offsets.delayed_control_value(&control_function, 2, 3, 2.5),
+            1.5
+        );
+    }
+}
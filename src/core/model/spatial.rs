@@ -38,19 +38,22 @@ impl SpatialDescription {
     ///
     /// Constructs the `heart`, `voxels`, and `sensors` fields by calling their
     /// respective `from_model_config()` methods.
-    #[must_use]
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the voxels can not be created.
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn from_model_config(config: &Model) -> Self {
+    pub fn from_model_config(config: &Model) -> Result<Self, String> {
         debug!("Creating spatial description from model config");
         let voxels = if config.handcrafted.is_some() {
-            Voxels::from_handcrafted_model_config(config)
+            Voxels::from_handcrafted_model_config(config)?
         } else {
-            Voxels::from_mri_model_config(config)
+            Voxels::from_mri_model_config(config)?
         };
 
         let sensors = Sensors::from_model_config(&config.common);
 
-        Self { voxels, sensors }
+        Ok(Self { voxels, sensors })
     }
 
     /// Saves the spatial description components to .npy files.
@@ -94,7 +97,7 @@ mod tests {
     #[test]
     fn from_simulation_config_no_crash() {
         let config = Model::default();
-        let _spatial_description = SpatialDescription::from_model_config(&config);
+        let _spatial_description = SpatialDescription::from_model_config(&config).unwrap();
     }
 
     #[test]
@@ -104,7 +107,7 @@ mod tests {
             handcrafted: Some(Handcrafted::default()),
             mri: None,
         };
-        let _spatial_description = SpatialDescription::from_model_config(&config);
+        let _spatial_description = SpatialDescription::from_model_config(&config).unwrap();
     }
 
     #[test]
@@ -114,7 +117,7 @@ mod tests {
             handcrafted: None,
             mri: Some(Mri::default()),
         };
-        let _spatial_description = SpatialDescription::from_model_config(&config);
+        let _spatial_description = SpatialDescription::from_model_config(&config).unwrap();
     }
 
     #[test]
@@ -127,7 +130,7 @@ mod tests {
             handcrafted: Some(Handcrafted::default()),
             mri: None,
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let duration_ms = 5000;
         let path = directory.join("types_over_x.gif");
@@ -177,7 +180,7 @@ mod tests {
             handcrafted: None,
             mri: Some(Mri::default()),
         };
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let duration_ms = 5000;
         let path = directory.join("types_over_x.gif");
@@ -228,7 +231,7 @@ mod tests {
             mri: Some(Mri::default()),
         };
         config.common.voxel_size_mm = 10.0;
-        let spatial_description = SpatialDescription::from_model_config(&config);
+        let spatial_description = SpatialDescription::from_model_config(&config).unwrap();
 
         let duration_ms = 5000;
         let path = directory.join("types_over_x_coarse.gif");
@@ -1,38 +1,431 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    path::Path,
+};
 
-use ndarray::{s, Ix3};
-use nifti::{IntoNdArray, NiftiObject, ReaderOptions};
+use nalgebra::{Matrix3, Quaternion, UnitQuaternion, Vector3};
+use ndarray::{Array3, Axis, Ix3};
+use nifti::{IntoNdArray, NiftiHeader, NiftiObject, ReaderOptions};
 use strum::EnumCount;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
 use super::voxels::VoxelType;
-use crate::core::config::model::Model;
+use crate::core::config::model::{Model, SegmentationLabels};
 
 #[derive(Debug)]
 pub struct MriData {
     pub segmentation: ndarray::ArrayBase<ndarray::OwnedRepr<f32>, ndarray::Dim<[usize; 3]>>,
     pub voxel_size_mm: [f32; 3],
+    /// Model coordinates of the first voxel of the segmentation in mm, taken
+    /// from the translation of the affine.
+    pub origin_mm: [f32; 3],
+    pub orientation: MriOrientation,
 }
 
+impl MriData {
+    /// Creates an empty, axis aligned segmentation with unit voxels at the
+    /// origin.
+    #[must_use]
+    #[tracing::instrument(level = "debug")]
+    pub fn empty(shape: [usize; 3]) -> Self {
+        debug!("Creating empty mri data");
+        Self {
+            segmentation: Array3::zeros(shape),
+            voxel_size_mm: [1.0; 3],
+            origin_mm: [0.0; 3],
+            orientation: MriOrientation {
+                axis_codes: "RAS".to_string(),
+                source: AffineSource::Pixdim,
+                oblique: false,
+            },
+        }
+    }
+}
+
+/// Orientation of a scan as given by the affine of its header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MriOrientation {
+    /// Anatomical direction every voxel axis points to, e.g. "RAS".
+    pub axis_codes: String,
+    /// Part of the header the affine was taken from.
+    pub source: AffineSource,
+    /// Set if the voxel axes are not aligned with the patient axes, in which
+    /// case the segmentation is resampled.
+    pub oblique: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AffineSource {
+    Sform,
+    Qform,
+    Pixdim,
+}
+
+impl std::fmt::Display for MriOrientation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({:?})", self.axis_codes, self.source)?;
+        if self.oblique {
+            write!(f, ", oblique, resampled")?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps the patient coordinates of the header (RAS+) to the model axes,
+/// x pointing right, y superior and z posterior.
+const PATIENT_TO_MODEL: [[f32; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 0.0, 1.0], [0.0, -1.0, 0.0]];
+
+/// Relative size of off-axis components below which a voxel axis is
+/// considered aligned with a model axis.
+const OBLIQUE_TOLERANCE: f32 = 1e-3;
+
+/// Loads a segmentation from a .nii file and brings it into the model axes
+/// using the affine of the header.
+///
+/// Oblique scans are resampled with nearest neighbour interpolation onto a
+/// grid aligned with the model axes.
+///
+/// # Panics
+///
+/// Panics if the file can not be read or the volume is not three dimensional.
 #[tracing::instrument(level = "debug")]
 pub(crate) fn load_from_nii<P>(path: P) -> MriData
 where
     P: AsRef<Path> + std::fmt::Debug,
 {
     debug!("Loading nifti file from {path:?}");
-    let object = ReaderOptions::new().read_file(path).unwrap();
+    let object = ReaderOptions::new()
+        .read_file(path)
+        .expect("Nifti file to be readable.");
     let header = object.header();
     debug!("Nifti header: {header:?}");
     let volume = object.volume();
-    let data = volume.into_ndarray::<f32>().unwrap();
-    let mut segmentation = data.into_dimensionality::<Ix3>().unwrap();
-    segmentation.swap_axes(1, 2);
-    let segmentation = segmentation.slice(s![.., .., ..;-1]).to_owned();
-    let voxel_size_mm = [header.pixdim[1], header.pixdim[3], header.pixdim[2]];
+    let data = volume
+        .into_ndarray::<f32>()
+        .expect("Nifti volume to be convertible.");
+    let volume = data
+        .into_dimensionality::<Ix3>()
+        .expect("Nifti volume to be three dimensional.");
+    let (affine, source) = voxel_to_model_affine(header);
+    let (segmentation, voxel_size_mm, origin_mm, oblique) = reorient(&volume, &affine);
+    let orientation = MriOrientation {
+        axis_codes: axis_codes(header),
+        source,
+        oblique,
+    };
+    debug!("Loaded segmentation with orientation {orientation}");
     MriData {
         segmentation,
         voxel_size_mm,
+        origin_mm,
+        orientation,
+    }
+}
+
+/// Loads the volume of a .nii file as stored, without reorienting it, e.g.
+/// for maps that are already on the grid of the model.
+///
+/// # Errors
+///
+/// Returns an error if the file can not be read or the volume is not three
+/// dimensional.
+#[tracing::instrument(level = "debug")]
+pub(crate) fn load_volume_from_nii<P>(path: P) -> Result<Array3<f32>, Box<dyn Error>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    debug!("Loading nifti volume from {path:?}");
+    let volume = ReaderOptions::new()
+        .read_file(path)?
+        .into_volume()
+        .into_ndarray::<f32>()?
+        .into_dimensionality::<Ix3>()?;
+    Ok(volume)
+}
+
+/// Reads the orientation of a .nii file from its header only.
+///
+/// # Errors
+///
+/// Returns an error if the header can not be read.
+#[tracing::instrument(level = "debug")]
+pub fn read_orientation<P>(path: P) -> Result<MriOrientation, Box<dyn Error>>
+where
+    P: AsRef<Path> + std::fmt::Debug,
+{
+    debug!("Reading nifti orientation from {path:?}");
+    let header = NiftiHeader::from_file(path)?;
+    let (affine, source) = voxel_to_model_affine(&header);
+    Ok(MriOrientation {
+        axis_codes: axis_codes(&header),
+        source,
+        oblique: !is_axis_aligned(&affine),
+    })
+}
+
+/// Returns the affine mapping voxel indices to patient coordinates in mm.
+///
+/// The sform is preferred over the qform. Without either only the voxel sizes
+/// are used and the voxel axes are assumed to be RAS.
+#[tracing::instrument(level = "trace", skip_all)]
+fn patient_affine(header: &NiftiHeader) -> ([[f32; 4]; 3], AffineSource) {
+    trace!("Determining patient affine");
+    if header.sform_code > 0 {
+        return (
+            [header.srow_x, header.srow_y, header.srow_z],
+            AffineSource::Sform,
+        );
+    }
+    let pixdim = [header.pixdim[1], header.pixdim[2], header.pixdim[3]];
+    if header.qform_code > 0 {
+        let rotation = UnitQuaternion::from_quaternion(Quaternion::new(
+            (1.0 - header.quatern_d.mul_add(
+                header.quatern_d,
+                header
+                    .quatern_c
+                    .mul_add(header.quatern_c, header.quatern_b.powi(2)),
+            ))
+            .max(0.0)
+            .sqrt(),
+            header.quatern_b,
+            header.quatern_c,
+            header.quatern_d,
+        ))
+        .to_rotation_matrix();
+        let qfac = if header.pixdim[0] < 0.0 { -1.0 } else { 1.0 };
+        let scale = [pixdim[0], pixdim[1], pixdim[2] * qfac];
+        let offset = [header.quatern_x, header.quatern_y, header.quatern_z];
+        let affine = std::array::from_fn(|row| {
+            std::array::from_fn(|column| {
+                if column == 3 {
+                    offset[row]
+                } else {
+                    rotation[(row, column)] * scale[column]
+                }
+            })
+        });
+        return (affine, AffineSource::Qform);
+    }
+    (
+        [
+            [pixdim[0], 0.0, 0.0, 0.0],
+            [0.0, pixdim[1], 0.0, 0.0],
+            [0.0, 0.0, pixdim[2], 0.0],
+        ],
+        AffineSource::Pixdim,
+    )
+}
+
+/// Returns the affine mapping voxel indices to model coordinates in mm.
+#[tracing::instrument(level = "trace", skip_all)]
+fn voxel_to_model_affine(header: &NiftiHeader) -> ([[f32; 4]; 3], AffineSource) {
+    trace!("Determining voxel to model affine");
+    let (patient, source) = patient_affine(header);
+    let affine = std::array::from_fn(|row| {
+        std::array::from_fn(|column| {
+            (0..3)
+                .map(|k| PATIENT_TO_MODEL[row][k] * patient[k][column])
+                .sum()
+        })
+    });
+    (affine, source)
+}
+
+/// Returns the anatomical direction (RAS+) every voxel axis points to.
+#[tracing::instrument(level = "trace", skip_all)]
+fn axis_codes(header: &NiftiHeader) -> String {
+    trace!("Determining axis codes");
+    let (patient, _) = patient_affine(header);
+    (0..3)
+        .map(|column| {
+            let row = (0..3)
+                .max_by(|&a, &b| {
+                    patient[a][column]
+                        .abs()
+                        .total_cmp(&patient[b][column].abs())
+                })
+                .unwrap();
+            let positive = patient[row][column] >= 0.0;
+            match (row, positive) {
+                (0, true) => 'R',
+                (0, false) => 'L',
+                (1, true) => 'A',
+                (1, false) => 'P',
+                (_, true) => 'S',
+                (_, false) => 'I',
+            }
+        })
+        .collect()
+}
+
+/// Returns the voxel axis mapped to every model axis if the affine is
+/// aligned with the model axes.
+#[tracing::instrument(level = "trace", skip_all)]
+fn axis_permutation(affine: &[[f32; 4]; 3]) -> Option<[usize; 3]> {
+    trace!("Determining axis permutation");
+    let mut permutation = [0; 3];
+    let mut used = [false; 3];
+    let directions: [[f32; 3]; 3] =
+        std::array::from_fn(|column| std::array::from_fn(|row| affine[row][column]));
+    for (column, direction) in directions.iter().enumerate() {
+        let norm = direction
+            .iter()
+            .map(|value| value.powi(2))
+            .sum::<f32>()
+            .sqrt();
+        let row = (0..3)
+            .max_by(|&a, &b| direction[a].abs().total_cmp(&direction[b].abs()))
+            .unwrap();
+        let off_axis = (0..3)
+            .filter(|&other| other != row)
+            .map(|other| direction[other].abs())
+            .fold(0.0, f32::max);
+        if norm == 0.0 || off_axis > OBLIQUE_TOLERANCE * norm || used[row] {
+            return None;
+        }
+        used[row] = true;
+        permutation[row] = column;
+    }
+    Some(permutation)
+}
+
+fn is_axis_aligned(affine: &[[f32; 4]; 3]) -> bool {
+    axis_permutation(affine).is_some()
+}
+
+/// Brings the volume into the model axes.
+///
+/// Returns the volume, its voxel sizes, the model coordinates of its first
+/// voxel and whether it had to be resampled. Resampled grids keep the first
+/// voxel of the scan on a grid point, so aligned and oblique scans of the
+/// same anatomy share their positions.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::cast_possible_truncation,
+    clippy::cast_sign_loss
+)]
+#[tracing::instrument(level = "debug", skip_all)]
+fn reorient(
+    volume: &Array3<f32>,
+    affine: &[[f32; 4]; 3],
+) -> (Array3<f32>, [f32; 3], [f32; 3], bool) {
+    debug!("Reorienting volume");
+    if let Some(permutation) = axis_permutation(affine) {
+        let mut reoriented = volume.view().permuted_axes(permutation);
+        let mut voxel_size_mm = [0.0; 3];
+        let mut origin_mm = [0.0; 3];
+        for (axis, &column) in permutation.iter().enumerate() {
+            let step_mm = affine[axis][column];
+            voxel_size_mm[axis] = step_mm.abs();
+            origin_mm[axis] = affine[axis][3];
+            if step_mm < 0.0 {
+                // the last voxel of the inverted axis becomes the first one
+                reoriented.invert_axis(Axis(axis));
+                origin_mm[axis] =
+                    step_mm.mul_add((volume.shape()[column] - 1) as f32, origin_mm[axis]);
+            }
+        }
+        return (
+            reoriented.as_standard_layout().into_owned(),
+            voxel_size_mm,
+            origin_mm,
+            false,
+        );
+    }
+
+    warn!("Segmentation is oblique and will be resampled.");
+    let linear = Matrix3::from_fn(|row, column| affine[row][column]);
+    let inverse = linear
+        .try_inverse()
+        .expect("Affine of the segmentation to be invertible.");
+    let translation = Vector3::new(affine[0][3], affine[1][3], affine[2][3]);
+    let size_mm = (0..3)
+        .map(|column| linear.column(column).norm())
+        .fold(f32::INFINITY, f32::min);
+
+    let shape = volume.shape();
+    let mut min_mm = Vector3::repeat(f32::INFINITY);
+    let mut max_mm = Vector3::repeat(f32::NEG_INFINITY);
+    for corner in 0..8 {
+        let index = Vector3::from_fn(|axis, _| {
+            if corner & (1 << axis) == 0 {
+                0.0
+            } else {
+                (shape[axis] - 1) as f32
+            }
+        });
+        let position = linear * index + translation;
+        min_mm = min_mm.inf(&position);
+        max_mm = max_mm.sup(&position);
     }
+    let first_mm = translation
+        + (min_mm - translation)
+            .map(|offset_mm| (offset_mm / size_mm + OBLIQUE_TOLERANCE).floor() * size_mm);
+    let resampled_shape = [0, 1, 2].map(|axis| {
+        ((max_mm[axis] - first_mm[axis]) / size_mm - OBLIQUE_TOLERANCE).ceil() as usize + 1
+    });
+
+    let resampled = Array3::from_shape_fn(resampled_shape, |(x, y, z)| {
+        let position = first_mm + Vector3::new(x as f32, y as f32, z as f32) * size_mm;
+        let index = inverse * (position - translation);
+        let index = [0, 1, 2].map(|axis| index[axis].round());
+        if index
+            .iter()
+            .zip(shape)
+            .any(|(&index, &length)| index < 0.0 || index > (length - 1) as f32)
+        {
+            0.0
+        } else {
+            volume[[index[0] as usize, index[1] as usize, index[2] as usize]]
+        }
+    });
+    (
+        resampled,
+        [size_mm; 3],
+        [first_mm[0], first_mm[1], first_mm[2]],
+        true,
+    )
+}
+
+/// Validates the label table against the labels present in the segmentation.
+///
+/// Fails if a label is mapped more than once or if none of the present labels
+/// maps to connectable tissue. Present labels without a mapping and mapped
+/// labels missing from the file are reported as warnings.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+#[tracing::instrument(level = "debug", skip(mri_data))]
+pub(crate) fn check_labels(mri_data: &MriData, labels: &SegmentationLabels) -> Result<(), String> {
+    debug!("Checking segmentation labels");
+    labels.validate()?;
+    let mapped: BTreeSet<usize> = labels.0.iter().map(|entry| entry.label).collect();
+
+    let mut present: BTreeMap<usize, usize> = BTreeMap::new();
+    for value in &mri_data.segmentation {
+        *present.entry(*value as usize).or_default() += 1;
+    }
+
+    for (label, count) in &present {
+        if *label != 0 && !mapped.contains(label) {
+            warn!("Label {label} ({count} voxels) has no mapping and is treated as empty space.");
+        }
+    }
+    for entry in &labels.0 {
+        if !present.contains_key(&entry.label) {
+            warn!(
+                "Label {} ({:?}) is mapped but not present in the segmentation.",
+                entry.label, entry.voxel_type
+            );
+        }
+    }
+
+    if !present
+        .keys()
+        .any(|label| labels.voxel_type(*label).is_connectable())
+    {
+        return Err("No label present in the segmentation maps to heart tissue.".to_string());
+    }
+    Ok(())
 }
 
 #[must_use]
@@ -46,20 +439,27 @@ pub(crate) fn determine_voxel_type(
 ) -> VoxelType {
     let mut count = [0; VoxelType::COUNT];
     trace!("Determining voxel type at position {position:?}");
+    let labels = &config.mri.as_ref().unwrap().labels;
 
     // calculate the search area
-    let x_start_mm =
-        position[0] - config.common.heart_offset_mm[0] - config.common.voxel_size_mm / 2.0;
-    let x_stop_mm =
-        position[0] - config.common.heart_offset_mm[0] + config.common.voxel_size_mm / 2.0;
-    let y_start_mm =
-        position[1] - config.common.heart_offset_mm[1] - config.common.voxel_size_mm / 2.0;
-    let y_stop_mm =
-        position[1] - config.common.heart_offset_mm[1] + config.common.voxel_size_mm / 2.0;
-    let z_start_mm =
-        position[2] - config.common.heart_offset_mm[2] - config.common.voxel_size_mm / 2.0;
-    let z_stop_mm =
-        position[2] - config.common.heart_offset_mm[2] + config.common.voxel_size_mm / 2.0;
+    let x_start_mm = position[0]
+        - config.common.heart_offset_mm[0]
+        - mri_data.origin_mm[0]
+        - config.common.voxel_size_mm / 2.0;
+    let x_stop_mm = position[0] - config.common.heart_offset_mm[0] - mri_data.origin_mm[0]
+        + config.common.voxel_size_mm / 2.0;
+    let y_start_mm = position[1]
+        - config.common.heart_offset_mm[1]
+        - mri_data.origin_mm[1]
+        - config.common.voxel_size_mm / 2.0;
+    let y_stop_mm = position[1] - config.common.heart_offset_mm[1] - mri_data.origin_mm[1]
+        + config.common.voxel_size_mm / 2.0;
+    let z_start_mm = position[2]
+        - config.common.heart_offset_mm[2]
+        - mri_data.origin_mm[2]
+        - config.common.voxel_size_mm / 2.0;
+    let z_stop_mm = position[2] - config.common.heart_offset_mm[2] - mri_data.origin_mm[2]
+        + config.common.voxel_size_mm / 2.0;
 
     let x_start_index = (x_start_mm / mri_data.voxel_size_mm[0]).floor() as usize;
     let x_stop_index = (x_stop_mm / mri_data.voxel_size_mm[0]).ceil() as usize;
@@ -71,8 +471,7 @@ pub(crate) fn determine_voxel_type(
     for x in x_start_index..x_stop_index {
         for y in y_start_index..y_stop_index {
             for z in z_start_index..z_stop_index {
-                let voxel_type =
-                    VoxelType::from_mri_data(mri_data.segmentation[[x, y, z]] as usize);
+                let voxel_type = labels.voxel_type(mri_data.segmentation[[x, y, z]] as usize);
                 count[voxel_type as usize] += 1;
             }
         }
@@ -106,10 +505,13 @@ mod tests {
 
     use std::path::Path;
 
-    use ndarray::Axis;
+    use approx::{assert_relative_eq, relative_eq};
 
     use super::*;
-    use crate::{tests::setup_folder, vis::plotting::gif::matrix::matrix_over_slices_plot};
+    use crate::{
+        core::config::model::SegmentationLabel, tests::setup_folder,
+        vis::plotting::gif::matrix::matrix_over_slices_plot,
+    };
 
     const COMMON_PATH: &str = "tests/core/model/spatial/nifti";
 
@@ -119,6 +521,170 @@ mod tests {
         let _ = load_from_nii("assets/segmentation.nii");
     }
 
+    #[test]
+    fn default_labels_match_asset() {
+        let mri_data = load_from_nii("assets/segmentation.nii");
+        check_labels(&mri_data, &SegmentationLabels::default()).unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn reorient_without_affine_matches_legacy_axes() {
+        let header = NiftiHeader {
+            pixdim: [1.0, 1.0, 2.0, 3.0, 0.0, 0.0, 0.0, 0.0],
+            qform_code: 0,
+            sform_code: 0,
+            ..NiftiHeader::default()
+        };
+        let volume = Array3::from_shape_fn((2, 3, 4), |(x, y, z)| (x * 100 + y * 10 + z) as f32);
+
+        let (affine, source) = voxel_to_model_affine(&header);
+        let (segmentation, voxel_size_mm, origin_mm, oblique) = reorient(&volume, &affine);
+
+        let mut legacy = volume;
+        legacy.swap_axes(1, 2);
+        let legacy = legacy.slice(ndarray::s![.., .., ..;-1]).to_owned();
+        assert_eq!(source, AffineSource::Pixdim);
+        assert_eq!(axis_codes(&header), "RAS");
+        assert!(!oblique);
+        assert_eq!(segmentation, legacy);
+        assert_relative_eq!(voxel_size_mm.as_slice(), [1.0, 3.0, 2.0].as_slice());
+        assert_relative_eq!(origin_mm.as_slice(), [0.0, 0.0, -4.0].as_slice());
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn reorient_mirrored_sform_matches_ras() {
+        let ras = NiftiHeader {
+            srow_x: [2.0, 0.0, 0.0, 0.0],
+            srow_y: [0.0, 1.0, 0.0, 0.0],
+            srow_z: [0.0, 0.0, 1.0, 0.0],
+            ..NiftiHeader::default()
+        };
+        let lps = NiftiHeader {
+            srow_x: [-2.0, 0.0, 0.0, 10.0],
+            srow_y: [0.0, 0.0, -1.0, 5.0],
+            srow_z: [0.0, 1.0, 0.0, 0.0],
+            ..NiftiHeader::default()
+        };
+        let volume = Array3::from_shape_fn((3, 4, 5), |(x, y, z)| (x * 100 + y * 10 + z) as f32);
+        // same anatomy stored with flipped x and y and swapped y/z voxel axes
+        let mut stored = volume.view().permuted_axes([0, 2, 1]);
+        stored.invert_axis(Axis(0));
+        stored.invert_axis(Axis(2));
+        let stored = stored.to_owned();
+
+        let (affine, _) = voxel_to_model_affine(&ras);
+        let (expected, expected_size_mm, _, _) = reorient(&volume, &affine);
+        let (affine, source) = voxel_to_model_affine(&lps);
+        let (segmentation, voxel_size_mm, _, oblique) = reorient(&stored, &affine);
+
+        assert_eq!(source, AffineSource::Sform);
+        assert_eq!(axis_codes(&lps), "LSP");
+        assert!(!oblique);
+        assert_eq!(segmentation, expected);
+        assert_relative_eq!(voxel_size_mm.as_slice(), expected_size_mm.as_slice());
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn reorient_keeps_affine_translation() {
+        let header = NiftiHeader {
+            srow_x: [2.0, 0.0, 0.0, 10.0],
+            srow_y: [0.0, 1.0, 0.0, 5.0],
+            srow_z: [0.0, 0.0, 1.0, -3.0],
+            ..NiftiHeader::default()
+        };
+        let volume = Array3::from_shape_fn((3, 4, 5), |(x, y, z)| (x * 100 + y * 10 + z) as f32);
+
+        let (affine, _) = voxel_to_model_affine(&header);
+        let (segmentation, _, origin_mm, oblique) = reorient(&volume, &affine);
+
+        // the anterior axis is flipped into the model z axis, so its last
+        // voxel becomes the first one
+        assert!(!oblique);
+        assert_relative_eq!(origin_mm.as_slice(), [10.0, -3.0, -8.0].as_slice());
+        assert_relative_eq!(segmentation[[0, 0, 3]], volume[[0, 0, 0]]);
+        assert_relative_eq!(segmentation[[2, 4, 0]], volume[[2, 3, 4]]);
+    }
+
+    #[test]
+    fn reorient_resamples_oblique_scans() {
+        let angle = std::f32::consts::FRAC_PI_4;
+        let header = NiftiHeader {
+            srow_x: [angle.cos(), -angle.sin(), 0.0, 0.0],
+            srow_y: [angle.sin(), angle.cos(), 0.0, 0.0],
+            srow_z: [0.0, 0.0, 1.0, 0.0],
+            ..NiftiHeader::default()
+        };
+        let volume = Array3::from_elem((4, 4, 4), 1.0);
+
+        let (affine, _) = voxel_to_model_affine(&header);
+        let (segmentation, voxel_size_mm, _, oblique) = reorient(&volume, &affine);
+
+        assert!(oblique);
+        assert_relative_eq!(voxel_size_mm[0], 1.0, epsilon = 1e-6);
+        assert_eq!(segmentation.shape()[1], 4);
+        assert!(segmentation.shape()[0] > 4);
+        assert!(segmentation.iter().any(|value| relative_eq!(*value, 1.0)));
+        assert!(segmentation.iter().any(|value| relative_eq!(*value, 0.0)));
+    }
+
+    #[test]
+    fn reorient_oblique_scans_keep_first_voxel_on_grid() {
+        let angle = std::f32::consts::FRAC_PI_4;
+        let header = NiftiHeader {
+            srow_x: [angle.cos(), -angle.sin(), 0.0, 3.0],
+            srow_y: [angle.sin(), angle.cos(), 0.0, 0.0],
+            srow_z: [0.0, 0.0, 1.0, 2.0],
+            ..NiftiHeader::default()
+        };
+        let mut volume = Array3::from_elem((4, 4, 4), 1.0);
+        volume[[0, 0, 0]] = 2.0;
+
+        let (affine, _) = voxel_to_model_affine(&header);
+        let (segmentation, _, origin_mm, oblique) = reorient(&volume, &affine);
+
+        // the grid is anchored on the translation (3, 2, 0), not on the
+        // lower corner of the rotated scan
+        assert!(oblique);
+        assert_relative_eq!(
+            origin_mm.as_slice(),
+            [0.0, 2.0, -5.0].as_slice(),
+            epsilon = 1e-5
+        );
+        assert_relative_eq!(segmentation[[3, 0, 5]], 2.0);
+    }
+
+    #[test]
+    fn check_labels_rejects_invalid_tables() {
+        let mut mri_data = MriData::empty([2, 2, 2]);
+        mri_data.segmentation[[0, 0, 0]] = 4.0;
+        mri_data.segmentation[[1, 1, 1]] = 7.0;
+
+        let duplicate = SegmentationLabels(vec![
+            SegmentationLabel {
+                label: 4,
+                voxel_type: VoxelType::Ventricle,
+            },
+            SegmentationLabel {
+                label: 4,
+                voxel_type: VoxelType::HPS,
+            },
+        ]);
+        assert!(check_labels(&mri_data, &duplicate).is_err());
+
+        assert!(check_labels(&mri_data, &SegmentationLabels::default()).is_err());
+
+        let ventricle = SegmentationLabels(vec![SegmentationLabel {
+            label: 4,
+            voxel_type: VoxelType::Ventricle,
+        }]);
+        check_labels(&mri_data, &ventricle).unwrap();
+        assert_eq!(ventricle.voxel_type(4), VoxelType::Ventricle);
+        assert_eq!(ventricle.voxel_type(7), VoxelType::None);
+    }
+
     #[test]
     #[allow(clippy::cast_possible_truncation)]
     #[ignore]
@@ -1,18 +1,23 @@
 use std::{
+    collections::VecDeque,
     fs::{self, File},
     io::BufWriter,
     ops::{Deref, DerefMut},
+    path::Path,
 };
 
-use ndarray::{arr1, s, Array3, Array4, Dim};
-use ndarray_npy::WriteNpyExt;
+use ndarray::{arr1, s, Array, Array1, Array3, Array4, Axis, Dim, Dimension};
+use ndarray_npy::{read_npy, WriteNpyExt};
 use num_derive::FromPrimitive;
 use serde::{Deserialize, Serialize};
 use strum_macros::{EnumCount, EnumIter};
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 
-use super::nifti::{determine_voxel_type, MriData};
-use crate::core::{config::model::Model, model::spatial::nifti::load_from_nii};
+use super::nifti::{check_labels, determine_voxel_type, load_volume_from_nii, MriData};
+use crate::core::{
+    config::model::{Barrier, FiberOrientation, Model, PathologyShape},
+    model::spatial::nifti::load_from_nii,
+};
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Voxels {
@@ -20,6 +25,17 @@ pub struct Voxels {
     pub types: VoxelTypes,
     pub numbers: VoxelNumbers,
     pub positions_mm: VoxelPositions,
+    #[serde(default)]
+    pub pathology_regions: PathologyRegions,
+    #[serde(default)]
+    pub pathology_severity: PathologySeverity,
+    #[serde(default)]
+    // fiber directions, not set if conduction is isotropic
+    pub fibers: Option<VoxelFibers>,
+    #[serde(default)]
+    pub velocities_m_per_s: VoxelVelocities,
+    #[serde(default)]
+    pub scar: VoxelScar,
 }
 
 impl Voxels {
@@ -33,41 +49,91 @@ impl Voxels {
             types: VoxelTypes::empty(voxels_in_dims),
             numbers: VoxelNumbers::empty(voxels_in_dims),
             positions_mm: VoxelPositions::empty(voxels_in_dims),
+            pathology_regions: PathologyRegions::empty(voxels_in_dims),
+            pathology_severity: PathologySeverity::empty(voxels_in_dims),
+            fibers: None,
+            velocities_m_per_s: VoxelVelocities::empty(voxels_in_dims),
+            scar: VoxelScar::empty(voxels_in_dims),
         }
     }
 
     /// Creates a Voxels struct from the given Model config.
-    #[must_use]
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a configured map or mask can not be read or does
+    /// not match the voxel grid.
     #[tracing::instrument(level = "debug")]
-    pub fn from_handcrafted_model_config(config: &Model) -> Self {
+    pub fn from_handcrafted_model_config(config: &Model) -> Result<Self, String> {
         debug!("Creating voxels from handcrafted model config");
-        let types = VoxelTypes::from_handcrafted_model_config(config);
-        let numbers = VoxelNumbers::from_voxel_types(&types);
+        let mut types = VoxelTypes::from_handcrafted_model_config(config);
         let positions = VoxelPositions::from_handcrafted_model_config(config, types.raw_dim());
-        Self {
+        let mut pathology_regions =
+            PathologyRegions::from_model_config(config, &mut types, &positions)?;
+        let pathology_severity =
+            PathologySeverity::from_model_config(config, &types, &mut pathology_regions);
+        let fibers = VoxelFibers::from_model_config(config, &types)?;
+        let velocities_m_per_s = VoxelVelocities::from_model_config(config, &types)?;
+        let scar = VoxelScar::from_model_config(config, &types)?;
+        let numbers = VoxelNumbers::from_voxel_types(&types);
+        Ok(Self {
             size_mm: config.common.voxel_size_mm,
             types,
             numbers,
             positions_mm: positions,
-        }
+            pathology_regions,
+            pathology_severity,
+            fibers,
+            velocities_m_per_s,
+            scar,
+        })
     }
 
-    #[must_use]
+    /// Creates voxels from the segmentation of an MRI model config.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the label table does not match the segmentation
+    /// or if a configured map or mask can not be read or does not match the
+    /// voxel grid.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the config has no MRI or the segmentation can not be read.
     #[tracing::instrument(level = "debug", skip_all)]
-    pub fn from_mri_model_config(config: &Model) -> Self {
+    pub fn from_mri_model_config(config: &Model) -> Result<Self, String> {
         debug!("Creating voxels from mri model config");
 
-        let mri_data = load_from_nii(&config.mri.as_ref().unwrap().path);
+        let mri = config.mri.as_ref().unwrap();
+        let mri_data = load_from_nii(&mri.path);
+        check_labels(&mri_data, &mri.labels).map_err(|message| {
+            format!(
+                "Invalid segmentation labels for {}: {message}",
+                mri.path.display()
+            )
+        })?;
 
         let positions = VoxelPositions::from_mri_model_config(config, &mri_data);
-        let types = VoxelTypes::from_mri_model_config(config, &positions, &mri_data);
+        let mut types = VoxelTypes::from_mri_model_config(config, &positions, &mri_data);
+        let mut pathology_regions =
+            PathologyRegions::from_model_config(config, &mut types, &positions)?;
+        let pathology_severity =
+            PathologySeverity::from_model_config(config, &types, &mut pathology_regions);
+        let fibers = VoxelFibers::from_model_config(config, &types)?;
+        let velocities_m_per_s = VoxelVelocities::from_model_config(config, &types)?;
+        let scar = VoxelScar::from_model_config(config, &types)?;
         let numbers = VoxelNumbers::from_voxel_types(&types);
-        Self {
+        Ok(Self {
             size_mm: config.common.voxel_size_mm,
             types,
             numbers,
             positions_mm: positions,
-        }
+            pathology_regions,
+            pathology_severity,
+            fibers,
+            velocities_m_per_s,
+            scar,
+        })
     }
 
     /// Returns the total number of voxels.
@@ -130,6 +196,35 @@ impl Voxels {
                 .is_connectable()
     }
 
+    /// Checks if conduction from the input to the output voxel is blocked,
+    /// either because one of them is scar or because the connection crosses
+    /// one of the barriers.
+    #[must_use]
+    #[tracing::instrument(level = "trace", skip(self, barriers))]
+    pub fn is_conduction_blocked(
+        &self,
+        barriers: &[Barrier],
+        input_index: [usize; 3],
+        output_index: [usize; 3],
+    ) -> bool {
+        trace!("Checking if conduction is blocked");
+        if self.scar[input_index] || self.scar[output_index] {
+            return true;
+        }
+        if barriers.is_empty() {
+            return false;
+        }
+        let corner_mm = self.positions_mm.corner_mm(self.size_mm);
+        let relative_position_mm = |[x, y, z]: [usize; 3]| -> [f32; 3] {
+            std::array::from_fn(|axis| self.positions_mm[(x, y, z, axis)] - corner_mm[axis])
+        };
+        let input_mm = relative_position_mm(input_index);
+        let output_mm = relative_position_mm(output_index);
+        barriers
+            .iter()
+            .any(|barrier| barrier.blocks(input_mm, output_mm))
+    }
+
     /// Returns the index of the first voxel of type `v_type`.
     ///
     /// # Panics
@@ -157,6 +252,649 @@ impl Voxels {
         self.types.save_npy(path);
         self.numbers.save_npy(path);
         self.positions_mm.save_npy(path);
+        self.pathology_regions.save_npy(path);
+        self.pathology_severity.save_npy(path);
+        if let Some(fibers) = &self.fibers {
+            fibers.save_npy(path);
+        }
+        self.velocities_m_per_s.save_npy(path);
+        self.scar.save_npy(path);
+    }
+}
+
+/// Index of the pathology region every voxel belongs to, `None` for healthy
+/// tissue.
+///
+/// The regions index `pathologies` of the common model config. Without
+/// configured pathologies all pathological voxels form region zero.
+#[allow(clippy::unsafe_derive_deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
+pub struct PathologyRegions(Array3<Option<usize>>);
+
+impl PathologyRegions {
+    /// Creates `PathologyRegions` without any region.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn empty(voxels_in_dims: [usize; 3]) -> Self {
+        trace!("Creating empty pathology regions");
+        Self(Array3::from_elem(voxels_in_dims, None))
+    }
+
+    /// Places the configured pathologies, turning the heart voxels they cover
+    /// into pathological voxels. Later pathologies override earlier ones.
+    /// The sinoatrial node is left untouched.
+    ///
+    /// If no pathologies are configured, the already pathological voxels are
+    /// collected into region zero.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a mask can not be read or does not match the voxel
+    /// grid.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(
+        config: &Model,
+        types: &mut VoxelTypes,
+        positions: &VoxelPositions,
+    ) -> Result<Self, String> {
+        debug!("Creating pathology regions from model config");
+        let mut regions = Self::empty([types.shape()[0], types.shape()[1], types.shape()[2]]);
+        if !config.common.pathological || config.common.pathologies.is_empty() {
+            regions
+                .iter_mut()
+                .zip(types.iter())
+                .filter(|(_, voxel_type)| **voxel_type == VoxelType::Pathological)
+                .for_each(|(region, _)| *region = Some(0));
+            return Ok(regions);
+        }
+
+        let voxel_size_mm = config.common.voxel_size_mm;
+        let corner_mm = positions.corner_mm(voxel_size_mm);
+        for (number, pathology) in config.common.pathologies.iter().enumerate() {
+            let mask = match &pathology.shape {
+                PathologyShape::Mask { path } => {
+                    let mask: Array3<f32> = read_grid_map(path, "Mask", types.shape())?;
+                    Some(mask.mapv(|value| value != 0.0))
+                }
+                PathologyShape::Seed { seed_mm, volume_ml } => {
+                    let target = (volume_ml * 1000.0 / voxel_size_mm.powi(3)).round() as usize;
+                    Some(grow_region(types, positions, &corner_mm, *seed_mm, target))
+                }
+                _ => None,
+            };
+            let mut count = 0;
+            for ((index, voxel_type), region) in types.indexed_iter_mut().zip(regions.iter_mut()) {
+                if !voxel_type.is_connectable() || *voxel_type == VoxelType::Sinoatrial {
+                    continue;
+                }
+                let inside = mask.as_ref().map_or_else(
+                    || {
+                        let (x, y, z) = index;
+                        let position: Array1<f32> = &positions.slice(s![x, y, z, ..]) - &corner_mm;
+                        pathology
+                            .shape
+                            .contains([position[0], position[1], position[2]])
+                    },
+                    |mask| mask[index],
+                );
+                if inside {
+                    *voxel_type = VoxelType::Pathological;
+                    *region = Some(number);
+                    count += 1;
+                }
+            }
+            if count == 0 {
+                warn!("Pathology {number} does not overlap with the heart.");
+            }
+            debug!("Placed pathology {number} with {count} voxels");
+        }
+        Ok(regions)
+    }
+
+    /// Returns the number of regions, i.e. the largest region index plus one.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn number_of_regions(&self) -> usize {
+        trace!("Counting pathology regions");
+        self.iter().flatten().max().map_or(0, |region| region + 1)
+    }
+
+    #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+    #[tracing::instrument(level = "trace")]
+    fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving pathology regions to npy");
+        let writer = BufWriter::new(File::create(path.join("pathology_regions.npy")).unwrap());
+        self.map(|region| region.map_or(-1, |region| region as i32))
+            .write_npy(writer)
+            .unwrap();
+    }
+}
+
+/// Reads a map on the voxel grid from a .npy or .nii file and checks that its
+/// shape matches the grid. The name is used in the error messages.
+#[tracing::instrument(level = "debug", skip(shape))]
+fn read_grid_map<D: Dimension>(
+    path: &Path,
+    name: &str,
+    shape: &[usize],
+) -> Result<Array<f32, D>, String> {
+    debug!("Reading grid map");
+    let map = if path.extension().is_some_and(|extension| extension == "npy") {
+        read_npy(path).map_err(|error| error.to_string())
+    } else {
+        load_volume_from_nii(path)
+            .and_then(|volume| Ok(volume.into_dimensionality::<D>()?))
+            .map_err(|error| error.to_string())
+    }
+    .map_err(|error| format!("Could not read {name} {}: {error}", path.display()))?;
+    if map.shape() != shape {
+        return Err(format!(
+            "{name} {} does not match the voxel grid: {:?} != {shape:?}.",
+            path.display(),
+            map.shape()
+        ));
+    }
+    Ok(map)
+}
+
+/// Grows a region from the heart voxel closest to the seed through connected
+/// heart voxels until it contains the target number of voxels. The seed is
+/// given relative to the heart corner. The sinoatrial node is left out.
+#[tracing::instrument(level = "debug", skip(types, positions, corner_mm))]
+fn grow_region(
+    types: &VoxelTypes,
+    positions: &VoxelPositions,
+    corner_mm: &Array1<f32>,
+    seed_mm: [f32; 3],
+    target: usize,
+) -> Array3<bool> {
+    debug!("Growing pathology region");
+    let is_heart =
+        |voxel_type: VoxelType| voxel_type.is_connectable() && voxel_type != VoxelType::Sinoatrial;
+    let mut region = Array3::from_elem(types.raw_dim(), false);
+    let seed = types
+        .indexed_iter()
+        .filter(|(_, voxel_type)| is_heart(**voxel_type))
+        .map(|((x, y, z), _)| {
+            let position = &positions.slice(s![x, y, z, ..]) - corner_mm;
+            let distance: f32 = (0..3)
+                .map(|axis| (position[axis] - seed_mm[axis]).powi(2))
+                .sum();
+            ((x, y, z), distance)
+        })
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(index, _)| index);
+    let Some(seed) = seed else {
+        return region;
+    };
+    let mut visited = Array3::from_elem(types.raw_dim(), false);
+    let mut queue = VecDeque::from([seed]);
+    visited[seed] = true;
+    let mut count = 0;
+    while count < target {
+        let Some((x, y, z)) = queue.pop_front() else {
+            break;
+        };
+        region[(x, y, z)] = true;
+        count += 1;
+        for (dx, dy, dz) in [
+            (-1, 0, 0),
+            (1, 0, 0),
+            (0, -1, 0),
+            (0, 1, 0),
+            (0, 0, -1),
+            (0, 0, 1),
+        ] {
+            let neighbour = (
+                x.wrapping_add_signed(dx),
+                y.wrapping_add_signed(dy),
+                z.wrapping_add_signed(dz),
+            );
+            if types
+                .get(neighbour)
+                .is_some_and(|voxel_type| is_heart(*voxel_type))
+                && !visited[neighbour]
+            {
+                visited[neighbour] = true;
+                queue.push_back(neighbour);
+            }
+        }
+    }
+    region
+}
+
+impl Deref for PathologyRegions {
+    type Target = Array3<Option<usize>>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PathologyRegions {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Severity of the pathology of every voxel, one in the dense core of a
+/// pathology and falling off to zero in healthy tissue.
+#[allow(clippy::unsafe_derive_deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+pub struct PathologySeverity(Array3<f32>);
+
+impl PathologySeverity {
+    /// Creates `PathologySeverity` of healthy tissue.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn empty(voxels_in_dims: [usize; 3]) -> Self {
+        trace!("Creating empty pathology severity");
+        Self(Array3::zeros(voxels_in_dims))
+    }
+
+    /// Sets the severity of pathological voxels to one and adds the border
+    /// zones of the configured pathologies.
+    ///
+    /// Heart voxels within the border zone of a pathology keep their type,
+    /// but are assigned to its region with a severity falling off with the
+    /// distance to the closest pathological voxel of the region. Voxels in
+    /// several border zones belong to the most severe one.
+    #[must_use]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(
+        config: &Model,
+        types: &VoxelTypes,
+        regions: &mut PathologyRegions,
+    ) -> Self {
+        debug!("Creating pathology severity from model config");
+        let mut severity = Self(types.mapv(|voxel_type| {
+            if voxel_type == VoxelType::Pathological {
+                1.0
+            } else {
+                0.0
+            }
+        }));
+        if !config.common.pathological {
+            return severity;
+        }
+
+        let voxel_size_mm = config.common.voxel_size_mm;
+        for (number, pathology) in config.common.pathologies.iter().enumerate() {
+            if pathology.border_zone_mm <= 0.0 {
+                continue;
+            }
+            let mut core = Array3::from_elem(types.raw_dim(), f32::INFINITY);
+            let mut empty = true;
+            for ((value, voxel_type), region) in
+                core.iter_mut().zip(types.iter()).zip(regions.iter())
+            {
+                if *voxel_type == VoxelType::Pathological && *region == Some(number) {
+                    *value = 0.0;
+                    empty = false;
+                }
+            }
+            if empty {
+                continue;
+            }
+            let squared_distances = squared_distance_transform(core);
+            for (index, voxel_type) in types.indexed_iter() {
+                if !voxel_type.is_connectable()
+                    || *voxel_type == VoxelType::Pathological
+                    || *voxel_type == VoxelType::Sinoatrial
+                {
+                    continue;
+                }
+                let distance_mm = squared_distances[index].sqrt() * voxel_size_mm;
+                let value = pathology
+                    .severity_falloff
+                    .severity(distance_mm, pathology.border_zone_mm);
+                if value > severity[index] {
+                    severity[index] = value;
+                    regions[index] = Some(number);
+                }
+            }
+        }
+        severity
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving pathology severity to npy");
+        let writer = BufWriter::new(File::create(path.join("pathology_severity.npy")).unwrap());
+        self.write_npy(writer).unwrap();
+    }
+}
+
+/// Returns the squared euclidean distance in voxels from every voxel to the
+/// closest voxel with a value of zero. All other voxels have to be infinite.
+///
+/// Uses the separable lower envelope algorithm of Felzenszwalb and
+/// Huttenlocher, one pass per axis.
+#[tracing::instrument(level = "trace", skip_all)]
+fn squared_distance_transform(mut distances: Array3<f32>) -> Array3<f32> {
+    trace!("Calculating distance transform");
+    for axis in 0..3 {
+        for mut lane in distances.lanes_mut(Axis(axis)) {
+            let transformed = squared_distance_transform_1d(&lane.to_vec());
+            lane.assign(&Array1::from(transformed));
+        }
+    }
+    distances
+}
+
+/// One dimensional squared distance transform of the sampled function.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace", skip_all)]
+fn squared_distance_transform_1d(values: &[f32]) -> Vec<f32> {
+    let parabola = |site: usize| (site as f32).mul_add(site as f32, values[site]);
+    // sites of the parabolas of the lower envelope and where they start
+    let mut sites: Vec<usize> = Vec::with_capacity(values.len());
+    let mut starts: Vec<f32> = Vec::with_capacity(values.len());
+    for site in (0..values.len()).filter(|site| values[*site].is_finite()) {
+        while let Some(&last) = sites.last() {
+            let start = (parabola(site) - parabola(last)) / (2.0 * (site - last) as f32);
+            if start > *starts.last().unwrap() {
+                sites.push(site);
+                starts.push(start);
+                break;
+            }
+            sites.pop();
+            starts.pop();
+        }
+        if sites.is_empty() {
+            sites.push(site);
+            starts.push(f32::NEG_INFINITY);
+        }
+    }
+    if sites.is_empty() {
+        return values.to_vec();
+    }
+    let mut parabola_index = 0;
+    (0..values.len())
+        .map(|position| {
+            while parabola_index + 1 < sites.len() && starts[parabola_index + 1] < position as f32 {
+                parabola_index += 1;
+            }
+            let site = sites[parabola_index];
+            let offset = position as f32 - site as f32;
+            offset.mul_add(offset, values[site])
+        })
+        .collect()
+}
+
+impl Deref for PathologySeverity {
+    type Target = Array3<f32>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for PathologySeverity {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Unit fiber direction of every voxel, zero vectors for isotropic voxels.
+#[allow(clippy::unsafe_derive_deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+pub struct VoxelFibers(Array4<f32>);
+
+impl VoxelFibers {
+    /// Creates `VoxelFibers` of isotropic tissue.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn empty(voxels_in_dims: [usize; 3]) -> Self {
+        trace!("Creating empty voxel fibers");
+        Self(Array4::zeros((
+            voxels_in_dims[0],
+            voxels_in_dims[1],
+            voxels_in_dims[2],
+            3,
+        )))
+    }
+
+    /// Assigns the configured fiber orientation to the heart voxels.
+    ///
+    /// Returns `None` if no fibers are configured and conduction is
+    /// isotropic.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a fiber file can not be read or does not match the
+    /// voxel grid.
+    #[allow(clippy::cast_precision_loss)]
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(config: &Model, types: &VoxelTypes) -> Result<Option<Self>, String> {
+        debug!("Creating voxel fibers from model config");
+        let Some(config_fibers) = config.common.fibers.as_ref() else {
+            return Ok(None);
+        };
+        let shape = types.shape();
+        let mut fibers = Self::empty([shape[0], shape[1], shape[2]]);
+        match &config_fibers.orientation {
+            FiberOrientation::Rule {
+                endocardial_angle_deg,
+                epicardial_angle_deg,
+            } => {
+                for ((x, y, z), voxel_type) in types.indexed_iter() {
+                    if !voxel_type.is_connectable() {
+                        continue;
+                    }
+                    let depth = if shape[2] > 1 {
+                        z as f32 / (shape[2] - 1) as f32
+                    } else {
+                        0.0
+                    };
+                    let angle_rad = depth
+                        .mul_add(
+                            epicardial_angle_deg - endocardial_angle_deg,
+                            *endocardial_angle_deg,
+                        )
+                        .to_radians();
+                    fibers.slice_mut(s![x, y, z, ..]).assign(&arr1(&[
+                        angle_rad.cos(),
+                        angle_rad.sin(),
+                        0.0,
+                    ]));
+                }
+            }
+            FiberOrientation::File { path } => {
+                let directions: Array4<f32> =
+                    read_grid_map(path, "Fiber directions", fibers.shape())?;
+                for ((x, y, z), voxel_type) in types.indexed_iter() {
+                    let direction = directions.slice(s![x, y, z, ..]);
+                    let norm = direction.mapv(|d| d.powi(2)).sum().sqrt();
+                    if !voxel_type.is_connectable() || norm == 0.0 {
+                        continue;
+                    }
+                    fibers
+                        .slice_mut(s![x, y, z, ..])
+                        .assign(&direction.mapv(|d| d / norm));
+                }
+            }
+        }
+        Ok(Some(fibers))
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving voxel fibers to npy");
+        let writer = BufWriter::new(File::create(path.join("voxel_fibers.npy")).unwrap());
+        self.write_npy(writer).unwrap();
+    }
+}
+
+impl Deref for VoxelFibers {
+    type Target = Array4<f32>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for VoxelFibers {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Propagation velocity of every voxel from the velocity map, `None` where
+/// the velocity of the voxel type is used.
+#[allow(clippy::unsafe_derive_deserialize)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize, Default)]
+pub struct VoxelVelocities(Array3<Option<f32>>);
+
+impl VoxelVelocities {
+    /// Creates `VoxelVelocities` using the velocities of the voxel types.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn empty(voxels_in_dims: [usize; 3]) -> Self {
+        trace!("Creating empty voxel velocities");
+        Self(Array3::from_elem(voxels_in_dims, None))
+    }
+
+    /// Reads the configured velocity map. Positive finite values of heart
+    /// voxels replace the velocity of their voxel type.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the map can not be read or does not match the
+    /// voxel grid.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(config: &Model, types: &VoxelTypes) -> Result<Self, String> {
+        debug!("Creating voxel velocities from model config");
+        let shape = types.shape();
+        let mut velocities = Self::empty([shape[0], shape[1], shape[2]]);
+        let Some(path) = config.common.propagation_velocity_map_path.as_ref() else {
+            return Ok(velocities);
+        };
+        let map: Array3<f32> = read_grid_map(path, "Velocity map", shape)?;
+        let mut count = 0;
+        for ((velocity, value), voxel_type) in
+            velocities.iter_mut().zip(map.iter()).zip(types.iter())
+        {
+            if voxel_type.is_connectable() && value.is_finite() && *value > 0.0 {
+                *velocity = Some(*value);
+                count += 1;
+            }
+        }
+        if count == 0 {
+            warn!(
+                "Velocity map {} does not cover any heart voxel.",
+                path.display()
+            );
+        }
+        debug!("Read {count} voxel velocities");
+        Ok(velocities)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving voxel velocities to npy");
+        let writer =
+            BufWriter::new(File::create(path.join("voxel_velocities_m_per_s.npy")).unwrap());
+        self.mapv(|velocity| velocity.unwrap_or(0.0))
+            .write_npy(writer)
+            .unwrap();
+    }
+}
+
+impl Deref for VoxelVelocities {
+    type Target = Array3<Option<f32>>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for VoxelVelocities {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Non-conducting scar from the barrier masks, scar voxels are never
+/// connected.
+#[allow(clippy::unsafe_derive_deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
+pub struct VoxelScar(Array3<bool>);
+
+impl VoxelScar {
+    /// Creates `VoxelScar` without any scar.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn empty(voxels_in_dims: [usize; 3]) -> Self {
+        trace!("Creating empty voxel scar");
+        Self(Array3::from_elem(voxels_in_dims, false))
+    }
+
+    /// Reads the masks of the configured barriers. Non-zero heart voxels of
+    /// any mask are scar.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a mask can not be read or does not match the voxel
+    /// grid.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn from_model_config(config: &Model, types: &VoxelTypes) -> Result<Self, String> {
+        debug!("Creating voxel scar from model config");
+        let shape = types.shape();
+        let mut scar = Self::empty([shape[0], shape[1], shape[2]]);
+        for barrier in &config.common.barriers {
+            let Barrier::Mask { path } = barrier else {
+                continue;
+            };
+            let mask: Array3<f32> = read_grid_map(path, "Mask", shape)?;
+            let mut count = 0;
+            for ((is_scar, value), voxel_type) in scar.iter_mut().zip(mask.iter()).zip(types.iter())
+            {
+                if voxel_type.is_connectable() && *value != 0.0 {
+                    *is_scar = true;
+                    count += 1;
+                }
+            }
+            if count == 0 {
+                warn!(
+                    "Scar mask {} does not cover any heart voxel.",
+                    path.display()
+                );
+            }
+        }
+        Ok(scar)
+    }
+
+    #[tracing::instrument(level = "trace")]
+    fn save_npy(&self, path: &std::path::Path) {
+        trace!("Saving voxel scar to npy");
+        let writer = BufWriter::new(File::create(path.join("voxel_scar.npy")).unwrap());
+        self.mapv(u8::from).write_npy(writer).unwrap();
+    }
+}
+
+impl Deref for VoxelScar {
+    type Target = Array3<bool>;
+
+    #[tracing::instrument(level = "trace")]
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl DerefMut for VoxelScar {
+    #[tracing::instrument(level = "trace")]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
     }
 }
 
@@ -236,6 +974,7 @@ impl VoxelTypes {
                 if (x == sa_x_center_index) && (y == sa_y_center_index) {
                     *voxel_type = VoxelType::Sinoatrial;
                 } else if (config.common.pathological)
+                    && config.common.pathologies.is_empty()
                     && (x >= pathology_x_start_index && x <= pathology_x_stop_index)
                     && (pathology_y_start_index <= y && y <= pathology_y_stop_index)
                 {
@@ -298,6 +1037,10 @@ impl VoxelTypes {
                 }
             });
 
+        if config.common.pathological && config.common.pathologies.is_empty() {
+            warn!("MRI anatomies only use the configured pathologies, no voxels are pathological.");
+        }
+
         voxel_types
     }
 }
@@ -456,6 +1199,16 @@ impl VoxelPositions {
         positions
     }
 
+    /// Returns the outer corner of the first voxel. Positions in the model
+    /// config, e.g. of pathologies and stimuli, are relative to it.
+    #[must_use]
+    #[tracing::instrument(level = "trace")]
+    pub fn corner_mm(&self, voxel_size_mm: f32) -> Array1<f32> {
+        trace!("Calculating voxel grid corner");
+        self.slice(s![0, 0, 0, ..])
+            .mapv(|p| p - voxel_size_mm / 2.0)
+    }
+
     #[must_use]
     #[allow(
         clippy::cast_precision_loss,
@@ -466,6 +1219,8 @@ impl VoxelPositions {
     pub fn from_mri_model_config(config: &Model, mri_data: &MriData) -> Self {
         trace!("Creating voxel positions from mri model config");
 
+        let labels = &config.mri.as_ref().unwrap().labels;
+
         let mut min_heart_x = mri_data.segmentation.shape()[0];
         let mut max_heart_x = 0;
         let mut min_heart_y = mri_data.segmentation.shape()[1];
@@ -476,7 +1231,8 @@ impl VoxelPositions {
         for x in 0..mri_data.segmentation.shape()[0] {
             for y in 0..mri_data.segmentation.shape()[1] {
                 for z in 0..mri_data.segmentation.shape()[2] {
-                    if (VoxelType::from_mri_data(mri_data.segmentation[[x, y, z]] as usize))
+                    if labels
+                        .voxel_type(mri_data.segmentation[[x, y, z]] as usize)
                         .is_connectable()
                     {
                         min_heart_x = min_heart_x.min(x);
@@ -510,15 +1266,15 @@ impl VoxelPositions {
         let offset = [
             (min_heart_x as f32).mul_add(
                 mri_data.voxel_size_mm[0],
-                offset + config.common.heart_offset_mm[0],
+                offset + config.common.heart_offset_mm[0] + mri_data.origin_mm[0],
             ),
             (min_heart_y as f32).mul_add(
                 mri_data.voxel_size_mm[1],
-                offset + config.common.heart_offset_mm[1],
+                offset + config.common.heart_offset_mm[1] + mri_data.origin_mm[1],
             ),
             (min_heart_z as f32).mul_add(
                 mri_data.voxel_size_mm[2],
-                offset + config.common.heart_offset_mm[2],
+                offset + config.common.heart_offset_mm[2] + mri_data.origin_mm[2],
             ),
         ];
 
@@ -594,17 +1350,6 @@ pub enum VoxelType {
 }
 
 impl VoxelType {
-    pub(crate) const fn from_mri_data(value: usize) -> Self {
-        match value {
-            1 => Self::Atrium,
-            2 => Self::Vessel,
-            3 => Self::Torso,
-            5 => Self::Chamber,
-            6 => Self::Sinoatrial,
-            _ => Self::None,
-        }
-    }
-
     pub(crate) const fn is_connectable(self) -> bool {
         matches!(
             self,
@@ -662,8 +1407,15 @@ pub fn is_connection_allowed(output_voxel_type: &VoxelType, input_voxel_type: &V
 #[cfg(test)]
 mod tests {
 
+    use approx::assert_relative_eq;
+
+    use std::path::Path;
+
     use super::*;
-    use crate::core::config::model::{Common, Handcrafted};
+    use crate::{
+        core::config::model::{Common, Fibers, Handcrafted, Pathology},
+        tests::{handcrafted_model, setup_folder},
+    };
 
     const _COMMON_PATH: &str = "tests/core/model/spatial/voxel/";
 
@@ -697,12 +1449,235 @@ mod tests {
             },
             ..Default::default()
         };
-        let voxels = Voxels::from_handcrafted_model_config(&config);
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
 
         assert_eq!(1000, voxels.count());
         assert_eq!(3000, voxels.count_states());
     }
 
+    #[test]
+    fn pathology_ellipsoid_and_seed() {
+        let mut config = handcrafted_model([10.0, 10.0, 10.0]);
+        config.common.pathological = true;
+        let count_pathological = |voxels: &Voxels| {
+            voxels
+                .types
+                .iter()
+                .filter(|voxel_type| **voxel_type == VoxelType::Pathological)
+                .count()
+        };
+
+        config.common.pathologies = vec![Pathology {
+            shape: PathologyShape::Ellipsoid {
+                center_mm: [5.0, 5.0, 5.0],
+                radii_mm: [2.0, 2.0, 2.0],
+            },
+            ..Default::default()
+        }];
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        assert_eq!(count_pathological(&voxels), 32);
+
+        config.common.pathologies = vec![Pathology {
+            shape: PathologyShape::Seed {
+                seed_mm: [5.0, 5.0, 5.0],
+                volume_ml: 0.1,
+            },
+            ..Default::default()
+        }];
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        assert_eq!(count_pathological(&voxels), 100);
+    }
+
+    #[test]
+    fn multiple_pathology_regions() {
+        let mut config = handcrafted_model([10.0, 10.0, 10.0]);
+        config.common.pathological = true;
+        config.common.pathologies = vec![
+            Pathology {
+                shape: PathologyShape::Box {
+                    min_mm: [0.0, 0.0, 0.0],
+                    max_mm: [2.0, 2.0, 2.0],
+                },
+                ..Default::default()
+            },
+            Pathology {
+                shape: PathologyShape::Cylinder {
+                    start_mm: [7.5, 7.5, 0.0],
+                    end_mm: [7.5, 7.5, 10.0],
+                    radius_mm: 1.0,
+                },
+                ..Default::default()
+            },
+        ];
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        let count_region = |number: usize| {
+            voxels
+                .pathology_regions
+                .iter()
+                .zip(voxels.types.iter())
+                .filter(|(region, voxel_type)| {
+                    **region == Some(number) && **voxel_type == VoxelType::Pathological
+                })
+                .count()
+        };
+
+        assert_eq!(voxels.pathology_regions.number_of_regions(), 2);
+        assert_eq!(count_region(0), 8);
+        assert_eq!(count_region(1), 50);
+        assert_eq!(
+            voxels
+                .types
+                .iter()
+                .filter(|voxel_type| **voxel_type == VoxelType::Pathological)
+                .count(),
+            58
+        );
+    }
+
+    #[test]
+    fn pathology_border_zone_severity() {
+        let mut config = handcrafted_model([10.0, 10.0, 1.0]);
+        config.common.pathological = true;
+        config.common.pathologies = vec![Pathology {
+            shape: PathologyShape::Box {
+                min_mm: [0.0, 0.0, 0.0],
+                max_mm: [1.0, 10.0, 1.0],
+            },
+            border_zone_mm: 4.0,
+            ..Default::default()
+        }];
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        let severity = &voxels.pathology_severity;
+
+        assert_relative_eq!(severity[(0, 5, 0)], 1.0);
+        assert_relative_eq!(severity[(1, 5, 0)], 0.75);
+        assert_relative_eq!(severity[(3, 5, 0)], 0.25);
+        assert_relative_eq!(severity[(4, 5, 0)], 0.0);
+        assert_eq!(voxels.types[(1, 5, 0)], VoxelType::Ventricle);
+        assert_eq!(voxels.pathology_regions[(1, 5, 0)], Some(0));
+        assert_eq!(voxels.pathology_regions[(4, 5, 0)], None);
+    }
+
+    #[test]
+    #[allow(clippy::cast_precision_loss)]
+    fn distance_transform_matches_brute_force() {
+        let shape = [7, 5, 6];
+        let core = [(1, 1, 1), (5, 0, 4), (3, 4, 0)];
+        let mut distances = Array3::from_elem(shape, f32::INFINITY);
+        for index in core {
+            distances[index] = 0.0;
+        }
+
+        let distances = squared_distance_transform(distances);
+
+        for ((x, y, z), distance) in distances.indexed_iter() {
+            let expected = core
+                .iter()
+                .map(|&(cx, cy, cz)| {
+                    [
+                        x as f32 - cx as f32,
+                        y as f32 - cy as f32,
+                        z as f32 - cz as f32,
+                    ]
+                    .iter()
+                    .map(|d| d.powi(2))
+                    .sum::<f32>()
+                })
+                .fold(f32::INFINITY, f32::min);
+            assert_relative_eq!(*distance, expected);
+        }
+    }
+
+    #[test]
+    fn rule_based_fibers_rotate_through_the_wall() {
+        let mut config = handcrafted_model([4.0, 4.0, 3.0]);
+        config.common.fibers = Some(Fibers {
+            orientation: FiberOrientation::Rule {
+                endocardial_angle_deg: 90.0,
+                epicardial_angle_deg: -90.0,
+            },
+            ..Default::default()
+        });
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        let fibers = voxels.fibers.as_ref().unwrap();
+        let fiber = |z: usize| fibers.slice(s![1, 1, z, ..]).to_owned();
+
+        assert_relative_eq!(fiber(0), arr1(&[0.0, 1.0, 0.0]), epsilon = 1e-6);
+        assert_relative_eq!(fiber(1), arr1(&[1.0, 0.0, 0.0]), epsilon = 1e-6);
+        assert_relative_eq!(fiber(2), arr1(&[0.0, -1.0, 0.0]), epsilon = 1e-6);
+    }
+
+    #[test]
+    fn barriers_block_conduction_across_them() {
+        let mut config = handcrafted_model([10.0, 10.0, 1.0]);
+        config.common.barriers = vec![
+            Barrier::Polyline {
+                points_mm: vec![[5.0, 0.0], [5.0, 8.0]],
+            },
+            Barrier::Plane {
+                point_mm: [0.0, 2.0, 0.0],
+                normal: [0.0, 1.0, 0.0],
+                radius_mm: 0.0,
+            },
+        ];
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        let barriers = &config.common.barriers;
+
+        assert!(voxels.is_conduction_blocked(barriers, [4, 3, 0], [5, 3, 0]));
+        assert!(voxels.is_conduction_blocked(barriers, [5, 4, 0], [4, 5, 0]));
+        assert!(!voxels.is_conduction_blocked(barriers, [4, 9, 0], [5, 9, 0]));
+        assert!(voxels.is_conduction_blocked(barriers, [7, 1, 0], [7, 2, 0]));
+        assert!(!voxels.is_conduction_blocked(barriers, [7, 5, 0], [7, 6, 0]));
+    }
+
+    #[test]
+    fn velocity_map_overrides_voxel_type_velocities() {
+        let path = Path::new(_COMMON_PATH);
+        setup_folder(path);
+        let map_path = path.join("velocity_map.npy");
+        let mut map = Array3::<f32>::zeros((4, 4, 1));
+        map[(2, 2, 0)] = 0.5;
+        map[(3, 3, 0)] = f32::NAN;
+        map.write_npy(BufWriter::new(File::create(&map_path).unwrap()))
+            .unwrap();
+        let mut config = handcrafted_model([4.0, 4.0, 1.0]);
+        config.common.propagation_velocity_map_path = Some(map_path);
+        let voxels = Voxels::from_handcrafted_model_config(&config).unwrap();
+        let velocity = |index: (usize, usize, usize)| {
+            config.common.propagation_velocity_m_per_s(
+                voxels.types[index],
+                voxels.velocities_m_per_s[index],
+                None,
+                0.0,
+            )
+        };
+
+        assert_eq!(voxels.velocities_m_per_s[(2, 2, 0)], Some(0.5));
+        assert_eq!(voxels.velocities_m_per_s[(3, 3, 0)], None);
+        assert_relative_eq!(velocity((2, 2, 0)), 0.5);
+        assert_relative_eq!(
+            velocity((3, 3, 0)),
+            config.common.propagation_velocities_m_per_s[&voxels.types[(3, 3, 0)]]
+        );
+    }
+
+    #[test]
+    fn maps_off_the_voxel_grid_are_rejected() {
+        let path = Path::new(_COMMON_PATH);
+        setup_folder(path);
+        let mask_path = path.join("scar_mask_off_grid.npy");
+        Array3::<f32>::ones((3, 4, 1))
+            .write_npy(BufWriter::new(File::create(&mask_path).unwrap()))
+            .unwrap();
+        let mut config = handcrafted_model([4.0, 4.0, 1.0]);
+        config.common.barriers = vec![Barrier::Mask { path: mask_path }];
+        assert!(Voxels::from_handcrafted_model_config(&config).is_err());
+
+        config.common.barriers.clear();
+        config.common.propagation_velocity_map_path = Some(path.join("missing_map.npy"));
+        assert!(Voxels::from_handcrafted_model_config(&config).is_err());
+    }
+
     #[test]
     fn is_connection_allowed_true() {
         let output_voxel_type = VoxelType::HPS;
@@ -8,6 +8,7 @@ use std::{
     io::{BufReader, BufWriter, Write},
     path::Path,
     sync::mpsc::Sender,
+    time::Instant,
 };
 
 use bincode;
@@ -20,8 +21,11 @@ use tracing::{debug, info, trace};
 use self::{results::Results, summary::Summary};
 use super::{
     algorithm::{self, calculate_pseudo_inverse},
-    config::{algorithm::AlgorithmType, Config},
-    data::Data,
+    config::{
+        algorithm::{AlgorithmType, CovarianceAdaptation},
+        Config,
+    },
+    data::{shapes::SystemStatesSpherical, Data},
     model::Model,
 };
 use crate::core::algorithm::{
@@ -215,12 +219,15 @@ impl Scenario {
     /// # Errors
     ///
     /// This function will return an error if scenario is not in plannig
-    /// phase.
+    /// phase or if the model or algorithm config is invalid.
     #[tracing::instrument(level = "debug")]
     pub fn schedule(&mut self) -> Result<(), String> {
         debug!("Scheduling scenario");
         match self.status {
             Status::Planning => {
+                self.config.simulation.model.validate()?;
+                self.config.algorithm.model.validate()?;
+                self.config.algorithm.validate()?;
                 self.status = Status::Scheduled;
                 self.unify_configs();
                 Ok(())
@@ -314,6 +321,14 @@ impl Scenario {
         self.duration_s = Some((self.finished.unwrap() - self.started.unwrap()).num_seconds());
     }
 
+    /// Sets the scenario status to Aborted.
+    #[tracing::instrument(level = "debug")]
+    pub fn set_aborted(&mut self) {
+        debug!("Setting scenario status to aborted");
+        self.status = Status::Aborted;
+        self.finished = Some(Utc::now());
+    }
+
     /// Deletes the results directory for this scenario.
     ///
     /// # Errors
@@ -474,23 +489,34 @@ impl Scenario {
 /// Updates the results and summary structs with the output. Sends the final epoch
 /// count and summary via the provided channels. Saves the results to the scenario.
 ///
+/// # Errors
+///
+/// Returns an error if the parameters do not yield a valid simulation or
+/// model, or if the preprocessing parameters are invalid.
+///
 /// # Panics
 ///
-/// Panics if simulation is none, an unimplemented algorithm is selected or
-/// the parameters do not yield a valid model.
+/// Panics if simulation is none or an unimplemented algorithm is selected.
 #[tracing::instrument(level = "info", skip_all, fields(id = %scenario.id))]
-pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender<Summary>) {
+pub fn run(
+    mut scenario: Scenario,
+    epoch_tx: &Sender<usize>,
+    summary_tx: &Sender<Summary>,
+) -> Result<(), String> {
     debug!("Running scenario with id {}", scenario.id);
 
     let simulation = &scenario.config.simulation;
 
-    let data = Data::from_simulation_config(simulation).expect("Model parametrs to be valid.");
+    let mut data = Data::from_simulation_config(simulation)
+        .map_err(|error| format!("Could not create the simulation: {error}"))?;
+    data.preprocess(&scenario.config.preprocessing, simulation.duration_s)
+        .map_err(|error| format!("Could not preprocess data: {error}"))?;
     let mut model = Model::from_model_config(
         &scenario.config.algorithm.model,
-        simulation.sample_rate_hz,
+        data.simulation.sample_rate_hz,
         simulation.duration_s,
     )
-    .unwrap();
+    .map_err(|error| format!("Could not create the model: {error}"))?;
 
     // synchronice model and simulation sensor parameters
     model.synchronize_parameters(&data);
@@ -516,6 +542,7 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
 
     let mut summary = Summary::default();
 
+    let start = Instant::now();
     match scenario.config.algorithm.algorithm_type {
         AlgorithmType::ModelBased => {
             results.model = Some(model);
@@ -544,6 +571,7 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
             results.model = Some(model);
         }
     }
+    summary.runtime_s = start.elapsed().as_secs_f32();
 
     calculate_plotting_arrays(&mut results, &data);
 
@@ -551,6 +579,18 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
         &mut results.metrics,
         &results.estimations,
         &data.simulation.model.spatial_description.voxels.types,
+        &data
+            .simulation
+            .model
+            .spatial_description
+            .voxels
+            .pathology_regions,
+        &data
+            .simulation
+            .model
+            .spatial_description
+            .voxels
+            .pathology_severity,
         &results
             .model
             .as_ref()
@@ -560,6 +600,41 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
             .numbers,
     );
 
+    metrics::calculate_propagation_speed_errors(
+        &mut results.metrics,
+        &results.estimations.average_delays,
+        &data.simulation.average_delays,
+        &results
+            .model
+            .as_ref()
+            .unwrap()
+            .spatial_description
+            .voxels
+            .numbers,
+        data.simulation.model.spatial_description.voxels.size_mm,
+        data.simulation.sample_rate_hz,
+    );
+
+    metrics::calculate_earliest_activation_error(
+        &mut results.metrics,
+        &results.estimations.activation_times,
+        &data.simulation.activation_times,
+        &results
+            .model
+            .as_ref()
+            .unwrap()
+            .spatial_description
+            .voxels
+            .numbers,
+        &results
+            .model
+            .as_ref()
+            .unwrap()
+            .spatial_description
+            .voxels
+            .positions_mm,
+    );
+
     let optimal_threshold = results
         .metrics
         .dice_score_over_threshold
@@ -574,6 +649,27 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
     summary.iou = results.metrics.iou_over_threshold[optimal_threshold];
     summary.recall = results.metrics.recall_over_threshold[optimal_threshold];
     summary.precision = results.metrics.precision_over_threshold[optimal_threshold];
+    summary.number_of_lesions = results.metrics.lesion_recall_over_threshold.nrows();
+    summary.lesions_detected = metrics::count_detected_lesions(&results.metrics, optimal_threshold);
+    summary.severity_mean_absolute_error = results.metrics.severity_mean_absolute_error;
+    summary.severity_correlation = results.metrics.severity_correlation;
+    summary.propagation_speed_mean_absolute_error_m_per_s = results
+        .metrics
+        .propagation_speed_mean_absolute_error_m_per_s;
+    summary.propagation_speed_root_mean_square_error_m_per_s = results
+        .metrics
+        .propagation_speed_root_mean_square_error_m_per_s;
+    summary.propagation_speed_mean_relative_error =
+        results.metrics.propagation_speed_mean_relative_error;
+    summary.earliest_activation_error_mm = results.metrics.earliest_activation_error_mm;
+    let ap_params = &results
+        .model
+        .as_ref()
+        .unwrap()
+        .functional_description
+        .ap_params;
+    summary.number_of_connections = ap_params.number_of_connections();
+    summary.number_of_gradients = ap_params.number_of_gradients(&scenario.config.algorithm);
 
     scenario.results = Some(results);
     scenario.data = Some(data);
@@ -582,14 +678,18 @@ pub fn run(mut scenario: Scenario, epoch_tx: &Sender<usize>, summary_tx: &Sender
     scenario.save().expect("Could not save scenario");
     let _ = epoch_tx.send(scenario.config.algorithm.epochs - 1);
     let _ = summary_tx.send(summary);
+    Ok(())
 }
 
 #[tracing::instrument(level = "trace", skip_all)]
 pub(crate) fn calculate_plotting_arrays(results: &mut Results, data: &Data) {
-    results
-        .estimations
-        .system_states_spherical
-        .calculate(&results.estimations.system_states);
+    let estimations = &mut results.estimations;
+    let mut system_states_spherical = std::mem::replace(
+        &mut estimations.system_states_spherical,
+        SystemStatesSpherical::empty(0, 0),
+    );
+    system_states_spherical.calculate(estimations.analysis_system_states());
+    estimations.system_states_spherical = system_states_spherical;
     results
         .estimations
         .system_states_spherical_max
@@ -728,6 +828,18 @@ fn run_model_based(
             .functional_description
             .ap_params,
     );
+    if scenario.config.algorithm.covariance_adaptation != CovarianceAdaptation::None {
+        let functional_description = &results.model.as_ref().unwrap().functional_description;
+        summary.learned_process_covariance_mean = functional_description
+            .process_covariance
+            .mean()
+            .unwrap_or_default();
+        summary.learned_measurement_covariance_mean = functional_description
+            .measurement_covariance
+            .diag()
+            .mean()
+            .unwrap_or_default();
+    }
     scenario.config.algorithm.learning_rate = original_learning_rate;
 }
 
@@ -769,6 +881,7 @@ fn run_model_based_gpu(
         number_of_states as i32,
         number_of_sensors as i32,
         number_of_steps as i32,
+        data.simulation.sample_rate_hz,
     );
 
     for epoch_index in 0..scenario.config.algorithm.epochs {
@@ -68,7 +68,7 @@ impl Results {
             number_of_steps,
             number_of_beats,
         );
-        let derivatives = Derivatives::new(number_of_states, optimizer);
+        let derivatives = Derivatives::new(number_of_states, number_of_steps, optimizer);
         let batch_size = if batch_size > 0 {
             batch_size
         } else {
@@ -145,6 +145,7 @@ impl Results {
             ),
             derivatives: Derivatives::new(
                 model.spatial_description.voxels.count_states(),
+                model.functional_description.control_function_values.len(),
                 Optimizer::default(),
             ),
             model: Some(model),
@@ -218,7 +219,7 @@ impl Snapshots {
         self.system_states
             .0
             .slice_mut(s![self.current_index, .., ..])
-            .assign(&*estimations.system_states);
+            .assign(&**estimations.analysis_system_states());
         self.measurements
             .0
             .slice_mut(s![self.current_index, .., .., ..])
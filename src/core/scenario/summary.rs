@@ -21,6 +21,10 @@ use tracing::trace;
 /// - `precision`: The precision.
 /// - `recall`: The recall.
 /// - `threshold`: The optimum classification threshold.
+/// - `runtime_s`: Runtime of the algorithm in seconds.
+/// - `learned_process_covariance_mean`: Mean of the adapted process covariance.
+/// - `learned_measurement_covariance_mean`: Mean of the diagonal of the
+///   adapted measurement covariance.
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Summary {
     #[serde(default)]
@@ -39,12 +43,42 @@ pub struct Summary {
     pub recall: f32,
     #[serde(default)]
     pub threshold: f32,
+    #[serde(default)]
+    pub number_of_lesions: usize,
+    #[serde(default)]
+    // lesions with at least half of their voxels detected at the threshold
+    pub lesions_detected: usize,
+    #[serde(default)]
+    pub severity_mean_absolute_error: f32,
+    #[serde(default)]
+    pub severity_correlation: f32,
+    #[serde(default)]
+    pub propagation_speed_mean_absolute_error_m_per_s: f32,
+    #[serde(default)]
+    pub propagation_speed_root_mean_square_error_m_per_s: f32,
+    #[serde(default)]
+    pub propagation_speed_mean_relative_error: f32,
+    #[serde(default)]
+    pub earliest_activation_error_mm: f32,
+    #[serde(default)]
+    // neighbour connections of the estimated model
+    pub number_of_connections: usize,
+    #[serde(default)]
+    // gains and delays the refinement calculates gradients for
+    pub number_of_gradients: usize,
+    #[serde(default)]
+    // wall-clock time of the algorithm, without data and model creation
+    pub runtime_s: f32,
+    #[serde(default)]
+    pub learned_process_covariance_mean: f32,
+    #[serde(default)]
+    pub learned_measurement_covariance_mean: f32,
 }
 
 impl Default for Summary {
     /// Returns a `Summary` struct initialized with default values.
     ///
-    /// Default values are 0.0 for all fields.
+    /// Default values are zero for all fields.
     #[must_use]
     #[tracing::instrument(level = "trace")]
     fn default() -> Self {
@@ -58,6 +92,19 @@ impl Default for Summary {
             precision: 0.0,
             recall: 0.0,
             threshold: 0.0,
+            number_of_lesions: 0,
+            lesions_detected: 0,
+            severity_mean_absolute_error: 0.0,
+            severity_correlation: 0.0,
+            propagation_speed_mean_absolute_error_m_per_s: 0.0,
+            propagation_speed_root_mean_square_error_m_per_s: 0.0,
+            propagation_speed_mean_relative_error: 0.0,
+            earliest_activation_error_mm: 0.0,
+            number_of_connections: 0,
+            number_of_gradients: 0,
+            runtime_s: 0.0,
+            learned_process_covariance_mean: 0.0,
+            learned_measurement_covariance_mean: 0.0,
         }
     }
 }
@@ -1,6 +1,12 @@
 use std::{fs, path::Path};
 
-use crate::core::scenario::Scenario;
+use crate::core::{
+    config::{
+        algorithm::AlgorithmType,
+        model::{Fibers, Mri, Stimulus},
+    },
+    scenario::{Scenario, Status},
+};
 
 #[test]
 fn building_saves_scenario() {
@@ -28,3 +34,45 @@ fn loading_scenarios_works() {
 
     fs::remove_dir_all(path).unwrap();
 }
+
+#[test]
+fn scheduling_rejects_duplicate_segmentation_labels() {
+    let mut scenario = Scenario::empty();
+    scenario.status = Status::Planning;
+    let mut mri = Mri::default();
+    mri.labels.0.push(mri.labels.0[0]);
+    scenario.config.algorithm.model.mri = Some(mri);
+
+    assert!(scenario.schedule().is_err());
+    assert_eq!(*scenario.get_status(), Status::Planning);
+}
+
+#[test]
+fn scheduling_rejects_invalid_fibers() {
+    let mut scenario = Scenario::empty();
+    scenario.status = Status::Planning;
+    scenario.config.simulation.model.common.fibers = Some(Fibers {
+        transverse_velocity_ratio: 0.0,
+        ..Default::default()
+    });
+    assert!(scenario.schedule().is_err());
+
+    scenario.config.simulation.model.common.fibers = Some(Fibers::default());
+    scenario.config.simulation.model.mri = Some(Mri::default());
+    assert!(scenario.schedule().is_err());
+    assert_eq!(*scenario.get_status(), Status::Planning);
+}
+
+#[test]
+fn scheduling_rejects_stimulus_onsets_on_gpu() {
+    let mut scenario = Scenario::empty();
+    scenario.status = Status::Planning;
+    scenario.config.algorithm.algorithm_type = AlgorithmType::ModelBasedGPU;
+    scenario.config.algorithm.model.common.stimuli = vec![Stimulus {
+        onsets_s: vec![0.0, 0.1],
+        ..Default::default()
+    }];
+
+    assert!(scenario.schedule().is_err());
+    assert_eq!(*scenario.get_status(), Status::Planning);
+}
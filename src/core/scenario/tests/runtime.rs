@@ -138,7 +138,7 @@ fn create_and_run(
 
     if RUN_IN_TESTS {
         for handle in join_handles {
-            handle.join().unwrap();
+            handle.join().unwrap().unwrap();
         }
         for scenario in &mut scenarios {
             let path = Path::new("results").join(scenario.id.clone());
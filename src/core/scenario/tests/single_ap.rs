@@ -647,7 +647,7 @@ fn create_and_run(target_delays: Vec<f32>, initial_delay: f32, base_id: &str, pa
 
     if RUN_IN_TESTS {
         for handle in join_handles {
-            handle.join().unwrap();
+            handle.join().unwrap().unwrap();
         }
         for scenario in &mut scenarios {
             let path = Path::new("results").join(scenario.id.clone());
@@ -26,7 +26,7 @@ pub struct SelectedSenario {
 #[derive(Debug)]
 pub struct ScenarioBundle {
     pub scenario: Scenario,
-    pub join_handle: Option<JoinHandle<()>>,
+    pub join_handle: Option<JoinHandle<Result<(), String>>>,
     pub epoch_rx: Option<Mutex<Receiver<usize>>>,
     pub summary_rx: Option<Mutex<Receiver<Summary>>>,
 }
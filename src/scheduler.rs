@@ -148,15 +148,20 @@ pub fn check_scenarios(
                 None => panic!("Running scenario has no summary receiver."),
             }
 
-            match &entry.join_handle {
+            match entry.join_handle.take() {
+                Some(join_handle) if !join_handle.is_finished() => {
+                    entry.join_handle = Some(join_handle);
+                }
                 Some(join_handle) => {
-                    if join_handle.is_finished() {
+                    if let Ok(Err(error)) = join_handle.join() {
+                        error!("Scenario {} failed: {error}", entry.scenario.get_id());
+                        entry.scenario.set_aborted();
+                    } else {
                         entry.scenario.set_done();
-                        entry.join_handle = None;
-                        entry.epoch_rx = None;
-                        entry.summary_rx = None;
-                        entry.scenario.save().expect("Scenarion to be parseable.");
                     }
+                    entry.epoch_rx = None;
+                    entry.summary_rx = None;
+                    entry.scenario.save().expect("Scenarion to be parseable.");
                 }
                 None => panic!("Running scenario does not have a join handle."),
             }
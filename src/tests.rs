@@ -1,5 +1,7 @@
 use std::path::{Path, PathBuf};
 
+use crate::core::config::model::{Common, Handcrafted, Model};
+
 #[tracing::instrument(level = "trace")]
 pub fn setup_folder<P>(path: P)
 where
@@ -16,3 +18,21 @@ pub fn clean_files(files: &Vec<PathBuf>) {
         }
     }
 }
+
+/// Handcrafted model of the given size with one millimeter voxels and the
+/// default settings otherwise.
+#[must_use]
+#[tracing::instrument(level = "trace")]
+pub fn handcrafted_model(heart_size_mm: [f32; 3]) -> Model {
+    Model {
+        handcrafted: Some(Handcrafted {
+            heart_size_mm,
+            ..Default::default()
+        }),
+        common: Common {
+            voxel_size_mm: 1.0,
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
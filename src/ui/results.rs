@@ -10,22 +10,26 @@ use bevy::prelude::*;
 use bevy_editor_cam::prelude::{EditorCam, EnabledMotion};
 use bevy_egui::{egui, EguiContexts};
 use egui::{Slider, Spinner};
-use ndarray::s;
+use ndarray::{s, Array1};
 use strum::IntoEnumIterator;
 use strum_macros::{Display, EnumIter};
 
 use crate::{
     core::{
-        algorithm::metrics::predict_voxeltype,
-        model::functional::allpass::shapes::ActivationTimeMs, scenario::Scenario,
+        algorithm::{metrics::predict_voxeltype, refinement::window::FitWindows},
+        model::functional::{allpass::shapes::ActivationTimeMs, time_offset::TimeOffsets},
+        scenario::Scenario,
     },
     vis::plotting::{
         gif::states::states_spherical_plot_over_time,
         png::{
             activation_time::activation_time_plot,
             delay::average_delay_plot,
-            line::{standard_log_y_plot, standard_time_plot, standard_y_plot},
-            propagation_speed::average_propagation_speed_plot,
+            line::{
+                line_plot, shaded_time_plot, standard_log_y_plot, standard_time_plot,
+                standard_y_plot,
+            },
+            propagation_speed::{average_propagation_speed_plot, propagation_speed_error_plot},
             states::states_spherical_plot,
             voxel_type::voxel_type_plot,
         },
@@ -60,6 +64,7 @@ pub enum ImageType {
     AverageDelayAlgorithm,
     AveragePropagationSpeedAlgorithm,
     AverageDelayDelta,
+    AveragePropagationSpeedDelta,
     // Metrics
     Dice,
     IoU,
@@ -72,6 +77,11 @@ pub enum ImageType {
     LossMse,
     LossMaximumRegularization,
     LossMaximumRegularizationEpoch,
+    LossHuberEpoch,
+    LossL1Epoch,
+    LossCauchyEpoch,
+    LossNccEpoch,
+    LossSpectralEpoch,
     // Time functions
     ControlFunctionAlgorithm,
     ControlFunctionSimulation,
@@ -81,7 +91,9 @@ pub enum ImageType {
     StateDelta,
     MeasurementAlgorithm,
     MeasurementSimulation,
+    MeasurementSimulationRaw,
     MeasurementDelta,
+    TimeOffsets,
 }
 
 #[derive(EnumIter, Debug, PartialEq, Eq, Hash, Display, Clone, Copy)]
@@ -292,6 +304,8 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
     let model = scenario.results.as_ref().unwrap().model.as_ref().unwrap();
     let data = scenario.data.as_ref().unwrap();
     let metrics = &scenario.results.as_ref().unwrap().metrics;
+    let fit_regions_s = FitWindows::from_config(&scenario.config.algorithm, data)
+        .regions_s(0, data.simulation.sample_rate_hz);
     match image_type {
         // might want to return this at some later point
         ImageType::StatesMaxAlgorithm => states_spherical_plot(
@@ -468,6 +482,13 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
             None,
             None,
         ),
+        ImageType::AveragePropagationSpeedDelta => propagation_speed_error_plot(
+            &metrics.propagation_speed_error_m_per_s,
+            &model.spatial_description.voxels.positions_mm,
+            model.spatial_description.voxels.size_mm,
+            &path,
+            None,
+        ),
         ImageType::LossEpoch => standard_log_y_plot(
             &metrics.loss_batch,
             &path,
@@ -504,6 +525,41 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
             "Loss",
             "Step",
         ),
+        ImageType::LossHuberEpoch => standard_y_plot(
+            &metrics.loss_huber_batch,
+            &path,
+            "Sum Huber Loss Per Epoch",
+            "Loss",
+            "Epoch",
+        ),
+        ImageType::LossL1Epoch => standard_y_plot(
+            &metrics.loss_l1_batch,
+            &path,
+            "Sum L1 Loss Per Epoch",
+            "Loss",
+            "Epoch",
+        ),
+        ImageType::LossCauchyEpoch => standard_y_plot(
+            &metrics.loss_cauchy_batch,
+            &path,
+            "Sum Cauchy Loss Per Epoch",
+            "Loss",
+            "Epoch",
+        ),
+        ImageType::LossNccEpoch => standard_y_plot(
+            &metrics.loss_ncc_batch,
+            &path,
+            "Sum NCC Loss Per Epoch",
+            "Loss",
+            "Epoch",
+        ),
+        ImageType::LossSpectralEpoch => standard_y_plot(
+            &metrics.loss_spectral_batch,
+            &path,
+            "Sum Spectral Loss Per Epoch",
+            "Loss",
+            "Epoch",
+        ),
         ImageType::Dice => standard_y_plot(
             &metrics.dice_score_over_threshold,
             &path,
@@ -534,7 +590,7 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
         ),
         ImageType::ControlFunctionAlgorithm => standard_time_plot(
             &model.functional_description.control_function_values,
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "Control Function Algorithm",
             "u [A/mm^2]",
@@ -545,7 +601,7 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
                 .model
                 .functional_description
                 .control_function_values,
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "Control Function Simulation",
             "u [A/mm^2]",
@@ -557,21 +613,21 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
                     .model
                     .functional_description
                     .control_function_values),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "Control Function Delta",
             "u [A/mm^2]",
         ),
         ImageType::StateAlgorithm => standard_time_plot(
             &estimations.system_states.slice(s![.., 0]).to_owned(),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "System State 0 Algorithm",
             "j [A/mm^2]",
         ),
         ImageType::StateSimulation => standard_time_plot(
             &data.simulation.system_states.slice(s![.., 0]).to_owned(),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "System State 0 Simulation",
             "j [A/mm^2]",
@@ -579,33 +635,78 @@ fn generate_image(scenario: Scenario, image_type: ImageType) -> Result<(), Box<d
         ImageType::StateDelta => standard_time_plot(
             &(&estimations.system_states.slice(s![.., 0]).to_owned()
                 - &data.simulation.system_states.slice(s![.., 0]).to_owned()),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &path,
             "System State 0 Delta",
             "j [A/mm^2]",
         ),
-        ImageType::MeasurementAlgorithm => standard_time_plot(
+        ImageType::MeasurementAlgorithm => shaded_time_plot(
             &estimations.measurements.slice(s![0, .., 0]).to_owned(),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
+            &fit_regions_s,
             &path,
             "Measurement 0 Algorithm",
             "z [pT]",
         ),
-        ImageType::MeasurementSimulation => standard_time_plot(
+        ImageType::MeasurementSimulation => shaded_time_plot(
             &data.simulation.measurements.slice(s![0, .., 0]).to_owned(),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
+            &fit_regions_s,
             &path,
             "Measurement 0 Simulation",
             "z [pT]",
         ),
-        ImageType::MeasurementDelta => standard_time_plot(
+        ImageType::MeasurementSimulationRaw => data.preprocessed.as_ref().map_or_else(
+            || {
+                standard_time_plot(
+                    &data.simulation.measurements.slice(s![0, .., 0]).to_owned(),
+                    data.simulation.sample_rate_hz,
+                    &path,
+                    "Measurement 0 Simulation Raw",
+                    "z [pT]",
+                )
+            },
+            |preprocessed| {
+                standard_time_plot(
+                    &preprocessed.raw_measurements.slice(s![0, .., 0]).to_owned(),
+                    preprocessed.raw_sample_rate_hz,
+                    &path,
+                    "Measurement 0 Simulation Raw",
+                    "z [pT]",
+                )
+            },
+        ),
+        ImageType::MeasurementDelta => shaded_time_plot(
             &(&estimations.measurements.slice(s![0, .., 0]).to_owned()
                 - &data.simulation.measurements.slice(s![0, .., 0]).to_owned()),
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
+            &fit_regions_s,
             &path,
             "Measurement 0 Delta",
             "z [pT]",
         ),
+        ImageType::TimeOffsets => {
+            let offsets_ms = |time_offsets: &TimeOffsets| {
+                (0..data.simulation.measurements.num_beats())
+                    .map(|beat| {
+                        time_offsets.at_beat(beat) * 1000.0 / data.simulation.sample_rate_hz
+                    })
+                    .collect::<Array1<f32>>()
+            };
+            let algorithm_offsets_ms = offsets_ms(&model.functional_description.time_offsets);
+            let simulation_offsets_ms =
+                offsets_ms(&data.simulation.model.functional_description.time_offsets);
+            line_plot(
+                None,
+                vec![&algorithm_offsets_ms, &simulation_offsets_ms],
+                Some(&path),
+                Some("Time Offset per Beat"),
+                Some("Offset [ms]"),
+                Some("Beat"),
+                Some(&vec!["Algorithm", "Simulation"]),
+                None,
+            )
+        }
     }?;
     Ok(())
 }
@@ -642,7 +743,7 @@ fn generate_gifs(
             &estimations.system_states_spherical_max,
             &model.spatial_description.voxels.positions_mm,
             model.spatial_description.voxels.size_mm,
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &model.spatial_description.voxels.numbers,
             Some(path.as_path()),
             Some(PlotSlice::Z(0)),
@@ -660,7 +761,7 @@ fn generate_gifs(
                 .voxels
                 .positions_mm,
             model.spatial_description.voxels.size_mm,
-            scenario.config.simulation.sample_rate_hz,
+            data.simulation.sample_rate_hz,
             &model.spatial_description.voxels.numbers,
             Some(path.as_path()),
             Some(PlotSlice::Z(0)),
@@ -115,7 +115,9 @@ fn draw_ui_scenario_topbar(
             match scenario.get_status() {
                 Status::Planning => {
                     if ui.button("Schedule").clicked() {
-                        scenario.schedule().unwrap();
+                        if let Err(error) = scenario.schedule() {
+                            warn!("Could not schedule scenario: {error}");
+                        }
                     }
                 }
                 Status::Scheduled => {
@@ -6,7 +6,9 @@ use super::{
 };
 use crate::core::{
     algorithm::refinement::Optimizer,
-    config::algorithm::{Algorithm, AlgorithmType},
+    config::algorithm::{
+        Algorithm, AlgorithmType, CovarianceAdaptation, EstimationBackend, FitWindowReference,
+    },
     scenario::{Scenario, Status},
 };
 
@@ -31,6 +33,8 @@ pub fn draw_ui_scenario_algoriothm(parent: &mut egui::Ui, scenario: &mut Scenari
                 draw_regularization_settings(ui, algorithm);
                 draw_metrics_settings(ui, algorithm);
                 draw_ui_scenario_common(ui, &mut algorithm.model);
+            } else if algorithm.algorithm_type == AlgorithmType::PseudoInverse {
+                draw_regularization_settings(ui, algorithm);
             }
         });
 }
@@ -80,6 +84,142 @@ fn draw_estimation_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                     });
                 });
                 if algorithm.model.common.apply_system_update {
+                    // Estimation backend
+                    let estimation_backend = &mut algorithm.estimation_backend;
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Estimation\nbackend");
+                        });
+                        row.col(|ui| {
+                            egui::ComboBox::new("cb_estimation_backend", "")
+                                .selected_text(format!("{estimation_backend:?}"))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(
+                                        estimation_backend,
+                                        EstimationBackend::Kalman,
+                                        "Kalman",
+                                    );
+                                    ui.selectable_value(
+                                        estimation_backend,
+                                        EstimationBackend::EnsembleKalman,
+                                        "Ensemble Kalman",
+                                    );
+                                    ui.selectable_value(
+                                        estimation_backend,
+                                        EstimationBackend::UnscentedKalman,
+                                        "Unscented Kalman",
+                                    );
+                                });
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The filter used to calculate the\
+                                    Kalman gain and state covariances.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    if algorithm.estimation_backend == EstimationBackend::EnsembleKalman {
+                        // Ensemble size
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label("Ensemble\nsize");
+                            });
+                            row.col(|ui| {
+                                ui.add(egui::Slider::new(&mut algorithm.ensemble_size, 2..=1000));
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "The number of ensemble members. Default: 32.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                        // Ensemble seed
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label("Ensemble\nseed");
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::DragValue::new(&mut algorithm.ensemble_seed).speed(1.0),
+                                );
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "The seed of the ensemble and observation noise. Default: 42.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                    }
+                    // Covariance adaptation
+                    let covariance_adaptation = &mut algorithm.covariance_adaptation;
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Covariance\nadaptation");
+                        });
+                        row.col(|ui| {
+                            egui::ComboBox::new("cb_covariance_adaptation", "")
+                                .selected_text(format!("{covariance_adaptation:?}"))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(
+                                        covariance_adaptation,
+                                        CovarianceAdaptation::None,
+                                        "None",
+                                    );
+                                    ui.selectable_value(
+                                        covariance_adaptation,
+                                        CovarianceAdaptation::Innovation,
+                                        "Innovation",
+                                    );
+                                    ui.selectable_value(
+                                        covariance_adaptation,
+                                        CovarianceAdaptation::ExpectationMaximization,
+                                        "Expectation Maximization",
+                                    );
+                                });
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Re-estimates the process and measurement\
+                                    covariances from the residuals, either after\
+                                    every step or once per beat.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    if algorithm.covariance_adaptation != CovarianceAdaptation::None {
+                        // Forgetting factor
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label("Forgetting\nfactor");
+                            });
+                            row.col(|ui| {
+                                ui.add(egui::Slider::new(
+                                    &mut algorithm.covariance_adaptation_forgetting_factor,
+                                    0.0..=1.0,
+                                ));
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "The weight of the old covariances\
+                                        when adapting them. Default: 0.95.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                    }
                     // Update Kalman Gain
                     body.row(ROW_HEIGHT, |mut row| {
                         row.col(|ui| {
@@ -100,6 +240,26 @@ fn draw_estimation_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                             );
                         });
                     });
+                    // Steady-state Kalman Gain
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Steady-state\nKalman Gain");
+                        });
+                        row.col(|ui| {
+                            ui.checkbox(&mut algorithm.steady_state_kalman_gain, "");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Wether or not to iterate the Kalman\
+                                    gain to convergence once per beat. The gains are\
+                                    cached per beat until the allpass gains\
+                                    change.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
                     // Process covariance mean
                     body.row(ROW_HEIGHT, |mut row| {
                         row.col(|ui| {
@@ -152,6 +312,28 @@ fn draw_estimation_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                         });
                     });
                 }
+                if algorithm.algorithm_type == AlgorithmType::ModelBased {
+                    // RTS smoothing
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("RTS\nsmoothing");
+                        });
+                        row.col(|ui| {
+                            ui.checkbox(&mut algorithm.rts_smoothing, "");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Wether or not to run a backward\
+                                    Rauch-Tung-Striebel pass after each epoch.\
+                                    The smoothed states are used for the\
+                                    activation times and metrics.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                }
             });
     });
 }
@@ -222,6 +404,303 @@ fn draw_regularization_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                             );
                         });
                     });
+                    // Data-fit losses
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Huber\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.huber_strength, 0.0..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the Huber loss. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Huber\ndelta");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.huber_delta, 0.01..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Residuals larger than delta are penalized linearly. Default: 1.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("L1\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.l1_strength, 0.0..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the L1 loss. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Cauchy\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.cauchy_strength, 0.0..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the Cauchy loss. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Cauchy\nscale");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.cauchy_scale, 0.01..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Residuals above the scale are down-weighted. Default: 1.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("NCC\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.ncc_strength, 0.0..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of one minus the normalized cross-correlation per sensor. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Spectral\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(&mut algorithm.spectral_strength, 0.0..=100.0));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the magnitude spectrum difference per sensor. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    // Fit windows
+                    let fit_window_reference = &mut algorithm.fit_window_reference;
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Fit window\nreference");
+                        });
+                        row.col(|ui| {
+                            egui::ComboBox::new("cb_fit_window_reference", "")
+                                .selected_text(format!("{fit_window_reference:?}"))
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(
+                                        fit_window_reference,
+                                        FitWindowReference::BeatStart,
+                                        "Beat start",
+                                    );
+                                    ui.selectable_value(
+                                        fit_window_reference,
+                                        FitWindowReference::RPeak,
+                                        "R-peak",
+                                    );
+                                });
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The time the fit windows are relative to.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    let mut removed_window = None;
+                    for (index, [start_ms, end_ms]) in
+                        algorithm.fit_windows_ms.iter_mut().enumerate()
+                    {
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label(format!("Fit window\n{index}"));
+                            });
+                            row.col(|ui| {
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::DragValue::new(start_ms).suffix(" ms"));
+                                    ui.add(egui::DragValue::new(end_ms).suffix(" ms"));
+                                    if ui.button("Remove").clicked() {
+                                        removed_window = Some(index);
+                                    }
+                                });
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "Start and end of a fitted part of the beat.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                    }
+                    if let Some(index) = removed_window {
+                        algorithm.fit_windows_ms.remove(index);
+                    }
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Fit windows");
+                        });
+                        row.col(|ui| {
+                            if ui.button("Add window").clicked() {
+                                algorithm.fit_windows_ms.push([0.0, 100.0]);
+                            }
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Only the windows contribute to the loss. Default: whole beat.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    // Time offsets
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Estimate\ntime offsets");
+                        });
+                        row.col(|ui| {
+                            ui.checkbox(&mut algorithm.estimate_time_offsets, "");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Wether or not to estimate a time shift\
+                                    between model and measurements for every beat.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    if algorithm.estimate_time_offsets {
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label("Time offset\nstep size");
+                            });
+                            row.col(|ui| {
+                                ui.add(egui::Slider::new(
+                                    &mut algorithm.time_offset_step_size,
+                                    0.0..=1.0,
+                                ));
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "The damping of the offset updates. Default: 0.5.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                        body.row(ROW_HEIGHT, |mut row| {
+                            row.col(|ui| {
+                                ui.label("Maximum\ntime offset");
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Slider::new(
+                                        &mut algorithm.maximum_time_offset_ms,
+                                        0.0..=500.0,
+                                    )
+                                    .suffix(" ms"),
+                                );
+                            });
+                            row.col(|ui| {
+                                ui.add(
+                                    egui::Label::new(
+                                        "The largest offset searched and allowed. Default: 50 ms.",
+                                    )
+                                    .truncate(),
+                                );
+                            });
+                        });
+                    }
+                }
+                if algorithm.algorithm_type == AlgorithmType::PseudoInverse {
+                    // Temporal regularization strength
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Temporal\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(
+                                &mut algorithm.pseudo_inverse_temporal_regularization_strength,
+                                0.0..=100.0,
+                            ));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the difference to the\
+                                    previous time step (Twomey). Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    // Spatial regularization strength
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Spatial\nstrength");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(
+                                &mut algorithm.pseudo_inverse_spatial_regularization_strength,
+                                0.0..=100.0,
+                            ));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The weighting of the laplacian over\
+                                    connected voxels. Default: 0.0.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
                 }
             });
     });
@@ -289,7 +768,7 @@ fn draw_algorithm_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                         );
                     });
                 });
-                if algorithm_type == &AlgorithmType::ModelBased {
+                if algorithm.algorithm_type == AlgorithmType::ModelBased {
                     // Epochs
                     body.row(ROW_HEIGHT, |mut row| {
                         row.col(|ui| {
@@ -360,8 +839,9 @@ fn draw_algorithm_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                             );
                         });
                     });
+                    draw_control_function_rows(&mut body, algorithm, true);
                 }
-                if algorithm_type == &AlgorithmType::ModelBasedGPU {
+                if algorithm.algorithm_type == AlgorithmType::ModelBasedGPU {
                     // Epochs
                     body.row(ROW_HEIGHT, |mut row| {
                         row.col(|ui| {
@@ -413,11 +893,88 @@ fn draw_algorithm_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
                             );
                         });
                     });
+                    draw_control_function_rows(&mut body, algorithm, false);
                 }
             });
     });
 }
 
+/// Draws the rows for learning the control function.
+///
+/// The bandwidth limit is only available on the CPU.
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_control_function_rows(
+    body: &mut egui_extras::TableBody,
+    algorithm: &mut Algorithm,
+    show_bandwidth: bool,
+) {
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Learn control\nfunction");
+        });
+        row.col(|ui| {
+            ui.checkbox(&mut algorithm.learn_control_function, "");
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Wether or not to optimize the control function\
+                    together with the gains and delays.",
+                )
+                .truncate(),
+            );
+        });
+    });
+    if !algorithm.learn_control_function {
+        return;
+    }
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Control function\nsmoothness");
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Slider::new(
+                    &mut algorithm.control_function_smoothness_strength,
+                    0.0..=1e6,
+                )
+                .logarithmic(true),
+            );
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Penalizes differences between neighbouring\
+                    control function values. Default: 0.",
+                )
+                .truncate(),
+            );
+        });
+    });
+    if show_bandwidth {
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Control function\nbandwidth");
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Slider::new(&mut algorithm.control_function_bandwidth_hz, 0.0..=500.0)
+                        .suffix(" Hz"),
+                );
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new(
+                        "Frequencies above this are removed from the\
+                        control function. Default: 0 - no limit.",
+                    )
+                    .truncate(),
+                );
+            });
+        });
+    }
+}
+
 #[tracing::instrument(skip_all, level = "trace")]
 fn draw_metrics_settings(ui: &mut egui::Ui, algorithm: &mut Algorithm) {
     ui.label(egui::RichText::new("Metrics Settings").underline());
@@ -1,12 +1,20 @@
 use std::path::PathBuf;
 
 use egui_extras::{Column, TableBuilder};
+use strum::IntoEnumIterator;
 use tracing::trace;
 
 use super::{FIRST_COLUMN_WIDTH, PADDING, ROW_HEIGHT, SECOND_COLUMN_WIDTH};
 use crate::core::{
-    config::model::{ControlFunction, Handcrafted, Model, Mri},
-    model::spatial::voxels::VoxelType,
+    config::model::{
+        Barrier, Common, ControlFunction, FiberOrientation, Fibers, Handcrafted, Model, Mri,
+        Neighbourhood, Pathology, PathologyShape, SegmentationLabel, SegmentationLabels,
+        SeverityFalloff, Stimulus,
+    },
+    model::spatial::{
+        nifti::read_orientation,
+        voxels::{is_connection_allowed, VoxelType},
+    },
 };
 
 /// Draws ui for settings common to data generation and optimization.
@@ -21,7 +29,7 @@ pub fn draw_ui_scenario_common(ui: &mut egui::Ui, model: &mut Model) {
         draw_handcrafted_settings(ui, handcrafted, model.common.pathological);
     }
     if let Some(mri) = model.mri.as_mut() {
-        draw_mri_settings(ui, mri, model.common.pathological);
+        draw_mri_settings(ui, mri);
     }
 }
 
@@ -99,6 +107,31 @@ fn draw_measurement_settings(ui: &mut egui::Ui, model: &mut Model) {
                         );
                     });
                 });
+                // Measurement covariance correlation length
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Measurement\ncorrelation length");
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Slider::new(
+                                &mut model.common.measurement_covariance_correlation_length_mm,
+                                0.0..=1000.0,
+                            )
+                            .suffix(" mm"),
+                        );
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "The distance over which the noise of sensors \
+                                with the same orientation is correlated. \
+                                If this is zero, the noise is uncorrelated.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
             });
     });
 }
@@ -147,6 +180,11 @@ fn draw_functional_settings(ui: &mut egui::Ui, model: &mut Model) {
                                     ControlFunction::Ohara,
                                     "Ohara",
                                 );
+                                ui.selectable_value(
+                                    control_function,
+                                    ControlFunction::Custom,
+                                    "Custom",
+                                );
                             });
                     });
                     row.col(|ui| {
@@ -159,6 +197,94 @@ fn draw_functional_settings(ui: &mut egui::Ui, model: &mut Model) {
                         );
                     });
                 });
+                if model.common.control_function == ControlFunction::Custom {
+                    // Control function path
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Control function\npath");
+                        });
+                        row.col(|ui| {
+                            let mut path = model
+                                .common
+                                .control_function_path
+                                .as_ref()
+                                .map_or_else(String::new, |path| {
+                                    path.to_string_lossy().to_string()
+                                });
+                            ui.add(egui::TextEdit::singleline(&mut path));
+                            model.common.control_function_path =
+                                (!path.is_empty()).then(|| PathBuf::from(path));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new("The path to the .npy or .csv waveform.")
+                                    .truncate(),
+                            );
+                        });
+                    });
+                    // Control function sample rate
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Control function\nsample rate");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Slider::new(
+                                    &mut model.common.control_function_sample_rate_hz,
+                                    0.0..=48000.0,
+                                )
+                                .suffix(" Hz"),
+                            );
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The sample rate of the waveform. \
+                                    Default: 0 - same as the simulation.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                }
+                // Control function cycle length
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Control function\ncycle length");
+                    });
+                    row.col(|ui| {
+                        ui.horizontal(|ui| {
+                            let mut single_beat =
+                                model.common.control_function_cycle_length_s.is_some();
+                            ui.checkbox(&mut single_beat, "");
+                            if single_beat != model.common.control_function_cycle_length_s.is_some()
+                            {
+                                model.common.control_function_cycle_length_s =
+                                    single_beat.then_some(1.0);
+                            }
+                            if let Some(cycle_length_s) =
+                                model.common.control_function_cycle_length_s.as_mut()
+                            {
+                                ui.add(
+                                    egui::DragValue::new(cycle_length_s)
+                                        .speed(0.01)
+                                        .range(0.01..=10.0)
+                                        .suffix(" s"),
+                                );
+                            }
+                        });
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Treats the waveform as a single beat that is repeated \
+                                with this cycle length.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                draw_stimulus_rows(&mut body, &mut model.common.stimuli);
                 // Pathological
                 body.row(ROW_HEIGHT, |mut row| {
                     row.col(|ui| {
@@ -198,9 +324,563 @@ fn draw_functional_settings(ui: &mut egui::Ui, model: &mut Model) {
                             );
                         });
                     });
+                    draw_pathology_rows(&mut body, &mut model.common.pathologies);
+                }
+                draw_barrier_rows(&mut body, &mut model.common.barriers);
+                draw_connection_rows(&mut body, &mut model.common);
+            });
+    });
+}
+
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_stimulus_rows(body: &mut egui_extras::TableBody, stimuli: &mut Vec<Stimulus>) {
+    let mut removed_stimulus = None;
+    for (index, stimulus) in stimuli.iter_mut().enumerate() {
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label(format!("Stimulus\n{index}"));
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    for value in &mut stimulus.position_mm {
+                        ui.add(egui::DragValue::new(value).speed(0.5).suffix(" mm"));
+                    }
+                    if ui.button("Remove").clicked() {
+                        removed_stimulus = Some(index);
+                    }
+                });
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new(
+                        "Position of the stimulus in mm relative to the heart corner. \
+                        It is placed at the closest heart voxel.",
+                    )
+                    .truncate(),
+                );
+            });
+        });
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Onsets");
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    for onset_s in &mut stimulus.onsets_s {
+                        ui.add(
+                            egui::DragValue::new(onset_s)
+                                .speed(0.001)
+                                .range(0.0..=10.0)
+                                .suffix(" s"),
+                        );
+                    }
+                    if ui.button("+").clicked() {
+                        let last_s = stimulus.onsets_s.last().copied().unwrap_or_default();
+                        stimulus.onsets_s.push(last_s);
+                    }
+                    if stimulus.onsets_s.len() > 1 && ui.button("-").clicked() {
+                        stimulus.onsets_s.pop();
+                    }
+                });
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new(
+                        "Onset of the stimulus after the start of every beat. \
+                        The last onset is used for all following beats.",
+                    )
+                    .truncate(),
+                );
+            });
+        });
+    }
+    if let Some(index) = removed_stimulus {
+        stimuli.remove(index);
+    }
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Stimuli");
+        });
+        row.col(|ui| {
+            if ui.button("Add stimulus").clicked() {
+                stimuli.push(Stimulus::default());
+            }
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "If any are added, they replace the sinoatrial node as pacing sites.",
+                )
+                .truncate(),
+            );
+        });
+    });
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_pathology_rows(body: &mut egui_extras::TableBody, pathologies: &mut Vec<Pathology>) {
+    let drag_values = |ui: &mut egui::Ui, values: &mut [f32; 3]| {
+        ui.horizontal(|ui| {
+            for value in values.iter_mut() {
+                ui.add(egui::DragValue::new(value).speed(0.5).suffix(" mm"));
+            }
+        });
+    };
+    let mut removed_pathology = None;
+    for (index, pathology) in pathologies.iter_mut().enumerate() {
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label(format!("Pathology\n{index}"));
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    let shape = &mut pathology.shape;
+                    let name = match shape {
+                        PathologyShape::Box { .. } => "Box",
+                        PathologyShape::Sphere { .. } => "Sphere",
+                        PathologyShape::Ellipsoid { .. } => "Ellipsoid",
+                        PathologyShape::Cylinder { .. } => "Cylinder",
+                        PathologyShape::Mask { .. } => "Mask",
+                        PathologyShape::Seed { .. } => "Seed",
+                    };
+                    egui::ComboBox::new(format!("cb_pathology_shape_{index}"), "")
+                        .selected_text(name)
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(name == "Box", "Box").clicked() {
+                                *shape = PathologyShape::Box {
+                                    min_mm: [0.0; 3],
+                                    max_mm: [10.0; 3],
+                                };
+                            }
+                            if ui.selectable_label(name == "Sphere", "Sphere").clicked() {
+                                *shape = PathologyShape::default();
+                            }
+                            if ui
+                                .selectable_label(name == "Ellipsoid", "Ellipsoid")
+                                .clicked()
+                            {
+                                *shape = PathologyShape::Ellipsoid {
+                                    center_mm: [10.0, 10.0, 0.0],
+                                    radii_mm: [5.0, 5.0, 5.0],
+                                };
+                            }
+                            if ui
+                                .selectable_label(name == "Cylinder", "Cylinder")
+                                .clicked()
+                            {
+                                *shape = PathologyShape::Cylinder {
+                                    start_mm: [10.0, 0.0, 0.0],
+                                    end_mm: [10.0, 20.0, 0.0],
+                                    radius_mm: 2.5,
+                                };
+                            }
+                            if ui.selectable_label(name == "Mask", "Mask").clicked() {
+                                *shape = PathologyShape::Mask {
+                                    path: PathBuf::from("assets/pathology_mask.npy"),
+                                };
+                            }
+                            if ui.selectable_label(name == "Seed", "Seed").clicked() {
+                                *shape = PathologyShape::Seed {
+                                    seed_mm: [10.0, 10.0, 0.0],
+                                    volume_ml: 1.0,
+                                };
+                            }
+                        });
+                    if ui.button("Remove").clicked() {
+                        removed_pathology = Some(index);
+                    }
+                });
+            });
+            row.col(|ui| {
+                ui.add(egui::Label::new("Shape of the pathological region.").truncate());
+            });
+        });
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Shape");
+            });
+            row.col(|ui| match &mut pathology.shape {
+                PathologyShape::Box { min_mm, max_mm } => {
+                    drag_values(ui, min_mm);
+                    drag_values(ui, max_mm);
+                }
+                PathologyShape::Sphere {
+                    center_mm,
+                    radius_mm,
+                } => {
+                    drag_values(ui, center_mm);
+                    ui.add(egui::DragValue::new(radius_mm).speed(0.5).suffix(" mm"));
+                }
+                PathologyShape::Ellipsoid {
+                    center_mm,
+                    radii_mm,
+                } => {
+                    drag_values(ui, center_mm);
+                    drag_values(ui, radii_mm);
+                }
+                PathologyShape::Cylinder {
+                    start_mm,
+                    end_mm,
+                    radius_mm,
+                } => {
+                    drag_values(ui, start_mm);
+                    drag_values(ui, end_mm);
+                    ui.add(egui::DragValue::new(radius_mm).speed(0.5).suffix(" mm"));
+                }
+                PathologyShape::Mask { path } => {
+                    let mut text = path.to_str().unwrap_or_default().to_string();
+                    ui.add(egui::TextEdit::singleline(&mut text));
+                    *path = PathBuf::from(text);
+                }
+                PathologyShape::Seed { seed_mm, volume_ml } => {
+                    drag_values(ui, seed_mm);
+                    ui.add(
+                        egui::DragValue::new(volume_ml)
+                            .speed(0.1)
+                            .range(0.0..=500.0)
+                            .suffix(" ml"),
+                    );
+                }
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new(
+                        "Corners, center and radii, cap centers and radius or seed in mm \
+                        relative to the heart corner and the grown volume, or the \
+                        .npy/.nii mask.",
+                    )
+                    .truncate(),
+                );
+            });
+        });
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Tissue");
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    ui.add(egui::Slider::new(&mut pathology.current_factor, 0.0..=1.0));
+                    ui.add(
+                        egui::DragValue::new(&mut pathology.propagation_velocity_m_per_s)
+                            .speed(0.01)
+                            .range(0.01..=10.0)
+                            .suffix(" m/s"),
+                    );
+                });
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new("Current factor and propagation velocity of the region.")
+                        .truncate(),
+                );
+            });
+        });
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Border zone");
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut pathology.border_zone_mm)
+                            .speed(0.5)
+                            .range(0.0..=100.0)
+                            .suffix(" mm"),
+                    );
+                    egui::ComboBox::new(format!("cb_pathology_falloff_{index}"), "")
+                        .selected_text(format!("{:?}", pathology.severity_falloff))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(
+                                &mut pathology.severity_falloff,
+                                SeverityFalloff::Linear,
+                                "Linear",
+                            );
+                            ui.selectable_value(
+                                &mut pathology.severity_falloff,
+                                SeverityFalloff::Gaussian,
+                                "Gaussian",
+                            );
+                        });
+                });
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new("Width and falloff of the severity around the dense core.")
+                        .truncate(),
+                );
+            });
+        });
+    }
+    if let Some(index) = removed_pathology {
+        pathologies.remove(index);
+    }
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Pathologies");
+        });
+        row.col(|ui| {
+            if ui.button("Add pathology").clicked() {
+                pathologies.push(Pathology::default());
+            }
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "If any are added, they replace the handcrafted pathological region.",
+                )
+                .truncate(),
+            );
+        });
+    });
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_barrier_rows(body: &mut egui_extras::TableBody, barriers: &mut Vec<Barrier>) {
+    let mut removed_barrier = None;
+    for (index, barrier) in barriers.iter_mut().enumerate() {
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label(format!("Barrier\n{index}"));
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    let name = match barrier {
+                        Barrier::Plane { .. } => "Plane",
+                        Barrier::Polyline { .. } => "Polyline",
+                        Barrier::Mask { .. } => "Mask",
+                    };
+                    egui::ComboBox::new(format!("cb_barrier_{index}"), "")
+                        .selected_text(name)
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(name == "Plane", "Plane").clicked() {
+                                *barrier = Barrier::Plane {
+                                    point_mm: [10.0, 10.0, 0.0],
+                                    normal: [1.0, 0.0, 0.0],
+                                    radius_mm: 0.0,
+                                };
+                            }
+                            if ui
+                                .selectable_label(name == "Polyline", "Polyline")
+                                .clicked()
+                            {
+                                *barrier = Barrier::default();
+                            }
+                            if ui.selectable_label(name == "Mask", "Mask").clicked() {
+                                *barrier = Barrier::Mask {
+                                    path: PathBuf::from("assets/scar_mask.npy"),
+                                };
+                            }
+                        });
+                    if ui.button("Remove").clicked() {
+                        removed_barrier = Some(index);
+                    }
+                });
+            });
+            row.col(|ui| {
+                ui.add(egui::Label::new("Shape of the line of block or the scar.").truncate());
+            });
+        });
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label("Shape");
+            });
+            row.col(|ui| match barrier {
+                Barrier::Plane {
+                    point_mm,
+                    normal,
+                    radius_mm,
+                } => {
+                    ui.horizontal(|ui| {
+                        for value in point_mm.iter_mut() {
+                            ui.add(egui::DragValue::new(value).speed(0.5).suffix(" mm"));
+                        }
+                    });
+                    ui.horizontal(|ui| {
+                        for value in normal.iter_mut() {
+                            ui.add(egui::DragValue::new(value).speed(0.1));
+                        }
+                        ui.add(
+                            egui::DragValue::new(radius_mm)
+                                .speed(0.5)
+                                .range(0.0..=1000.0)
+                                .suffix(" mm"),
+                        );
+                    });
+                }
+                Barrier::Polyline { points_mm } => {
+                    ui.horizontal(|ui| {
+                        for point_mm in points_mm.iter_mut() {
+                            for value in point_mm.iter_mut() {
+                                ui.add(egui::DragValue::new(value).speed(0.5).suffix(" mm"));
+                            }
+                        }
+                        if ui.button("+").clicked() {
+                            let last_mm = points_mm.last().copied().unwrap_or_default();
+                            points_mm.push(last_mm);
+                        }
+                        if points_mm.len() > 2 && ui.button("-").clicked() {
+                            points_mm.pop();
+                        }
+                    });
+                }
+                Barrier::Mask { path } => {
+                    let mut text = path.to_str().unwrap_or_default().to_string();
+                    ui.add(egui::TextEdit::singleline(&mut text));
+                    *path = PathBuf::from(text);
+                }
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new(
+                        "Point, normal and radius of the plane (zero blocks the whole \
+                        plane), x-y points of the line in mm relative to the heart \
+                        corner, or the .npy scar mask.",
+                    )
+                    .truncate(),
+                );
+            });
+        });
+    }
+    if let Some(index) = removed_barrier {
+        barriers.remove(index);
+    }
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Barriers");
+        });
+        row.col(|ui| {
+            if ui.button("Add barrier").clicked() {
+                barriers.push(Barrier::default());
+            }
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new("Conduction is blocked across barriers and inside of scar.")
+                    .truncate(),
+            );
+        });
+    });
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_connection_rows(body: &mut egui_extras::TableBody, common: &mut Common) {
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Neighbourhood");
+        });
+        row.col(|ui| {
+            ui.horizontal(|ui| {
+                let neighbourhood = &mut common.neighbourhood;
+                let name = match neighbourhood {
+                    Neighbourhood::Six => "6",
+                    Neighbourhood::Eighteen => "18",
+                    Neighbourhood::TwentySix => "26",
+                    Neighbourhood::Radius { .. } => "Radius",
+                };
+                egui::ComboBox::new("cb_neighbourhood", "")
+                    .selected_text(name)
+                    .show_ui(ui, |ui| {
+                        ui.selectable_value(neighbourhood, Neighbourhood::Six, "6");
+                        ui.selectable_value(neighbourhood, Neighbourhood::Eighteen, "18");
+                        ui.selectable_value(neighbourhood, Neighbourhood::TwentySix, "26");
+                        if ui.selectable_label(name == "Radius", "Radius").clicked() {
+                            *neighbourhood = Neighbourhood::Radius {
+                                radius_mm: common.voxel_size_mm,
+                            };
+                        }
+                    });
+                if let Neighbourhood::Radius { radius_mm } = neighbourhood {
+                    ui.add(
+                        egui::DragValue::new(radius_mm)
+                            .speed(0.1)
+                            .range(common.voxel_size_mm..=3f32.sqrt() * common.voxel_size_mm)
+                            .suffix(" mm"),
+                    );
                 }
             });
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Neighbours every voxel can be connected to. A radius selects \
+                    among the 26 direct neighbours.",
+                )
+                .truncate(),
+            );
+        });
+    });
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Connection rules");
+        });
+        row.col(|ui| {
+            let mut custom = !common.allowed_connections.is_empty();
+            ui.checkbox(&mut custom, "");
+            if custom == common.allowed_connections.is_empty() {
+                common.allowed_connections.clear();
+                if custom {
+                    for output_voxel_type in VoxelType::iter().filter(|t| t.is_connectable()) {
+                        common.allowed_connections.insert(
+                            output_voxel_type,
+                            VoxelType::iter()
+                                .filter(|input_voxel_type| {
+                                    input_voxel_type.is_connectable()
+                                        && is_connection_allowed(
+                                            &output_voxel_type,
+                                            input_voxel_type,
+                                        )
+                                })
+                                .collect(),
+                        );
+                    }
+                }
+            }
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Whether to configure which voxel types activate which, \
+                    otherwise the anatomical rules are used.",
+                )
+                .truncate(),
+            );
+        });
     });
+    if common.allowed_connections.is_empty() {
+        return;
+    }
+    for output_voxel_type in VoxelType::iter().filter(|t| t.is_connectable()) {
+        body.row(ROW_HEIGHT, |mut row| {
+            row.col(|ui| {
+                ui.label(format!("{output_voxel_type:?}\nactivates"));
+            });
+            row.col(|ui| {
+                ui.horizontal(|ui| {
+                    let input_voxel_types = common
+                        .allowed_connections
+                        .entry(output_voxel_type)
+                        .or_default();
+                    for input_voxel_type in VoxelType::iter().filter(|t| t.is_connectable()) {
+                        let mut allowed = input_voxel_types.contains(&input_voxel_type);
+                        ui.checkbox(&mut allowed, format!("{input_voxel_type:?}"));
+                        if allowed && !input_voxel_types.contains(&input_voxel_type) {
+                            input_voxel_types.push(input_voxel_type);
+                        } else if !allowed {
+                            input_voxel_types.retain(|voxel_type| *voxel_type != input_voxel_type);
+                        }
+                    }
+                });
+            });
+            row.col(|ui| {
+                ui.add(
+                    egui::Label::new("Voxel types a voxel of this type can activate.").truncate(),
+                );
+            });
+        });
+    }
 }
 
 #[allow(clippy::too_many_lines)]
@@ -410,10 +1090,141 @@ fn draw_velocity_settings(ui: &mut egui::Ui, model: &mut Model) {
                         });
                     });
                 }
+                // Velocity map
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Velocity map");
+                    });
+                    row.col(|ui| {
+                        let mut path = model
+                            .common
+                            .propagation_velocity_map_path
+                            .as_ref()
+                            .map_or_else(String::new, |path| path.to_string_lossy().to_string());
+                        ui.add(egui::TextEdit::singleline(&mut path));
+                        model.common.propagation_velocity_map_path =
+                            (!path.is_empty()).then(|| PathBuf::from(path));
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Optional .npy or .nii file with one velocity in m/s \
+                                per voxel on the model grid. Positive values replace \
+                                the velocities above.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                draw_fiber_rows(&mut body, &mut model.common.fibers);
             });
     });
 }
 
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_fiber_rows(body: &mut egui_extras::TableBody, fibers: &mut Option<Fibers>) {
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Fibers");
+        });
+        row.col(|ui| {
+            ui.horizontal(|ui| {
+                let mut anisotropic = fibers.is_some();
+                ui.checkbox(&mut anisotropic, "");
+                if anisotropic != fibers.is_some() {
+                    *fibers = anisotropic.then(Fibers::default);
+                }
+                if let Some(fibers) = fibers.as_mut() {
+                    let orientation = &mut fibers.orientation;
+                    let is_rule = matches!(orientation, FiberOrientation::Rule { .. });
+                    egui::ComboBox::new("cb_fiber_orientation", "")
+                        .selected_text(if is_rule { "Rule" } else { "File" })
+                        .show_ui(ui, |ui| {
+                            if ui.selectable_label(is_rule, "Rule").clicked() {
+                                *orientation = FiberOrientation::default();
+                            }
+                            if ui.selectable_label(!is_rule, "File").clicked() {
+                                *orientation = FiberOrientation::File {
+                                    path: PathBuf::from("assets/fibers.npy"),
+                                };
+                            }
+                        });
+                }
+            });
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Whether conduction is faster along the fibers than across \
+                    them. The velocities above are the ones along the fibers.",
+                )
+                .truncate(),
+            );
+        });
+    });
+    let Some(fibers) = fibers.as_mut() else {
+        return;
+    };
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Fiber orientation");
+        });
+        row.col(|ui| match &mut fibers.orientation {
+            FiberOrientation::Rule {
+                endocardial_angle_deg,
+                epicardial_angle_deg,
+            } => {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(endocardial_angle_deg)
+                            .range(-90.0..=90.0)
+                            .suffix(" °"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(epicardial_angle_deg)
+                            .range(-90.0..=90.0)
+                            .suffix(" °"),
+                    );
+                });
+            }
+            FiberOrientation::File { path } => {
+                let mut text = path.to_str().unwrap_or_default().to_string();
+                ui.add(egui::TextEdit::singleline(&mut text));
+                *path = PathBuf::from(text);
+            }
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Helix angles to the x-axis in the lowest and highest \
+                    layer along z, or the .npy file of fiber directions.",
+                )
+                .truncate(),
+            );
+        });
+    });
+    body.row(ROW_HEIGHT, |mut row| {
+        row.col(|ui| {
+            ui.label("Transverse ratio");
+        });
+        row.col(|ui| {
+            ui.add(egui::Slider::new(
+                &mut fibers.transverse_velocity_ratio,
+                0.05..=1.0,
+            ));
+        });
+        row.col(|ui| {
+            ui.add(
+                egui::Label::new(
+                    "Velocity across the fibers as a fraction of the velocity \
+                    along them.",
+                )
+                .truncate(),
+            );
+        });
+    });
+}
+
 #[allow(clippy::too_many_lines)]
 #[tracing::instrument(skip_all, level = "trace")]
 fn draw_handcrafted_settings(ui: &mut egui::Ui, handcrafted: &mut Handcrafted, patholoical: bool) {
@@ -751,7 +1562,7 @@ fn draw_handcrafted_settings(ui: &mut egui::Ui, handcrafted: &mut Handcrafted, p
 
 #[allow(clippy::too_many_lines)]
 #[tracing::instrument(skip_all, level = "trace")]
-fn draw_mri_settings(ui: &mut egui::Ui, mri: &mut Mri, _patholoical: bool) {
+fn draw_mri_settings(ui: &mut egui::Ui, mri: &mut Mri) {
     ui.label(egui::RichText::new("MRI Model Settings").underline());
     ui.group(|ui| {
         let width = ui.available_width();
@@ -788,6 +1599,100 @@ fn draw_mri_settings(ui: &mut egui::Ui, mri: &mut Mri, _patholoical: bool) {
                         ui.add(egui::Label::new("The path to the .nii file.").truncate());
                     });
                 });
+                // Orientation
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Orientation");
+                    });
+                    row.col(|ui| {
+                        // the header is only read again when the path changes
+                        let id = egui::Id::new(("mri_orientation", &mri.path));
+                        let orientation = ui.data_mut(|data| {
+                            data.get_temp_mut_or_insert_with(id, || {
+                                read_orientation(&mri.path).map_or_else(
+                                    |_| "Unreadable file".to_string(),
+                                    |orientation| orientation.to_string(),
+                                )
+                            })
+                            .clone()
+                        });
+                        ui.label(orientation);
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Voxel axes of the scan, oblique scans are resampled.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                // Labels
+                let mut removed_label = None;
+                for (index, entry) in mri.labels.0.iter_mut().enumerate() {
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label(format!("Label\n{index}"));
+                        });
+                        row.col(|ui| {
+                            ui.horizontal(|ui| {
+                                ui.add(egui::DragValue::new(&mut entry.label));
+                                egui::ComboBox::new(format!("cb_mri_label_{index}"), "")
+                                    .selected_text(format!("{:?}", entry.voxel_type))
+                                    .show_ui(ui, |ui| {
+                                        for voxel_type in VoxelType::iter() {
+                                            ui.selectable_value(
+                                                &mut entry.voxel_type,
+                                                voxel_type,
+                                                format!("{voxel_type:?}"),
+                                            );
+                                        }
+                                    });
+                                if ui.button("Remove").clicked() {
+                                    removed_label = Some(index);
+                                }
+                            });
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The voxel type of a label id in the segmentation.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                }
+                if let Some(index) = removed_label {
+                    mri.labels.0.remove(index);
+                }
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Labels");
+                    });
+                    row.col(|ui| {
+                        ui.horizontal(|ui| {
+                            if ui.button("Add label").clicked() {
+                                let label = mri.labels.0.iter().map(|entry| entry.label).max();
+                                mri.labels.0.push(SegmentationLabel {
+                                    label: label.map_or(1, |label| label + 1),
+                                    voxel_type: VoxelType::None,
+                                });
+                            }
+                            if ui.button("Reset").clicked() {
+                                mri.labels = SegmentationLabels::default();
+                            }
+                        });
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Labels without a mapping are treated as empty space.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
             });
     });
 }
@@ -10,6 +10,7 @@ use crate::{
                 SensorArrayGeometry, SensorArrayMotion, DEFAULT_SENSOR_ORIGIN_CUBE,
                 DEFAULT_SENSOR_ORIGIN_CYLINDER,
             },
+            preprocessing::{Detrend, Preprocessing},
             simulation::Simulation,
         },
         scenario::{Scenario, Status},
@@ -26,6 +27,7 @@ pub fn draw_ui_scenario_data(parent: &mut egui::Ui, scenario: &mut Scenario) {
         parent.disable();
     }
     let simulation = &mut scenario.config.simulation;
+    let preprocessing = &mut scenario.config.preprocessing;
     egui::ScrollArea::vertical()
         .id_salt("simulation")
         .vscroll(true)
@@ -35,6 +37,8 @@ pub fn draw_ui_scenario_data(parent: &mut egui::Ui, scenario: &mut Scenario) {
             ui.separator();
             draw_basic_settings(ui, simulation);
             draw_sensor_settings(ui, simulation);
+            draw_noise_settings(ui, simulation);
+            draw_preprocessing_settings(ui, preprocessing);
             draw_general_heart_settings(ui, simulation);
             draw_ui_scenario_common(ui, &mut simulation.model);
         });
@@ -102,6 +106,443 @@ fn draw_basic_settings(ui: &mut egui::Ui, simulation: &mut Simulation) {
                         );
                     });
                 });
+                // Onset jitter
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Onset jitter");
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Slider::new(&mut simulation.onset_jitter_ms, 0.0..=100.0)
+                                .suffix(" ms"),
+                        );
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "The standard deviation of the beat onsets. Zero disables it.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                // Seed
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Seed");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::DragValue::new(&mut simulation.seed).speed(1.0));
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new("The seed of the random noise and the onset jitter.")
+                                .truncate(),
+                        );
+                    });
+                });
+            });
+    });
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_preprocessing_settings(ui: &mut egui::Ui, preprocessing: &mut Preprocessing) {
+    ui.label(egui::RichText::new("Preprocessing Settings").underline());
+    ui.group(|ui| {
+        let width = ui.available_width();
+        TableBuilder::new(ui)
+            .column(Column::exact(FIRST_COLUMN_WIDTH))
+            .column(Column::exact(SECOND_COLUMN_WIDTH))
+            .column(Column::exact(
+                width - FIRST_COLUMN_WIDTH - SECOND_COLUMN_WIDTH - PADDING,
+            ))
+            .striped(true)
+            .header(ROW_HEIGHT, |mut header| {
+                header.col(|ui| {
+                    ui.heading("Parameter");
+                });
+                header.col(|ui| {
+                    ui.heading("Value");
+                });
+                header.col(|ui| {
+                    ui.heading("Description");
+                });
+            })
+            .body(|mut body| {
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("High-pass\ncutoff");
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Slider::new(&mut preprocessing.highpass_cutoff_hz, 0.0..=100.0)
+                                .suffix(" Hz"),
+                        );
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "The cutoff of the high-pass filter. Zero disables it.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Low-pass\ncutoff");
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Slider::new(&mut preprocessing.lowpass_cutoff_hz, 0.0..=1000.0)
+                                .suffix(" Hz"),
+                        );
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "The cutoff of the low-pass filter. Zero disables it.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Detrend");
+                    });
+                    row.col(|ui| {
+                        egui::ComboBox::new("cb_detrend", "")
+                            .selected_text(format!("{:?}", preprocessing.detrend))
+                            .show_ui(ui, |ui| {
+                                ui.selectable_value(&mut preprocessing.detrend, Detrend::None, "None");
+                                ui.selectable_value(
+                                    &mut preprocessing.detrend,
+                                    Detrend::Constant,
+                                    "Constant",
+                                );
+                                ui.selectable_value(
+                                    &mut preprocessing.detrend,
+                                    Detrend::Linear,
+                                    "Linear",
+                                );
+                            });
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Removes the mean or a linear trend from every channel.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Channel\nrejection");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(
+                            &mut preprocessing.channel_rejection_threshold,
+                            0.0..=10.0,
+                        ));
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Rejects channels with a standard deviation above this multiple of the median. Zero disables it.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("SSP\ncomponents");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut preprocessing.ssp_components, 0..=10));
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "The number of noise components removed by signal-space projection.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Resample");
+                    });
+                    row.col(|ui| {
+                        let mut resample = preprocessing.target_sample_rate_hz.is_some();
+                        ui.checkbox(&mut resample, "");
+                        if resample != preprocessing.target_sample_rate_hz.is_some() {
+                            preprocessing.target_sample_rate_hz = resample.then_some(1000.0);
+                        }
+                    });
+                    row.col(|ui| {
+                        ui.add(
+                            egui::Label::new(
+                                "Resamples the measurements before the algorithm runs.",
+                            )
+                            .truncate(),
+                        );
+                    });
+                });
+                if let Some(target_sample_rate_hz) = preprocessing.target_sample_rate_hz.as_mut() {
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Target\nsample rate");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Slider::new(target_sample_rate_hz, 1.0..=48000.0).suffix(" Hz"),
+                            );
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The sample rate the measurements are resampled to.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                }
+                if preprocessing.beat_averaging.is_enabled() {
+                    let beat_averaging = &mut preprocessing.beat_averaging;
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Window\nbefore R-peak");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Slider::new(&mut beat_averaging.window_before_ms, 0.0..=1000.0)
+                                    .suffix(" ms"),
+                            );
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The start of the averaged window relative to the R-peak.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Detection\nthreshold");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(
+                                &mut beat_averaging.detection_threshold,
+                                0.0..=1.0,
+                            ));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "The fraction of the largest peak an R-peak has to exceed.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Refractory\nperiod");
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Slider::new(
+                                    &mut beat_averaging.refractory_period_ms,
+                                    0.0..=2000.0,
+                                )
+                                .suffix(" ms"),
+                            );
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new("The minimum distance between two R-peaks.")
+                                    .truncate(),
+                            );
+                        });
+                    });
+                    body.row(ROW_HEIGHT, |mut row| {
+                        row.col(|ui| {
+                            ui.label("Minimum\ncorrelation");
+                        });
+                        row.col(|ui| {
+                            ui.add(egui::Slider::new(
+                                &mut beat_averaging.minimum_correlation,
+                                -1.0..=1.0,
+                            ));
+                        });
+                        row.col(|ui| {
+                            ui.add(
+                                egui::Label::new(
+                                    "Beats with a lower correlation to the mean beat are rejected.",
+                                )
+                                .truncate(),
+                            );
+                        });
+                    });
+                }
+            });
+    });
+}
+
+#[allow(clippy::too_many_lines)]
+#[tracing::instrument(skip_all, level = "trace")]
+fn draw_noise_settings(ui: &mut egui::Ui, simulation: &mut Simulation) {
+    ui.label(egui::RichText::new("Noise Settings").underline());
+    ui.group(|ui| {
+        let width = ui.available_width();
+        TableBuilder::new(ui)
+            .column(Column::exact(FIRST_COLUMN_WIDTH))
+            .column(Column::exact(SECOND_COLUMN_WIDTH))
+            .column(Column::exact(
+                width - FIRST_COLUMN_WIDTH - SECOND_COLUMN_WIDTH - PADDING,
+            ))
+            .striped(true)
+            .header(ROW_HEIGHT, |mut header| {
+                header.col(|ui| {
+                    ui.heading("Parameter");
+                });
+                header.col(|ui| {
+                    ui.heading("Value");
+                });
+                header.col(|ui| {
+                    ui.heading("Description");
+                });
+            })
+            .body(|mut body| {
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Pink noise\namplitude");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.pink.amplitude, 0.0..=10.0).suffix(" pT"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The standard deviation of the 1/f noise. Zero disables it.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Pink noise\nexponent");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.pink.exponent, 0.0..=2.0));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The exponent of the 1/f^a spectrum. Default: 1.0.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Powerline\namplitude");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.powerline.amplitude, 0.0..=10.0).suffix(" pT"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The amplitude of the power-line fundamental. Zero disables it.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Powerline\nfrequency");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.powerline.frequency_hz, 40.0..=70.0).suffix(" Hz"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The power-line frequency. Default: 50.0 Hz.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Powerline\nharmonics");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.powerline.harmonics, 0..=10));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The number of harmonics added to the fundamental. Default: 3.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Harmonic\ndecay");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.powerline.harmonic_decay, 0.0..=1.0));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The amplitude ratio between consecutive harmonics. Default: 0.5.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Drift\namplitude");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.drift.amplitude, 0.0..=10.0).suffix(" pT"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The standard deviation of the baseline drift. Zero disables it.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Drift time\nconstant");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.drift.time_constant_s, 0.01..=10.0).suffix(" s"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The time constant of the drift. Default: 1.0 s.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Spike\namplitude");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.spikes.amplitude, 0.0..=100.0).suffix(" pT"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The height of spikes and jumps. Zero disables them.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Spike\nrate");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.spikes.rate_hz, 0.0..=100.0).suffix(" Hz"));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The expected number of events per second and sensor. Default: 1.0 Hz.").truncate());
+                    });
+                });
+                body.row(ROW_HEIGHT, |mut row| {
+                    row.col(|ui| {
+                        ui.label("Jump\nprobability");
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Slider::new(&mut simulation.noise.spikes.jump_probability, 0.0..=1.0));
+                    });
+                    row.col(|ui| {
+                        ui.add(egui::Label::new("The fraction of events that shift the baseline. Default: 0.2.").truncate());
+                    });
+                });
             });
     });
 }
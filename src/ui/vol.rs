@@ -300,7 +300,7 @@ pub fn draw_ui_volumetric(
                         };
                     }
                 }
-                let samplerate_hz = f64::from(scenario.config.simulation.sample_rate_hz);
+                let samplerate_hz = f64::from(sample_tracker.sample_rate);
                 let signal: PlotPoints = (0..sample_tracker.max_sample)
                     .map(|i| {
                         #[allow(clippy::cast_precision_loss)]
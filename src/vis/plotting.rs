@@ -27,6 +27,8 @@ const AXIS_LABEL_NUM_MAX: usize = 10;
 
 const LEGEND_PATH_LENGTH: i32 = 20;
 const LEGEND_OPACITY: f64 = 0.8;
+const SHADED_REGION_COLOR: RGBColor = RGBColor(100, 100, 100);
+const SHADED_REGION_OPACITY: f64 = 0.15;
 
 const COLORS: [RGBColor; 12] = [
     RGBColor(0, 114, 178),   // Blue
@@ -10,7 +10,8 @@ use crate::{
     core::data::shapes::SystemStates,
     vis::plotting::{
         allocate_buffer, AXIS_LABEL_AREA, AXIS_STYLE, CAPTION_STYLE, CHART_MARGIN, COLORS,
-        LEGEND_OPACITY, LEGEND_PATH_LENGTH, STANDARD_RESOLUTION, X_MARGIN, Y_MARGIN,
+        LEGEND_OPACITY, LEGEND_PATH_LENGTH, SHADED_REGION_COLOR, SHADED_REGION_OPACITY,
+        STANDARD_RESOLUTION, X_MARGIN, Y_MARGIN,
     },
 };
 
@@ -18,7 +19,7 @@ use crate::{
 ///
 /// Saves the plot to the optionally provided path as a PNG,
 /// returns the raw pixel buffer.
-#[allow(clippy::cast_precision_loss, clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments)]
 #[tracing::instrument(level = "trace")]
 pub fn line_plot<A>(
     x: Option<&Array1<f32>>,
@@ -34,6 +35,45 @@ where
     A: Data<Elem = f32>,
 {
     trace!("Generating xy plot.");
+    shaded_line_plot(
+        x,
+        ys,
+        &[],
+        path,
+        title,
+        y_label,
+        x_label,
+        item_labels,
+        resolution,
+    )
+}
+
+/// Generates an XY plot from the provided x and y data and shades the
+/// given (start, end) regions of the x axis, e.g. the fitted time windows.
+///
+/// Saves the plot to the optionally provided path as a PNG,
+/// returns the raw pixel buffer.
+#[allow(
+    clippy::cast_precision_loss,
+    clippy::too_many_arguments,
+    clippy::too_many_lines
+)]
+#[tracing::instrument(level = "trace")]
+pub fn shaded_line_plot<A>(
+    x: Option<&Array1<f32>>,
+    ys: Vec<&ArrayBase<A, Ix1>>,
+    shaded_regions: &[(f32, f32)],
+    path: Option<&Path>,
+    title: Option<&str>,
+    y_label: Option<&str>,
+    x_label: Option<&str>,
+    item_labels: Option<&Vec<&str>>,
+    resolution: Option<(u32, u32)>,
+) -> Result<PngBundle, Box<dyn Error>>
+where
+    A: Data<Elem = f32>,
+{
+    trace!("Generating shaded xy plot.");
 
     let (width, height) = resolution.unwrap_or(STANDARD_RESOLUTION);
 
@@ -114,6 +154,13 @@ where
             .y_label_style(AXIS_STYLE.into_font())
             .draw()?;
 
+        chart.draw_series(shaded_regions.iter().map(|(start, end)| {
+            Rectangle::new(
+                [(*start, y_min), (*end, y_max)],
+                SHADED_REGION_COLOR.mix(SHADED_REGION_OPACITY).filled(),
+            )
+        }))?;
+
         for (i, y) in ys.iter().enumerate() {
             let color = &COLORS[i % COLORS.len()];
             if let Some(item_labels) = item_labels {
@@ -405,6 +452,45 @@ where
     )
 }
 
+/// Generates a time plot from the provided y values and sample rate and
+/// shades the given (start, end) regions in seconds.
+///
+/// Used to show the fitted time windows of a beat. Behaves like
+/// [`standard_time_plot`] otherwise.
+#[allow(clippy::cast_precision_loss)]
+#[tracing::instrument(level = "trace")]
+pub fn shaded_time_plot<A>(
+    y: &ArrayBase<A, Ix1>,
+    sample_rate_hz: f32,
+    shaded_regions_s: &[(f32, f32)],
+    path: &Path,
+    title: &str,
+    y_label: &str,
+) -> Result<PngBundle, Box<dyn Error>>
+where
+    A: Data<Elem = f32>,
+{
+    trace!("Generating shaded time plot.");
+    if sample_rate_hz <= 0.0 {
+        return Err(Box::new(std::io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "sample_rate_hz must be greater than zero",
+        )));
+    }
+    let x = Array1::linspace(0.0, y.len() as f32 / sample_rate_hz, y.len());
+    shaded_line_plot(
+        Some(&x),
+        vec![y],
+        shaded_regions_s,
+        Some(path),
+        Some(title),
+        Some(y_label),
+        Some("t [s]"),
+        None,
+        None,
+    )
+}
+
 /// Generates a plot of the x, y, and z values for a specific state index from
 /// the provided system state data.
 ///
@@ -748,6 +834,28 @@ mod test {
         assert!(files[0].is_file());
     }
 
+    #[test]
+    fn test_shaded_time_plot() {
+        let path = Path::new(COMMON_PATH);
+        setup_folder(path.to_path_buf());
+        let files = vec![path.join("shaded_time_plot.png")];
+        clean_files(&files);
+
+        let y = Array1::linspace(0.0, 1.0, 100).mapv(|x: f32| (10.0 * x).sin());
+
+        shaded_time_plot(
+            &y,
+            100.0,
+            &[(0.1, 0.3), (0.6, 0.7)],
+            files[0].as_path(),
+            "Test Plot",
+            "Y Label",
+        )
+        .unwrap();
+
+        assert!(files[0].is_file());
+    }
+
     #[test]
     fn test_standard_time_plot_zero_sample_rate() {
         let path = Path::new(COMMON_PATH);
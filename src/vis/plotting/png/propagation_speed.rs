@@ -1,7 +1,7 @@
 use core::f32;
 use std::{error::Error, path::Path};
 
-use ndarray::{Array2, Axis};
+use ndarray::{Array3, Axis};
 use tracing::trace;
 
 use super::PngBundle;
@@ -26,66 +26,97 @@ pub(crate) fn average_propagation_speed_plot(
     slice: Option<PlotSlice>,
 ) -> Result<PngBundle, Box<dyn Error>> {
     trace!("Generating activation time plot");
+    let speeds = average_delays
+        .propagation_speeds_m_per_s(voxel_numbers, voxel_size_mm, sample_rate_hz)
+        .mapv(|speed| speed.unwrap_or(0.0));
+    speed_slice_plot(
+        &speeds,
+        voxel_positions_mm,
+        voxel_size_mm,
+        path,
+        slice,
+        "Average Propagation Speed",
+    )
+}
+
+/// Plots the error of the estimated average propagation speed for a given
+/// slice (x, y or z).
+#[tracing::instrument(level = "trace")]
+pub(crate) fn propagation_speed_error_plot(
+    speed_error_m_per_s: &Array3<f32>,
+    voxel_positions_mm: &VoxelPositions,
+    voxel_size_mm: f32,
+    path: &Path,
+    slice: Option<PlotSlice>,
+) -> Result<PngBundle, Box<dyn Error>> {
+    trace!("Generating propagation speed error plot");
+    speed_slice_plot(
+        speed_error_m_per_s,
+        voxel_positions_mm,
+        voxel_size_mm,
+        path,
+        slice,
+        "Propagation Speed Error",
+    )
+}
+
+#[tracing::instrument(level = "trace", skip(speeds_m_per_s, voxel_positions_mm))]
+fn speed_slice_plot(
+    speeds_m_per_s: &Array3<f32>,
+    voxel_positions_mm: &VoxelPositions,
+    voxel_size_mm: f32,
+    path: &Path,
+    slice: Option<PlotSlice>,
+    name: &str,
+) -> Result<PngBundle, Box<dyn Error>> {
     let slice = slice.unwrap_or(PlotSlice::Z(0));
     let step = Some((voxel_size_mm, voxel_size_mm));
 
-    let (numbers, offset, title, x_label, y_label, flip_axis) = match slice {
+    let (data, offset, title, x_label, y_label, flip_axis) = match slice {
         PlotSlice::X(index) => {
-            let numbers = voxel_numbers.index_axis(Axis(0), index);
+            let data = speeds_m_per_s.index_axis(Axis(0), index);
             let offset = Some((
                 voxel_positions_mm[(0, 0, 0, 1)],
                 voxel_positions_mm[(0, 0, 0, 2)],
             ));
             let x = voxel_positions_mm[(index, 0, 0, 0)];
-            let title = format!("Average Propagation Speed x-index = {index}, x = {x} mm");
+            let title = format!("{name} x-index = {index}, x = {x} mm");
             let x_label = Some("y [mm]");
             let y_label = Some("z [mm]");
             let flip_axis = Some((true, false));
 
-            (numbers, offset, title, x_label, y_label, flip_axis)
+            (data, offset, title, x_label, y_label, flip_axis)
         }
         PlotSlice::Y(index) => {
-            let numbers = voxel_numbers.index_axis(Axis(1), index);
+            let data = speeds_m_per_s.index_axis(Axis(1), index);
             let offset = Some((
                 voxel_positions_mm[(0, 0, 0, 0)],
                 voxel_positions_mm[(0, 0, 0, 2)],
             ));
             let y = voxel_positions_mm[(0, index, 0, 1)];
-            let title = format!("Average Propagation Speed y-index = {index}, y = {y} mm");
+            let title = format!("{name} y-index = {index}, y = {y} mm");
             let x_label = Some("x [mm]");
             let y_label = Some("z [mm]");
             let flip_axis = Some((false, false));
 
-            (numbers, offset, title, x_label, y_label, flip_axis)
+            (data, offset, title, x_label, y_label, flip_axis)
         }
         PlotSlice::Z(index) => {
-            let numbers = voxel_numbers.index_axis(Axis(2), index);
+            let data = speeds_m_per_s.index_axis(Axis(2), index);
             let offset = Some((
                 voxel_positions_mm[(0, 0, 0, 0)],
                 voxel_positions_mm[(0, 0, 0, 1)],
             ));
             let z = voxel_positions_mm[(0, 0, index, 2)];
-            let title = format!("Average Propagation Speed z-index = {index}, z = {z} mm");
+            let title = format!("{name} z-index = {index}, z = {z} mm");
             let x_label = Some("x [mm]");
             let y_label = Some("y [mm]");
             let flip_axis = Some((false, false));
 
-            (numbers, offset, title, x_label, y_label, flip_axis)
+            (data, offset, title, x_label, y_label, flip_axis)
         }
     };
 
-    let mut data = Array2::zeros(numbers.raw_dim());
-
-    data.iter_mut()
-        .zip(numbers.iter())
-        .for_each(|(datum, number)| {
-            if number.is_some() {
-                *datum = voxel_size_mm
-                    / 1000.0
-                    / (average_delays[number.unwrap() / 3].unwrap_or(f32::MAX) / sample_rate_hz);
-            }
-        });
-
     matrix_plot(
         &data,
         None,
@@ -48,14 +48,9 @@ impl Default for SampleTracker {
 pub fn init_sample_tracker(sample_tracker: &mut SampleTracker, scenario: &Scenario) {
     debug!("Initializing sample tracker.");
     sample_tracker.current_sample = 0;
-    sample_tracker.max_sample = scenario
-        .data
-        .as_ref()
-        .expect("Data to be some")
-        .simulation
-        .measurements
-        .num_steps();
-    sample_tracker.sample_rate = scenario.config.simulation.sample_rate_hz;
+    let simulation = &scenario.data.as_ref().expect("Data to be some").simulation;
+    sample_tracker.max_sample = simulation.measurements.num_steps();
+    sample_tracker.sample_rate = simulation.sample_rate_hz;
 }
 /// If not in manual mode, calculates a new sample index based on the elapsed
 /// time, sample rate, and playback speed. Takes the result modulo the max sample
@@ -16,6 +16,7 @@ pub(crate) struct SensorData {
 /// Then iterates through the sensor positions and orientations defined in the scenario,
 /// and spawns an entity for each with the appropriate transform.
 /// The color and scale are also set based on the sensor orientation.
+/// Sensors masked in the estimation are drawn in grey.
 #[allow(clippy::needless_pass_by_value)]
 #[tracing::instrument(level = "debug", skip_all)]
 pub(crate) fn spawn_sensors(
@@ -57,11 +58,28 @@ pub(crate) fn spawn_sensors(
         ..Default::default()
     });
 
+    let material_grey = materials.add(StandardMaterial {
+        base_color: Color::srgb(0.4, 0.4, 0.4),
+        metallic: 0.0,
+        ..Default::default()
+    });
+
+    let sensor_weights = scenario
+        .results
+        .as_ref()
+        .and_then(|results| results.model.as_ref())
+        .map(|model| &model.functional_description.sensor_weights);
+
     for index_sensor in 0..sensors.positions_mm.shape()[0] {
-        let material = match index_sensor % 3 {
-            0 => material_red.clone(),
-            1 => materials_green.clone(),
-            _ => material_blue.clone(),
+        let masked = sensor_weights.is_some_and(|weights| weights.is_masked(index_sensor));
+        let material = if masked {
+            material_grey.clone()
+        } else {
+            match index_sensor % 3 {
+                0 => material_red.clone(),
+                1 => materials_green.clone(),
+                _ => material_blue.clone(),
+            }
         };
         let mut positions_mm = Array2::zeros((motion_steps, 3));
         for i in 0..motion_steps {